@@ -8,25 +8,40 @@ use crate::{
     convert_rawstr_to_string, 
     routes as Routes,
     DatabaseItem,
-    auth::{generate_http_cookie, get_username_from_cookie},
-    config::JWT_NAME,
+    markup::BodyFormat,
+    auth::{ClientIp, generate_http_cookie, get_username_from_cookie},
+    config::{JWT_NAME, UPLOAD_MAX_BYTES},
     models::{
+        annotation::DocAnnotation,
+        collection::{DocCollection, parse_tags},
+        feed::FeedSubscription,
+        job::IngestJob,
+        progress::ReadingProgress,
         public::{AppFeedback, SandboxDoc},
-        user::{User, UserDoc, UserVocab, UserVocabList},
-        zh::{CnType, CnPhonetics}
+        search::{DocSearchIndex, VocabOccurrences},
+        user::{DocVisibility, User, UserDoc, UserDocEdit, UserVocab, UserVocabList},
+        zh::{CnType, CnPhonetics, CnGranularity, CnRegion}
     }
 };
 use mongodb::{
     bson::{doc, document::Document},
     sync::Database
 };
+use multipart::server::Multipart;
 use rocket::{
-    http::{RawStr, Cookie, Cookies, Status},
+    data::Data,
+    http::{RawStr, ContentType, Cookie, Cookies, Status},
     request::Form,
     response::Redirect,
     State,
 };
 use rocket_contrib::{json, json::{Json, JsonValue}};
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{Cursor, Read},
+    thread
+};
 use tokio::runtime::Handle;
 
 // /* GET */
@@ -42,7 +57,7 @@ pub fn get_doc(cookies: Cookies, db: State<Database>, doc_id: &RawStr) -> Json<J
     let res: Document = match user_doc_query {
         Some(doc) => doc,
         None => {
-            match SandboxDoc::try_lookup_one(&db, doc!{"doc_id": &doc_id}) {
+            match SandboxDoc::lookup_unexpired(&db, &doc_id) {
                 Some(doc) => doc,
                 None => doc!{"error": "No document found"}
             }
@@ -50,22 +65,29 @@ pub fn get_doc(cookies: Cookies, db: State<Database>, doc_id: &RawStr) -> Json<J
     };
     return Json(json!{res});
 }
-/// /api/get-all-user-items
-#[get("/get-all-user-items")]
-pub fn get_all_user_items(cookies: Cookies, db: State<Database>) -> Json<JsonValue> {
+/// /api/get-all-user-items?<collection_id>&<tag>
+/// If collection_id (including its sub-collections, or empty for docs without one) and/or tag is given,
+/// only matching docs and the vocab saved from them are returned.
+#[get("/get-all-user-items?<collection_id>&<tag>")]
+pub fn get_all_user_items(cookies: Cookies, db: State<Database>, collection_id: Option<&RawStr>, tag: Option<&RawStr>) -> Json<JsonValue> {
     let username = get_username_from_cookie(&db, cookies.get(JWT_NAME)).unwrap();
     let (cn_type, cn_phonetics) = User::get_user_settings(&db, &username);
-    let doc_list = UserDoc::try_lookup_all(&db,
-        doc!{"username": &username},
-    ).unwrap();
-    let vocab_list = UserVocab::try_lookup_all(&db,
-        doc!{"username": &username}
-    ).unwrap();
+    let collection_id = collection_id.map(convert_rawstr_to_string);
+    let tag = tag.map(convert_rawstr_to_string);
+    let doc_query = UserDoc::generate_filter_query(&db, &username, collection_id.as_deref(), tag.as_deref());
+    let mut vocab_query = doc!{"username": &username};
+    if collection_id.is_some() || tag.is_some() {
+        let doc_ids = UserDoc::lookup_doc_ids(&db, doc_query.clone());
+        vocab_query.insert("from_doc_id", doc!{"$in": doc_ids});
+    }
+    let doc_list = UserDoc::try_lookup_all(&db, doc_query).unwrap();
+    let vocab_list = UserVocab::try_lookup_all(&db, vocab_query).unwrap();
     return Json(json!({
         "cn_type": cn_type,
         "cn_phonetics": cn_phonetics,
         "doc_list": doc_list, // Vec<Document>
         "vocab_list": vocab_list, // Vec<Document>
+        "collections": format_collection_list(&db, &username),
     }));
 }
 /// /api/get-user-vocab-string
@@ -80,12 +102,106 @@ pub fn get_user_vocab_string(cookies: Cookies, db: State<Database>) -> Json<Json
         "res": res
     }));
 }
-/// /api/delete-user-doc/<doc_title>
-#[get("/delete-user-doc/<doc_title>")]
-pub fn delete_user_doc(cookies: Cookies, db: State<Database>, rt: State<Handle>, doc_title: &RawStr) -> Redirect {
-    let title = convert_rawstr_to_string(doc_title);
+/// /api/jobs/<job_id>
+/// Returns the status/progress of the user's IngestJob. doc_title is set once status is "Done".
+#[get("/jobs/<job_id>")]
+pub fn get_job_status(cookies: Cookies, db: State<Database>, job_id: &RawStr) -> Json<JsonValue> {
+    let job_id = convert_rawstr_to_string(job_id);
+    let job = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => IngestJob::from_job_id(&db, &username, &job_id),
+        None => None
+    };
+    let res = match job {
+        Some(job) => json!({
+            "job_id": job.job_id,
+            "doc_id": job.doc_id,
+            "status": job.status.as_str(),
+            "stage": job.stage,
+            "progress": job.progress,
+            "error": job.error,
+            "doc_title": job.doc_title,
+        }),
+        None => json!({"error": "No job found"})
+    };
+    return Json(res);
+}
+/// /api/search/docs?<q>
+/// Searches the user's docs by Hanzi, pinyin, or English (refer to DocSearchIndex::search).
+/// Each result has the doc_id, title, title_html, n_matches, and snippets (HTML with matches in <mark>).
+#[get("/search/docs?<q>")]
+pub fn search_docs(cookies: Cookies, db: State<Database>, q: &RawStr) -> Json<JsonValue> {
+    let username = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => username,
+        None => return Json(json!({"error": "Not authenticated as user"}))
+    };
+    let q = convert_rawstr_to_string(q);
+    let res = match DocSearchIndex::search(&db, &username, &q) {
+        Ok(results) => json!({
+            "query": q,
+            "results": results,
+        }),
+        Err(e) => json!({"error": e.to_string()})
+    };
+    return Json(res);
+}
+/// /api/vocab/<uid>/occurrences
+/// Returns keyword-in-context lines for every occurrence of the dictionary entry in the user's docs (refer to VocabOccurrences::lookup_concordance).
+/// Each line has the doc_id, doc_title, token_index, page, left, phrase, right, and url (opening the reader at the occurrence).
+#[get("/vocab/<uid>/occurrences")]
+pub fn get_vocab_occurrences(cookies: Cookies, db: State<Database>, uid: &RawStr) -> Json<JsonValue> {
+    let username = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => username,
+        None => return Json(json!({"error": "Not authenticated as user"}))
+    };
+    let uid = convert_rawstr_to_string(uid);
+    let (lines, n_total) = VocabOccurrences::lookup_concordance(&db, &username, &uid);
+    return Json(json!({
+        "uid": uid,
+        "n_total": n_total,
+        "lines": lines,
+    }));
+}
+/// /api/progress/<doc_id>
+/// Returns the user's last-read position in the UserDoc (page 1, token 0 if they haven't started it).
+#[get("/progress/<doc_id>")]
+pub fn get_reading_progress(cookies: Cookies, db: State<Database>, doc_id: &RawStr) -> Json<JsonValue> {
+    let username = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => username,
+        None => return Json(json!({"error": "Not authenticated as user"}))
+    };
+    let doc_id = convert_rawstr_to_string(doc_id);
+    let res = match ReadingProgress::from_doc_id(&db, &username, &doc_id) {
+        Some(progress) => json!({
+            "doc_id": doc_id,
+            "page": progress.page,
+            "token_index": progress.token_index,
+            "percent_complete": progress.percent_complete(),
+        }),
+        None => json!({"doc_id": doc_id, "page": 1, "token_index": 0, "percent_complete": 0})
+    };
+    return Json(res);
+}
+/// /api/annotations/<doc_id>
+/// Returns the user's annotations in the UserDoc (in reading order). Each has the annotation_id, token_start, token_end (exclusive), text, and note.
+#[get("/annotations/<doc_id>")]
+pub fn get_doc_annotations(cookies: Cookies, db: State<Database>, doc_id: &RawStr) -> Json<JsonValue> {
+    let username = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => username,
+        None => return Json(json!({"error": "Not authenticated as user"}))
+    };
+    let doc_id = convert_rawstr_to_string(doc_id);
+    let annotations = DocAnnotation::lookup_all(&db, &username, &doc_id);
+    return Json(json!({
+        "doc_id": doc_id,
+        "annotations": annotations,
+    }));
+}
+/// /api/delete-user-doc/<doc_id>
+#[get("/delete-user-doc/<doc_id>")]
+pub fn delete_user_doc(cookies: Cookies, db: State<Database>, rt: State<Handle>, doc_id: &RawStr) -> Redirect {
+    let doc_id = convert_rawstr_to_string(doc_id);
     let username = get_username_from_cookie(&db, cookies.get(JWT_NAME)).unwrap();
-    rt.block_on(UserDoc::try_delete(&db, &username, &title));
+    rt.block_on(UserDoc::try_delete(&db, &username, &doc_id));
     return Redirect::to(uri!(Routes::user_profile: &username));
 }
 /// /api/delete-user-vocab/<vocab_uid>
@@ -97,6 +213,23 @@ pub fn delete_user_vocab(cookies: Cookies, db: State<Database>, rt: State<Handle
     rt.block_on(UserVocab::try_delete(&db, &username, &phrase_uid, &cn_type));
     return Redirect::to(uri!(Routes::user_profile: &username));
 }
+/// /api/delete-feed/<feed_id>
+#[get("/delete-feed/<feed_id>")]
+pub fn delete_feed(cookies: Cookies, db: State<Database>, feed_id: &RawStr) -> Redirect {
+    let feed_id = convert_rawstr_to_string(feed_id);
+    let username = get_username_from_cookie(&db, cookies.get(JWT_NAME)).unwrap();
+    FeedSubscription::try_delete(&db, &username, &feed_id);
+    return Redirect::to(uri!(Routes::user_profile: &username));
+}
+/// /api/delete-collection/<collection_id>
+/// Docs and sub-collections in the collection are moved to its parent collection.
+#[get("/delete-collection/<collection_id>")]
+pub fn delete_collection(cookies: Cookies, db: State<Database>, collection_id: &RawStr) -> Redirect {
+    let collection_id = convert_rawstr_to_string(collection_id);
+    let username = get_username_from_cookie(&db, cookies.get(JWT_NAME)).unwrap();
+    DocCollection::try_delete(&db, &username, &collection_id);
+    return Redirect::to(uri!(Routes::user_profile: &username));
+}
 /// /api/logout
 #[get("/logout")]
 pub fn logout(mut cookies: Cookies) -> Redirect {
@@ -169,25 +302,34 @@ pub struct UserDocForm<'f> {
     url: &'f RawStr,
     title: &'f RawStr,
     source: &'f RawStr,
+    convert_region: Option<&'f RawStr>,
+    body_format: Option<&'f RawStr>,
 }
 /// /api/upload-user-doc
+/// Queues an IngestJob, then redirects to the job page (which shows the reader once processing is done).
 #[post("/upload-user-doc", data="<upload_doc>")]
-pub fn upload_user_doc(cookies: Cookies, db: State<Database>, rt: State<Handle>, upload_doc: Form<UserDocForm<'_>>) -> Redirect {
-    let UserDocForm { title, source, body, url } = upload_doc.into_inner();
+pub fn upload_user_doc(cookies: Cookies, db: State<Database>, upload_doc: Form<UserDocForm<'_>>) -> Redirect {
+    let UserDocForm { title, source, body, url, convert_region, body_format } = upload_doc.into_inner();
     let desired_title = convert_rawstr_to_string(title);
     let body = convert_rawstr_to_string(body);
     let source = convert_rawstr_to_string(source);
     let url = convert_rawstr_to_string(url);
+    let region_conversion = match convert_region {
+        Some(s) => CnRegion::from_str(&convert_rawstr_to_string(s)),
+        None => None
+    };
+    let body_format = match body_format {
+        Some(s) => BodyFormat::from_str(&convert_rawstr_to_string(s)),
+        None => BodyFormat::Text
+    };
     let res_status = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
         Some(username) => { 
-            let new_doc = match url.as_str() != "" {
-                true => rt.block_on(UserDoc::from_url(&db, username.clone(), url)),
-                false => rt.block_on(UserDoc::new(&db, username.clone(), desired_title, body, source))
-            };
-            match new_doc.try_insert(&db) {
-                Ok(doc_title) => Redirect::to(uri!(Routes::user_doc: &username, doc_title)),
+            // Tokenizing is done by the background workers, so the request returns right away
+            let new_job = IngestJob::new(username.clone(), desired_title, body, body_format, url, source, region_conversion);
+            match new_job.try_insert(&db) {
+                Ok(job_id) => Redirect::to(uri!(Routes::job_status: job_id)),
                 Err(e) => {
-                    eprintln!("Exception when inserting doc: {:?}", e);
+                    eprintln!("Exception when queueing doc: {:?}", e);
                     Redirect::to(uri!(Routes::user_profile: &username))
                 }
             }
@@ -196,45 +338,215 @@ pub fn upload_user_doc(cookies: Cookies, db: State<Database>, rt: State<Handle>,
     };
     return res_status;
 }
+/// Fields from a multipart/form-data upload. Text fields are in fields, the "file" field is kept as bytes.
+struct MultipartUpload {
+    fields: HashMap<String, String>,
+    filename: String,
+    file_content_type: Option<String>,
+    file_bytes: Vec<u8>
+}
+/// /api/upload-user-doc (multipart/form-data)
+/// Expected fields: title, source, convert_region, and file (.txt, .md, .html, .epub, .srt, .vtt, or .ass).
+/// Only matched when the urlencoded form above is forwarded (i.e. for multipart requests).
+/// Queues IngestJobs (one per EPUB chapter), then redirects to the first job page.
+#[post("/upload-user-doc", format="multipart/form-data", data="<data>", rank=2)]
+pub fn upload_user_doc_file(cookies: Cookies, db: State<Database>, content_type: &ContentType, data: Data) -> Redirect {
+    let username = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => username,
+        None => return Redirect::to("/")
+    };
+    let upload = match read_multipart_upload(content_type, data) {
+        Ok(upload) => upload,
+        Err(e) => {
+            eprintln!("Exception when reading file upload: {:?}", e);
+            return Redirect::to(uri!(Routes::user_profile: &username));
+        }
+    };
+    let MultipartUpload { fields, filename, file_content_type, file_bytes } = upload;
+    let desired_title = fields.get("title").cloned().unwrap_or_default();
+    let source = fields.get("source").cloned().unwrap_or_default();
+    let region_conversion = match fields.get("convert_region") {
+        Some(s) => CnRegion::from_str(s),
+        None => None
+    };
+    let new_jobs = IngestJob::from_file(username.clone(), desired_title, source, &filename,
+        file_content_type.as_deref(), &file_bytes, region_conversion);
+    let res = match new_jobs {
+        Ok(new_jobs) => {
+            // Redirect to the first job (e.g. the first EPUB chapter)
+            let mut first_job_id: Option<String> = None;
+            for new_job in new_jobs {
+                match new_job.try_insert(&db) {
+                    Ok(job_id) => { first_job_id.get_or_insert(job_id); },
+                    Err(e) => eprintln!("Exception when queueing job: {:?}", e)
+                }
+            }
+            match first_job_id {
+                Some(job_id) => Redirect::to(uri!(Routes::job_status: job_id)),
+                None => Redirect::to(uri!(Routes::user_profile: &username))
+            }
+        },
+        Err(e) => {
+            eprintln!("Exception when processing file upload: {:?}", e);
+            Redirect::to(uri!(Routes::user_profile: &username))
+        }
+    };
+    return res;
+}
+/// Reads a multipart/form-data body (up to UPLOAD_MAX_BYTES).
+fn read_multipart_upload(content_type: &ContentType, data: Data) -> Result<MultipartUpload, Box<dyn Error>> {
+    let boundary = content_type.params()
+        .find(|&(k, _)| k == "boundary")
+        .map(|(_, v)| String::from(v))
+        .ok_or("Multipart request has no boundary")?;
+    let mut body = Vec::new();
+    data.open().take(UPLOAD_MAX_BYTES as u64 + 1).read_to_end(&mut body)?;
+    if body.len() > UPLOAD_MAX_BYTES {
+        return Err(Box::from(format!("Upload is larger than {} bytes", UPLOAD_MAX_BYTES)));
+    }
+    let mut upload = MultipartUpload { fields: HashMap::new(), filename: String::new(), file_content_type: None, file_bytes: Vec::new() };
+    let mut multipart = Multipart::with_body(Cursor::new(body), boundary);
+    while let Some(mut entry) = multipart.read_entry()? {
+        let mut buf = Vec::new();
+        entry.data.read_to_end(&mut buf)?;
+        match (&*entry.headers.name, entry.headers.filename) {
+            ("file", Some(filename)) => {
+                upload.filename = filename;
+                upload.file_content_type = entry.headers.content_type.map(|mime| mime.to_string());
+                upload.file_bytes = buf;
+            },
+            (name, _) => {
+                upload.fields.insert(String::from(name), String::from_utf8_lossy(&buf).into_owned());
+            }
+        }
+    }
+    if upload.filename.len() == 0 {
+        return Err(Box::from("Multipart request has no file"));
+    }
+    return Ok(upload);
+}
+#[derive(FromForm)]
+pub struct FeedSubscriptionForm<'f> {
+    feed_url: &'f RawStr,
+    feed_name: Option<&'f RawStr>,
+}
+/// /api/subscribe-feed
+/// The first poll (which queues an IngestJob per new article) runs in the background, so the redirect doesn't wait on refetching the feed.
+#[post("/subscribe-feed", data="<feed_form>")]
+pub fn subscribe_feed(cookies: Cookies, db: State<Database>, rt: State<Handle>, feed_form: Form<FeedSubscriptionForm<'_>>) -> Redirect {
+    let FeedSubscriptionForm { feed_url, feed_name } = feed_form.into_inner();
+    let feed_url = convert_rawstr_to_string(feed_url);
+    let feed_name = match feed_name {
+        Some(s) => convert_rawstr_to_string(s),
+        None => String::new()
+    };
+    let res_status = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => {
+            match rt.block_on(FeedSubscription::new(username.clone(), feed_url, feed_name)) {
+                Ok(new_sub) => {
+                    match new_sub.try_insert(&db) {
+                        Ok(_) => {
+                            let (db, rt) = (db.inner().clone(), rt.inner().clone());
+                            thread::spawn(move || {
+                                if let Err(e) = rt.block_on(new_sub.poll(&db)) {
+                                    eprintln!("Exception when polling new feed: {:?}", e);
+                                }
+                            });
+                        },
+                        Err(e) => { eprintln!("Exception when inserting feed: {:?}", e); }
+                    }
+                },
+                Err(e) => { eprintln!("Exception when fetching feed: {:?}", e); }
+            }
+            Redirect::to(uri!(Routes::user_profile: &username))
+        },
+        None => Redirect::to("/")
+    };
+    return res_status;
+}
 #[derive(FromForm)]
 pub struct SandboxDocForm<'f> {
     body: &'f RawStr,
     url: &'f RawStr,
     cn_type: &'f RawStr,
     cn_phonetics: &'f RawStr,
+    cn_granularity: Option<&'f RawStr>,
 }
 /// /api/upload-sandbox-doc
+/// Uploads are limited per IP address (refer to SandboxDoc::try_reserve_quota).
 #[post("/upload-sandbox-doc", data="<upload_doc>")]
-pub fn upload_sandbox_doc(db: State<Database>, rt: State<Handle>, upload_doc: Form<SandboxDocForm<'_>>) -> Redirect {
-    let SandboxDocForm { body, url, cn_type, cn_phonetics } = upload_doc.into_inner();
+pub fn upload_sandbox_doc(db: State<Database>, rt: State<Handle>, client_ip: ClientIp, upload_doc: Form<SandboxDocForm<'_>>) -> Redirect {
+    let ClientIp(client_ip) = client_ip;
+    if !SandboxDoc::try_reserve_quota(&db, &client_ip) {
+        return Redirect::to("/sandbox?error=quota");
+    }
+    let SandboxDocForm { body, url, cn_type, cn_phonetics, cn_granularity } = upload_doc.into_inner();
     let body = convert_rawstr_to_string(body);
     let url = convert_rawstr_to_string(url);
     let cn_type = convert_rawstr_to_string(cn_type);
     let cn_phonetics = convert_rawstr_to_string(cn_phonetics);
+    let cn_granularity = match cn_granularity {
+        Some(s) => convert_rawstr_to_string(s),
+        None => String::new()
+    };
     let new_doc = match url.as_str() != "" {
-        true => rt.block_on(SandboxDoc::from_url(url, cn_type, cn_phonetics)),
-        false => rt.block_on(SandboxDoc::new(body, cn_type, cn_phonetics, url))
+        true => rt.block_on(SandboxDoc::from_url(url, cn_type, cn_phonetics, cn_granularity, &client_ip)),
+        false => Ok(rt.block_on(SandboxDoc::new(body, cn_type, cn_phonetics, cn_granularity, url, &client_ip)))
+    };
+    let res = match new_doc {
+        Ok(new_doc) => {
+            match new_doc.try_insert(&db) {
+                Ok(doc_id) => Redirect::to(uri!(Routes::sandbox_doc: doc_id)),
+                Err(e) => {
+                    eprintln!("Exception when inserting sandbox doc: {:?}", e);
+                    Redirect::to("/sandbox?error=upload")
+                }
+            }
+        },
+        Err(e) => {
+            eprintln!("Exception when fetching URL: {}", e);
+            Redirect::to("/sandbox?error=fetch")
+        }
     };
-    let doc_id = new_doc.try_insert(&db).unwrap();
-    return Redirect::to(uri!(Routes::sandbox_doc: doc_id));
+    return res;
 }
 #[derive(FromForm)]
 pub struct UserVocabForm<'f> {
     phrase_uid: &'f RawStr,
     from_doc_title: &'f RawStr,
+    from_doc_id: Option<&'f RawStr>,
+    from_share_id: Option<&'f RawStr>,
     from_sandbox: bool,
+    cue_timestamp: Option<&'f RawStr>,
 }
 /// /api/upload-vocab
+/// For shared docs (from_share_id), the vocab is linked to the share link using the shared doc's title, rather than to a doc_id.
 #[post("/upload-vocab", data="<user_vocab>")]
 pub fn upload_vocab(cookies: Cookies, db: State<Database>, rt: State<Handle>, user_vocab: Form<UserVocabForm<'_>>) -> Status {
-    let UserVocabForm { phrase_uid, from_doc_title, from_sandbox } = user_vocab.into_inner();
+    let UserVocabForm { phrase_uid, from_doc_title, from_doc_id, from_share_id, from_sandbox, cue_timestamp } = user_vocab.into_inner();
     let phrase = convert_rawstr_to_string(phrase_uid);
-    let from_doc_title = convert_rawstr_to_string(from_doc_title);
+    let from_share_id = match from_share_id {
+        Some(s) => convert_rawstr_to_string(s),
+        None => String::new()
+    };
+    let (from_doc_title, from_doc_id) = match (from_share_id.len() > 0, from_doc_id) {
+        (true, _) => match UserDoc::from_share_id(&db, &from_share_id) {
+            Some(doc_res) => (String::from(doc_res.get_str("title").unwrap_or("")), String::new()),
+            None => return Status::NotFound
+        },
+        (false, Some(s)) => (convert_rawstr_to_string(from_doc_title), convert_rawstr_to_string(s)),
+        (false, None) => (convert_rawstr_to_string(from_doc_title), String::new())
+    };
+    let cue_timestamp = match cue_timestamp {
+        Some(s) => convert_rawstr_to_string(s),
+        None => String::new()
+    };
     
     let username_from_cookie = get_username_from_cookie(&db, cookies.get(JWT_NAME));
     let res_status = match username_from_cookie {
         Some(username) => { 
-            let new_vocab = rt.block_on(UserVocab::new(&db, username, phrase, from_doc_title, from_sandbox));
+            let mut new_vocab = rt.block_on(UserVocab::new(&db, username, phrase, from_doc_title, from_doc_id, from_sandbox, cue_timestamp));
+            new_vocab.from_share_id = from_share_id;
             match new_vocab.try_insert(&db) {
                 Ok(_) => Status::Accepted,
                 Err(_) => Status::ExpectationFailed
@@ -258,7 +570,9 @@ pub fn update_settings(cookies: Cookies, db: State<Database>, user_setting: Form
         Some(username) => {
             let cn_type = CnType::from_str(&setting);
             let cn_phonetics = CnPhonetics::from_str(&setting);
-            match User::update_user_settings(&db, &username, cn_type, cn_phonetics) {
+            let cn_granularity = CnGranularity::from_str(&setting);
+            let cn_region = CnRegion::from_str(&setting);
+            match User::update_user_settings(&db, &username, cn_type, cn_phonetics, cn_granularity, cn_region) {
                 Ok(_) => Status::Accepted,
                 Err(_) => Status::BadRequest
             }
@@ -266,4 +580,271 @@ pub fn update_settings(cookies: Cookies, db: State<Database>, user_setting: Form
         None => Status::Unauthorized
     };
     return res_status;
+}
+
+#[derive(FromForm)]
+pub struct DocCollectionForm<'f> {
+    name: &'f RawStr,
+    parent_id: Option<&'f RawStr>,
+}
+/// /api/create-collection
+/// If parent_id is given, the new collection is nested in it.
+#[post("/create-collection", data="<collection_form>")]
+pub fn create_collection(cookies: Cookies, db: State<Database>, collection_form: Form<DocCollectionForm<'_>>) -> Redirect {
+    let DocCollectionForm { name, parent_id } = collection_form.into_inner();
+    let name = convert_rawstr_to_string(name);
+    let parent_id = match parent_id {
+        Some(s) => convert_rawstr_to_string(s),
+        None => String::new()
+    };
+    let res_status = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => {
+            match DocCollection::new(&db, username.clone(), name, parent_id) {
+                Ok(new_collection) => {
+                    if let Err(e) = new_collection.try_insert(&db) {
+                        eprintln!("Exception when inserting collection: {:?}", e);
+                    }
+                },
+                Err(e) => { eprintln!("Exception when creating collection: {:?}", e); }
+            }
+            Redirect::to(uri!(Routes::user_profile: &username))
+        },
+        None => Redirect::to("/")
+    };
+    return res_status;
+}
+#[derive(FromForm)]
+pub struct TagRenameForm<'f> {
+    tag: &'f RawStr,
+    new_tag: &'f RawStr,
+}
+/// /api/rename-tag
+/// Renames the tag on all of the user's UserDocs (an empty new_tag removes it). Returns the number of updated docs.
+#[post("/rename-tag", data="<tag_form>")]
+pub fn rename_tag(cookies: Cookies, db: State<Database>, tag_form: Form<TagRenameForm<'_>>) -> Json<JsonValue> {
+    let username = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => username,
+        None => return Json(json!({"error": "Not authenticated as user"}))
+    };
+    let TagRenameForm { tag, new_tag } = tag_form.into_inner();
+    let tag = convert_rawstr_to_string(tag);
+    let new_tag = convert_rawstr_to_string(new_tag);
+    let res = match UserDoc::try_rename_tag(&db, &username, &tag, &new_tag) {
+        Ok(n_updated) => json!({"n_updated": n_updated}),
+        Err(e) => json!({"error": e.to_string()})
+    };
+    return Json(res);
+}
+#[derive(FromForm)]
+pub struct DocShareForm<'f> {
+    visibility: &'f RawStr,
+}
+/// /api/share-doc/<doc_id>
+/// Sets the visibility of the user's UserDoc (Private, Unlisted, or Public). Returns the share link, which only works if the doc isn't Private.
+#[post("/share-doc/<doc_id>", data="<share_form>")]
+pub fn share_doc(cookies: Cookies, db: State<Database>, doc_id: &RawStr, share_form: Form<DocShareForm<'_>>) -> Json<JsonValue> {
+    let username = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => username,
+        None => return Json(json!({"error": "Not authenticated as user"}))
+    };
+    let doc_id = convert_rawstr_to_string(doc_id);
+    let visibility = match DocVisibility::from_str(&convert_rawstr_to_string(share_form.into_inner().visibility)) {
+        Some(visibility) => visibility,
+        None => return Json(json!({"error": "Visibility must be Private, Unlisted, or Public"}))
+    };
+    let res = match UserDoc::try_set_visibility(&db, &username, &doc_id, &visibility) {
+        Ok(share_id) => json!({
+            "doc_id": doc_id,
+            "visibility": visibility.as_str(),
+            "share_url": uri!(Routes::shared_doc: share_id).to_string(),
+        }),
+        Err(e) => json!({"error": e.to_string()})
+    };
+    return Json(res);
+}
+/// /api/clone-doc/<share_id>
+/// Copies the shared (Unlisted or Public) UserDoc into the logged-in user's library, then opens the copy.
+#[post("/clone-doc/<share_id>")]
+pub fn clone_shared_doc(cookies: Cookies, db: State<Database>, share_id: &RawStr) -> Redirect {
+    let share_id = convert_rawstr_to_string(share_id);
+    let res = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => {
+            match UserDoc::try_clone_shared(&db, &share_id, &username) {
+                Ok(doc_id) => Redirect::to(uri!(Routes::doc_reader: doc_id)),
+                Err(e) => {
+                    eprintln!("Exception when cloning shared doc {}: {:?}", share_id, e);
+                    Redirect::to(uri!(Routes::shared_doc: share_id))
+                }
+            }
+        },
+        None => Redirect::to(uri!(Routes::login))
+    };
+    return res;
+}
+#[derive(FromForm)]
+pub struct ReadingProgressForm {
+    page: i32,
+    token_index: i32,
+}
+/// /api/progress/<doc_id>
+/// Saves the user's last-read position (the first phrase on screen) in the UserDoc. Sent periodically from the reader, so it's kept small.
+#[post("/progress/<doc_id>", data="<progress_form>")]
+pub fn save_reading_progress(cookies: Cookies, db: State<Database>, doc_id: &RawStr, progress_form: Form<ReadingProgressForm>) -> Json<JsonValue> {
+    let username = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => username,
+        None => return Json(json!({"error": "Not authenticated as user"}))
+    };
+    let doc_id = convert_rawstr_to_string(doc_id);
+    let ReadingProgressForm { page, token_index } = progress_form.into_inner();
+    let res = match ReadingProgress::try_save(&db, &username, &doc_id, page, token_index) {
+        Ok(progress) => json!({
+            "doc_id": doc_id,
+            "page": progress.page,
+            "token_index": progress.token_index,
+            "percent_complete": progress.percent_complete(),
+        }),
+        Err(e) => json!({"error": e.to_string()})
+    };
+    return Json(res);
+}
+#[derive(FromForm)]
+pub struct DocAnnotationForm<'f> {
+    token_start: i32,
+    token_end: i32,
+    note: Option<&'f RawStr>,
+}
+/// /api/annotations/<doc_id>
+/// Highlights tokenized_body_json[token_start..token_end] of the user's UserDoc, with an optional note. Returns the new annotation.
+#[post("/annotations/<doc_id>", data="<annotation_form>")]
+pub fn create_doc_annotation(cookies: Cookies, db: State<Database>, doc_id: &RawStr, annotation_form: Form<DocAnnotationForm<'_>>) -> Json<JsonValue> {
+    let username = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => username,
+        None => return Json(json!({"error": "Not authenticated as user"}))
+    };
+    let doc_id = convert_rawstr_to_string(doc_id);
+    let DocAnnotationForm { token_start, token_end, note } = annotation_form.into_inner();
+    let note = match note {
+        Some(s) => convert_rawstr_to_string(s),
+        None => String::new()
+    };
+    let res = match DocAnnotation::new(&db, username, doc_id, token_start, token_end, note) {
+        Ok(new_annotation) => {
+            match new_annotation.try_insert(&db) {
+                Ok(_) => json!(new_annotation),
+                Err(e) => json!({"error": e.to_string()})
+            }
+        },
+        Err(e) => json!({"error": e.to_string()})
+    };
+    return Json(res);
+}
+
+/* PUT/PATCH */
+#[derive(FromForm)]
+pub struct UserDocEditForm<'f> {
+    title: Option<&'f RawStr>,
+    body: Option<&'f RawStr>,
+    body_format: Option<&'f RawStr>,
+    source: Option<&'f RawStr>,
+    collection_id: Option<&'f RawStr>,
+    tags: Option<&'f RawStr>,
+}
+/// /api/doc/<doc_id> (PUT)
+/// Replaces the title and body (and optionally the source) of the user's UserDoc. Refer to edit_user_doc().
+#[put("/doc/<doc_id>", data="<edit_doc>")]
+pub fn replace_user_doc(cookies: Cookies, db: State<Database>, doc_id: &RawStr, edit_doc: Form<UserDocEditForm<'_>>) -> Json<JsonValue> {
+    let edit_doc = edit_doc.into_inner();
+    if edit_doc.title.is_none() || edit_doc.body.is_none() {
+        return Json(json!({"error": "Both title and body are required (use PATCH to edit some fields)"}));
+    }
+    return edit_user_doc(&cookies, &db, doc_id, edit_doc);
+}
+/// /api/doc/<doc_id> (PATCH)
+/// Edits any of the title, body, source, collection, and (comma-separated) tags of the user's UserDoc. Refer to edit_user_doc().
+#[patch("/doc/<doc_id>", data="<edit_doc>")]
+pub fn update_user_doc(cookies: Cookies, db: State<Database>, doc_id: &RawStr, edit_doc: Form<UserDocEditForm<'_>>) -> Json<JsonValue> {
+    return edit_user_doc(&cookies, &db, doc_id, edit_doc.into_inner());
+}
+#[derive(FromForm)]
+pub struct DocCollectionEditForm<'f> {
+    name: Option<&'f RawStr>,
+    parent_id: Option<&'f RawStr>,
+}
+/// /api/collections/<collection_id> (PATCH)
+/// Renames the user's collection and/or moves it under another collection (an empty parent_id moves it to the top level).
+#[patch("/collections/<collection_id>", data="<edit_collection>")]
+pub fn update_collection(cookies: Cookies, db: State<Database>, collection_id: &RawStr, edit_collection: Form<DocCollectionEditForm<'_>>) -> Json<JsonValue> {
+    let username = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => username,
+        None => return Json(json!({"error": "Not authenticated as user"}))
+    };
+    let collection_id = convert_rawstr_to_string(collection_id);
+    let DocCollectionEditForm { name, parent_id } = edit_collection.into_inner();
+    let name = name.map(convert_rawstr_to_string);
+    let parent_id = parent_id.map(convert_rawstr_to_string);
+    let res = match DocCollection::try_edit(&db, &username, &collection_id, name, parent_id) {
+        Ok(collection) => json!({
+            "collection_id": collection.collection_id,
+            "name": collection.name,
+            "parent_id": collection.parent_id,
+        }),
+        Err(e) => json!({"error": e.to_string()})
+    };
+    return Json(res);
+}
+/// Applies the edit (refer to UserDoc::try_edit). A new body is re-tokenized in the background.
+/// Returns the doc_id, the saved title (which may have -# appended), and the job_id of the body edit (null if no body was given, refer to /api/jobs/<job_id>).
+fn edit_user_doc(cookies: &Cookies, db: &Database, doc_id: &RawStr, edit_doc: UserDocEditForm<'_>) -> Json<JsonValue> {
+    let username = match get_username_from_cookie(db, cookies.get(JWT_NAME)) {
+        Some(username) => username,
+        None => return Json(json!({"error": "Not authenticated as user"}))
+    };
+    let doc_id = convert_rawstr_to_string(doc_id);
+    let UserDocEditForm { title, body, body_format, source, collection_id, tags } = edit_doc;
+    let edit = UserDocEdit {
+        title: title.map(convert_rawstr_to_string),
+        body: body.map(convert_rawstr_to_string),
+        body_format: body_format.map(|s| BodyFormat::from_str(&convert_rawstr_to_string(s))),
+        source: source.map(convert_rawstr_to_string),
+        collection_id: collection_id.map(convert_rawstr_to_string),
+        tags: tags.map(|s| parse_tags(&convert_rawstr_to_string(s)))
+    };
+    let res = match UserDoc::try_edit(db, &username, &doc_id, edit) {
+        Ok((title, job_id)) => json!({
+            "doc_id": doc_id,
+            "title": title,
+            "job_id": job_id,
+        }),
+        Err(e) => json!({"error": e.to_string()})
+    };
+    return Json(res);
+}
+/// Lists the user's collections, with the full path of each (e.g. "Novels / 鲁迅").
+fn format_collection_list(db: &Database, username: &str) -> Vec<JsonValue> {
+    let all_collections = DocCollection::lookup_all(db, username);
+    let collection_paths = DocCollection::format_paths(&all_collections);
+    let res = all_collections.into_iter().map(|c| {
+        let path = collection_paths.get(&c.collection_id).cloned().unwrap_or_default();
+        json!({
+            "collection_id": c.collection_id,
+            "name": c.name,
+            "parent_id": c.parent_id,
+            "path": path,
+            "created_on": c.created_on,
+        })
+    }).collect();
+    return res;
+}
+/* DELETE */
+/// /api/annotations/<doc_id>/<annotation_id> (DELETE)
+#[delete("/annotations/<doc_id>/<annotation_id>")]
+pub fn delete_doc_annotation(cookies: Cookies, db: State<Database>, doc_id: &RawStr, annotation_id: &RawStr) -> Json<JsonValue> {
+    let username = match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => username,
+        None => return Json(json!({"error": "Not authenticated as user"}))
+    };
+    let doc_id = convert_rawstr_to_string(doc_id);
+    let annotation_id = convert_rawstr_to_string(annotation_id);
+    let is_deleted = DocAnnotation::try_delete(&db, &username, &doc_id, &annotation_id);
+    return Json(json!({"annotation_id": annotation_id, "deleted": is_deleted}));
 }
\ No newline at end of file
@@ -5,7 +5,7 @@
 use blake2::{Blake2b, Digest};
 use crate::{
     DatabaseItem,
-    config::{JWT_LIFETIME, JWT_NAME, JWT_SECRET, USER_COLL_NAME},
+    config::{JWT_LIFETIME, JWT_NAME, JWT_SECRET, TRUSTED_PROXY_IPS, USER_COLL_NAME},
     models::user::User
 };
 use chrono::{Duration, Utc, DateTime};
@@ -14,7 +14,11 @@ use mongodb::{
     bson::{self, doc},
     sync::Database
 };
-use rocket::http::{Cookie, Cookies, SameSite};
+use rocket::{
+    http::{Cookie, Cookies, SameSite, Status},
+    request::{self, FromRequest, Request},
+    Outcome
+};
 use serde::{Serialize, Deserialize};
 use std::{
     collections::HashMap,
@@ -35,6 +39,26 @@ struct UserToken {
     exp: i64
 }
 
+/// Request guard for the client's IP address. This is the remote address, unless the request comes from one of TRUSTED_PROXY_IPS
+/// (then the X-Real-IP header is used, since clients could otherwise spoof it). Used for per-IP quotas on anonymous uploads.
+pub struct ClientIp(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let remote_ip = match request.remote() {
+            Some(addr) => addr.ip(),
+            None => return Outcome::Failure((Status::BadRequest, ()))
+        };
+        let is_trusted_proxy = TRUSTED_PROXY_IPS.iter().any(|proxy_ip| *proxy_ip == remote_ip.to_string());
+        let client_ip = match (is_trusted_proxy, request.real_ip()) {
+            (true, Some(real_ip)) => real_ip,
+            _ => remote_ip
+        };
+        return Outcome::Success(ClientIp(client_ip.to_string()));
+    }
+}
+
 /* Public Functions */
 /// Generates browser cookie (storing a JWT) with appropriate settings.
 pub fn generate_http_cookie(db: &Database, username: String, password: String) -> Cookie<'static> {
@@ -0,0 +1,136 @@
+/*
+/// Module for detecting and decoding legacy Chinese text encodings (e.g. GBK, GB18030, Big5).
+*/
+
+use encoding_rs::{Encoding, BIG5, GB18030, UTF_8};
+use regex::Regex;
+
+/* Public Functions */
+/// Decodes an HTML page to a String. The encoding is picked (in order) from the BOM, the Content-Type header,
+/// the <meta charset> tag, and finally byte heuristics. A declared UTF-8 that doesn't validate is treated as undeclared.
+pub fn decode_html_bytes(bytes: &[u8], content_type: Option<&str>) -> String {
+    let encoding = detect_bom(bytes)
+        .or_else(|| content_type.and_then(charset_from_content_type).filter(|e| is_plausible(e, bytes)))
+        .or_else(|| charset_from_meta_tag(bytes).filter(|e| is_plausible(e, bytes)))
+        .unwrap_or_else(|| guess_encoding(bytes));
+    return decode_with(encoding, bytes);
+}
+
+/// Decodes uploaded plain text to a String. Same as decode_html_bytes(), without the <meta charset> check.
+pub fn decode_text_bytes(bytes: &[u8], content_type: Option<&str>) -> String {
+    let encoding = detect_bom(bytes)
+        .or_else(|| content_type.and_then(charset_from_content_type).filter(|e| is_plausible(e, bytes)))
+        .unwrap_or_else(|| guess_encoding(bytes));
+    return decode_with(encoding, bytes);
+}
+
+/// Percent-decodes a form value (with + as space) to raw bytes. Used when the value isn't valid UTF-8.
+/// Invalid escapes are kept as-is.
+pub fn url_decode_to_bytes(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => res.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i+1..i+3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => {
+                        res.push(b);
+                        i += 2;
+                    },
+                    Err(_) => res.push(b'%')
+                }
+            },
+            b => res.push(b)
+        }
+        i += 1;
+    }
+    return res;
+}
+
+/* Private Functions */
+/// Returns the encoding indicated by a byte-order mark, if any.
+fn detect_bom(bytes: &[u8]) -> Option<&'static Encoding> {
+    return Encoding::for_bom(bytes).map(|(encoding, _)| encoding);
+}
+
+/// Parses the charset parameter from a Content-Type header. Ex. "text/html; charset=GBK" => GBK
+fn charset_from_content_type(content_type: &str) -> Option<&'static Encoding> {
+    let res = content_type.split(';')
+        .skip(1)
+        .filter_map(|param| {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k.trim().eq_ignore_ascii_case("charset") => Some(v.trim().trim_matches(|c| c == '"' || c == '\'')),
+                _ => None
+            }
+        })
+        .find_map(|label| Encoding::for_label(label.as_bytes()));
+    return res;
+}
+
+/// Finds the charset from <meta charset=...> or <meta http-equiv="Content-Type" content="...; charset=...">
+/// within the first 4KB of the page.
+fn charset_from_meta_tag(bytes: &[u8]) -> Option<&'static Encoding> {
+    const PRESCAN_LEN: usize = 4096;
+    let head = String::from_utf8_lossy(&bytes[..std::cmp::min(bytes.len(), PRESCAN_LEN)]);
+    let meta_re = Regex::new(r#"(?i)<meta[^>]+charset\s*=\s*["']?\s*([a-z0-9_\-:.]+)"#).unwrap();
+    let label = meta_re.captures(&head)?.get(1)?.as_str().to_string();
+    return Encoding::for_label(label.as_bytes());
+}
+
+/// Returns false if a declared UTF-8 doesn't validate (a common server misconfiguration). Other declarations are trusted.
+fn is_plausible(encoding: &&'static Encoding, bytes: &[u8]) -> bool {
+    return *encoding != UTF_8 || std::str::from_utf8(bytes).is_ok();
+}
+
+/// Guesses between UTF-8, GB18030 (superset of GB2312/GBK) and Big5 from the bytes.
+/// Big5 uses trail bytes 0x40-0x7E for roughly a third of its chars, while GB2312 text almost never does.
+fn guess_encoding(bytes: &[u8]) -> &'static Encoding {
+    const BIG5_TRAIL_RATIO: f64 = 0.1;
+    if std::str::from_utf8(bytes).is_ok() {
+        return UTF_8;
+    }
+    let gb_is_valid = GB18030.decode_without_bom_handling_and_without_replacement(bytes).is_some();
+    let big5_is_valid = BIG5.decode_without_bom_handling_and_without_replacement(bytes).is_some();
+    let res = match (gb_is_valid, big5_is_valid) {
+        (true, false) => GB18030,
+        (false, true) => BIG5,
+        _ => {
+            let (n_pairs, n_low_trails) = count_double_byte_trails(bytes);
+            match n_pairs > 0 && (n_low_trails as f64 / n_pairs as f64) > BIG5_TRAIL_RATIO {
+                true => BIG5,
+                false => GB18030
+            }
+        }
+    };
+    return res;
+}
+
+/// Counts double-byte pairs (lead byte 0x81-0xFE), and how many of those have a trail byte in 0x40-0x7E.
+fn count_double_byte_trails(bytes: &[u8]) -> (usize, usize) {
+    let mut n_pairs = 0;
+    let mut n_low_trails = 0;
+    let mut i = 0;
+    while i + 1 < bytes.len() {
+        match (bytes[i], bytes[i+1]) {
+            (0x81..=0xFE, trail) => {
+                n_pairs += 1;
+                if (0x40..=0x7E).contains(&trail) {
+                    n_low_trails += 1;
+                }
+                i += 2;
+            },
+            _ => { i += 1; }
+        }
+    }
+    return (n_pairs, n_low_trails);
+}
+
+/// Decodes with the given encoding, replacing malformed sequences with �.
+fn decode_with(encoding: &'static Encoding, bytes: &[u8]) -> String {
+    let (res, _, _) = encoding.decode(bytes);
+    return res.into_owned();
+}
@@ -9,12 +9,58 @@ pub static DB_NAME: &str = "duguo";
 pub static REDIS_URI: &str = "redis://redis-cache:6379/";
 pub static USER_COLL_NAME: &str = "users";
 pub static SANDBOX_COLL_NAME: &str = "sandbox";
+pub static SANDBOX_QUOTA_COLL_NAME: &str = "sandbox-quota";
 pub static USER_DOC_COLL_NAME: &str = "docs";
+pub static USER_DOC_PAGE_COLL_NAME: &str = "doc-pages";
 pub static USER_VOCAB_COLL_NAME: &str = "vocab";
 pub static USER_VOCAB_LIST_COLL_NAME: &str = "vocab-list";
 pub static USER_FEEDBACK_COLL_NAME: &str = "feedback";
+pub static COLLECTION_COLL_NAME: &str = "collections";
+pub static DOC_SEARCH_COLL_NAME: &str = "doc-search";
+pub static VOCAB_OCCURRENCE_COLL_NAME: &str = "vocab-occurrences";
+pub static READING_PROGRESS_COLL_NAME: &str = "reading-progress";
+pub static ANNOTATION_COLL_NAME: &str = "annotations";
+pub static TW_PHRASES_TRAD_KEY: &str = "tw-phrases-trad"; // Redis hash loaded in loadcedict.py
+pub static TW_PHRASES_SIMP_KEY: &str = "tw-phrases-simp";
 pub static TOKENIZER_PORT: u16 = 8881;
 pub static TOKENIZER_HOSTNAME: &str = "duguo-tokenizer"; // Container name from docker-compose.yml
+pub static TOKENIZER_HEADER_BYTES: usize = 64; // Matches HEADER_BYTES in data_services/config.py
+pub static TOKENIZER_CHUNK_BYTES: usize = 256 * 1024; // Max bytes per tokenizer request frame (must be <= MAX_BUF in data_services/config.py)
 pub static JWT_NAME: &str = "duguo-代币";
 pub static JWT_SECRET: &[u8; 20] = b"somesupersecretthing";
-pub static JWT_LIFETIME: i64 = 24 * 7; // 1 week (match with Rocket cookie length)
\ No newline at end of file
+pub static JWT_LIFETIME: i64 = 24 * 7; // 1 week (match with Rocket cookie length)
+pub static CLIENT_IP_HASH_SALT: &str = "someothersecretthing"; // Salt for stored IP address hashes (keep secret, like JWT_SECRET)
+pub static TRUSTED_PROXY_IPS: [&str; 0] = []; // Reverse proxies allowed to set X-Real-IP (otherwise the remote address is used)
+pub static FETCH_CONNECT_TIMEOUT_SECS: u64 = 5; // URL imports (fetch.rs)
+pub static FETCH_READ_TIMEOUT_SECS: u64 = 15;
+pub static FETCH_TOTAL_TIMEOUT_SECS: u64 = 30; // Overall deadline, including redirects
+pub static FETCH_MAX_BODY_BYTES: usize = 5 * 1024 * 1024; // 5 MB
+pub static FETCH_MAX_REDIRECTS: usize = 5;
+pub static FETCH_ALLOWED_CONTENT_TYPES: [&str; 3] = ["text/html", "application/xhtml+xml", "text/plain"];
+pub static UPLOAD_MAX_BYTES: usize = 20 * 1024 * 1024; // 20 MB per uploaded file (.epub files are compressed and split per chapter, so can be larger than one body)
+pub static DOC_BODY_MAX_BYTES: usize = 15 * 1024 * 1024; // 15 MB per document body (bodies are stored whole in jobs/docs, and MongoDB's document limit is 16 MB)
+pub static EPUB_MAX_UNCOMPRESSED_BYTES: usize = 50 * 1024 * 1024; // 50 MB, total across all files read from an .epub
+pub static FEED_COLL_NAME: &str = "feeds";
+pub static FEED_POLL_INTERVAL_SECS: u64 = 30 * 60; // 30 minutes
+pub static FEED_MAX_ITEMS_PER_POLL: usize = 10;
+pub static FEED_SEEN_HISTORY_LEN: i32 = 500; // GUIDs/URLs kept per feed for de-duplication
+pub static FEED_ALLOWED_CONTENT_TYPES: [&str; 6] = ["application/rss+xml", "application/atom+xml", "application/rdf+xml", "application/xml", "text/xml", "text/plain"];
+pub static JOB_COLL_NAME: &str = "jobs";
+pub static JOB_WORKER_COUNT: usize = 2;
+pub static JOB_POLL_INTERVAL_MS: u64 = 500; // How often idle workers check for queued jobs
+pub static DOC_PAGE_MAX_CHARS: usize = 2000; // Max chars per reader page (pages also start at chapter headings)
+pub static DOC_HTML_CACHE_KEY_PREFIX: &str = "doc-html-v2:"; // Redis hash per UserDoc, with the rendered HTML per page + settings (v2: phrases have data-token)
+pub static DOC_HTML_CACHE_TTL_SECS: usize = 24 * 60 * 60; // 1 day
+pub static SEARCH_MAX_CANDIDATES: i64 = 200; // Docs checked per search (before ranking)
+pub static SEARCH_MAX_RESULTS: usize = 20;
+pub static SEARCH_SNIPPET_CONTEXT_CHARS: usize = 20; // Chars shown on each side of a match
+pub static SEARCH_MAX_SNIPPETS: usize = 3; // Per doc
+pub static CONCORDANCE_MAX_LINES: usize = 200;
+pub static CONCORDANCE_CONTEXT_CHARS: usize = 30; // Max chars shown on each side of the phrase (within its sentence)
+pub static CONCORDANCE_WINDOW_TOKENS: i32 = 40; // Tokens loaded on each side of the phrase
+pub static SANDBOX_TTL_SECS: i64 = 7 * 24 * 60 * 60; // 1 week, SandboxDocs older than this are deleted
+pub static SANDBOX_SWEEP_INTERVAL_SECS: u64 = 60 * 60; // How often expired SandboxDocs are deleted
+pub static SANDBOX_QUOTA_PER_IP: i64 = 20; // Max SandboxDocs uploaded per IP address within SANDBOX_QUOTA_WINDOW_SECS
+pub static SANDBOX_QUOTA_WINDOW_SECS: i64 = 60 * 60; // 1 hour
+pub static ANNOTATION_MAX_TOKENS: i32 = 500; // Max phrases per highlight
+pub static ANNOTATION_MAX_NOTE_CHARS: usize = 2000;
\ No newline at end of file
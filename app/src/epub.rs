@@ -0,0 +1,195 @@
+/*
+/// Module for reading EPUB files (metadata and chapter text in spine order).
+*/
+
+use crate::{
+    config::{UPLOAD_MAX_BYTES, EPUB_MAX_UNCOMPRESSED_BYTES},
+    readability::extract_full_text
+};
+use roxmltree::{Document, Node};
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{Cursor, Read}
+};
+use zip::ZipArchive;
+
+/* Structs */
+#[derive(Clone, Debug, Default)]
+pub struct EpubBook {
+    pub title: String,
+    pub author: String,
+    pub chapters: Vec<EpubChapter>
+}
+
+/// A chapter is one spine item with text. Paragraphs in body are newline-delimited.
+#[derive(Clone, Debug, Default)]
+pub struct EpubChapter {
+    pub title: String,
+    pub body: String
+}
+
+/* Public Functions */
+/// Parses an EPUB file: container.xml => OPF package => metadata, manifest, and spine.
+/// Spine items without text (e.g. cover images) are skipped.
+/// Decompressed reads are capped at UPLOAD_MAX_BYTES per file and EPUB_MAX_UNCOMPRESSED_BYTES in total, to guard against zip bombs.
+pub fn parse_epub(bytes: &[u8]) -> Result<EpubBook, Box<dyn Error>> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+    let mut remaining_bytes = EPUB_MAX_UNCOMPRESSED_BYTES;
+    let container_str = read_zip_entry(&mut archive, "META-INF/container.xml", &mut remaining_bytes)?;
+    let container = parse_xml(&container_str)?;
+    let opf_path = container.descendants()
+        .filter(|n| n.has_tag_name("rootfile"))
+        .filter_map(|n| n.attribute("full-path"))
+        .next()
+        .map(String::from)
+        .ok_or("EPUB container.xml has no rootfile")?;
+    let opf_dir = match opf_path.rfind('/') {
+        Some(i) => &opf_path[..i+1],
+        None => ""
+    };
+    let opf_str = read_zip_entry(&mut archive, &opf_path, &mut remaining_bytes)?;
+    let opf = parse_xml(&opf_str)?;
+    let title = descendant_texts(&opf, "title").into_iter().next().unwrap_or_default();
+    let author = descendant_texts(&opf, "creator").join(", ");
+    // Manifest: id => href
+    let manifest: HashMap<&str, &str> = opf.descendants()
+        .filter(|n| n.has_tag_name("item") && n.parent().map_or(false, |p| p.has_tag_name("manifest")))
+        .filter_map(|n| Some((n.attribute("id")?, n.attribute("href")?)))
+        .collect();
+    let spine_hrefs: Vec<String> = opf.descendants()
+        .filter(|n| n.has_tag_name("itemref") && n.parent().map_or(false, |p| p.has_tag_name("spine")))
+        .filter_map(|n| n.attribute("idref"))
+        .filter_map(|idref| manifest.get(idref))
+        .map(|href| normalize_zip_path(&format!("{}{}", opf_dir, decode_href(href))))
+        .collect();
+    let mut chapters = Vec::with_capacity(spine_hrefs.len());
+    for href in spine_hrefs {
+        let chapter_html = match read_zip_entry(&mut archive, &href, &mut remaining_bytes) {
+            Ok(s) => s,
+            Err(e) if remaining_bytes == 0 => return Err(e),
+            Err(e) => {
+                eprintln!("Skipping EPUB spine item {}: {:?}", href, e);
+                continue;
+            }
+        };
+        let article = extract_full_text(&chapter_html);
+        if article.body.len() > 0 {
+            chapters.push(EpubChapter { title: article.title, body: article.body });
+        }
+    }
+    if chapters.len() == 0 {
+        return Err(Box::from("EPUB has no readable chapters"));
+    }
+    return Ok(EpubBook { title, author, chapters });
+}
+
+/* Private Functions */
+/// Reads a file in the archive as UTF-8 text, counting it against remaining_bytes.
+/// Fails if the file is larger than UPLOAD_MAX_BYTES or remaining_bytes (which is then set to 0).
+fn read_zip_entry(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str, remaining_bytes: &mut usize) -> Result<String, Box<dyn Error>> {
+    let entry = archive.by_name(name)?;
+    let max_bytes = UPLOAD_MAX_BYTES.min(*remaining_bytes);
+    let mut buf = Vec::new();
+    // Reading one byte past the limit detects oversized files without decompressing all of them
+    entry.take(max_bytes as u64 + 1).read_to_end(&mut buf)?;
+    if buf.len() > max_bytes {
+        if max_bytes == *remaining_bytes {
+            *remaining_bytes = 0;
+        }
+        return Err(Box::from(format!("EPUB file {} is too large when decompressed", name)));
+    }
+    *remaining_bytes -= buf.len();
+    return Ok(String::from_utf8_lossy(&buf).into_owned());
+}
+
+/// Parses the XML, ignoring a leading byte order mark.
+fn parse_xml(xml_str: &str) -> Result<Document, Box<dyn Error>> {
+    let doc = Document::parse(xml_str.trim_start_matches('\u{FEFF}'))?;
+    return Ok(doc);
+}
+
+/// Returns the trimmed, non-empty text of all elements with the tag name (ignoring namespaces, e.g. "dc:title").
+fn descendant_texts(doc: &Document, tag_name: &str) -> Vec<String> {
+    let res = doc.descendants()
+        .filter(|n| n.has_tag_name(tag_name))
+        .map(|n| n.descendants().filter(Node::is_text).filter_map(|t| t.text()).collect::<String>().trim().to_string())
+        .filter(|s| s.len() > 0)
+        .collect();
+    return res;
+}
+
+/// Resolves "." and ".." segments, since zip entries are looked up by exact name. Ex. "OEBPS/../Text/c1.xhtml" => "Text/c1.xhtml"
+fn normalize_zip_path(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => { },
+            ".." => { segments.pop(); },
+            s => segments.push(s)
+        }
+    }
+    return segments.join("/");
+}
+
+/// Manifest hrefs are URL-encoded and may have a #fragment. Ex. "Text/chapter%201.xhtml#top" => "Text/chapter 1.xhtml"
+fn decode_href(href: &str) -> String {
+    let href = href.split('#').next().unwrap_or("");
+    let bytes = href.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = match bytes[i] == b'%' && i + 3 <= bytes.len() {
+            true => std::str::from_utf8(&bytes[i+1..i+3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok()),
+            false => None
+        };
+        match hex {
+            Some(b) => {
+                res.push(b);
+                i += 3;
+            },
+            None => {
+                res.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    return String::from_utf8_lossy(&res).into_owned();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::{ZipWriter, write::FileOptions};
+
+    fn build_epub(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files.iter() {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+        return writer.finish().unwrap().into_inner();
+    }
+
+    #[test]
+    fn parse_epub_reads_chapters_in_spine_order() {
+        let bytes = build_epub(&[
+            ("META-INF/container.xml", r#"<?xml version="1.0"?><container xmlns="urn:oasis:names:tc:opendocument:xmlns:container" version="1.0"><rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles></container>"#),
+            ("OEBPS/content.opf", r#"<?xml version="1.0"?><package xmlns="http://www.idpf.org/2007/opf" version="3.0"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>骆驼祥子</dc:title><dc:creator>老舍</dc:creator></metadata><manifest><item id="c2" href="Text/chapter%202.xhtml" media-type="application/xhtml+xml"/><item id="c1" href="Text/c1.xhtml" media-type="application/xhtml+xml"/></manifest><spine><itemref idref="c1"/><itemref idref="c2"/></spine></package>"#),
+            ("OEBPS/Text/c1.xhtml", "<html><body><h1>第一章</h1><p>我们所要介绍的是祥子。</p></body></html>"),
+            ("OEBPS/Text/chapter 2.xhtml", "<html><body><h1>第二章</h1><p>恰好有人来了。</p></body></html>")
+        ]);
+        let book = parse_epub(&bytes).unwrap();
+        assert_eq!(book.title, "骆驼祥子");
+        assert_eq!(book.author, "老舍");
+        let chapter_titles: Vec<&str> = book.chapters.iter().map(|c| c.title.as_str()).collect();
+        assert_eq!(chapter_titles, vec!["第一章", "第二章"]);
+        assert_eq!(book.chapters[0].body, "第一章\n我们所要介绍的是祥子。");
+    }
+
+    #[test]
+    fn parse_epub_rejects_archives_without_a_container() {
+        assert!(parse_epub(&build_epub(&[("mimetype", "application/epub+zip")])).is_err());
+    }
+}
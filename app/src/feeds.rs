@@ -0,0 +1,131 @@
+/*
+/// Module for parsing RSS 2.0, RSS 1.0 (RDF), and Atom feeds.
+*/
+
+use roxmltree::{Document, Node};
+use std::error::Error;
+
+/* Structs */
+/// A feed parsed into its title and items (in feed order, usually newest first).
+#[derive(Clone, Debug, Default)]
+pub struct ParsedFeed {
+    pub title: String,
+    pub items: Vec<FeedItem>
+}
+
+/// A single feed item. guid falls back to the url if the feed doesn't provide one.
+#[derive(Clone, Debug, Default)]
+pub struct FeedItem {
+    pub guid: String,
+    pub url: String,
+    pub title: String
+}
+
+/* Public Functions */
+/// Parses an RSS or Atom feed. Items without a link are skipped.
+pub fn parse_feed(xml_str: &str) -> Result<ParsedFeed, Box<dyn Error>> {
+    let xml_str = xml_str.trim_start_matches('\u{FEFF}');
+    let doc = Document::parse(xml_str)?;
+    let root = doc.root_element();
+    let res = match root.tag_name().name() {
+        "feed" => parse_atom(&root),
+        "rss" | "RDF" => parse_rss(&root),
+        other => return Err(Box::from(format!("Unrecognized feed root element: {}", other)))
+    };
+    return Ok(res);
+}
+
+/* Private Functions */
+/// RSS 2.0 has <item>s inside <channel>, while RSS 1.0 has them beside it. Both use <link> text and an optional <guid>.
+fn parse_rss(root: &Node) -> ParsedFeed {
+    let title = root.descendants()
+        .find(|n| n.has_tag_name("channel"))
+        .map(|channel| child_text(&channel, "title"))
+        .unwrap_or_default();
+    let items = root.descendants()
+        .filter(|n| n.has_tag_name("item"))
+        .filter_map(|item| {
+            let url = child_text(&item, "link");
+            let guid = match child_text(&item, "guid") {
+                guid if guid.len() > 0 => guid,
+                _ => url.clone()
+            };
+            match url.len() > 0 {
+                true => Some(FeedItem { guid, url, title: child_text(&item, "title") }),
+                false => None
+            }
+        })
+        .collect();
+    return ParsedFeed { title, items };
+}
+
+/// Atom uses <entry> with <id>, and <link href> (preferring rel="alternate", which is also the default).
+fn parse_atom(root: &Node) -> ParsedFeed {
+    let title = child_text(root, "title");
+    let items = root.children()
+        .filter(|n| n.has_tag_name("entry"))
+        .filter_map(|entry| {
+            let links: Vec<Node> = entry.children().filter(|n| n.has_tag_name("link")).collect();
+            let url = links.iter()
+                .find(|link| link.attribute("rel").unwrap_or("alternate") == "alternate")
+                .or(links.first())
+                .and_then(|link| link.attribute("href"))
+                .map(|href| href.trim().to_string())
+                .unwrap_or_default();
+            let guid = match child_text(&entry, "id") {
+                guid if guid.len() > 0 => guid,
+                _ => url.clone()
+            };
+            match url.len() > 0 {
+                true => Some(FeedItem { guid, url, title: child_text(&entry, "title") }),
+                false => None
+            }
+        })
+        .collect();
+    return ParsedFeed { title, items };
+}
+
+/// Returns the trimmed text of the first child element with the tag name (ignoring namespaces), or an empty String.
+fn child_text(node: &Node, tag_name: &str) -> String {
+    let res = node.children()
+        .find(|n| n.is_element() && n.tag_name().name() == tag_name)
+        .map(|n| n.descendants().filter(|d| d.is_text()).filter_map(|d| d.text()).collect::<String>().trim().to_string())
+        .unwrap_or_default();
+    return res;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_feed_reads_rss_fixture() {
+        let feed = parse_feed(include_str!("../tests/fixtures/feed.rss")).unwrap();
+        assert_eq!(feed.title, "每日新闻");
+        assert_eq!(feed.items.len(), 2);
+        assert_eq!(feed.items[0].guid, "news-2021-0001");
+        assert_eq!(feed.items[0].url, "https://news.example.com/2021/01/snow");
+        assert_eq!(feed.items[0].title, "北京迎来今年第一场雪");
+        // Without a <guid>, the (trimmed) link is used
+        assert_eq!(feed.items[1].guid, "https://news.example.com/2021/01/metro");
+        assert_eq!(feed.items[1].url, "https://news.example.com/2021/01/metro");
+        assert_eq!(feed.items[1].title, "上海地铁<新线路>开通");
+    }
+
+    #[test]
+    fn parse_feed_reads_atom_fixture() {
+        let feed = parse_feed(include_str!("../tests/fixtures/feed.atom")).unwrap();
+        assert_eq!(feed.title, "中文学习博客");
+        assert_eq!(feed.items.len(), 2);
+        assert_eq!(feed.items[0].url, "https://blog.example.com/posts/hanzi");
+        assert_eq!(feed.items[0].guid, "urn:uuid:1225c695-cfb8-4ebb-aaaa-80da344efa6a");
+        assert_eq!(feed.items[1].url, "https://blog.example.com/posts/tones");
+        assert_eq!(feed.items[1].guid, "https://blog.example.com/posts/tones");
+    }
+
+    #[test]
+    fn parse_feed_rejects_other_xml() {
+        assert!(parse_feed("<html><body>Not a feed</body></html>").is_err());
+        assert!(parse_feed("not xml").is_err());
+    }
+}
@@ -0,0 +1,302 @@
+/*
+/// Module for fetching user-supplied URLs safely (SSRF protection, timeouts, and size limits).
+*/
+
+use crate::config::{FETCH_CONNECT_TIMEOUT_SECS, FETCH_READ_TIMEOUT_SECS, FETCH_TOTAL_TIMEOUT_SECS, FETCH_MAX_BODY_BYTES, FETCH_MAX_REDIRECTS, FETCH_ALLOWED_CONTENT_TYPES};
+use hyper::{
+    Body, Client, Request, Uri,
+    body::HttpBody,
+    client::{HttpConnector, connect::dns::Name},
+    header
+};
+use hyper_tls::HttpsConnector;
+use url::Url;
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration
+};
+use tokio::{
+    net::lookup_host,
+    time::{Instant, timeout, timeout_at}
+};
+use tower_service::Service;
+
+/* Structs */
+/// A successfully fetched page. url is the final URL after redirects.
+#[derive(Clone, Debug)]
+pub struct FetchedPage {
+    pub url: String,
+    pub content_type: Option<String>,
+    pub bytes: Vec<u8>
+}
+
+impl FetchedPage {
+    /// Returns true if the Content-Type is plain text (rather than HTML).
+    pub fn is_plain_text(&self) -> bool {
+        return self.content_type.as_deref().map(media_type).as_deref() == Some("text/plain");
+    }
+}
+
+/* Enums */
+/// Reasons a URL fetch can fail.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FetchError {
+    InvalidUrl(String),
+    UnsupportedScheme(String),
+    DnsFailure(String),
+    BlockedAddress(IpAddr),
+    Timeout,
+    TooManyRedirects,
+    HttpStatus(u16),
+    MissingContentType,
+    UnsupportedContentType(String),
+    TooLarge,
+    Network(String)
+}
+
+/// Implements to_string()
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            FetchError::InvalidUrl(url) => write!(f, "Invalid URL: {}", url),
+            FetchError::UnsupportedScheme(scheme) => write!(f, "Unsupported URL scheme (only http/https allowed): {}", scheme),
+            FetchError::DnsFailure(host) => write!(f, "Could not resolve host: {}", host),
+            FetchError::BlockedAddress(ip) => write!(f, "Blocked non-public address: {}", ip),
+            FetchError::Timeout => write!(f, "Timed out fetching URL"),
+            FetchError::TooManyRedirects => write!(f, "Too many redirects (max {})", FETCH_MAX_REDIRECTS),
+            FetchError::HttpStatus(code) => write!(f, "Server responded with HTTP {}", code),
+            FetchError::MissingContentType => write!(f, "Response has no content type"),
+            FetchError::UnsupportedContentType(content_type) => write!(f, "Unsupported content type: {}", content_type),
+            FetchError::TooLarge => write!(f, "Response is larger than {} bytes", FETCH_MAX_BODY_BYTES),
+            FetchError::Network(e) => write!(f, "Network error: {}", e)
+        };
+    }
+}
+
+impl Error for FetchError {}
+
+/// Resolves hostnames for the fetch client, failing if any resolved address isn't public.
+/// Since the client connects to the addresses returned here, the checked addresses are the ones connected to (no DNS rebinding).
+#[derive(Clone, Debug, Default)]
+struct PublicIpResolver;
+
+impl Service<Name> for PublicIpResolver {
+    type Response = std::vec::IntoIter<IpAddr>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        return Poll::Ready(Ok(()));
+    }
+    fn call(&mut self, name: Name) -> Self::Future {
+        let host = String::from(name.as_str());
+        return Box::pin(async move {
+            let addrs: Vec<IpAddr> = match lookup_host((host.as_str(), 0)).await {
+                Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+                Err(_) => Vec::new()
+            };
+            if addrs.len() == 0 {
+                return Err(Box::from(FetchError::DnsFailure(host)));
+            }
+            if let Some(blocked_ip) = addrs.iter().find(|ip| !is_public_ip(ip)) {
+                return Err(Box::from(FetchError::BlockedAddress(*blocked_ip)));
+            }
+            return Ok(addrs.into_iter());
+        });
+    }
+}
+
+/* Public Functions */
+/// Fetches the URL with SSRF protection: only http/https is allowed, and every hop (including redirects)
+/// must resolve to a public IP, which is the IP connected to (refer to PublicIpResolver).
+/// Connect/read timeouts, an overall deadline, a maximum body size, and a content-type allowlist are enforced.
+/// URLs without a scheme are assumed to be https.
+pub async fn fetch_url(url: &str) -> Result<FetchedPage, FetchError> {
+    return fetch_url_with_content_types(url, &FETCH_ALLOWED_CONTENT_TYPES).await;
+}
+
+/// Same as fetch_url(), with a different content-type allowlist (e.g. for RSS/Atom feeds).
+pub async fn fetch_url_with_content_types(url: &str, allowed_content_types: &[&str]) -> Result<FetchedPage, FetchError> {
+    let deadline = Instant::now() + Duration::from_secs(FETCH_TOTAL_TIMEOUT_SECS);
+    let res = match timeout_at(deadline, fetch_with_redirects(url, allowed_content_types)).await {
+        Ok(res) => res,
+        Err(_) => Err(FetchError::Timeout)
+    };
+    return res;
+}
+
+/// Returns false for loopback, private, link-local, CGNAT, multicast, and other non-routable addresses.
+pub fn is_public_ip(ip: &IpAddr) -> bool {
+    return match ip {
+        IpAddr::V4(ipv4) => is_public_ipv4(ipv4),
+        IpAddr::V6(ipv6) => is_public_ipv6(ipv6)
+    };
+}
+
+/* Private Functions */
+/// Fetches the URL, following up to FETCH_MAX_REDIRECTS redirects. Refer to fetch_url() for details.
+async fn fetch_with_redirects(url: &str, allowed_content_types: &[&str]) -> Result<FetchedPage, FetchError> {
+    let url = url.trim();
+    let url_with_scheme = match url.contains("://") {
+        true => String::from(url),
+        false => format!("https://{}", url)
+    };
+    let mut curr_url = Url::parse(&url_with_scheme).map_err(|_| FetchError::InvalidUrl(String::from(url)))?;
+    let mut http = HttpConnector::new_with_resolver(PublicIpResolver);
+    http.enforce_http(false);
+    http.set_connect_timeout(Some(Duration::from_secs(FETCH_CONNECT_TIMEOUT_SECS)));
+    let client: Client<_, Body> = Client::builder().build(HttpsConnector::new_with_connector(http));
+    for _ in 0..=FETCH_MAX_REDIRECTS {
+        check_url_is_public(&curr_url)?;
+        let uri: Uri = curr_url.as_str().parse().map_err(|_| FetchError::InvalidUrl(curr_url.to_string()))?;
+        let req = Request::get(uri).body(Body::empty()).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+        let mut resp = match timeout(Duration::from_secs(FETCH_READ_TIMEOUT_SECS), client.request(req)).await {
+            Ok(Ok(resp)) => resp,
+            Ok(Err(e)) => return Err(find_fetch_error(&e).unwrap_or(FetchError::Network(e.to_string()))),
+            Err(_) => return Err(FetchError::Timeout)
+        };
+        let status = resp.status();
+        if status.is_redirection() {
+            let location = resp.headers().get(header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or(FetchError::HttpStatus(status.as_u16()))?;
+            curr_url = curr_url.join(location).map_err(|_| FetchError::InvalidUrl(String::from(location)))?;
+            continue;
+        } else if !status.is_success() {
+            return Err(FetchError::HttpStatus(status.as_u16()));
+        }
+        let content_type = resp.headers().get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+            .ok_or(FetchError::MissingContentType)?;
+        if !allowed_content_types.contains(&media_type(&content_type).as_str()) {
+            return Err(FetchError::UnsupportedContentType(content_type));
+        }
+        let content_length = resp.headers().get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+        if content_length > FETCH_MAX_BODY_BYTES {
+            return Err(FetchError::TooLarge);
+        }
+        let mut bytes: Vec<u8> = Vec::new();
+        loop {
+            let chunk = match timeout(Duration::from_secs(FETCH_READ_TIMEOUT_SECS), resp.body_mut().data()).await {
+                Ok(Some(Ok(chunk))) => chunk,
+                Ok(Some(Err(e))) => return Err(FetchError::Network(e.to_string())),
+                Ok(None) => break,
+                Err(_) => return Err(FetchError::Timeout)
+            };
+            if bytes.len() + chunk.len() > FETCH_MAX_BODY_BYTES {
+                return Err(FetchError::TooLarge);
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        return Ok(FetchedPage { url: curr_url.to_string(), content_type: Some(content_type), bytes });
+    }
+    return Err(FetchError::TooManyRedirects);
+}
+
+/// Checks the scheme, and checks that the host is public if it's an IP address.
+/// Hostnames are checked when they're resolved (refer to PublicIpResolver).
+fn check_url_is_public(url: &Url) -> Result<(), FetchError> {
+    match url.scheme() {
+        "http" | "https" => { },
+        scheme => return Err(FetchError::UnsupportedScheme(String::from(scheme)))
+    }
+    let host = url.host_str().ok_or(FetchError::InvalidUrl(url.to_string()))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']'); // IPv6 literals
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        if !is_public_ip(&ip) {
+            return Err(FetchError::BlockedAddress(ip));
+        }
+    }
+    return Ok(());
+}
+
+/// Finds the FetchError (e.g. from PublicIpResolver) that caused the client error, if any.
+fn find_fetch_error(e: &(dyn Error + 'static)) -> Option<FetchError> {
+    let mut curr_error: Option<&(dyn Error + 'static)> = Some(e);
+    while let Some(err) = curr_error {
+        if let Some(fetch_error) = err.downcast_ref::<FetchError>() {
+            return Some(fetch_error.clone());
+        }
+        curr_error = err.source();
+    }
+    return None;
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    let is_shared = octets[0] == 100 && (octets[1] & 0b1100_0000) == 64; // 100.64.0.0/10 (CGNAT)
+    let is_reserved = octets[0] >= 240; // 240.0.0.0/4
+    let is_zero_net = octets[0] == 0; // 0.0.0.0/8
+    let is_benchmarking = octets[0] == 198 && (octets[1] & 0xFE) == 18; // 198.18.0.0/15
+    return !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_broadcast()
+        || ip.is_documentation() || ip.is_unspecified() || ip.is_multicast()
+        || is_shared || is_reserved || is_zero_net || is_benchmarking);
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // IPv4-mapped (::ffff:a.b.c.d) and IPv4-compatible (::a.b.c.d) addresses are checked as IPv4
+    if segments[0..5].iter().all(|s| *s == 0) && (segments[5] == 0xFFFF || segments[5] == 0) && !ip.is_loopback() && !ip.is_unspecified() {
+        if let Some(ipv4) = ip.to_ipv4() {
+            return is_public_ipv4(&ipv4);
+        }
+    }
+    let is_unique_local = (segments[0] & 0xFE00) == 0xFC00; // fc00::/7
+    let is_link_local = (segments[0] & 0xFFC0) == 0xFE80; // fe80::/10
+    let is_documentation = segments[0] == 0x2001 && segments[1] == 0x0DB8; // 2001:db8::/32
+    // Transition addresses can route to an embedded IPv4 address, so they're blocked entirely
+    let is_6to4 = segments[0] == 0x2002; // 2002::/16
+    let is_teredo = segments[0] == 0x2001 && segments[1] == 0; // 2001::/32
+    let is_nat64 = segments[0] == 0x64 && segments[1] == 0xFF9B && (segments[2] <= 1); // 64:ff9b::/96, 64:ff9b:1::/48
+    return !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+        || is_unique_local || is_link_local || is_documentation
+        || is_6to4 || is_teredo || is_nat64);
+}
+
+/// Returns the lowercase media type without parameters. Ex. "text/HTML; charset=GBK" => "text/html"
+fn media_type(content_type: &str) -> String {
+    return content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_public(ip: &str) -> bool {
+        return is_public_ip(&ip.parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn blocks_non_public_addresses() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+            "::1", "::", "fc00::1", "fe80::1", "::ffff:127.0.0.1", "::ffff:10.0.0.1",
+            "2002:7f00:1::", "64:ff9b::7f00:1", "64:ff9b:1::a00:1", "2001:0:4136:e378:8000:63bf:3fff:fdd2"].iter() {
+            assert!(!is_public(ip), "{} should be blocked", ip);
+        }
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700:4700::1111", "::ffff:1.1.1.1"].iter() {
+            assert!(is_public(ip), "{} should be allowed", ip);
+        }
+    }
+
+    #[test]
+    fn rejects_blocked_urls_before_connecting() {
+        let check = |url: &str| check_url_is_public(&Url::parse(url).unwrap());
+        assert_eq!(check("ftp://example.com/"), Err(FetchError::UnsupportedScheme(String::from("ftp"))));
+        assert_eq!(check("http://127.0.0.1:6379/"), Err(FetchError::BlockedAddress("127.0.0.1".parse().unwrap())));
+        assert_eq!(check("http://[::1]/"), Err(FetchError::BlockedAddress("::1".parse().unwrap())));
+        assert_eq!(check("https://example.com/"), Ok(()));
+    }
+}
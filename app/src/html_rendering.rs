@@ -3,173 +3,350 @@
 */
 
 use crate::{
-    CacheItem,
+    DatabaseItem,
     connect_to_redis,
-    config::{TOKENIZER_HOSTNAME, TOKENIZER_PORT, USER_DOC_COLL_NAME, USER_VOCAB_COLL_NAME},
+    convert_string_to_tokenized_phrases,
+    split_phrase_into_chars,
+    script,
+    numerals::{self, NumeralReading},
+    markup::{MarkupEvent, render_markup, escape_text},
+    subtitles::format_timestamp,
+    config::{USER_VOCAB_COLL_NAME, FEED_COLL_NAME, DOC_HTML_CACHE_KEY_PREFIX, DOC_HTML_CACHE_TTL_SECS},
     models::{
-        user::{User, UserDoc, UserVocab},
-        zh::{CnType, CnPhonetics, CnEnDictEntry}
+        annotation::DocAnnotation,
+        collection::DocCollection,
+        feed::FeedSubscription,
+        progress::ReadingProgress,
+        search::ConcordanceLine,
+        user::{DocVisibility, User, UserDoc, UserDocPage, UserVocab},
+        zh::{CnType, CnPhonetics, CnGranularity, CnRegion, CnEnDictEntry, CnPhrase}
     }
 };
 use mongodb::{
     bson::{self, doc, Bson},
     sync::Database
 };
-use regex::Regex;
-use std::{
-    io::prelude::*,
-    net::TcpStream
+use redis::{
+    AsyncCommands,
+    aio::Connection
 };
+use regex::Regex;
 
 /* Public Functions */
 /// Organizes data from CnEnDictEntry, then renders the appropriate HTML.
 pub fn render_phrase_html(entry: &CnEnDictEntry, cn_type: &CnType, cn_phonetics: &CnPhonetics) -> String {
+    return render_phrase_html_with_parent(entry, None, cn_type, cn_phonetics);
+}
+
+/// Renders the phrase HTML for a single character, with a link back to the word it was split from.
+pub fn render_char_html(entry: &CnEnDictEntry, parent_entry: &CnEnDictEntry, cn_type: &CnType, cn_phonetics: &CnPhonetics) -> String {
+    return render_phrase_html_with_parent(entry, Some(parent_entry), cn_type, cn_phonetics);
+}
+
+/// Renders a numeric phrase (e.g. "2021年", "35%") with its spoken reading.
+/// The reading is stored in data-reading so text-to-speech uses the Chinese reading instead of the digits.
+pub fn render_numeral_html(phrase: &str, reading: &NumeralReading, cn_type: &CnType, cn_phonetics: &CnPhonetics) -> String {
     const SOUND_ICON: &str = "/static/img/volume-up-fill.svg";
-    const DOWNLOAD_ICON: &str = "/static/img/download.svg";
-    let (phrase, char_list): (&str, Vec<char>) = match cn_type {
-        CnType::Traditional => (&entry.trad, entry.trad.chars().collect()),
-        CnType::Simplified => (&entry.simp, entry.simp.chars().collect())
-    };
-    let (phonetic_str, phonetic_list): (&str, Vec<&str>) = match cn_phonetics {
-        CnPhonetics::Pinyin => (&entry.raw_pinyin, entry.formatted_pinyin.split(' ').collect()),
-        CnPhonetics::Zhuyin => (&entry.zhuyin, entry.zhuyin.split(' ').collect())
-    };
-    let mut res = String::with_capacity(2500);
-    // Start <span> (popup config)
-    res += format!("<span class=\"{}\" tabindex=\"0\"", entry.uid).as_str();
-    res += format!(" data-bs-toggle=\"popover\" data-bs-content=\"{}\"", format_defn_html(entry)).as_str();
+    let hanzi = reading.as_hanzi(cn_type);
+    let phonetic_str = reading.as_phonetics(cn_phonetics);
+    let mut res = String::with_capacity(1000);
+    res += format!("<span class=\"numeral\" tabindex=\"0\" data-reading=\"{}\"", hanzi).as_str();
+    res += format!(" data-bs-toggle=\"popover\" data-bs-content=\"{} [{}]\"", hanzi, phonetic_str).as_str();
     res += format!(" title=\"{} [{}]", phrase, phonetic_str).as_str();
-
-    res += format!(" <a role=&quot;button&quot; href=&quot;#~{}&quot;>", phrase).as_str();
+    res += format!(" <a role=&quot;button&quot; href=&quot;#~{}&quot;>", hanzi).as_str();
     res += format!("<img src=&quot;{}&quot;></img>", SOUND_ICON).as_str();
     res += "</a>";
-
-    res += format!(" <a role=&quot;button&quot; href=&quot;#{}&quot;>", entry.uid).as_str();
-    res += format!("<img src=&quot;{}&quot;></img>", DOWNLOAD_ICON).as_str();
-    res += "</a>";
-
     res += "\"";
     res += " data-bs-html=\"true\">";
-    // Start <table> entry (phrase with phonetics)
-    // add phonetic row
     res += "<table>";
-    res += "<tr>";
-    for i in 0..char_list.len() {
-        res += format!("<td class=\"phonetic\" name=\"{}\">", char_list[i]).as_str();
-        res += phonetic_list[i];
-        res += "</td>";
-    }
-    res += "</tr>";
-    // add phrase row
-    res += "<tr>";
-    for i in 0..char_list.len() {
-        res += "<td class=\"char\">";
-        res += &char_list[i].to_string();
-        res += "</td>";
-    }
-    res += "</tr>";
+    res += format!("<tr><td class=\"phonetic\">{}</td></tr>", phonetic_str).as_str();
+    res += format!("<tr><td class=\"char\">{}</td></tr>", phrase).as_str();
     res += "</table>";
     res += "</span>";
-
     return res;
 }
 
-/// Renders the HTML using the given CnType and CnPhonetics.
+/// Renders the HTML using the given CnType, CnPhonetics, CnGranularity and CnRegion.
 /// Refer to tokenizer_string() for formatting details.
-pub async fn convert_string_to_tokenized_html(s: &str, cn_type: &CnType, cn_phonetics: &CnPhonetics) -> String {
-    const PHRASE_DELIM: char = '$';
-    const PINYIN_DELIM: char = '`';
+pub async fn convert_string_to_tokenized_html(s: &str, cn_type: &CnType, cn_phonetics: &CnPhonetics, cn_granularity: &CnGranularity, cn_region: &CnRegion) -> String {
+    let phrases = convert_string_to_tokenized_phrases(s, &CnGranularity::Word).await;
+    return convert_phrases_to_tokenized_html(&phrases, 0, cn_type, cn_phonetics, cn_granularity, cn_region).await;
+}
+
+/// Renders already-tokenized phrases (e.g. from tokenized_body_json) using the given CnType, CnPhonetics, CnGranularity and CnRegion.
+/// Phrases should be tokenized with CnGranularity::Word, since characters are split here for CnGranularity::Character.
+/// Each rendered phrase has its index in the doc (token_offset + its index in phrases) in data-token, e.g. for reading progress.
+pub async fn convert_phrases_to_tokenized_html(phrases: &[CnPhrase], token_offset: usize, cn_type: &CnType, cn_phonetics: &CnPhonetics, cn_granularity: &CnGranularity, cn_region: &CnRegion) -> String {
     let mut conn = connect_to_redis().await.unwrap();
-    let tokenized_string = tokenize_string(s.to_string()).expect("Tokenizer connection error");
-    let n_phrases = tokenized_string.matches(PHRASE_DELIM).count();
-    // Estimate pre-allocated size: max ~2100 chars per phrase (conservitively 2500), 1 usize per char
-    let mut res = String::with_capacity(n_phrases * 2500);
-    // TODO: track set of unique entries, then iterate at end to generate modals
-    // TODO: refactor to Add ruby tags accordingly
-    for token in tokenized_string.split(PHRASE_DELIM) {
-        let token_vec: Vec<&str> = token.split(PINYIN_DELIM).collect();
-        let phrase = token_vec[0]; // If Chinese, then Simplified
-        let raw_pinyin = token_vec[1];
-        let uid = CnEnDictEntry::generate_uid(vec![phrase,raw_pinyin]);
-        // Skip lookup for phrases with no Chinese chars
-        if is_english_phrase(phrase) || has_chinese_punctuation(phrase) {
-            // handle newlines, else render word aligned with other text
-            if phrase.contains('\n') {
-                res += &phrase.replace('\n', "<br>");
-            } else {
-                let mut new_phrase = String::with_capacity(250);
-                new_phrase += "<span><table><tr><td></td></tr><tr><td>";
-                new_phrase += &phrase.replace('\n', "<br>");
-                new_phrase += "</td></tr></table></span>";
-                res += &new_phrase;
-            }
-        } else {
-            // For each phrase, lookup as CnEnDictEntry
-            let entry = CnEnDictEntry::from_uid(&mut conn, uid).await;
-            // TODO: add entry to set
-            if !entry.lookup_succeeded() {
-                res += generate_html_for_not_found_phrase(phrase).as_str();
-            } else {
-                res += render_phrase_html(&entry, cn_type, cn_phonetics).as_str();
+    return render_phrases_html(&mut conn, phrases, token_offset, cn_type, cn_phonetics, cn_granularity, cn_region).await;
+}
+
+/// Renders the phrases of subtitle cues (refer to UserDoc::from_subtitles) as paragraphs, each prefixed with its start timestamp.
+/// Phrases are grouped into cues by their cue start/end. The cue start is kept in data-cue-start so vocab saved from the cue can record it.
+/// token_offset is the index of the first phrase in the doc (refer to convert_phrases_to_tokenized_html).
+pub async fn convert_cues_to_tokenized_html(phrases: &[CnPhrase], token_offset: usize, cn_type: &CnType, cn_phonetics: &CnPhonetics, cn_granularity: &CnGranularity, cn_region: &CnRegion) -> String {
+    let mut conn = connect_to_redis().await.unwrap();
+    let mut res = String::with_capacity(phrases.len() * 2500);
+    let mut i = 0;
+    while i < phrases.len() {
+        // Skip the newlines between cues
+        let (start_ms, end_ms) = match (phrases[i].cue_start_ms, phrases[i].cue_end_ms) {
+            (Some(start_ms), Some(end_ms)) => (start_ms, end_ms),
+            _ => {
+                i += 1;
+                continue;
             }
+        };
+        let mut j = i;
+        while j < phrases.len() && phrases[j].cue_start_ms == Some(start_ms) && phrases[j].cue_end_ms == Some(end_ms) {
+            j += 1;
+        }
+        let start = format_timestamp(start_ms);
+        res += format!("<p class=\"cue\" data-cue-start=\"{}\" data-cue-end=\"{}\"><span class=\"cue-timestamp text-muted\">{}</span> ",
+            &start, format_timestamp(end_ms), &start).as_str();
+        res += render_phrases_html(&mut conn, &phrases[i..j], token_offset + i, cn_type, cn_phonetics, cn_granularity, cn_region).await.as_str();
+        res += "</p>\n";
+        i = j;
+    }
+    return res;
+}
+
+/// Renders sanitized markup (from markup::parse_markup), replacing each text node with its tokenized phrases while keeping the tags.
+/// Phrases are matched to text nodes by their text_node index (refer to match_phrases_to_text_nodes).
+/// token_offset is the index of the first phrase in the doc (refer to convert_phrases_to_tokenized_html).
+pub async fn convert_markup_to_tokenized_html(events: &[MarkupEvent], phrases: &[CnPhrase], token_offset: usize, cn_type: &CnType, cn_phonetics: &CnPhonetics, cn_granularity: &CnGranularity, cn_region: &CnRegion) -> String {
+    let mut conn = connect_to_redis().await.unwrap();
+    let mut res = String::with_capacity(events.len() * 2500);
+    let mut text_ranges = match_phrases_to_text_nodes(events, phrases).into_iter();
+    for event in events {
+        match event {
+            MarkupEvent::Text(_) => {
+                let (i, j) = text_ranges.next().unwrap_or((0, 0));
+                res += render_phrases_html(&mut conn, &phrases[i..j], token_offset + i, cn_type, cn_phonetics, cn_granularity, cn_region).await.as_str();
+            },
+            _ => { res += render_markup(std::slice::from_ref(event)).as_str(); }
         }
     }
-    // Add modals
     return res;
 }
 
-/// Renders the UserDoc table for profile.html.tera.
-pub fn render_document_table(db: &Database, username: &str) -> String {
+/// Gets the rendered HTML for the page of the UserDoc from the cache, if present. Refer to cache_doc_page_html() for the key format.
+pub async fn get_cached_doc_page_html(doc_id: &str, page: i32, cn_type: &CnType, cn_phonetics: &CnPhonetics, cn_granularity: &CnGranularity, cn_region: &CnRegion) -> Option<String> {
+    let mut conn = connect_to_redis().await.ok()?;
+    let key = format!("{}{}", DOC_HTML_CACHE_KEY_PREFIX, doc_id);
+    let field = format_doc_page_cache_field(page, cn_type, cn_phonetics, cn_granularity, cn_region);
+    return conn.hget::<&str, &str, Option<String>>(&key, &field).await.unwrap_or(None);
+}
+
+/// Caches the rendered HTML for the page of the UserDoc. Each UserDoc has one Redis hash (with one field per page + settings),
+/// which expires DOC_HTML_CACHE_TTL_SECS after the last page is cached.
+pub async fn cache_doc_page_html(doc_id: &str, page: i32, cn_type: &CnType, cn_phonetics: &CnPhonetics, cn_granularity: &CnGranularity, cn_region: &CnRegion, html: &str) {
+    let mut conn = match connect_to_redis().await {
+        Ok(conn) => conn,
+        Err(_) => { return; }
+    };
+    let key = format!("{}{}", DOC_HTML_CACHE_KEY_PREFIX, doc_id);
+    let field = format_doc_page_cache_field(page, cn_type, cn_phonetics, cn_granularity, cn_region);
+    if let Err(e) = conn.hset::<&str, &str, &str, ()>(&key, &field, html).await {
+        eprintln!("Error caching HTML for doc {}: {:?}", doc_id, e);
+        return;
+    }
+    let _ = conn.expire::<&str, ()>(&key, DOC_HTML_CACHE_TTL_SECS).await;
+}
+
+/// Removes all cached HTML for the UserDoc. Should be called whenever the UserDoc is changed or deleted.
+pub async fn clear_cached_doc_html(doc_id: &str) {
+    if let Ok(mut conn) = connect_to_redis().await {
+        let key = format!("{}{}", DOC_HTML_CACHE_KEY_PREFIX, doc_id);
+        let _ = conn.del::<&str, ()>(&key).await;
+    }
+}
+
+/// Renders the UserDoc table for profile.html.tera, grouped by collection and showing tags.
+/// If collection_id is given, only docs in that collection (including its sub-collections) are shown.
+pub fn render_document_table(db: &Database, username: &str, collection_id: Option<&str>) -> String {
     // get all documents for user
     const TRASH_ICON: &str = "/static/img/trash.svg";
-    let coll = (*db).collection(USER_DOC_COLL_NAME);
-    let (cn_type, cn_phonetics) = User::get_user_settings(db, username);
     let mut res = String::new();
     res += "<table id=\"doc-table\" class=\"table table-hover\">\n";
-    res += "<thead class=\"table-light\">\n<tr><th>Title</th><th>Source</th><th>Created On (UTC)</th><th>Delete</th></tr>\n";
+    res += "<thead class=\"table-light\">\n<tr><th>Collection</th><th>Title</th><th>Source</th><th>Progress</th><th>Created On (UTC)</th><th>Delete</th></tr>\n";
     res += "</thead>\n";
-    let query_doc = doc! { "username": username, "cn_type": cn_type.as_str(), "cn_phonetics": cn_phonetics.as_str() };
-    match coll.find(query_doc, None) {
-        Ok(cursor) => {
+    // Docs are shown regardless of the current settings, since they're rendered on demand
+    let query_doc = UserDoc::generate_filter_query(db, username, collection_id, None);
+    match UserDoc::try_lookup_all(db, query_doc) {
+        Some(doc_list) => {
+            let collection_paths = DocCollection::format_paths(&DocCollection::lookup_all(db, username));
+            let all_progress = ReadingProgress::lookup_all(db, username);
+            let mut user_docs: Vec<UserDoc> = doc_list.into_iter().map(|user_doc| bson::from_bson(Bson::Document(user_doc)).unwrap()).collect();
+            // group docs by collection (docs without a collection go last)
+            let get_path = |user_doc: &UserDoc| collection_paths.get(&user_doc.collection_id).cloned();
+            user_docs.sort_by(|a, b| (get_path(a).is_none(), get_path(a), &a.created_on).cmp(&(get_path(b).is_none(), get_path(b), &b.created_on)));
             // add each document as a <tr> item
             res += "<tbody>\n";
             let url_re = Regex::new(r"^(http{1}s?://)?(([a-zA-z0-9])+\.)+([a-zA-z0-9]*)(/{1}.*)?$").unwrap();
-            for item in cursor {
-                // unwrap BSON document
-                let user_doc = item.unwrap();
-                let UserDoc { title, created_on, source, .. } = bson::from_bson(Bson::Document(user_doc)).unwrap(); 
-                let delete_button = format!("<a href=\"/api/delete-user-doc/{}\"><img src={}></img></a>", &title, TRASH_ICON);
-                let title = format!("<a href=\"/u/{}/{}\">{}</a>", &username, &title, &title);
-                // only format as link if it's a URL
+            for user_doc in user_docs {
+                let collection = match get_path(&user_doc) {
+                    Some(path) => format!("<a href=\"/collections/{}\">{}</a>", &user_doc.collection_id, path),
+                    None => String::from("n/a")
+                };
+                let UserDoc { doc_id, title, created_on, source, feed_name, tags, visibility, .. } = user_doc;
+                let delete_button = format!("<a href=\"/api/delete-user-doc/{}\"><img src={}></img></a>", &doc_id, TRASH_ICON);
+                let mut title = render_doc_link(&doc_id, &title);
+                if visibility != DocVisibility::Private {
+                    title += format!(" <span class=\"badge bg-warning text-dark\">{}</span>", visibility).as_str();
+                }
+                for tag in tags.iter() {
+                    title += format!(" <span class=\"badge bg-info text-dark\">{}</span>", tag).as_str();
+                }
+                // only format as link if it's a URL (feed items use the item link, so it's escaped like the other text)
                 let source = match url_re.is_match(&source) {
-                    true => format!("<a href=\"{}\" target=\"_blank\">Link</a>", source),
+                    true => format!("<a href=\"{}\" target=\"_blank\">Link</a>", escape_text(&source)),
                     false => {
                         match source.as_str() {
                             "" => String::from("n/a"),
-                            _ => source
+                            _ => escape_text(&source)
                         }
                     }
                 };
-                res += format!("<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n", title, source, &created_on[0..10], delete_button).as_str();
+                let progress = match all_progress.get(&doc_id).map(|progress| progress.percent_complete()) {
+                    Some(Some(percent)) => format!("<a href=\"/doc/{}\">{}%</a>", &doc_id, percent),
+                    Some(None) => String::from("n/a"),
+                    None => String::from("0%")
+                };
+                // tag docs imported from a feed subscription with the feed name
+                let source = match feed_name.as_str() {
+                    "" => source,
+                    _ => format!("{} <span class=\"badge bg-secondary\">{}</span>", source, feed_name)
+                };
+                res += format!("<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n", collection, title, source, progress, &created_on[0..10], delete_button).as_str();
             }
             res += "</tbody>\n";
         },
-        Err(e) => { eprintln!("Error when searching for documents for user {}: {:?}", username, e); }
+        None => { eprintln!("Error when searching for documents for user {}", username); }
     }
     res += "<caption hidden>List of your saved vocabulary.</caption>\n</table>";
     return res;
 }
 
-/// Renders the UserVocab table for profile.html.tera.
-pub fn render_vocab_table(db: &Database, username: &str) -> String {
+/// Renders the table of the user's Public docs for profile.html.tera (shown to other users), linked to their share pages.
+pub fn render_public_document_table(db: &Database, username: &str) -> String {
+    let mut res = String::new();
+    res += "<table id=\"public-doc-table\" class=\"table table-hover\">\n";
+    res += "<thead class=\"table-light\">\n<tr><th>Title</th><th>Author</th><th>Created On (UTC)</th></tr>\n";
+    res += "</thead>\n";
+    res += "<tbody>\n";
+    let query_doc = doc! { "username": username, "visibility": DocVisibility::Public.as_str() };
+    if let Some(doc_list) = UserDoc::try_lookup_all(db, query_doc) {
+        let mut user_docs: Vec<UserDoc> = doc_list.into_iter().filter_map(|user_doc| bson::from_bson(Bson::Document(user_doc)).ok()).collect();
+        user_docs.sort_by(|a, b| b.created_on.cmp(&a.created_on));
+        for user_doc in user_docs {
+            let author = match user_doc.author.as_str() {
+                "" => String::from("n/a"),
+                author => escape_text(author)
+            };
+            res += format!("<tr><td><a href=\"/share/{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                &user_doc.share_id, escape_text(&user_doc.title), author, &user_doc.created_on[0..10]).as_str();
+        }
+    }
+    res += "</tbody>\n";
+    res += "<caption hidden>List of public documents.</caption>\n</table>";
+    return res;
+}
+
+/// Renders the DocCollection table for profile.html.tera. Sub-collections are listed by their full path.
+pub fn render_collection_table(db: &Database, username: &str) -> String {
+    const TRASH_ICON: &str = "/static/img/trash.svg";
+    let all_collections = DocCollection::lookup_all(db, username);
+    let collection_paths = DocCollection::format_paths(&all_collections);
+    let mut res = String::new();
+    res += "<table id=\"collection-table\" class=\"table table-hover\">\n";
+    res += "<thead class=\"table-light\">\n<tr><th>Collection</th><th>Documents</th><th>Created On (UTC)</th><th>Delete</th></tr>\n";
+    res += "</thead>\n";
+    res += "<tbody>\n";
+    let doc_counts = UserDoc::count_by_collection(db, username);
+    let mut rows: Vec<(String, String)> = Vec::new();
+    for c in all_collections.iter() {
+        let path = collection_paths.get(&c.collection_id).cloned().unwrap_or_default();
+        // counts include docs in sub-collections
+        let collection_ids = DocCollection::get_descendant_ids(&all_collections, &c.collection_id);
+        let n_docs: i64 = collection_ids.iter().filter_map(|collection_id| doc_counts.get(collection_id)).sum();
+        let delete_button = format!("<a href=\"/api/delete-collection/{}\"><img src={}></img></a>", &c.collection_id, TRASH_ICON);
+        let row = format!("<tr><td><a href=\"/collections/{}\">{}</a></td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            &c.collection_id, &path, n_docs, &c.created_on[0..10], delete_button);
+        rows.push((path, row));
+    }
+    rows.sort();
+    for (_, row) in rows {
+        res += &row;
+    }
+    res += "</tbody>\n";
+    res += "<caption hidden>List of your document collections.</caption>\n</table>";
+    return res;
+}
+
+/// Renders the FeedSubscription table for profile.html.tera.
+pub fn render_feed_table(db: &Database, username: &str) -> String {
+    const TRASH_ICON: &str = "/static/img/trash.svg";
+    let coll = (*db).collection(FEED_COLL_NAME);
+    let mut res = String::new();
+    res += "<table id=\"feed-table\" class=\"table table-hover\">\n";
+    res += "<thead class=\"table-light\">\n<tr><th>Feed</th><th>URL</th><th>Last Checked (UTC)</th><th>Unsubscribe</th></tr>\n";
+    res += "</thead>\n";
+    match coll.find(doc! { "username": username }, None) {
+        Ok(cursor) => {
+            res += "<tbody>\n";
+            // add each subscription as a <tr> item
+            for item in cursor {
+                // unwrap BSON document
+                let feed_doc = item.unwrap();
+                let FeedSubscription { feed_id, feed_url, feed_name, last_polled_on, .. } = bson::from_bson(Bson::Document(feed_doc)).unwrap();
+                let delete_button = format!("<a href=\"/api/delete-feed/{}\"><img src={}></img></a>", &feed_id, TRASH_ICON);
+                let feed_url = format!("<a href=\"{}\" target=\"_blank\">Link</a>", escape_text(&feed_url));
+                let last_polled_on = match last_polled_on.len() >= 16 {
+                    true => String::from(&last_polled_on[0..16]),
+                    false => String::from("Pending")
+                };
+                res += format!("<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n", feed_name, feed_url, last_polled_on, delete_button).as_str();
+            }
+            res += "</tbody>\n";
+        },
+        Err(e) => { eprintln!("Error when searching for feeds for user {}: {:?}", username, e); }
+    }
+    res += "<caption hidden>List of your feed subscriptions.</caption>\n</table>";
+    return res;
+}
+
+/// Renders an <option> for each of the user's collections (by full path), for collection <select> inputs.
+pub fn render_collection_options(db: &Database, username: &str, selected_id: &str) -> String {
+    let collection_paths = DocCollection::format_paths(&DocCollection::lookup_all(db, username));
+    let mut options: Vec<(&String, &String)> = collection_paths.iter().map(|(collection_id, path)| (path, collection_id)).collect();
+    options.sort();
+    let mut res = String::new();
+    for (path, collection_id) in options {
+        let selected = match collection_id == selected_id {
+            true => " selected",
+            false => ""
+        };
+        res += format!("<option value=\"{}\"{}>{}</option>\n", collection_id, selected, path).as_str();
+    }
+    return res;
+}
+
+/// Renders the UserVocab table for profile.html.tera. If collection_id is given, only vocab saved from docs in that collection is shown.
+pub fn render_vocab_table(db: &Database, username: &str, collection_id: Option<&str>) -> String {
     const TRASH_ICON: &str = "/static/img/trash.svg";
     let coll = (*db).collection(USER_VOCAB_COLL_NAME);
     let (cn_type, cn_phonetics) = User::get_user_settings(db, username);
     let mut res = String::new();
     res += "<table id=\"vocab-table\" class=\"table table-hover\">\n";
-    res += "<thead class=\"table-light\">\n<tr><th>Phrase</th><th>Saved From</th><th>Radical Map</th><th>Saved On (UTC)</th><th>Delete</th></tr>\n";
+    res += "<thead class=\"table-light\">\n<tr><th>Phrase</th><th>Saved From</th><th>Radical Map</th><th>Saved On (UTC)</th><th>Contexts</th><th>Delete</th></tr>\n";
     res += "</thead>\n";
-    let query_doc = doc! { "username": username, "cn_type": cn_type.as_str(), "cn_phonetics": cn_phonetics.as_str() };
+    let mut query_doc = doc! { "username": username, "cn_type": cn_type.as_str(), "cn_phonetics": cn_phonetics.as_str() };
+    // only show vocab saved from docs in the collection (including its sub-collections)
+    if let Some(collection_id) = collection_id {
+        let doc_ids = UserDoc::lookup_doc_ids(db, UserDoc::generate_filter_query(db, username, Some(collection_id), None));
+        query_doc.insert("from_doc_id", doc! { "$in": doc_ids });
+    }
     match coll.find(query_doc, None) {
         Ok(cursor) => {
             res += "<tbody>\n";
@@ -177,13 +354,15 @@ pub fn render_vocab_table(db: &Database, username: &str) -> String {
             for item in cursor {
                 // unwrap BSON document
                 let user_doc = item.unwrap();
-                let UserVocab { uid, from_doc_title, phrase_html, created_on, radical_map, from_sandbox, .. } = bson::from_bson(Bson::Document(user_doc)).unwrap();
-                let from_doc_title = match from_sandbox {
-                    true => format!("<a href=\"../{}/{}\">{}</a>", "sandbox", from_doc_title, "Sandbox"),
-                    false => format!("<a href=\"{}/{}\">{}</a>", username, from_doc_title, from_doc_title)
-                };
+                let UserVocab { uid, from_doc_title, from_doc_id, from_share_id, phrase_html, created_on, radical_map, from_sandbox, cue_timestamp, .. } = bson::from_bson(Bson::Document(user_doc)).unwrap();
+                let mut from_doc_title = render_vocab_source_link(username, &from_doc_title, &from_doc_id, &from_share_id, from_sandbox);
+                // For subtitle documents, show where the phrase was seen
+                if cue_timestamp.len() > 0 {
+                    from_doc_title += format!(" ({})", cue_timestamp).as_str();
+                }
                 let delete_button = format!("<a href=\"/api/delete-user-vocab/{}\"><img src={}></img></a>", uid, TRASH_ICON);
-                let row = format!("<tr><td>{}</td><td>{}</td><td style\"white-space: pre\">{}</td><td>{}</td><td>{}</td></tr>\n", phrase_html, &from_doc_title, radical_map, &created_on[0..10], &delete_button);
+                let contexts_link = format!("<a href=\"/vocab/{}\">View</a>", uid);
+                let row = format!("<tr><td>{}</td><td>{}</td><td style\"white-space: pre\">{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n", phrase_html, &from_doc_title, radical_map, &created_on[0..10], &contexts_link, &delete_button);
                 res += &row;
             }
             res += "</tbody>\n";
@@ -194,25 +373,328 @@ pub fn render_vocab_table(db: &Database, username: &str) -> String {
     return res;
 }
 
+/// Renders keyword-in-context lines as a table (context aligned around the phrase), each linked to its position in the reader.
+pub fn render_concordance_table(lines: &[ConcordanceLine]) -> String {
+    let mut res = String::new();
+    res += "<table id=\"concordance-table\" class=\"table table-hover\">\n";
+    res += "<thead class=\"table-light\">\n<tr><th>Document</th><th></th><th>Phrase</th><th></th></tr>\n";
+    res += "</thead>\n";
+    res += "<tbody>\n";
+    for line in lines.iter() {
+        let doc_link = format!("<a href=\"{}\">{}</a>", line.url, escape_text(&line.doc_title));
+        res += format!("<tr><td>{}</td><td class=\"text-end\">{}</td><td><a href=\"{}\"><strong>{}</strong></a></td><td class=\"text-start\">{}</td></tr>\n",
+            doc_link, escape_text(&line.left), line.url, escape_text(&line.phrase), escape_text(&line.right)).as_str();
+    }
+    res += "</tbody>\n";
+    res += "<caption hidden>Occurrences of the phrase in your saved documents.</caption>\n</table>";
+    return res;
+}
+
+/// Marks the annotated phrases in the rendered page HTML using their data-token, with the space-separated annotation_ids in data-annotation-ids.
+/// Annotations are applied on top of the (cached) page HTML at request time, so they're kept when the reader settings change.
+pub fn render_annotation_marks(html: &str, annotations: &[DocAnnotation]) -> String {
+    const TOKEN_ATTR: &str = " data-token=\"";
+    if annotations.len() == 0 {
+        return String::from(html);
+    }
+    let mut res = String::with_capacity(html.len() + annotations.len() * 100);
+    let mut rest = html;
+    while let Some(i) = rest.find(TOKEN_ATTR) {
+        let value_start = i + TOKEN_ATTR.len();
+        let value_end = match rest[value_start..].find('"') {
+            Some(n) => value_start + n,
+            None => break
+        };
+        res += &rest[..value_end + 1];
+        if let Ok(token_index) = rest[value_start..value_end].parse::<i32>() {
+            let annotation_ids: Vec<&str> = annotations.iter()
+                .filter(|a| a.token_start <= token_index && token_index < a.token_end)
+                .map(|a| a.annotation_id.as_str())
+                .collect();
+            if annotation_ids.len() > 0 {
+                res += format!(" data-annotation-ids=\"{}\"", annotation_ids.join(" ")).as_str();
+            }
+        }
+        rest = &rest[value_end + 1..];
+    }
+    res += rest;
+    return res;
+}
+
+/// Renders the list of annotations (highlighted text, note, and a delete button) for reader.html.tera.
+/// Each annotation links to its page (refer to highlightAnnotation in reader.html.tera).
+pub fn render_annotation_list(annotations: &[DocAnnotation], pages: &[UserDocPage], doc_url: &str) -> String {
+    const MAX_TEXT_CHARS: usize = 50;
+    if annotations.len() == 0 {
+        return String::from("<p class=\"text-muted\">No highlights yet. Select some text, then click Highlight Selection.</p>");
+    }
+    let mut res = String::new();
+    res += "<ul class=\"list-group\">\n";
+    for a in annotations.iter() {
+        let page = pages.iter()
+            .find(|p| p.token_start <= a.token_start && a.token_start < p.token_end)
+            .map(|p| p.page)
+            .unwrap_or(1);
+        let mut text: String = a.text.chars().take(MAX_TEXT_CHARS).collect();
+        if a.text.chars().count() > MAX_TEXT_CHARS {
+            text += "…";
+        }
+        res += format!("<li class=\"list-group-item\"><a href=\"{}/{}#annotation-{}\">{}</a>", doc_url, page, &a.annotation_id, escape_text(&text)).as_str();
+        if a.note.len() > 0 {
+            res += format!("<p class=\"mb-1 text-muted\">{}</p>", escape_text(&a.note).replace('\n', "<br>")).as_str();
+        }
+        res += format!("<button class=\"btn btn-sm btn-outline-danger\" type=\"button\" onclick=\"deleteAnnotation('{}')\">Delete</button></li>\n", &a.annotation_id).as_str();
+    }
+    res += "</ul>";
+    return res;
+}
+
+/// Renders the previous/next page links and the page (chapter) selector for reader.html.tera.
+/// Pages are 1-indexed, and linked as {doc_url}/{page} (e.g. /doc/<doc_id>/2). Pages without a heading are listed by number.
+pub fn render_page_nav(doc_url: &str, page: i32, page_headings: &[&str]) -> String {
+    let n_pages = page_headings.len() as i32;
+    let mut res = String::new();
+    res += "<nav class=\"page-nav d-flex justify-content-between align-items-center my-3\">\n";
+    res += match page > 1 {
+        true => format!("<a class=\"btn btn-outline-secondary\" href=\"{}/{}\">&laquo; Previous</a>\n", doc_url, page - 1),
+        false => String::from("<a class=\"btn btn-outline-secondary disabled\" aria-disabled=\"true\">&laquo; Previous</a>\n")
+    }.as_str();
+    res += "<select class=\"form-select w-auto\" aria-label=\"Go to page\" onchange=\"window.location.href=this.value\">\n";
+    for (i, heading) in page_headings.iter().enumerate() {
+        let i = i as i32 + 1;
+        let selected = match i == page {
+            true => " selected",
+            false => ""
+        };
+        let label = match heading.len() {
+            0 => format!("Page {} of {}", i, n_pages),
+            _ => format!("{} ({}/{})", escape_text(heading), i, n_pages)
+        };
+        res += format!("<option value=\"{}/{}\"{}>{}</option>\n", doc_url, i, selected, label).as_str();
+    }
+    res += "</select>\n";
+    res += match page < n_pages {
+        true => format!("<a class=\"btn btn-outline-secondary\" href=\"{}/{}\">Next &raquo;</a>\n", doc_url, page + 1),
+        false => String::from("<a class=\"btn btn-outline-secondary disabled\" aria-disabled=\"true\">Next &raquo;</a>\n")
+    }.as_str();
+    res += "</nav>";
+    return res;
+}
+
 /* Private Functions */
-/// Connect to tokenizer service and tokenizes the string. The delimiters are $ and ` since neither character appears in CEDICT.
-/// The format of the string is: "phrase1`raw_pinyin$phrase2`raw_pinyin2$ ..."
-/// The string is written to the TCP stream until completion.
-/// From the tokenizer, 2 messages are sent: 
-///     1) A u64 (as bytes) indicating the size of the tokenizer results
-///     2) The tokenizer result string (as bytes)
-fn tokenize_string(mut s: String) -> std::io::Result<String> {
-    s = s.replace("  ", ""); // remove excess whitespace for tokenization, keep newlines. "  " instead of " " to preserve non-Chinese text
-    let mut stream = TcpStream::connect(format!("{}:{}", TOKENIZER_HOSTNAME, TOKENIZER_PORT))?;
-    stream.write_all(s.as_bytes())?;
-    let mut header_bytes = [0; 64];
-    stream.read_exact(&mut header_bytes)?;
-    let n_bytes: usize = String::from_utf8(header_bytes.to_vec()).unwrap()
-        .trim().parse::<usize>().unwrap();
-    let mut tokenized_bytes = vec![0; n_bytes];
-    stream.read_exact(&mut tokenized_bytes)?;
-    let res = String::from_utf8(tokenized_bytes).unwrap();
-    return Ok(res);
+/// Returns the range of phrases [i, j) for each Text event, in order. The k-th Text event on the page gets the phrases with text_node == Some(k)
+/// (refer to UserDoc::tokenize_markup), so text nodes without any phrases (e.g. whitespace between tags) get an empty range.
+fn match_phrases_to_text_nodes(events: &[MarkupEvent], phrases: &[CnPhrase]) -> Vec<(usize, usize)> {
+    let mut res: Vec<(usize, usize)> = Vec::new();
+    let mut i = 0;
+    let n_text_nodes = events.iter().filter(|event| matches!(event, MarkupEvent::Text(_))).count();
+    for k in 0..n_text_nodes {
+        // Skip the newlines between blocks
+        while i < phrases.len() && phrases[i].text_node.is_none() {
+            i += 1;
+        }
+        let mut j = i;
+        while j < phrases.len() && phrases[j].text_node == Some(k as i32) {
+            j += 1;
+        }
+        res.push((i, j));
+        i = j;
+    }
+    return res;
+}
+
+/// Renders a link to the doc in the reader. Titles can come from other sites (e.g. feed items), so they're escaped.
+fn render_doc_link(doc_id: &str, title: &str) -> String {
+    return format!("<a href=\"/doc/{}\">{}</a>", doc_id, escape_text(title));
+}
+
+/// Renders a link to the doc a UserVocab was saved from. Titles of shared (and cloned) docs are set by other users, so they're always escaped.
+fn render_vocab_source_link(username: &str, from_doc_title: &str, from_doc_id: &str, from_share_id: &str, from_sandbox: bool) -> String {
+    let res = match (from_sandbox, from_share_id.len() > 0, from_doc_id.len() > 0) {
+        (true, _, _) => format!("<a href=\"../{}/{}\">{}</a>", "sandbox", escape_text(from_doc_title), "Sandbox"),
+        // Saved from a doc shared by another user
+        (false, true, _) => format!("<a href=\"/share/{}\">{}</a>", from_share_id, escape_text(from_doc_title)),
+        (false, false, true) => render_doc_link(from_doc_id, from_doc_title),
+        // Not linked to a doc_id (e.g. the doc was deleted), so fallback to the title URL
+        (false, false, false) => format!("<a href=\"{}/{}\">{}</a>", username, escape_text(from_doc_title), escape_text(from_doc_title))
+    };
+    return res;
+}
+
+/// Renders the phrases using the given Redis connection (refer to convert_phrases_to_tokenized_html), so a page with many text nodes
+/// or cues only connects once.
+async fn render_phrases_html(conn: &mut Connection, phrases: &[CnPhrase], token_offset: usize, cn_type: &CnType, cn_phonetics: &CnPhonetics, cn_granularity: &CnGranularity, cn_region: &CnRegion) -> String {
+    // Estimate pre-allocated size: max ~2100 chars per phrase (conservitively 2500), 1 usize per char
+    let mut res = String::with_capacity(phrases.len() * 2500);
+    // TODO: track set of unique entries, then iterate at end to generate modals
+    // TODO: refactor to Add ruby tags accordingly
+    // Set when a numeral reading includes the following token (e.g. "35" followed by "%")
+    let mut skip_next_phrase = false;
+    for (i, cn_phrase) in phrases.iter().enumerate() {
+        if skip_next_phrase {
+            skip_next_phrase = false;
+            continue;
+        }
+        let (phrase, raw_pinyin) = (cn_phrase.raw_phrase.as_str(), cn_phrase.raw_phonetics.as_str()); // If Chinese, then Simplified
+        let phrase_start = res.len();
+        // Following phrase is used as context for numerals (e.g. "2021" followed by "年")
+        let next_phrase = match phrases.get(i + 1) {
+            Some(next_phrase) => next_phrase.raw_phrase.as_str(),
+            None => ""
+        };
+        if let Some(reading) = numerals::read_numeral(phrase, next_phrase) {
+            skip_next_phrase = reading.consumes_next_phrase();
+            let numeral = match skip_next_phrase {
+                true => format!("{}{}", phrase, next_phrase),
+                false => String::from(phrase)
+            };
+            res += render_numeral_html(&numeral, &reading, cn_type, cn_phonetics).as_str();
+        } else if !script::contains_han(phrase) || script::has_cjk_punctuation(phrase) {
+            // Skip lookup for phrases with no Chinese chars
+            // handle newlines, else render word aligned with other text
+            if phrase.contains('\n') {
+                res += &phrase.replace('\n', "<br>");
+            } else {
+                let mut new_phrase = String::with_capacity(250);
+                new_phrase += "<span><table><tr><td></td></tr><tr><td>";
+                new_phrase += &escape_text(phrase);
+                new_phrase += "</td></tr></table></span>";
+                res += &new_phrase;
+            }
+        } else {
+            // The entry was looked-up when the phrase was tokenized
+            let mut entry = cn_phrase.entry.clone();
+            // Regional equivalents are shown for Traditional only (e.g. Taiwan usage)
+            if let CnType::Traditional = cn_type {
+                entry.add_regional_phrase(conn, cn_region).await;
+            }
+            // TODO: add entry to set
+            if let (CnGranularity::Character, Some(char_list)) = (cn_granularity, split_phrase_into_chars(phrase, raw_pinyin)) {
+                // For character mode, render each character separately (linked to the parent word)
+                for (raw_char, raw_char_pinyin) in char_list {
+                    let char_entry = CnEnDictEntry::from_phrase(conn, &raw_char, &raw_char_pinyin).await;
+                    let char_start = res.len();
+                    if !char_entry.lookup_succeeded() {
+                        res += generate_html_for_not_found_phrase(&raw_char).as_str();
+                    } else if !cn_phrase.lookup_success {
+                        res += render_phrase_html(&char_entry, cn_type, cn_phonetics).as_str();
+                    } else {
+                        res += render_char_html(&char_entry, &entry, cn_type, cn_phonetics).as_str();
+                    }
+                    // Each character is marked with the index of its phrase
+                    add_token_index_attr(&mut res, char_start, token_offset + i);
+                }
+            } else if !cn_phrase.lookup_success {
+                res += generate_html_for_not_found_phrase(phrase).as_str();
+            } else {
+                res += render_phrase_html(&entry, cn_type, cn_phonetics).as_str();
+            }
+        }
+        if !res[phrase_start..].starts_with("<span data-token=") {
+            add_token_index_attr(&mut res, phrase_start, token_offset + i);
+        }
+    }
+    // Add modals
+    return res;
+}
+
+/// Adds data-token (the index of the phrase in the doc) to the phrase element starting at html[start..], if it's a <span>.
+fn add_token_index_attr(html: &mut String, start: usize, token_index: usize) {
+    if html[start..].starts_with("<span") {
+        html.insert_str(start + "<span".len(), format!(" data-token=\"{}\"", token_index).as_str());
+    }
+}
+
+/// Formats the cache field for a page rendered with the given settings (e.g. "1:Simplified:Pinyin:Word:Mainland").
+fn format_doc_page_cache_field(page: i32, cn_type: &CnType, cn_phonetics: &CnPhonetics, cn_granularity: &CnGranularity, cn_region: &CnRegion) -> String {
+    return format!("{}:{}:{}:{}:{}", page, cn_type.as_str(), cn_phonetics.as_str(), cn_granularity.as_str(), cn_region.as_str());
+}
+
+/// Renders the phrase HTML. If a parent_entry is given, the popover links back to that word.
+fn render_phrase_html_with_parent(entry: &CnEnDictEntry, parent_entry: Option<&CnEnDictEntry>, cn_type: &CnType, cn_phonetics: &CnPhonetics) -> String {
+    const SOUND_ICON: &str = "/static/img/volume-up-fill.svg";
+    const DOWNLOAD_ICON: &str = "/static/img/download.svg";
+    let (phrase, char_list): (&str, Vec<char>) = match cn_type {
+        CnType::Traditional => (&entry.trad, entry.trad.chars().collect()),
+        CnType::Simplified => (&entry.simp, entry.simp.chars().collect())
+    };
+    let (phonetic_str, phonetic_list): (&str, Vec<&str>) = match cn_phonetics {
+        CnPhonetics::Pinyin => (&entry.raw_pinyin, entry.formatted_pinyin.split(' ').collect()),
+        CnPhonetics::Zhuyin => (&entry.zhuyin, entry.zhuyin.split(' ').collect())
+    };
+    let mut res = String::with_capacity(2500);
+    // Start <span> (popup config)
+    res += format!("<span class=\"{}\" tabindex=\"0\"", entry.uid).as_str();
+    if let Some(parent) = parent_entry {
+        res += format!(" data-parent-uid=\"{}\"", parent.uid).as_str();
+    }
+    res += format!(" data-bs-toggle=\"popover\" data-bs-content=\"{}", format_defn_html(entry)).as_str();
+    if let Some(parent) = parent_entry {
+        res += format_parent_html(parent, cn_type, cn_phonetics).as_str();
+    }
+    if let (CnType::Traditional, false) = (cn_type, entry.regional_phrase.is_empty()) {
+        res += format!("<hr>Regional usage: {}", entry.regional_phrase).as_str();
+    }
+    res += "\"";
+    res += format!(" title=\"{} [{}]", phrase, phonetic_str).as_str();
+
+    res += format!(" <a role=&quot;button&quot; href=&quot;#~{}&quot;>", phrase).as_str();
+    res += format!("<img src=&quot;{}&quot;></img>", SOUND_ICON).as_str();
+    res += "</a>";
+
+    res += format!(" <a role=&quot;button&quot; href=&quot;#{}&quot;>", entry.uid).as_str();
+    res += format!("<img src=&quot;{}&quot;></img>", DOWNLOAD_ICON).as_str();
+    res += "</a>";
+
+    res += "\"";
+    res += " data-bs-html=\"true\">";
+    // Start <table> entry (phrase with phonetics)
+    // add phonetic row
+    res += "<table>";
+    res += "<tr>";
+    if entry.is_generated_erhua() {
+        // Erhua readings are merged across the phrase (e.g. yīdiǎnr), since 儿 has no syllable of its own
+        let merged_phonetics = match cn_phonetics {
+            CnPhonetics::Pinyin => phonetic_list.concat(),
+            CnPhonetics::Zhuyin => phonetic_list.join(" ")
+        };
+        res += format!("<td class=\"phonetic\" colspan=\"{}\">{}</td>", char_list.len(), merged_phonetics).as_str();
+    } else {
+        for (i, c) in char_list.iter().enumerate() {
+            res += format!("<td class=\"phonetic\" name=\"{}\">", c).as_str();
+            res += phonetic_list.get(i).unwrap_or(&"");
+            res += "</td>";
+        }
+    }
+    res += "</tr>";
+    // add phrase row
+    res += "<tr>";
+    for c in char_list.iter() {
+        res += "<td class=\"char\">";
+        res += &c.to_string();
+        res += "</td>";
+    }
+    res += "</tr>";
+    res += "</table>";
+    res += "</span>";
+
+    return res;
+}
+
+/// Formats the "part of" section linking a character back to its parent word. Used in render_phrase_html_with_parent().
+fn format_parent_html(parent: &CnEnDictEntry, cn_type: &CnType, cn_phonetics: &CnPhonetics) -> String {
+    let phrase = match cn_type {
+        CnType::Traditional => &parent.trad,
+        CnType::Simplified => &parent.simp
+    };
+    let phonetic_str = match cn_phonetics {
+        CnPhonetics::Pinyin => &parent.formatted_pinyin,
+        CnPhonetics::Zhuyin => &parent.zhuyin
+    };
+    let mut res = String::with_capacity(250);
+    res += "<hr>Part of: ";
+    res += format!("<a role=&quot;button&quot; href=&quot;#{}&quot;>{}</a> [{}]", parent.uid, phrase, phonetic_str).as_str();
+    return res;
 }
 
 /// Formats the definition in the phrase HTML. Used in render_phrase_html().
@@ -237,33 +719,6 @@ fn format_defn_html(entry: &CnEnDictEntry) -> String {
     return res;
 }
 
-/// A weak check to distinguish if a phrase is English.
-/// English chars use 1 byte, Chinese chars use 3 bytes.
-fn is_english_phrase(s: &str) -> bool {
-    return s.len() == s.chars().count();
-}
-
-/// Identifies phrases with Chinese puntuation.
-/// Chinese punctuation is a Chinese char that shouldn't be processed as a phrase.
-/// Unicode ranges from: https://jrgraphix.net/r/Unicode/
-fn has_chinese_punctuation(s: &str) -> bool {
-    let mut res = false;
-    for c in s.chars() {
-        match *(&c) {
-            '\u{3000}'..='\u{303D}' => {
-                res = true;
-                break;
-            },
-            '\u{FF00}'..='\u{FFEF}' => {
-                res = true;
-                break;
-            },
-            _ => {}
-        }
-    }
-    return res;
-}
-
 /// Generates generic HTML with a "Phrase not found in database" popup.
 fn generate_html_for_not_found_phrase(phrase: &str) -> String {
     let mut res = String::with_capacity(2500); // Using ~2500 characters as conservative estimate
@@ -287,4 +742,74 @@ fn generate_html_for_not_found_phrase(phrase: &str) -> String {
 //         None => String::new()
 //     };
 //     html! { <>{c}<rp>{"("}</rp><rt class="mr-1">{phonetic}</rt><rp>{")"}</rp></> }
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markup::{BodyFormat, parse_markup};
+
+    /// Numbers phrases like UserDoc::tokenize_markup, with one phrase per non-whitespace text node (whitespace-only nodes yield no phrases).
+    fn make_markup_phrases(events: &[MarkupEvent]) -> Vec<CnPhrase> {
+        let mut res: Vec<CnPhrase> = Vec::new();
+        let mut text_node = 0;
+        for event in events.iter() {
+            match event {
+                MarkupEvent::Text(text) => {
+                    if text.trim().len() > 0 {
+                        res.push(CnPhrase { raw_phrase: text.clone(), text_node: Some(text_node), ..CnPhrase::default() });
+                    }
+                    text_node += 1;
+                },
+                _ if event.is_line_break() && res.len() > 0 => {
+                    res.push(CnPhrase { raw_phrase: String::from("\n"), ..CnPhrase::default() });
+                },
+                _ => { }
+            }
+        }
+        return res;
+    }
+
+    fn render_text_nodes(events: &[MarkupEvent], phrases: &[CnPhrase]) -> Vec<String> {
+        let res = match_phrases_to_text_nodes(events, phrases).into_iter()
+            .map(|(i, j)| phrases[i..j].iter().map(|phrase| phrase.raw_phrase.as_str()).collect())
+            .collect();
+        return res;
+    }
+
+    #[test]
+    fn markup_phrases_skip_whitespace_nodes() {
+        for (body, format) in [("<p><b>你好</b> <i>世界</i></p>\n<p>再见</p>", BodyFormat::Html), ("**你好** *世界*\n\n再见", BodyFormat::Markdown)].iter() {
+            let events = parse_markup(body, format);
+            let phrases = make_markup_phrases(&events);
+            let expected: Vec<String> = events.iter().filter_map(|event| match event {
+                MarkupEvent::Text(text) if text.trim().len() > 0 => Some(text.clone()),
+                MarkupEvent::Text(_) => Some(String::new()),
+                _ => None
+            }).collect();
+            assert!(expected.contains(&String::new()), "no whitespace-only node in {:?}", events);
+            assert_eq!(render_text_nodes(&events, &phrases), expected);
+        }
+    }
+
+    #[test]
+    fn doc_link_escapes_title() {
+        let link = render_doc_link("doc-id", "<img src=x onerror=alert(1)>");
+        assert_eq!(link, "<a href=\"/doc/doc-id\">&lt;img src=x onerror=alert(1)&gt;</a>");
+    }
+
+    #[test]
+    fn vocab_source_link_escapes_title() {
+        let title = "<script>alert(1)</script>";
+        let links = [
+            render_vocab_source_link("user", title, "", "", true),
+            render_vocab_source_link("user", title, "doc-id", "share-id", false),
+            render_vocab_source_link("user", title, "doc-id", "", false),
+            render_vocab_source_link("user", title, "", "", false)
+        ];
+        for link in links.iter() {
+            assert!(!link.contains("<script>"), "{}", link);
+        }
+        assert_eq!(links[2], "<a href=\"/doc/doc-id\">&lt;script&gt;alert(1)&lt;/script&gt;</a>");
+    }
+}
@@ -6,6 +6,7 @@
 #[macro_use] extern crate rocket;
 
 /// Module handling user authentication and cookies
+#[allow(clippy::redundant_field_names, clippy::needless_borrow)]
 pub mod auth;
 /// Centralized config file provided for reference and deployment
 pub mod config;
@@ -17,28 +18,55 @@ pub mod api;
 pub mod routes;
 /// Module for server-side html rendering (e.g. phrase rendering)
 pub mod html_rendering;
+/// Module for Unicode script classification (e.g. Han vs. Latin text)
+pub mod script;
+/// Module for reading numbers, dates and units as spoken Chinese
+pub mod numerals;
+/// Module for extracting the readable main content from HTML pages
+pub mod readability;
+/// Module for detecting and decoding legacy Chinese encodings (e.g. GBK, Big5)
+pub mod charset;
+/// Module for fetching user-supplied URLs safely
+pub mod fetch;
+/// Module for reading EPUB files
+pub mod epub;
+/// Module for parsing subtitle files (.srt, .vtt, .ass)
+pub mod subtitles;
+/// Module for parsing RSS and Atom feeds
+pub mod feeds;
+/// Module for sanitizing Markdown/HTML document bodies
+pub mod markup;
+/// Module for splitting long documents into pages
+pub mod sections;
+/// Module for Chinese-aware full-text search
+pub mod search;
 
 use crate::{
-    config::{DB_URI, DB_NAME, REDIS_URI, TOKENIZER_HOSTNAME, TOKENIZER_PORT},
+    config::{DB_URI, DB_NAME, REDIS_URI, TOKENIZER_HOSTNAME, TOKENIZER_PORT, TOKENIZER_CHUNK_BYTES, TOKENIZER_HEADER_BYTES, TW_PHRASES_TRAD_KEY, TW_PHRASES_SIMP_KEY},
     models::{
-        zh::{CnEnDictEntry, CnPhrase}
-    }
+        zh::{CnEnDictEntry, CnGranularity, CnPhrase, CnRegion}
+    },
+    readability::{ExtractedArticle, extract_article},
+    charset::{decode_html_bytes, decode_text_bytes, url_decode_to_bytes},
+    fetch::{FetchError, fetch_url}
 };
 use mongodb::{
     bson::{self, doc, document::Document, Bson},
     sync::Database
 };
-use reqwest;
 use rocket::http::RawStr;
 use serde::Serialize;
-use scraper;
 use std::{
+    collections::HashMap,
     error::Error,
     marker::Sized,
     io::prelude::*,
     net::TcpStream
 };
-use redis::aio::Connection;
+use redis::{
+    AsyncCommands,
+    aio::Connection
+};
 
 
 /* Traits */
@@ -129,6 +157,59 @@ pub trait DatabaseItem {
     fn primary_key(&self) -> &str;
 }
 
+/* Structs */
+/// Connections to the tokenizer service and Redis, kept open while a document is tokenized in many pieces
+/// (e.g. one per subtitle cue or markup text node), so each piece doesn't reconnect.
+pub struct TokenizerSession {
+    stream: TcpStream,
+    conn: Connection
+}
+
+impl TokenizerSession {
+    pub async fn connect() -> Result<Self, Box<dyn Error>> {
+        let stream = TcpStream::connect(format!("{}:{}", TOKENIZER_HOSTNAME, TOKENIZER_PORT))?;
+        let conn = connect_to_redis().await?;
+        return Ok(TokenizerSession { stream, conn });
+    }
+    /// Tokenizes the string and looks up each phrase, splitting phrases into characters for CnGranularity::Character.
+    pub async fn tokenize(&mut self, s: &str, cn_granularity: &CnGranularity) -> Result<Vec<CnPhrase>, Box<dyn Error>> {
+        let tokenized_string = tokenize_string(&mut self.stream, s)?;
+        let token_list = split_tokenized_string(&tokenized_string);
+        let mut res = Vec::with_capacity(token_list.len());
+        for (raw_phrase, raw_phonetics) in token_list {
+            let uid = CnEnDictEntry::generate_uid(vec![&raw_phrase,&raw_phonetics]);
+            // For character mode, each character is looked-up separately and linked to the parent word
+            if let (CnGranularity::Character, Some(char_list)) = (cn_granularity, split_phrase_into_chars(&raw_phrase, &raw_phonetics)) {
+                for (raw_char, raw_char_phonetics) in char_list {
+                    let entry = CnEnDictEntry::from_phrase(&mut self.conn, &raw_char, &raw_char_phonetics).await;
+                    let lookup_success = entry.lookup_succeeded();
+                    let curr_phrase = CnPhrase {
+                        entry,
+                        lookup_success,
+                        raw_phrase: raw_char,
+                        raw_phonetics: raw_char_phonetics,
+                        parent_uid: Some(uid.clone()),
+                        ..CnPhrase::default()
+                    };
+                    res.push(curr_phrase);
+                }
+                continue;
+            }
+            let entry = CnEnDictEntry::from_phrase(&mut self.conn, &raw_phrase, &raw_phonetics).await;
+            let lookup_success = entry.lookup_succeeded();
+            let curr_phrase = CnPhrase {
+                entry,
+                lookup_success,
+                raw_phrase,
+                raw_phonetics,
+                ..CnPhrase::default()
+            };
+            res.push(curr_phrase);
+        }
+        return Ok(res);
+    }
+}
+
 /* Public Functions */
 /// Connects to MongoDB (locally: Docker Container, in production: mongoDB Atlas). Connection is handled in main.rs.
 pub fn connect_to_mongodb() -> Result<Database, Box<dyn Error>> {
@@ -145,73 +226,233 @@ async fn connect_to_redis() -> Result<Connection, Box<dyn Error>> {
 }
 
 /// Sanitizes user input. Chinese punctuation is unaffected by this.
+/// Values that aren't valid UTF-8 (e.g. GBK or Big5 text) are decoded using charset detection.
 pub fn convert_rawstr_to_string(s: &RawStr) -> String {
-    let res = s.url_decode_lossy().to_string(); // � for invalid UTF-8
+    let res = match s.url_decode() {
+        Ok(decoded) => decoded,
+        Err(_) => decode_text_bytes(&url_decode_to_bytes(s.as_str()), None)
+    };
     return res;
 }
 
-/// Scrapes relevant text from HTML. Returns: (Title, Body Text).
-pub async fn scrape_text_from_url(url: &str) -> (String, String) {
-    let resp = reqwest::get(url).await.unwrap()
-        .text().await.unwrap();
-    let html = scraper::Html::parse_document(&resp);
-    let title_selector = scraper::Selector::parse("title").unwrap();
-    let title_text: String = html.select(&title_selector)
-        .next().unwrap()
-        .text().collect();
-    // Grabs body headers+paragraphs in-order
-    let body_selector = scraper::Selector::parse("body h1,h2,h3,h4,h5,h6,p").unwrap();
-    let mut body_text = String::with_capacity(resp.len());
-    for item in html.select(&body_selector) {
-        body_text += &item.text().collect::<String>();
-    }
-    return (title_text, body_text);
+/// Scrapes the readable article (title, body, byline, publish date) from the HTML at the URL.
+/// Paragraphs in the body are newline-delimited. Refer to readability::extract_article() for details.
+/// The page is decoded using charset detection, since .text() mis-decodes many GBK and Big5 pages.
+/// Refer to fetch::fetch_url() for the restrictions on which URLs can be fetched.
+pub async fn scrape_text_from_url(url: &str) -> Result<ExtractedArticle, FetchError> {
+    let page = fetch_url(url).await?;
+    let res = match page.is_plain_text() {
+        true => ExtractedArticle {
+            body: decode_text_bytes(&page.bytes, page.content_type.as_deref()),
+            ..ExtractedArticle::default()
+        },
+        false => extract_article(&decode_html_bytes(&page.bytes, page.content_type.as_deref()))
+    };
+    return Ok(res);
+}
+
+/// Converts regional vocabulary in the string using the phrase table (e.g. 软件 => 软体 for Taiwan, 軟體 => 软件 for Mainland).
+/// The output stays in Simplified, since the tokenizer converts to Simplified anyways.
+pub async fn convert_string_to_region(s: &str, cn_region: &CnRegion) -> String {
+    return convert_strings_to_region(vec![String::from(s)], cn_region).await.pop().unwrap_or_default();
 }
 
-/// Renders the HTML using the given CnType and CnPhonetics.
-/// Refer to tokenizer_string() for formatting details.
-pub async fn convert_string_to_tokenized_phrases(s: &str) -> Vec<CnPhrase> {
+/// Converts regional vocabulary in each string (e.g. each subtitle cue), loading the phrase table once. Refer to convert_string_to_region().
+pub async fn convert_strings_to_region(strings: Vec<String>, cn_region: &CnRegion) -> Vec<String> {
+    let mut conn = connect_to_redis().await.unwrap();
+    let tw_simp_map = conn.hgetall::<&str, HashMap<String, String>>(TW_PHRASES_SIMP_KEY).await.unwrap_or_default();
+    let phrase_map: HashMap<String, String> = match cn_region {
+        CnRegion::Taiwan => tw_simp_map,
+        CnRegion::Mainland => {
+            // Invert the table, and match Taiwan phrases written in either Simplified or Traditional
+            let tw_trad_map = conn.hgetall::<&str, HashMap<String, String>>(TW_PHRASES_TRAD_KEY).await.unwrap_or_default();
+            tw_simp_map.into_iter().chain(tw_trad_map.into_iter())
+                .map(|(mainland, taiwan)| (taiwan, mainland))
+                .collect()
+        }
+    };
+    return strings.iter().map(|s| replace_phrases(s, &phrase_map)).collect();
+}
+
+/// Tokenizes the string and looks up each phrase, splitting phrases into characters for CnGranularity::Character.
+/// Refer to tokenizer_string() for formatting details. To tokenize many strings (e.g. one per subtitle cue), use a TokenizerSession.
+pub async fn convert_string_to_tokenized_phrases(s: &str, cn_granularity: &CnGranularity) -> Vec<CnPhrase> {
+    let mut session = TokenizerSession::connect().await.expect("Tokenizer connection error");
+    return session.tokenize(s, cn_granularity).await.expect("Tokenizer connection error");
+}
+
+/// Splits the tokenizer output into (phrase, raw_pinyin) pairs. The delimiters are $ and ` since neither character appears in CEDICT.
+/// A standalone 儿 is merged into the previous phrase as erhua only if it's a known erhua word (refer to ERHUA_BASE_PHRASES),
+/// since 儿 is often a separate word (e.g. "玩", "儿" => "玩儿" with "wan2 r5", but "我", "儿" is kept as-is).
+/// Erhua words in CEDICT are already merged by the tokenizer.
+pub fn split_tokenized_string(tokenized_string: &str) -> Vec<(String, String)> {
     const PHRASE_DELIM: char = '$';
     const PINYIN_DELIM: char = '`';
-    let mut conn = connect_to_redis().await.unwrap();
-    let tokenized_string = tokenize_string(s.to_string()).expect("Tokenizer connection error");
-    let n_phrases = tokenized_string.matches(PHRASE_DELIM).count();
-    // Estimate pre-allocated size: max ~2100 chars per phrase (conservitively 2500), 1 usize per char
-    let mut res = Vec::with_capacity(n_phrases);
+    let n_phrases = tokenized_string.matches(PHRASE_DELIM).count() + 1;
+    let mut res: Vec<(String, String)> = Vec::with_capacity(n_phrases);
     for token in tokenized_string.split(PHRASE_DELIM) {
-        let token_vec: Vec<&str> = token.split(PINYIN_DELIM).collect();
-        let raw_phrase = token_vec[0].to_string(); // If Chinese, then Simplified
-        let raw_phonetics = token_vec[1].to_string();
-        let uid = CnEnDictEntry::generate_uid(vec![&raw_phrase,&raw_phonetics]);
-        let entry = CnEnDictEntry::from_uid(&mut conn, uid).await;
-        let lookup_success = entry.lookup_succeeded();
-        let curr_phrase = CnPhrase {
-            entry,
-            lookup_success,
-            raw_phrase,
-            raw_phonetics
-        };
-        res.push(curr_phrase);
+        let mut token_iter = token.splitn(2, PINYIN_DELIM);
+        let raw_phrase = token_iter.next().unwrap_or("").to_string(); // If Chinese, then Simplified
+        let raw_phonetics = token_iter.next().unwrap_or("").to_string();
+        if let Some((prev_phrase, prev_phonetics)) = res.last_mut() {
+            let is_erhua_suffix = raw_phrase.chars().count() == 1 && raw_phrase.chars().all(is_erhua_char);
+            if is_erhua_suffix && ERHUA_BASE_PHRASES.contains(&prev_phrase.as_str()) {
+                *prev_phrase += &raw_phrase;
+                *prev_phonetics += " r5";
+                continue;
+            }
+        }
+        // Tokens without Chinese chars can still mix scripts (e.g. "iPhone🎉"), so they're split into words
+        if !script::contains_han(&raw_phrase) && raw_phrase == raw_phonetics {
+            res.extend(script::split_into_words(&raw_phrase).into_iter().map(|word| (word.to_string(), word.to_string())));
+            continue;
+        }
+        res.push((raw_phrase, raw_phonetics));
     }
     return res;
 }
 
-/// Connect to tokenizer service and tokenizes the string. The delimiters are $ and ` since neither character appears in CEDICT.
+/// Common words that take an erhua (儿化) suffix but are missing from CEDICT in their erhua form. Used in split_tokenized_string().
+const ERHUA_BASE_PHRASES: [&str; 24] = [
+    "哪", "这", "那", "玩", "一点", "有点", "一会", "一块", "一下", "好好", "慢慢", "小孩",
+    "聊天", "差点", "玩意", "今", "明", "昨", "事", "味", "空", "门", "面条", "冰棍"
+];
+
+/// Returns true for 儿/兒, used to identify erhua (儿化) phrases.
+pub fn is_erhua_char(c: char) -> bool {
+    return c == '儿' || c == '兒';
+}
+
+/// Splits a multi-character phrase into (character, raw_pinyin) pairs. Used for CnGranularity::Character.
+/// Returns None for single characters, non-Chinese phrases, or when the pinyin syllables don't line-up with the characters.
+pub fn split_phrase_into_chars(raw_phrase: &str, raw_phonetics: &str) -> Option<Vec<(String, String)>> {
+    let char_list: Vec<char> = raw_phrase.chars().collect();
+    let phonetic_list: Vec<&str> = raw_phonetics.split(' ').collect();
+    if !script::is_entirely_han(raw_phrase) || char_list.len() < 2 || char_list.len() != phonetic_list.len() {
+        return None;
+    }
+    let res = char_list.iter().zip(phonetic_list.iter())
+        .map(|(c, p)| (c.to_string(), p.to_string()))
+        .collect();
+    return Some(res);
+}
+
+/// Tokenizes the string using the tokenizer service connection. The delimiters are $ and ` since neither character appears in CEDICT.
 /// The format of the string is: "phrase1`raw_pinyin$phrase2`raw_pinyin2$ ..."
-/// The string is written to the TCP stream until completion.
-/// From the tokenizer, 2 messages are sent: 
-///     1) A u64 (as bytes) indicating the size of the tokenizer results
-///     2) The tokenizer result string (as bytes)
-fn tokenize_string(mut s: String) -> std::io::Result<String> {
-    s = s.replace("  ", ""); // remove excess whitespace for tokenization, keep newlines. "  " instead of " " to preserve non-Chinese text
-    let mut stream = TcpStream::connect(format!("{}:{}", TOKENIZER_HOSTNAME, TOKENIZER_PORT))?;
-    stream.write_all(s.as_bytes())?;
-    let mut header_bytes = [0; 64];
+/// Large strings are split at paragraph boundaries into chunks (refer to split_into_chunks), which are sent in order over the connection.
+/// Each request and response is a frame: a TOKENIZER_HEADER_BYTES header with the payload size (as ASCII digits, space-padded),
+/// then the payload (as bytes). The tokenized chunks are joined in order.
+fn tokenize_string(stream: &mut TcpStream, s: &str) -> std::io::Result<String> {
+    let s = s.replace("  ", ""); // remove excess whitespace for tokenization, keep newlines. "  " instead of " " to preserve non-Chinese text
+    let mut res = String::with_capacity(s.len() * 4);
+    for chunk in split_into_chunks(&s, TOKENIZER_CHUNK_BYTES) {
+        write_frame(stream, chunk.as_bytes())?;
+        let tokenized_chunk = read_frame(stream)?;
+        if tokenized_chunk.len() == 0 {
+            continue;
+        }
+        if res.len() > 0 {
+            res.push('$');
+        }
+        res += &tokenized_chunk;
+    }
+    return Ok(res);
+}
+
+/// Splits the string into chunks of at most max_bytes, preferring to split after a newline, then after sentence-ending punctuation.
+/// Chunks always end on a char boundary, and empty chunks are skipped.
+fn split_into_chunks(s: &str, max_bytes: usize) -> Vec<&str> {
+    let mut res = Vec::with_capacity(s.len() / max_bytes + 1);
+    let mut rest = s;
+    while rest.len() > max_bytes {
+        let mut limit = max_bytes;
+        while !rest.is_char_boundary(limit) {
+            limit -= 1;
+        }
+        let window = &rest[..limit];
+        let split_idx = match window.rfind('\n') {
+            Some(i) if i > 0 => i + 1,
+            _ => match window.rfind(['。', '！', '？']) {
+                Some(i) if i > 0 => i + window[i..].chars().next().unwrap().len_utf8(),
+                _ => limit
+            }
+        };
+        res.push(&rest[..split_idx]);
+        rest = &rest[split_idx..];
+    }
+    if rest.len() > 0 {
+        res.push(rest);
+    }
+    return res;
+}
+
+/// Writes a frame (header with the payload size, then the payload) to the tokenizer stream.
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> std::io::Result<()> {
+    let header = format!("{:<width$}", payload.len(), width = TOKENIZER_HEADER_BYTES);
+    stream.write_all(header.as_bytes())?;
+    stream.write_all(payload)?;
+    return Ok(());
+}
+
+/// Reads a frame from the tokenizer stream, returning the payload as a String.
+fn read_frame(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut header_bytes = vec![0; TOKENIZER_HEADER_BYTES];
     stream.read_exact(&mut header_bytes)?;
-    let n_bytes: usize = String::from_utf8(header_bytes.to_vec()).unwrap()
-        .trim().parse::<usize>().unwrap();
-    let mut tokenized_bytes = vec![0; n_bytes];
-    stream.read_exact(&mut tokenized_bytes)?;
-    let res = String::from_utf8(tokenized_bytes).unwrap();
+    let n_bytes: usize = String::from_utf8_lossy(&header_bytes).trim().parse::<usize>()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let mut payload_bytes = vec![0; n_bytes];
+    stream.read_exact(&mut payload_bytes)?;
+    let res = String::from_utf8(payload_bytes)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
     return Ok(res);
-}
\ No newline at end of file
+}
+
+/// Replaces phrases in the string using the longest match from phrase_map. Used in convert_string_to_region().
+fn replace_phrases(s: &str, phrase_map: &HashMap<String, String>) -> String {
+    let max_phrase_len = phrase_map.keys().map(|k| k.chars().count()).max().unwrap_or(0);
+    let char_list: Vec<char> = s.chars().collect();
+    let mut res = String::with_capacity(s.len());
+    let mut i = 0;
+    while i < char_list.len() {
+        let mut match_len = 0;
+        for len in (1..=max_phrase_len.min(char_list.len() - i)).rev() {
+            let candidate: String = char_list[i..i+len].iter().collect();
+            if let Some(replacement) = phrase_map.get(&candidate) {
+                res += replacement;
+                match_len = len;
+                break;
+            }
+        }
+        if match_len == 0 {
+            res.push(char_list[i]);
+            match_len = 1;
+        }
+        i += match_len;
+    }
+    return res;
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_tokenized_string_splits_mixed_script_tokens() {
+        let tokenized_string = "2021`2021$年`nian2$iPhone🎉`iPhone🎉$发布会`fa1 bu4 hui4";
+        let expected: Vec<(String, String)> = vec![("2021", "2021"), ("年", "nian2"), ("iPhone", "iPhone"), ("🎉", "🎉"), ("发布会", "fa1 bu4 hui4")]
+            .into_iter()
+            .map(|(phrase, phonetics)| (phrase.to_string(), phonetics.to_string()))
+            .collect();
+        assert_eq!(split_tokenized_string(tokenized_string), expected);
+    }
+
+    #[test]
+    fn split_tokenized_string_merges_only_known_erhua() {
+        let phrases = |tokenized_string: &str| -> Vec<String> {
+            return split_tokenized_string(tokenized_string).into_iter().map(|(phrase, _)| phrase).collect();
+        };
+        assert_eq!(split_tokenized_string("玩`wan2$儿`er2"), vec![(String::from("玩儿"), String::from("wan2 r5"))]);
+        assert_eq!(phrases("等`deng3$一会`yi1 hui4$儿`er2"), vec!["等", "一会儿"]);
+        assert_eq!(phrases("我`wo3$儿`er2"), vec!["我", "儿"]);
+        assert_eq!(phrases("女儿`nv3 er2$儿`er2"), vec!["女儿", "儿"]);
+    }
+}
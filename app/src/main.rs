@@ -11,7 +11,7 @@ use rocket_contrib::{
     serve::StaticFiles
 };
 use tokio::runtime::Runtime;
-use std::error::Error;
+use std::{error::Error, thread};
 
 /// Catches a 404 error
 
@@ -24,6 +24,32 @@ pub fn launch_rocket() -> Result<(), Box<dyn Error>> {
     let db = connect_to_mongodb()?;
     let runtime = Runtime::new().unwrap();
     let rt = runtime.handle().clone();
+    // Polls feed subscriptions on a separate thread, using the same runtime
+    let (poller_db, poller_rt) = (db.clone(), rt.clone());
+    thread::spawn(move || models::feed::FeedSubscription::run_poller(poller_db, poller_rt));
+    // Merges docs saved per-setting (from before docs were rendered on demand), moves doc phrases onto their pages,
+    // links vocab to docs by doc_id, then indexes docs for search + concordances, on a separate thread using the same runtime
+    let (migration_db, migration_rt) = (db.clone(), rt.clone());
+    thread::spawn(move || {
+        // Failed docs are logged and retried on the next start, so the later migrations still run
+        if let Err(e) = migration_rt.block_on(models::user::UserDoc::migrate_per_setting_docs(&migration_db)) {
+            eprintln!("Error migrating per-setting docs: {}", e);
+        }
+        if let Err(e) = models::user::UserDoc::migrate_doc_phrases_to_pages(&migration_db) {
+            eprintln!("Error moving doc phrases to pages: {}", e);
+        }
+        models::user::UserVocab::migrate_doc_title_links(&migration_db);
+        models::search::migrate_unindexed_docs(&migration_db);
+    });
+    // Deletes expired sandbox docs on a separate thread
+    let sweeper_db = db.clone();
+    thread::spawn(move || models::public::SandboxDoc::run_sweeper(sweeper_db));
+    // Processes queued document ingestion jobs on separate threads, using the same runtime
+    models::job::IngestJob::requeue_interrupted(&db);
+    for _ in 0..config::JOB_WORKER_COUNT {
+        let (worker_db, worker_rt) = (db.clone(), rt.clone());
+        thread::spawn(move || models::job::IngestJob::run_worker(worker_db, worker_rt));
+    }
     rocket::ignite()
         .attach(Template::fairing())
         .manage(db)
@@ -32,16 +58,35 @@ pub fn launch_rocket() -> Result<(), Box<dyn Error>> {
             api::get_doc,
             api::get_all_user_items,
             api::get_user_vocab_string,
+            api::get_job_status,
+            api::search_docs,
+            api::get_vocab_occurrences,
+            api::get_reading_progress,
+            api::get_doc_annotations,
             api::delete_user_doc,
             api::delete_user_vocab,
+            api::delete_feed,
+            api::delete_collection,
             api::logout,
             api::feedback,
             api::login,
             api::register,
             api::upload_sandbox_doc,
             api::upload_user_doc,
+            api::upload_user_doc_file,
+            api::subscribe_feed,
+            api::create_collection,
+            api::rename_tag,
+            api::share_doc,
+            api::clone_shared_doc,
+            api::save_reading_progress,
+            api::create_doc_annotation,
             api::upload_vocab,
             api::update_settings,
+            api::replace_user_doc,
+            api::update_user_doc,
+            api::update_collection,
+            api::delete_doc_annotation,
             ])
         .mount("/", routes![
             routes::index,
@@ -52,6 +97,14 @@ pub fn launch_rocket() -> Result<(), Box<dyn Error>> {
             routes::feedback,
             routes::user_profile,
             routes::user_doc,
+            routes::user_doc_page,
+            routes::doc_reader,
+            routes::doc_reader_page,
+            routes::shared_doc,
+            routes::shared_doc_page,
+            routes::doc_collection,
+            routes::vocab_concordance,
+            routes::job_status,
         ])
         .register(catchers![
             routes::not_found,
@@ -0,0 +1,163 @@
+/*
+/// Module for structure-preserving Markdown/HTML ingestion.
+/// Markup is sanitized into a flat list of events (allowlisted tags without attributes, and text), so only text nodes get tokenized.
+*/
+
+use pulldown_cmark::{html, Options, Parser};
+use scraper::{ElementRef, Html, Node};
+use serde::{Serialize, Deserialize};
+
+/// Tags kept as-is. Everything else is unwrapped (children kept), except DROPPED_TAGS.
+const ALLOWED_TAGS: [&str; 21] = ["h1", "h2", "h3", "h4", "h5", "h6", "p", "blockquote", "ul", "ol", "li", "pre",
+    "em", "strong", "i", "b", "u", "s", "del", "code", "br"];
+/// Tags removed along with their contents.
+const DROPPED_TAGS: [&str; 9] = ["script", "style", "noscript", "template", "iframe", "object", "embed", "svg", "math"];
+/// Tags that start a new line in the plain-text body.
+const BLOCK_TAGS: [&str; 12] = ["h1", "h2", "h3", "h4", "h5", "h6", "p", "blockquote", "ul", "ol", "li", "pre"];
+
+/* Enums */
+/// Format of an uploaded document body.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BodyFormat {
+    Text,
+    Markdown,
+    Html
+}
+
+impl BodyFormat {
+    /// Defaults to Text for unrecognized input.
+    pub fn from_str(s: &str) -> Self {
+        return match s.trim().to_lowercase().as_str() {
+            "markdown" | "md" => BodyFormat::Markdown,
+            "html" | "htm" => BodyFormat::Html,
+            _ => BodyFormat::Text
+        };
+    }
+    pub fn as_str(&self) -> &str {
+        return match *self {
+            BodyFormat::Text => "text",
+            BodyFormat::Markdown => "markdown",
+            BodyFormat::Html => "html"
+        };
+    }
+    /// Gets the format from a file extension (e.g. "notes.md" => Markdown).
+    pub fn from_filename(filename: &str) -> Self {
+        let ext = filename.rsplit('.').next().unwrap_or("").to_lowercase();
+        return match ext.as_str() {
+            "md" | "markdown" => BodyFormat::Markdown,
+            "html" | "htm" | "xhtml" => BodyFormat::Html,
+            _ => BodyFormat::Text
+        };
+    }
+}
+
+/// A sanitized piece of markup. Tag names are always from ALLOWED_TAGS.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum MarkupEvent {
+    Open(String),
+    Close(String),
+    Text(String)
+}
+
+impl MarkupEvent {
+    /// Returns true if the event opens/closes a block (or is a <br>), i.e. it separates lines of text.
+    pub fn is_line_break(&self) -> bool {
+        return match self {
+            MarkupEvent::Open(tag) | MarkupEvent::Close(tag) => tag == "br" || BLOCK_TAGS.contains(&tag.as_str()),
+            MarkupEvent::Text(_) => false
+        };
+    }
+}
+
+/* Public Functions */
+/// Parses a Markdown or HTML body into sanitized markup events. Plain text becomes a single paragraph per line.
+pub fn parse_markup(body: &str, format: &BodyFormat) -> Vec<MarkupEvent> {
+    let html_str = match format {
+        BodyFormat::Markdown => {
+            let mut html_str = String::with_capacity(body.len() * 2);
+            html::push_html(&mut html_str, Parser::new_ext(body, Options::ENABLE_STRIKETHROUGH));
+            html_str
+        },
+        BodyFormat::Html => String::from(body),
+        BodyFormat::Text => {
+            body.lines()
+                .filter(|line| line.trim().len() > 0)
+                .map(|line| format!("<p>{}</p>", escape_text(line)))
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+    };
+    let fragment = Html::parse_fragment(&html_str);
+    let mut res = Vec::new();
+    collect_events(&fragment.root_element(), false, &mut res);
+    return res;
+}
+
+/// Renders markup events back to (sanitized) HTML.
+pub fn render_markup(events: &[MarkupEvent]) -> String {
+    let mut res = String::new();
+    for event in events {
+        match event {
+            MarkupEvent::Open(tag) if tag == "br" => { res += "<br>"; },
+            MarkupEvent::Close(tag) if tag == "br" => { },
+            MarkupEvent::Open(tag) => { res += format!("<{}>", tag).as_str(); },
+            MarkupEvent::Close(tag) => { res += format!("</{}>", tag).as_str(); },
+            MarkupEvent::Text(text) => { res += escape_text(text).as_str(); }
+        }
+    }
+    return res;
+}
+
+/// Escapes &, <, >, and " for use in HTML text and (double-quoted) attribute values.
+pub fn escape_text(s: &str) -> String {
+    return s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;");
+}
+
+/* Private Functions */
+/// Recursively collects sanitized events. Outside of <pre>, whitespace is collapsed (like in the browser),
+/// and whitespace-only text between blocks is skipped.
+fn collect_events(element: &ElementRef, in_pre: bool, res: &mut Vec<MarkupEvent>) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => {
+                let text = match in_pre {
+                    true => text.to_string(),
+                    false => {
+                        let mut collapsed = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+                        if text.starts_with(char::is_whitespace) && collapsed.len() > 0 {
+                            collapsed.insert(0, ' ');
+                        }
+                        if text.ends_with(char::is_whitespace) {
+                            collapsed.push(' ');
+                        }
+                        collapsed
+                    }
+                };
+                let after_line_break = res.last().map(|e| e.is_line_break()).unwrap_or(true);
+                let text = match after_line_break && !in_pre {
+                    true => String::from(text.trim_start()),
+                    false => text
+                };
+                if text.len() > 0 {
+                    res.push(MarkupEvent::Text(text));
+                }
+            },
+            Node::Element(_) => {
+                let child_element = ElementRef::wrap(child).unwrap();
+                let tag = child_element.value().name().to_lowercase();
+                if DROPPED_TAGS.contains(&tag.as_str()) {
+                    continue;
+                }
+                match ALLOWED_TAGS.contains(&tag.as_str()) {
+                    true => {
+                        res.push(MarkupEvent::Open(tag.clone()));
+                        collect_events(&child_element, in_pre || tag == "pre", res);
+                        res.push(MarkupEvent::Close(tag));
+                    },
+                    false => collect_events(&child_element, in_pre, res)
+                }
+            },
+            _ => { }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+/*
+/// Data Structures for highlights and notes on UserDocs.
+*/
+
+use chrono::Utc;
+use crate::{
+    DatabaseItem,
+    config::{ANNOTATION_COLL_NAME, ANNOTATION_MAX_NOTE_CHARS, ANNOTATION_MAX_TOKENS, USER_DOC_COLL_NAME},
+    models::{
+        user::UserDoc,
+        zh::CnPhrase
+    }
+};
+use mongodb::{
+    bson::{doc, Bson, from_bson},
+    options::FindOneOptions,
+    sync::Database
+};
+use serde::{Serialize, Deserialize};
+use std::{
+    collections::HashMap,
+    error::Error
+};
+use uuid::Uuid;
+
+/// A highlight (with an optional note) on a range of phrases in a UserDoc.
+/// Anchored by token indices in tokenized_body_json, so it's independent of the reader settings (refer to the data-token attribute in html_rendering).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DocAnnotation {
+    pub annotation_id: String,
+    username: String,
+    pub doc_id: String,
+    pub token_start: i32,
+    /// Exclusive
+    pub token_end: i32,
+    /// The highlighted text, used to re-anchor the annotation when the doc is edited (refer to try_reanchor_all)
+    pub text: String,
+    pub note: String,
+    pub created_on: String
+}
+
+impl DatabaseItem for DocAnnotation {
+    fn collection_name() -> &'static str { return ANNOTATION_COLL_NAME; }
+    fn all_field_names() -> Vec<&'static str> {
+        return vec!["annotation_id", "username", "doc_id", "token_start", "token_end", "text", "note", "created_on"];
+    }
+    fn primary_key(&self) -> &str { return &self.annotation_id; }
+}
+
+impl DocAnnotation {
+    /// Generates a new DocAnnotation on tokenized_body_json[token_start..token_end] of the user's UserDoc.
+    /// The range can't be empty or longer than ANNOTATION_MAX_TOKENS, and notes are limited to ANNOTATION_MAX_NOTE_CHARS.
+    pub fn new(db: &Database, username: String, doc_id: String, token_start: i32, token_end: i32, note: String) -> Result<Self, Box<dyn Error>> {
+        let doc_coll = (*db).collection(USER_DOC_COLL_NAME);
+        let find_options = FindOneOptions::builder().projection(doc! { "doc_id": 1 }).build();
+        if doc_coll.find_one(doc! { "username": &username, "doc_id": &doc_id }, find_options)?.is_none() {
+            return Err(Box::from("No document found"));
+        }
+        if token_start < 0 || token_end <= token_start {
+            return Err(Box::from("Invalid token range"));
+        }
+        if token_end - token_start > ANNOTATION_MAX_TOKENS {
+            return Err(Box::from(format!("Annotations can't be longer than {} phrases", ANNOTATION_MAX_TOKENS)));
+        }
+        let note = note.trim().to_string();
+        if note.chars().count() > ANNOTATION_MAX_NOTE_CHARS {
+            return Err(Box::from(format!("Notes can't be longer than {} characters", ANNOTATION_MAX_NOTE_CHARS)));
+        }
+        let phrases = UserDoc::lookup_phrases(db, &doc_id, token_start, token_end);
+        if phrases.len() != (token_end - token_start) as usize {
+            return Err(Box::from("Invalid token range"));
+        }
+        let text: String = phrases.iter().map(|phrase| phrase.raw_phrase.as_str()).collect();
+        let annotation_id = Uuid::new_v4().to_string();
+        let created_on = Utc::now().to_string();
+        let new_annotation = DocAnnotation { annotation_id, username, doc_id, token_start, token_end, text, note, created_on };
+        return Ok(new_annotation);
+    }
+    /// Returns all of the user's annotations in the UserDoc, in reading order.
+    pub fn lookup_all(db: &Database, username: &str, doc_id: &str) -> Vec<Self> {
+        let annotation_docs = DocAnnotation::try_lookup_all(db, doc! { "username": username, "doc_id": doc_id }).unwrap_or_default();
+        let mut res: Vec<Self> = annotation_docs.into_iter()
+            .filter_map(|annotation_doc| from_bson(Bson::Document(annotation_doc)).ok())
+            .collect();
+        res.sort_by_key(|a| (a.token_start, a.token_end));
+        return res;
+    }
+    /// Attempts to delete the user's annotation in the UserDoc.
+    pub fn try_delete(db: &Database, username: &str, doc_id: &str, annotation_id: &str) -> bool {
+        let coll = (*db).collection(ANNOTATION_COLL_NAME);
+        let res = match coll.delete_one(doc! { "username": username, "doc_id": doc_id, "annotation_id": annotation_id }, None) {
+            Ok(delete_res) => delete_res.deleted_count > 0,
+            Err(_) => false
+        };
+        return res;
+    }
+    /// Deletes all annotations in the UserDoc.
+    pub fn try_delete_all(db: &Database, username: &str, doc_id: &str) -> bool {
+        let coll = (*db).collection(ANNOTATION_COLL_NAME);
+        let res = coll.delete_many(doc! { "username": username, "doc_id": doc_id }, None).is_ok();
+        return res;
+    }
+    /// Moves the annotations in the UserDoc after it's re-tokenized (e.g. when the body is edited).
+    /// Each annotation is moved to the occurrence of its text closest to its previous position, and deleted if the text is gone.
+    /// Moved annotations are saved in one update command, and removed ones in one delete.
+    pub fn try_reanchor_all(db: &Database, doc_id: &str, phrases: &[CnPhrase]) -> Result<(), Box<dyn Error>> {
+        let annotation_docs = DocAnnotation::try_lookup_all(db, doc! { "doc_id": doc_id }).unwrap_or_default();
+        if annotation_docs.len() == 0 {
+            return Ok(());
+        }
+        // Byte offset of each phrase in the concatenated text
+        let mut body = String::new();
+        let mut phrase_starts: Vec<usize> = Vec::with_capacity(phrases.len());
+        for phrase in phrases.iter() {
+            phrase_starts.push(body.len());
+            body += &phrase.raw_phrase;
+        }
+        // Token ranges of each occurrence, shared by annotations on the same text
+        let mut occurrences: HashMap<String, Vec<(i32, i32)>> = HashMap::new();
+        let mut updates: Vec<Bson> = Vec::new();
+        let mut deleted_ids: Vec<String> = Vec::new();
+        for annotation_doc in annotation_docs {
+            let annotation: DocAnnotation = match from_bson(Bson::Document(annotation_doc)) {
+                Ok(annotation) => annotation,
+                Err(_) => continue
+            };
+            let text_ranges = occurrences.entry(annotation.text.clone())
+                .or_insert_with(|| DocAnnotation::find_text_ranges(&body, &phrase_starts, &annotation.text));
+            match DocAnnotation::find_nearest_range(text_ranges, annotation.token_start) {
+                Some((token_start, token_end)) => {
+                    if token_start != annotation.token_start || token_end != annotation.token_end {
+                        updates.push(Bson::Document(doc! {
+                            "q": { "annotation_id": &annotation.annotation_id },
+                            "u": { "$set": { "token_start": token_start, "token_end": token_end } }
+                        }));
+                    }
+                },
+                None => deleted_ids.push(annotation.annotation_id)
+            }
+        }
+        if updates.len() > 0 {
+            (*db).run_command(doc! { "update": ANNOTATION_COLL_NAME, "updates": updates, "ordered": false }, None)?;
+        }
+        if deleted_ids.len() > 0 {
+            let coll = (*db).collection(ANNOTATION_COLL_NAME);
+            coll.delete_many(doc! { "annotation_id": { "$in": deleted_ids } }, None)?;
+        }
+        return Ok(());
+    }
+    /// Token ranges of each occurrence of the text in the body, given the byte offset of each phrase in it.
+    fn find_text_ranges(body: &str, phrase_starts: &[usize], text: &str) -> Vec<(i32, i32)> {
+        if text.len() == 0 {
+            return Vec::new();
+        }
+        let get_token_index = |byte_index: usize| match phrase_starts.binary_search(&byte_index) {
+            Ok(i) => i as i32,
+            Err(i) => i.saturating_sub(1) as i32
+        };
+        return body.match_indices(text)
+            .map(|(i, _)| (get_token_index(i), get_token_index(i + text.len() - 1) + 1))
+            .collect();
+    }
+    /// The range starting closest to the previous token_start (None if the text is gone).
+    fn find_nearest_range(text_ranges: &[(i32, i32)], token_start: i32) -> Option<(i32, i32)> {
+        return text_ranges.iter().min_by_key(|(start, _)| (start - token_start).abs()).copied();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_body(raw_phrases: &[&str]) -> (String, Vec<usize>) {
+        let mut body = String::new();
+        let mut phrase_starts = Vec::new();
+        for raw_phrase in raw_phrases {
+            phrase_starts.push(body.len());
+            body += raw_phrase;
+        }
+        return (body, phrase_starts);
+    }
+
+    #[test]
+    fn reanchors_to_nearest_occurrence() {
+        // "我们" was at tokens 3..4, and a phrase was inserted before it
+        let (body, phrase_starts) = make_body(&["我们", "去", "新", "的", "我们", "学校"]);
+        let text_ranges = DocAnnotation::find_text_ranges(&body, &phrase_starts, "我们");
+        assert_eq!(text_ranges, vec![(0, 1), (4, 5)]);
+        assert_eq!(DocAnnotation::find_nearest_range(&text_ranges, 3), Some((4, 5)));
+        // Text spanning several phrases, starting mid-phrase
+        let text_ranges = DocAnnotation::find_text_ranges(&body, &phrase_starts, "们学");
+        assert_eq!(text_ranges, vec![(4, 6)]);
+    }
+
+    #[test]
+    fn reanchor_drops_missing_text() {
+        let (body, phrase_starts) = make_body(&["我们", "去", "学校"]);
+        let text_ranges = DocAnnotation::find_text_ranges(&body, &phrase_starts, "医院");
+        assert_eq!(DocAnnotation::find_nearest_range(&text_ranges, 2), None);
+        assert_eq!(DocAnnotation::find_text_ranges(&body, &phrase_starts, ""), Vec::new());
+    }
+}
@@ -0,0 +1,169 @@
+/*
+/// Data Structures for organizing UserDocs into (nested) collections.
+*/
+
+use chrono::Utc;
+use crate::{
+    DatabaseItem,
+    config::{COLLECTION_COLL_NAME, USER_DOC_COLL_NAME}
+};
+use mongodb::{
+    bson::{doc, Bson, from_bson},
+    sync::Database
+};
+use serde::{Serialize, Deserialize};
+use std::{
+    collections::HashMap,
+    error::Error
+};
+use uuid::Uuid;
+
+/// A user-defined folder of UserDocs (refer to UserDoc.collection_id). Collections can be nested using parent_id.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DocCollection {
+    pub collection_id: String,
+    username: String,
+    pub name: String,
+    /// collection_id of the parent collection (empty for top-level collections)
+    pub parent_id: String,
+    pub created_on: String
+}
+
+impl DatabaseItem for DocCollection {
+    fn collection_name() -> &'static str { return COLLECTION_COLL_NAME; }
+    fn all_field_names() -> Vec<&'static str> {
+        return vec!["collection_id", "username", "name", "parent_id", "created_on"];
+    }
+    fn primary_key(&self) -> &str { return &self.collection_id; }
+}
+
+impl DocCollection {
+    /// Generates a new DocCollection. If parent_id is non-empty, it must be one of the user's collections.
+    pub fn new(db: &Database, username: String, name: String, parent_id: String) -> Result<Self, Box<dyn Error>> {
+        let name = DocCollection::sanitize_name(&name)?;
+        if parent_id.len() > 0 && DocCollection::from_collection_id(db, &username, &parent_id).is_none() {
+            return Err(Box::from("Parent collection not found"));
+        }
+        let collection_id = Uuid::new_v4().to_string();
+        let created_on = Utc::now().to_string();
+        let new_collection = DocCollection { collection_id, username, name, parent_id, created_on };
+        return Ok(new_collection);
+    }
+    /// Looks up the collection, only if it belongs to the user.
+    pub fn from_collection_id(db: &Database, username: &str, collection_id: &str) -> Option<Self> {
+        let collection_doc = DocCollection::try_lookup_one(db, doc! { "username": username, "collection_id": collection_id })?;
+        return from_bson(Bson::Document(collection_doc)).ok();
+    }
+    /// Returns all of the user's collections (in creation order).
+    pub fn lookup_all(db: &Database, username: &str) -> Vec<Self> {
+        let collection_docs = DocCollection::try_lookup_all(db, doc! { "username": username }).unwrap_or_default();
+        let mut res: Vec<Self> = collection_docs.into_iter()
+            .filter_map(|collection_doc| from_bson(Bson::Document(collection_doc)).ok())
+            .collect();
+        res.sort_by(|a, b| a.created_on.cmp(&b.created_on));
+        return res;
+    }
+    /// Renames and/or moves the user's collection (None keeps the current value, and an empty parent_id moves it to the top level).
+    /// A collection can't be moved into itself or one of its sub-collections.
+    pub fn try_edit(db: &Database, username: &str, collection_id: &str, name: Option<String>, parent_id: Option<String>) -> Result<Self, Box<dyn Error>> {
+        let mut collection = DocCollection::from_collection_id(db, username, collection_id).ok_or("No collection found")?;
+        if let Some(name) = name {
+            collection.name = DocCollection::sanitize_name(&name)?;
+        }
+        if let Some(parent_id) = parent_id {
+            if parent_id.len() > 0 {
+                let all_collections = DocCollection::lookup_all(db, username);
+                if !all_collections.iter().any(|c| c.collection_id == parent_id) {
+                    return Err(Box::from("Parent collection not found"));
+                }
+                if DocCollection::get_descendant_ids(&all_collections, collection_id).contains(&parent_id) {
+                    return Err(Box::from("A collection can't be moved into itself"));
+                }
+            }
+            collection.parent_id = parent_id;
+        }
+        let coll = (*db).collection(COLLECTION_COLL_NAME);
+        let update_query = doc! { "$set": { "name": &collection.name, "parent_id": &collection.parent_id } };
+        coll.update_one(doc! { "username": username, "collection_id": collection_id }, update_query, None)?;
+        return Ok(collection);
+    }
+    /// Returns the collection_id along with the ids of all of its sub-collections (at any depth).
+    pub fn get_descendant_ids(all_collections: &[Self], collection_id: &str) -> Vec<String> {
+        let mut res: Vec<String> = vec![String::from(collection_id)];
+        let mut i = 0;
+        while i < res.len() {
+            for c in all_collections.iter() {
+                if c.parent_id == res[i] && !res.contains(&c.collection_id) {
+                    res.push(c.collection_id.clone());
+                }
+            }
+            i += 1;
+        }
+        return res;
+    }
+    /// Maps each collection_id to its full path, e.g. "Novels / 鲁迅".
+    pub fn format_paths(all_collections: &[Self]) -> HashMap<String, String> {
+        let by_id: HashMap<&str, &DocCollection> = all_collections.iter().map(|c| (c.collection_id.as_str(), c)).collect();
+        let mut res: HashMap<String, String> = HashMap::new();
+        for c in all_collections.iter() {
+            let mut names: Vec<&str> = vec![c.name.as_str()];
+            let mut parent_id = c.parent_id.as_str();
+            // Bounded by the number of collections, in case of a cycle in the stored data
+            while let Some(parent) = by_id.get(parent_id) {
+                if names.len() > all_collections.len() {
+                    break;
+                }
+                names.push(parent.name.as_str());
+                parent_id = parent.parent_id.as_str();
+            }
+            names.reverse();
+            res.insert(c.collection_id.clone(), names.join(" / "));
+        }
+        return res;
+    }
+    /// Attempts to delete the user's collection. Its UserDocs and sub-collections are moved to its parent (they're not deleted).
+    pub fn try_delete(db: &Database, username: &str, collection_id: &str) -> bool {
+        let collection = match DocCollection::from_collection_id(db, username, collection_id) {
+            Some(collection) => collection,
+            None => return false
+        };
+        let coll = (*db).collection(COLLECTION_COLL_NAME);
+        let res = match coll.delete_one(doc! { "username": username, "collection_id": collection_id }, None) {
+            Ok(delete_res) => delete_res.deleted_count > 0,
+            Err(_) => false
+        };
+        if res {
+            let move_query = doc! { "$set": { "parent_id": &collection.parent_id } };
+            if let Err(e) = coll.update_many(doc! { "username": username, "parent_id": collection_id }, move_query, None) {
+                eprintln!("Error moving sub-collections of {}: {:?}", collection_id, e);
+            }
+            let doc_coll = (*db).collection(USER_DOC_COLL_NAME);
+            let move_query = doc! { "$set": { "collection_id": &collection.parent_id } };
+            if let Err(e) = doc_coll.update_many(doc! { "username": username, "collection_id": collection_id }, move_query, None) {
+                eprintln!("Error moving documents of {}: {:?}", collection_id, e);
+            }
+        }
+        return res;
+    }
+    /// Collection names are rendered in the profile tables, so markup characters are removed.
+    fn sanitize_name(name: &str) -> Result<String, Box<dyn Error>> {
+        let name = name.trim().replace(['<', '>', '"'], "");
+        if name.len() == 0 {
+            return Err(Box::from("Collection name can't be empty"));
+        }
+        return Ok(name);
+    }
+}
+
+/* Public Functions */
+/// Parses a comma-separated list of tags (either ',' or '，'). Tags are trimmed, de-duplicated, and have markup characters removed.
+pub fn parse_tags(s: &str) -> Vec<String> {
+    let mut res: Vec<String> = Vec::new();
+    for tag in s.split([',', '，']) {
+        let tag = tag.trim().replace(['<', '>', '"'], "");
+        if tag.len() > 0 && !res.contains(&tag) {
+            res.push(tag);
+        }
+    }
+    return res;
+}
@@ -0,0 +1,149 @@
+/*
+/// Data Structures for RSS/Atom feed subscriptions.
+*/
+
+use chrono::Utc;
+use crate::{
+    DatabaseItem,
+    charset::decode_text_bytes,
+    config::{FEED_COLL_NAME, FEED_ALLOWED_CONTENT_TYPES, FEED_MAX_ITEMS_PER_POLL, FEED_POLL_INTERVAL_SECS, FEED_SEEN_HISTORY_LEN},
+    feeds::{ParsedFeed, parse_feed},
+    fetch::fetch_url_with_content_types,
+    models::{
+        job::IngestJob,
+        user::UserDoc
+    }
+};
+use mongodb::{
+    bson::{doc, Bson, from_bson},
+    sync::Database
+};
+use serde::{Serialize, Deserialize};
+use std::{
+    error::Error,
+    thread,
+    time::Duration
+};
+use tokio::runtime::Handle;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FeedSubscription {
+    pub feed_id: String,
+    username: String,
+    pub feed_url: String,
+    pub feed_name: String,
+    /// Most recent item GUIDs/URLs (up to FEED_SEEN_HISTORY_LEN), used to skip already-imported items
+    seen_guids: Vec<String>,
+    seen_urls: Vec<String>,
+    pub last_polled_on: String,
+    pub created_on: String
+}
+
+impl DatabaseItem for FeedSubscription {
+    fn collection_name() -> &'static str { return FEED_COLL_NAME; }
+    fn all_field_names() -> Vec<&'static str> {
+        return vec!["feed_id", "username", "feed_url", "feed_name",
+            "seen_guids", "seen_urls", "last_polled_on", "created_on"];
+    }
+    fn primary_key(&self) -> &str { return &self.feed_id; }
+}
+
+impl FeedSubscription {
+    /// Generates a new FeedSubscription. The feed is fetched once to check that it's valid.
+    /// If feed_name is empty, the feed's own title is used.
+    pub async fn new(username: String, feed_url: String, feed_name: String) -> Result<Self, Box<dyn Error>> {
+        let feed = FeedSubscription::fetch_feed(&feed_url).await?;
+        let feed_id = Uuid::new_v4().to_string();
+        let feed_name = match feed_name.trim().len() > 0 {
+            true => String::from(feed_name.trim()),
+            false => feed.title
+        };
+        // feed_name is rendered in the profile tables, so markup characters are removed
+        let feed_name = feed_name.replace(['<', '>', '"'], "");
+        let created_on = Utc::now().to_string();
+        let new_sub = FeedSubscription {
+            feed_id, username, feed_url, feed_name,
+            seen_guids: Vec::new(), seen_urls: Vec::new(),
+            last_polled_on: String::new(), created_on
+        };
+        return Ok(new_sub);
+    }
+    /// Queues new feed items as IngestJobs (imported via UserDoc::from_url and tagged with the feed name). Returns the number of queued items.
+    /// Items are de-duplicated by GUID and URL (including URLs the user imported manually, and items still queued from a previous poll).
+    /// At most FEED_MAX_ITEMS_PER_POLL items are queued per poll, newest first.
+    pub async fn poll(&self, db: &Database) -> Result<usize, Box<dyn Error>> {
+        let feed = FeedSubscription::fetch_feed(&self.feed_url).await?;
+        let new_items = feed.items.into_iter()
+            .filter(|item| !self.seen_guids.contains(&item.guid) && !self.seen_urls.contains(&item.url))
+            .filter(|item| !IngestJob::is_url_pending(db, &self.username, &item.url))
+            .take(FEED_MAX_ITEMS_PER_POLL);
+        let mut n_queued = 0;
+        for item in new_items {
+            // Items are only marked as seen once imported (refer to IngestJob::process), so failed imports are retried on the next poll
+            if UserDoc::try_lookup_one(db, doc! { "username": &self.username, "source": &item.url }).is_some() {
+                FeedSubscription::mark_seen(db, &self.feed_id, &item.guid, &item.url)?;
+                continue;
+            }
+            let new_job = IngestJob::from_feed_item(self.username.clone(), item.url.clone(), &self.feed_id, &self.feed_name, item.guid);
+            match new_job.try_insert(db) {
+                Ok(_) => n_queued += 1,
+                Err(e) => eprintln!("Error queueing feed item {} for {}: {:?}", item.url, self.username, e)
+            }
+        }
+        let coll = (*db).collection(FEED_COLL_NAME);
+        coll.update_one(doc! { "feed_id": &self.feed_id }, doc! { "$set": { "last_polled_on": Utc::now().to_string() } }, None)?;
+        return Ok(n_queued);
+    }
+    /// Marks a feed item as seen, keeping the most recent FEED_SEEN_HISTORY_LEN GUIDs/URLs.
+    pub fn mark_seen(db: &Database, feed_id: &str, guid: &str, url: &str) -> Result<(), Box<dyn Error>> {
+        let coll = (*db).collection(FEED_COLL_NAME);
+        let update_query = doc! {
+            "$push": {
+                "seen_guids": { "$each": [guid], "$slice": -FEED_SEEN_HISTORY_LEN },
+                "seen_urls": { "$each": [url], "$slice": -FEED_SEEN_HISTORY_LEN }
+            }
+        };
+        coll.update_one(doc! { "feed_id": feed_id }, update_query, None)?;
+        return Ok(());
+    }
+    /// Polls every FeedSubscription once. Errors are logged and skipped.
+    pub async fn poll_all(db: &Database) {
+        let all_subs = FeedSubscription::try_lookup_all(db, doc! {}).unwrap_or_default();
+        for sub_doc in all_subs {
+            let sub: FeedSubscription = match from_bson(Bson::Document(sub_doc)) {
+                Ok(sub) => sub,
+                Err(e) => {
+                    eprintln!("Error parsing FeedSubscription: {:?}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = sub.poll(db).await {
+                eprintln!("Error polling feed {} for {}: {:?}", sub.feed_url, sub.username, e);
+            }
+        }
+    }
+    /// Polls all feeds every FEED_POLL_INTERVAL_SECS. This blocks, so it should be run on its own thread.
+    /// Uses the existing runtime handle since new Tokio runtimes shouldn't be created (refer to main.rs).
+    pub fn run_poller(db: Database, rt: Handle) {
+        loop {
+            rt.block_on(FeedSubscription::poll_all(&db));
+            thread::sleep(Duration::from_secs(FEED_POLL_INTERVAL_SECS));
+        }
+    }
+    /// Attempts to delete the user's matching FeedSubscription. Previously imported UserDocs are kept.
+    pub fn try_delete(db: &Database, username: &str, feed_id: &str) -> bool {
+        let coll = (*db).collection(FEED_COLL_NAME);
+        let res = match coll.delete_one(doc! { "username": username, "feed_id": feed_id }, None) {
+            Ok(delete_res) => delete_res.deleted_count > 0,
+            Err(_) => false
+        };
+        return res;
+    }
+    /// Fetches and parses the feed. Feeds are decoded using charset detection.
+    async fn fetch_feed(feed_url: &str) -> Result<ParsedFeed, Box<dyn Error>> {
+        let page = fetch_url_with_content_types(feed_url, &FEED_ALLOWED_CONTENT_TYPES).await?;
+        let xml_str = decode_text_bytes(&page.bytes, page.content_type.as_deref());
+        return parse_feed(&xml_str);
+    }
+}
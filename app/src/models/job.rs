@@ -0,0 +1,385 @@
+/*
+/// Data Structures for background jobs (e.g. ingesting large documents).
+*/
+
+use chrono::Utc;
+use crate::{
+    DatabaseItem,
+    charset::{decode_html_bytes, decode_text_bytes},
+    config::{DOC_BODY_MAX_BYTES, JOB_COLL_NAME, JOB_POLL_INTERVAL_MS},
+    epub::parse_epub,
+    markup::BodyFormat,
+    subtitles::{is_subtitle_file, parse_subtitles},
+    models::{
+        feed::FeedSubscription,
+        user::UserDoc,
+        zh::CnRegion
+    }
+};
+use mongodb::{
+    bson::{doc, document::Document, Bson, from_bson},
+    sync::Database
+};
+use serde::{Serialize, Deserialize};
+use std::{
+    error::Error,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    thread,
+    time::Duration
+};
+use tokio::runtime::Handle;
+use uuid::Uuid;
+
+/* Enums */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Processing,
+    Done,
+    Failed
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &str {
+        return match *self {
+            JobStatus::Queued => "Queued",
+            JobStatus::Processing => "Processing",
+            JobStatus::Done => "Done",
+            JobStatus::Failed => "Failed"
+        };
+    }
+}
+
+/// Implements to_string()
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.as_str());
+    }
+}
+
+/* Structs */
+/// A queued UserDoc ingestion. The doc_id is generated up-front so it can be returned immediately,
+/// and doc_title is set once the UserDoc is saved. Inputs are the same as the /api/upload-user-doc form
+/// (file uploads are decoded first, refer to IngestJob::from_file), or a feed item to import.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IngestJob {
+    pub job_id: String,
+    pub username: String,
+    pub doc_id: String,
+    pub status: JobStatus,
+    /// Human-readable description of the current step (e.g. "Tokenizing")
+    pub stage: String,
+    /// Percent complete (0-100)
+    pub progress: i32,
+    pub error: String,
+    pub doc_title: String,
+    desired_title: String,
+    body: String,
+    body_format: String,
+    url: String,
+    source: String,
+    convert_region: String,
+    /// For subtitle uploads, the file name (the body is the decoded file). Empty otherwise
+    #[serde(default)]
+    filename: String,
+    /// For feed items, the FeedSubscription that found the item, its name, and the item GUID (marked as seen once imported). Empty otherwise
+    #[serde(default)]
+    feed_id: String,
+    #[serde(default)]
+    feed_name: String,
+    #[serde(default)]
+    feed_guid: String,
+    /// For body edits, the body replaces the body of the existing UserDoc (doc_id) instead of generating a new one
+    #[serde(default)]
+    replace_body: bool,
+    pub created_on: String,
+    pub updated_on: String
+}
+
+impl DatabaseItem for IngestJob {
+    fn collection_name() -> &'static str { return JOB_COLL_NAME; }
+    fn all_field_names() -> Vec<&'static str> {
+        return vec!["job_id", "username", "doc_id", "status", "stage", "progress", "error", "doc_title",
+            "desired_title", "body", "body_format", "url", "source", "convert_region", "filename", "feed_id", "feed_name", "feed_guid", "replace_body", "created_on", "updated_on"];
+    }
+    fn primary_key(&self) -> &str { return &self.job_id; }
+}
+
+impl IngestJob {
+    /// Generates a new queued IngestJob. If url is non-empty, the document is imported from the URL instead of the body.
+    pub fn new(username: String, desired_title: String, body: String, body_format: BodyFormat, url: String, source: String, region_conversion: Option<CnRegion>) -> Self {
+        let job_id = Uuid::new_v4().to_string();
+        let doc_id = Uuid::new_v4().to_string();
+        let convert_region = match region_conversion {
+            Some(region) => region.to_string(),
+            None => String::new()
+        };
+        let created_on = Utc::now().to_string();
+        let new_job = IngestJob {
+            job_id, username, doc_id,
+            status: JobStatus::Queued, stage: String::from("Queued"), progress: 0,
+            error: String::new(), doc_title: String::new(),
+            desired_title, body, body_format: String::from(body_format.as_str()), url, source, convert_region,
+            filename: String::new(), feed_id: String::new(), feed_name: String::new(), feed_guid: String::new(), replace_body: false,
+            created_on: created_on.clone(), updated_on: created_on
+        };
+        return new_job;
+    }
+    /// Generates queued IngestJobs from an uploaded .txt, .md, .html, .epub, or subtitle (.srt, .vtt, .ass) file. Text files are decoded using charset detection.
+    /// EPUBs generate one job per chapter (in spine order) titled "{title}-{n}-{chapter}", with the book title and author kept in source.
+    /// If desired_title is empty, the EPUB title (or the file name) is used. Only decoding happens here, tokenizing is left to the workers.
+    /// Each body (i.e. each EPUB chapter) must be at most DOC_BODY_MAX_BYTES.
+    pub fn from_file(username: String, desired_title: String, source: String, filename: &str,
+        content_type: Option<&str>, bytes: &[u8], region_conversion: Option<CnRegion>) -> Result<Vec<Self>, Box<dyn Error>> {
+        let lowercase_filename = filename.to_lowercase();
+        let filename_stem = match filename.rfind('.') {
+            Some(i) => &filename[..i],
+            None => filename
+        };
+        let title = match desired_title.trim().len() > 0 {
+            true => desired_title.clone(),
+            false => String::from(filename_stem)
+        };
+        let source = match source.len() > 0 {
+            true => source,
+            false => String::from(filename)
+        };
+        let mut res = Vec::new();
+        if lowercase_filename.ends_with(".epub") {
+            let book = parse_epub(bytes)?;
+            let book_title = [desired_title.as_str(), book.title.as_str(), filename_stem].iter()
+                .find(|s| s.trim().len() > 0)
+                .map(|s| String::from(*s))
+                .unwrap_or_default();
+            let book_source = match book.author.len() > 0 {
+                true => format!("{} - {} (EPUB)", book.title, book.author),
+                false => format!("{} (EPUB)", book.title)
+            };
+            for (i, chapter) in book.chapters.into_iter().enumerate() {
+                let chapter_title = match chapter.title.len() > 0 && chapter.title != book.title {
+                    true => format!("{}-{}-{}", book_title, i + 1, chapter.title),
+                    false => format!("{}-{}", book_title, i + 1)
+                };
+                res.push(IngestJob::new(username.clone(), chapter_title, chapter.body, BodyFormat::Text, String::new(), book_source.clone(), region_conversion.clone()));
+            }
+        } else if is_subtitle_file(&lowercase_filename) {
+            let contents = decode_text_bytes(bytes, content_type);
+            // Parsed here too, so invalid files are rejected right away
+            parse_subtitles(filename, &contents)?;
+            let mut new_job = IngestJob::new(username, title, contents, BodyFormat::Text, String::new(), source, region_conversion);
+            new_job.filename = String::from(filename);
+            res.push(new_job);
+        } else if lowercase_filename.ends_with(".txt") {
+            let body = decode_text_bytes(bytes, content_type);
+            res.push(IngestJob::new(username, title, body, BodyFormat::Text, String::new(), source, region_conversion));
+        } else if BodyFormat::from_filename(&lowercase_filename) != BodyFormat::Text {
+            let format = BodyFormat::from_filename(&lowercase_filename);
+            let markup = match format {
+                BodyFormat::Html => decode_html_bytes(bytes, content_type),
+                _ => decode_text_bytes(bytes, content_type)
+            };
+            res.push(IngestJob::new(username, title, markup, format, String::new(), source, region_conversion));
+        } else {
+            return Err(Box::from(format!("Unsupported file type (expected .txt, .md, .html, .epub, .srt, .vtt, or .ass): {}", filename)));
+        }
+        if let Some(large_job) = res.iter().find(|job| job.body.len() > DOC_BODY_MAX_BYTES) {
+            return Err(Box::from(format!("{} is larger than {} bytes", large_job.desired_title, DOC_BODY_MAX_BYTES)));
+        }
+        return Ok(res);
+    }
+    /// Generates a queued IngestJob importing a feed item from its URL. The item is marked as seen on the FeedSubscription once imported.
+    pub fn from_feed_item(username: String, url: String, feed_id: &str, feed_name: &str, feed_guid: String) -> Self {
+        let mut new_job = IngestJob::new(username, String::new(), String::new(), BodyFormat::Text, url, String::new(), None);
+        new_job.feed_id = String::from(feed_id);
+        new_job.feed_name = String::from(feed_name);
+        new_job.feed_guid = feed_guid;
+        return new_job;
+    }
+    /// Generates a queued IngestJob replacing the body of the user's existing UserDoc (refer to UserDoc::try_edit).
+    pub fn from_body_edit(username: String, doc_id: &str, body: String, body_format: BodyFormat) -> Self {
+        let mut new_job = IngestJob::new(username, String::new(), body, body_format, String::new(), String::new(), None);
+        new_job.doc_id = String::from(doc_id);
+        new_job.replace_body = true;
+        return new_job;
+    }
+    /// Cancels the queued body edits of the UserDoc (they'd be replaced by the new edit anyway), so only one edit re-tokenizes the doc at a time.
+    /// Returns an error if a body edit is already processing. Queued edits are cancelled first, so one claimed in between is still caught.
+    pub fn try_cancel_body_edits(db: &Database, doc_id: &str) -> Result<(), Box<dyn Error>> {
+        let coll = (*db).collection(JOB_COLL_NAME);
+        let queued_query = doc! { "doc_id": doc_id, "replace_body": true, "status": JobStatus::Queued.as_str() };
+        let update_query = doc! { "$set": {
+            "status": JobStatus::Failed.as_str(), "stage": "Failed",
+            "error": "Replaced by a newer edit", "body": "", "updated_on": Utc::now().to_string()
+        }};
+        coll.update_many(queued_query, update_query, None)?;
+        let processing_query = doc! { "doc_id": doc_id, "replace_body": true, "status": JobStatus::Processing.as_str() };
+        if IngestJob::try_lookup_one(db, processing_query).is_some() {
+            return Err(Box::from("The previous edit of this document is still processing, try again once it's done"));
+        }
+        return Ok(());
+    }
+    /// Returns true if the user has a queued or processing job importing the URL (e.g. a feed item from a previous poll).
+    pub fn is_url_pending(db: &Database, username: &str, url: &str) -> bool {
+        let pending_status = vec![JobStatus::Queued.as_str(), JobStatus::Processing.as_str()];
+        return IngestJob::try_lookup_one(db, doc! { "username": username, "url": url, "status": { "$in": pending_status } }).is_some();
+    }
+    /// Looks up the job, only if it belongs to the user.
+    pub fn from_job_id(db: &Database, username: &str, job_id: &str) -> Option<Self> {
+        let job_doc = IngestJob::try_lookup_one(db, doc! { "username": username, "job_id": job_id })?;
+        return from_bson(Bson::Document(job_doc)).ok();
+    }
+    /// Processes queued jobs until the program exits. This blocks, so it should be run on its own thread
+    /// (one per worker). Uses the existing runtime handle since new Tokio runtimes shouldn't be created (refer to main.rs).
+    /// A panic while processing fails the job instead of stopping the worker.
+    pub fn run_worker(db: Database, rt: Handle) {
+        loop {
+            match IngestJob::try_claim_next(&db) {
+                Some(job) => {
+                    let res = match panic::catch_unwind(AssertUnwindSafe(|| rt.block_on(job.process(&db)))) {
+                        Ok(res) => res,
+                        Err(_) => Err(Box::from("Unexpected error while processing"))
+                    };
+                    job.finish(&db, res);
+                },
+                None => { thread::sleep(Duration::from_millis(JOB_POLL_INTERVAL_MS)); }
+            }
+        }
+    }
+    /// Re-queues jobs that were interrupted (e.g. by a restart) while processing. Should be called before workers start.
+    pub fn requeue_interrupted(db: &Database) {
+        let coll = (*db).collection(JOB_COLL_NAME);
+        let update_query = doc! { "$set": { "status": JobStatus::Queued.as_str(), "stage": "Queued", "progress": 0 } };
+        if let Err(e) = coll.update_many(doc! { "status": JobStatus::Processing.as_str() }, update_query, None) {
+            eprintln!("Error re-queueing interrupted jobs: {:?}", e);
+        }
+    }
+    /// Atomically marks the next queued job as processing, so each job is only claimed by one worker.
+    fn try_claim_next(db: &Database) -> Option<Self> {
+        let coll = (*db).collection(JOB_COLL_NAME);
+        let update_query = doc! { "$set": { "status": JobStatus::Processing.as_str(), "stage": "Starting", "updated_on": Utc::now().to_string() } };
+        let job_doc = match coll.find_one_and_update(doc! { "status": JobStatus::Queued.as_str() }, update_query, None) {
+            Ok(job_doc) => job_doc?,
+            Err(e) => {
+                eprintln!("Error claiming next job: {:?}", e);
+                return None;
+            }
+        };
+        return from_bson(Bson::Document(job_doc)).ok();
+    }
+    /// Generates and saves the UserDoc (with the pre-generated doc_id), or replaces its body for edits. Returns the saved title.
+    /// Progress goes from 10 to 90 as pages are tokenized.
+    async fn process(&self, db: &Database) -> Result<String, Box<dyn Error>> {
+        let region_conversion = CnRegion::from_str(&self.convert_region);
+        let body_format = BodyFormat::from_str(&self.body_format);
+        let on_page = |n_done: usize, n_pages: usize| {
+            self.set_progress(db, &format!("Tokenizing page {} of {}", n_done, n_pages), IngestJob::page_progress(n_done, n_pages));
+        };
+        if self.replace_body {
+            return UserDoc::try_replace_body(db, &self.username, &self.doc_id, self.body.clone(), body_format, &on_page).await;
+        }
+        let mut new_doc = match (self.url.len() > 0, self.filename.len() > 0, body_format) {
+            (true, _, _) => {
+                self.set_progress(db, "Fetching URL", 5);
+                let mut new_doc = UserDoc::from_url(db, self.username.clone(), self.url.clone(), region_conversion, &on_page).await?;
+                new_doc.feed_name = self.feed_name.clone();
+                new_doc
+            },
+            (false, true, _) => {
+                self.set_progress(db, "Reading subtitles", 5);
+                let cues = parse_subtitles(&self.filename, &self.body)?;
+                UserDoc::from_subtitles(db, self.username.clone(), self.desired_title.clone(), cues, self.source.clone(), region_conversion, &on_page).await?
+            },
+            (false, false, BodyFormat::Text) => {
+                UserDoc::new(db, self.username.clone(), self.desired_title.clone(), self.body.clone(), self.source.clone(), region_conversion, &on_page).await?
+            },
+            (false, false, body_format) => {
+                UserDoc::from_markup(db, self.username.clone(), self.desired_title.clone(), self.body.clone(), body_format, self.source.clone(), region_conversion, &on_page).await?
+            }
+        };
+        self.set_progress(db, "Saving", 90);
+        new_doc.set_doc_id(self.doc_id.clone());
+        let title = new_doc.try_insert(db)?;
+        if self.feed_id.len() > 0 {
+            FeedSubscription::mark_seen(db, &self.feed_id, &self.feed_guid, &self.url)?;
+        }
+        return Ok(title);
+    }
+    /// Updates the stage and progress shown by /api/jobs/<job_id>.
+    fn set_progress(&self, db: &Database, stage: &str, progress: i32) {
+        let coll = (*db).collection(JOB_COLL_NAME);
+        let update_query = doc! { "$set": { "stage": stage, "progress": progress, "updated_on": Utc::now().to_string() } };
+        if let Err(e) = coll.update_one(doc! { "job_id": &self.job_id }, update_query, None) {
+            eprintln!("Error updating job {}: {:?}", self.job_id, e);
+        }
+    }
+    /// Progress (10-90) after n_done of n_pages pages are tokenized.
+    fn page_progress(n_done: usize, n_pages: usize) -> i32 {
+        return 10 + (80 * n_done / usize::max(n_pages, 1)) as i32;
+    }
+    /// Marks the job as done (with the saved doc title) or failed. The body is cleared since it's stored in the UserDoc.
+    fn finish(&self, db: &Database, res: Result<String, Box<dyn Error>>) {
+        let coll = (*db).collection(JOB_COLL_NAME);
+        if let Err(e) = &res {
+            eprintln!("Error processing job {}: {}", self.job_id, e);
+        }
+        let update_query = IngestJob::generate_finish_query(res);
+        if let Err(e) = coll.update_one(doc! { "job_id": &self.job_id }, update_query, None) {
+            eprintln!("Error updating job {}: {:?}", self.job_id, e);
+        }
+    }
+    /// Generates the update setting the final status of a job (refer to finish).
+    fn generate_finish_query(res: Result<String, Box<dyn Error>>) -> Document {
+        let updated_on = Utc::now().to_string();
+        let res = match res {
+            Ok(doc_title) => doc! { "$set": {
+                "status": JobStatus::Done.as_str(), "stage": "Done", "progress": 100,
+                "doc_title": doc_title, "body": "", "updated_on": updated_on
+            }},
+            Err(e) => doc! { "$set": {
+                "status": JobStatus::Failed.as_str(), "stage": "Failed",
+                "error": e.to_string(), "body": "", "updated_on": updated_on
+            }}
+        };
+        return res;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finish_query_sets_final_status() {
+        let done = IngestJob::generate_finish_query(Ok(String::from("title")));
+        let done = done.get_document("$set").unwrap();
+        assert_eq!(done.get_str("status").unwrap(), JobStatus::Done.as_str());
+        assert_eq!(done.get_i32("progress").unwrap(), 100);
+        assert_eq!(done.get_str("doc_title").unwrap(), "title");
+        let failed = IngestJob::generate_finish_query(Err(Box::from("Tokenizer connection error")));
+        let failed = failed.get_document("$set").unwrap();
+        assert_eq!(failed.get_str("status").unwrap(), JobStatus::Failed.as_str());
+        assert_eq!(failed.get_str("error").unwrap(), "Tokenizer connection error");
+        // Failed jobs keep the last progress, so it isn't set
+        assert!(failed.get("progress").is_none());
+    }
+
+    #[test]
+    fn page_progress_stays_between_start_and_saving() {
+        assert_eq!(IngestJob::page_progress(0, 4), 10);
+        assert_eq!(IngestJob::page_progress(2, 4), 50);
+        assert_eq!(IngestJob::page_progress(4, 4), 90);
+        // Empty docs have no pages
+        assert_eq!(IngestJob::page_progress(0, 0), 10);
+    }
+
+    #[test]
+    fn new_jobs_are_queued() {
+        let job = IngestJob::new(String::from("user"), String::new(), String::from("body"), BodyFormat::Text, String::new(), String::new(), None);
+        assert_eq!(job.status, JobStatus::Queued);
+        assert_eq!(job.progress, 0);
+        let edit_job = IngestJob::from_body_edit(String::from("user"), "doc-id", String::from("body"), BodyFormat::Text);
+        assert_eq!(edit_job.doc_id, "doc-id");
+        assert!(edit_job.replace_body);
+    }
+}
@@ -1,5 +1,17 @@
+/// Data Structures for highlights and notes on UserDocs.
+pub mod annotation;
+/// Data Structures for organizing UserDocs into collections.
+pub mod collection;
+/// Data Structures for RSS/Atom feed subscriptions.
+pub mod feed;
+/// Data Structures for background jobs.
+pub mod job;
+/// Data Structures for tracking where a user is in their UserDocs.
+pub mod progress;
 /// Data Structures not associated with a User account.
 pub mod public;
+/// Data Structures for full-text search of UserDocs.
+pub mod search;
 /// Data Structures related to a user account.
 pub mod user;
 /// Data Structures relating to Chinese text.
@@ -0,0 +1,132 @@
+/*
+/// Data Structures for tracking where a user is in their UserDocs.
+*/
+
+use chrono::Utc;
+use crate::{
+    DatabaseItem,
+    config::{READING_PROGRESS_COLL_NAME, USER_DOC_COLL_NAME, USER_DOC_PAGE_COLL_NAME},
+    models::user::UserDocPage
+};
+use mongodb::{
+    bson::{doc, Bson, from_bson},
+    options::{FindOneOptions, ReplaceOptions},
+    sync::Database
+};
+use serde::{Serialize, Deserialize};
+use std::{
+    collections::HashMap,
+    error::Error
+};
+
+/// The last-read position of a user in one of their UserDocs, saved from the reader (refer to /api/progress/<doc_id>).
+/// Positions are token indices in tokenized_body_json (refer to the data-token attribute in html_rendering), so they don't depend on the reader settings.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReadingProgress {
+    pub doc_id: String,
+    username: String,
+    pub page: i32,
+    pub token_index: i32,
+    /// Number of tokens in the doc when the progress was saved (0 for docs without pages)
+    pub n_tokens: i32,
+    pub updated_on: String
+}
+
+impl DatabaseItem for ReadingProgress {
+    fn collection_name() -> &'static str { return READING_PROGRESS_COLL_NAME; }
+    fn all_field_names() -> Vec<&'static str> {
+        return vec!["doc_id", "username", "page", "token_index", "n_tokens", "updated_on"];
+    }
+    fn primary_key(&self) -> &str { return &self.doc_id; }
+}
+
+impl ReadingProgress {
+    /// Saves the user's position in their UserDoc, replacing the previous one. The token_index is kept within the page.
+    pub fn try_save(db: &Database, username: &str, doc_id: &str, page: i32, token_index: i32) -> Result<Self, Box<dyn Error>> {
+        let doc_coll = (*db).collection(USER_DOC_COLL_NAME);
+        let find_options = FindOneOptions::builder().projection(doc! { "doc_id": 1 }).build();
+        if doc_coll.find_one(doc! { "username": username, "doc_id": doc_id }, find_options)?.is_none() {
+            return Err(Box::from("No document found"));
+        }
+        let pages = UserDocPage::lookup_all_for_doc(db, doc_id);
+        let (page, token_index) = match pages.iter().find(|p| p.page == page) {
+            Some(p) => (page, token_index.max(p.token_start).min((p.token_end - 1).max(p.token_start))),
+            None => (1, 0)
+        };
+        let n_tokens = pages.last().map(|p| p.token_end).unwrap_or(0);
+        let progress = ReadingProgress { doc_id: String::from(doc_id), username: String::from(username), page, token_index, n_tokens,
+            updated_on: Utc::now().to_string() };
+        let coll = (*db).collection(READING_PROGRESS_COLL_NAME);
+        let options = ReplaceOptions::builder().upsert(true).build();
+        coll.replace_one(doc! { "username": username, "doc_id": doc_id }, progress.as_document(), options)?;
+        return Ok(progress);
+    }
+    /// Looks up the user's position in the UserDoc (None if they haven't read it yet).
+    pub fn from_doc_id(db: &Database, username: &str, doc_id: &str) -> Option<Self> {
+        let progress_doc = ReadingProgress::try_lookup_one(db, doc! { "username": username, "doc_id": doc_id })?;
+        return from_bson(Bson::Document(progress_doc)).ok();
+    }
+    /// Maps the doc_id of each UserDoc the user has started to their position.
+    pub fn lookup_all(db: &Database, username: &str) -> HashMap<String, Self> {
+        let progress_docs = ReadingProgress::try_lookup_all(db, doc! { "username": username }).unwrap_or_default();
+        return progress_docs.into_iter()
+            .filter_map(|progress_doc| from_bson::<ReadingProgress>(Bson::Document(progress_doc)).ok())
+            .map(|progress| (progress.doc_id.clone(), progress))
+            .collect();
+    }
+    /// Percent of the doc read, counting the last-read token. None for docs without pages.
+    pub fn percent_complete(&self) -> Option<i32> {
+        if self.n_tokens <= 0 {
+            return None;
+        }
+        let percent = ((self.token_index as i64 + 1) * 100 / self.n_tokens as i64).min(100);
+        return Some(percent as i32);
+    }
+    /// Updates the saved positions after the UserDoc is re-tokenized. Positions past the new end go back to the start.
+    pub fn try_resize_all(db: &Database, doc_id: &str, n_tokens: i32) -> Result<(), Box<dyn Error>> {
+        let coll = (*db).collection(READING_PROGRESS_COLL_NAME);
+        coll.update_many(doc! { "doc_id": doc_id }, doc! { "$set": { "n_tokens": n_tokens } }, None)?;
+        coll.update_many(doc! { "doc_id": doc_id, "token_index": { "$gte": n_tokens } }, doc! { "$set": { "page": 1, "token_index": 0 } }, None)?;
+        // Page boundaries may have moved, so the page is re-computed from the token_index
+        let page_coll = (*db).collection(USER_DOC_PAGE_COLL_NAME);
+        let progress_docs = ReadingProgress::try_lookup_all(db, doc! { "doc_id": doc_id }).unwrap_or_default();
+        for progress_doc in progress_docs {
+            let token_index = progress_doc.get_i32("token_index").unwrap_or(0);
+            let page_query = doc! { "doc_id": doc_id, "token_start": { "$lte": token_index }, "token_end": { "$gt": token_index } };
+            if let Some(page_doc) = page_coll.find_one(page_query, None)? {
+                let page = page_doc.get_i32("page").unwrap_or(1);
+                coll.update_one(doc! { "_id": progress_doc.get("_id").cloned().unwrap_or(Bson::Null) }, doc! { "$set": { "page": page } }, None)?;
+            }
+        }
+        return Ok(());
+    }
+    /// Deletes all saved positions in the UserDoc.
+    pub fn try_delete_all(db: &Database, username: &str, doc_id: &str) -> bool {
+        let coll = (*db).collection(READING_PROGRESS_COLL_NAME);
+        let res = coll.delete_many(doc! { "username": username, "doc_id": doc_id }, None).is_ok();
+        return res;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_progress(token_index: i32, n_tokens: i32) -> ReadingProgress {
+        return ReadingProgress { doc_id: String::from("doc"), username: String::from("user"), page: 1, token_index, n_tokens,
+            updated_on: String::new() };
+    }
+
+    #[test]
+    fn percent_counts_last_read_token() {
+        assert_eq!(make_progress(0, 4).percent_complete(), Some(25));
+        assert_eq!(make_progress(3, 4).percent_complete(), Some(100));
+    }
+
+    #[test]
+    fn percent_is_none_without_pages_and_capped() {
+        assert_eq!(make_progress(0, 0).percent_complete(), None);
+        // Positions saved before a shorter re-tokenize can be past the end
+        assert_eq!(make_progress(9, 4).percent_complete(), Some(100));
+    }
+}
@@ -2,17 +2,27 @@
 /// Data Structures not associated with a User account.
 */
 
-use chrono::Utc;
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
 use crate::{
     DatabaseItem,
     scrape_text_from_url,
     html_rendering,
     convert_string_to_tokenized_phrases,
-    config::{SANDBOX_COLL_NAME, USER_FEEDBACK_COLL_NAME},
-    models::zh::{CnType, CnPhonetics, CnPhrase}
+    auth::str_to_hashed_string,
+    config::{SANDBOX_COLL_NAME, SANDBOX_QUOTA_COLL_NAME, USER_FEEDBACK_COLL_NAME, CLIENT_IP_HASH_SALT, SANDBOX_TTL_SECS, SANDBOX_SWEEP_INTERVAL_SECS, SANDBOX_QUOTA_PER_IP, SANDBOX_QUOTA_WINDOW_SECS},
+    fetch::FetchError,
+    models::zh::{CnType, CnPhonetics, CnGranularity, CnRegion, CnPhrase}
+};
+use mongodb::{
+    bson::{self, doc, document::Document, Bson},
+    options::FindOptions,
+    sync::Database
 };
-use mongodb::bson::doc;
 use serde::{Serialize, Deserialize};
+use std::{
+    thread,
+    time
+};
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -24,35 +34,140 @@ pub struct SandboxDoc {
     source: String,
     cn_type: CnType,
     pub cn_phonetics: CnPhonetics,
-    created_on: String
+    #[serde(default)]
+    cn_granularity: CnGranularity,
+    /// Stored as a date (unlike other models), so expired docs can be found by age (refer to sweep_expired)
+    created_on: bson::DateTime,
+    /// Hash of the uploader's IP address, kept to identify the uploader (quota entries are stored separately, refer to try_reserve_quota)
+    #[serde(default)]
+    client_ip_hash: String
 }
 
 impl DatabaseItem for SandboxDoc {
     fn collection_name() -> &'static str { return SANDBOX_COLL_NAME; }
     fn all_field_names() -> Vec<&'static str> { 
         return vec!["doc_id", "body", "tokenized_body_json", "source", 
-            "cn_type", "cn_phonetics", "created_on"]; 
+            "cn_type", "cn_phonetics", "cn_granularity", "created_on", "client_ip_hash"]; 
     }
     fn primary_key(&self) -> &str { return &self.doc_id; }
 }
 
 impl SandboxDoc {
     /// Generates a new SandboxDoc. A uuid is generated and assigned.
-    pub async fn new(body: String, cn_type: String, cn_phonetics: String, source: String) -> Self {
+    pub async fn new(body: String, cn_type: String, cn_phonetics: String, cn_granularity: String, source: String, client_ip: &str) -> Self {
         let doc_id = Uuid::new_v4().to_string();
         let cn_type = CnType::from_str(&cn_type).unwrap();
         let cn_phonetics = CnPhonetics::from_str(&cn_phonetics).unwrap();
-        let created_on = Utc::now().to_string();
-        let body_html = html_rendering::convert_string_to_tokenized_html(&body, &cn_type, &cn_phonetics).await;
-        let tokenized_body_json = convert_string_to_tokenized_phrases(&body).await;
-        let new_doc = SandboxDoc { doc_id, body, body_html, tokenized_body_json, source, cn_type, cn_phonetics, created_on };
+        let cn_granularity = CnGranularity::from_str(&cn_granularity).unwrap_or_default();
+        let created_on = bson::DateTime(Utc::now());
+        let client_ip_hash = SandboxDoc::hash_client_ip(client_ip);
+        let body_html = html_rendering::convert_string_to_tokenized_html(&body, &cn_type, &cn_phonetics, &cn_granularity, &CnRegion::default()).await;
+        let tokenized_body_json = convert_string_to_tokenized_phrases(&body, &cn_granularity).await;
+        let new_doc = SandboxDoc { doc_id, body, body_html, tokenized_body_json, source, cn_type, cn_phonetics, cn_granularity, created_on, client_ip_hash };
         return new_doc;
     }
 
-    /// Generates a new SandboxDoc using HTML-parsed text from the specified URL.
-    pub async fn from_url(url: String, cn_type: String, cn_phonetics: String) -> Self {
-        let (_, body_text) = scrape_text_from_url(&url).await;
-        return SandboxDoc::new(body_text, cn_type, cn_phonetics, url).await;
+    /// Generates a new SandboxDoc using the readable text from the specified URL.
+    pub async fn from_url(url: String, cn_type: String, cn_phonetics: String, cn_granularity: String, client_ip: &str) -> Result<Self, FetchError> {
+        let article = scrape_text_from_url(&url).await?;
+        return Ok(SandboxDoc::new(article.body, cn_type, cn_phonetics, cn_granularity, url, client_ip).await);
+    }
+    /// Reserves an upload for the IP address. Returns false if it already has SANDBOX_QUOTA_PER_IP uploads in the last SANDBOX_QUOTA_WINDOW_SECS.
+    /// The entry is inserted before counting (and removed if over quota), so concurrent uploads can't all pass the check.
+    pub fn try_reserve_quota(db: &Database, client_ip: &str) -> bool {
+        let coll = (*db).collection(SANDBOX_QUOTA_COLL_NAME);
+        let client_ip_hash = SandboxDoc::hash_client_ip(client_ip);
+        let window_start = Utc::now() - Duration::seconds(SANDBOX_QUOTA_WINDOW_SECS);
+        let entry_id = match coll.insert_one(doc! { "client_ip_hash": &client_ip_hash, "created_on": Bson::DateTime(Utc::now()) }, None) {
+            Ok(insert_res) => insert_res.inserted_id,
+            Err(e) => {
+                eprintln!("Error when reserving sandbox quota: {:?}", e);
+                return true;
+            }
+        };
+        let query_doc = doc! { "client_ip_hash": &client_ip_hash, "created_on": { "$gte": Bson::DateTime(window_start) } };
+        let is_within_quota = match coll.count_documents(query_doc, None) {
+            Ok(n_entries) => SandboxDoc::is_within_quota(n_entries),
+            Err(e) => {
+                eprintln!("Error when checking sandbox quota: {:?}", e);
+                true
+            }
+        };
+        if !is_within_quota {
+            // Rejected uploads don't count towards the quota
+            if let Err(e) = coll.delete_one(doc! { "_id": entry_id }, None) {
+                eprintln!("Error when releasing sandbox quota: {:?}", e);
+            }
+        }
+        return is_within_quota;
+    }
+    /// Looks up the SandboxDoc, unless it's older than SANDBOX_TTL_SECS (docs are only deleted periodically, so it may not be deleted yet).
+    pub fn lookup_unexpired(db: &Database, doc_id: &str) -> Option<Document> {
+        let doc_res = SandboxDoc::try_lookup_one(db, doc! { "doc_id": doc_id })?;
+        let created_on = SandboxDoc::parse_created_on(&doc_res)?;
+        return match created_on > SandboxDoc::get_expiry_cutoff() {
+            true => Some(doc_res),
+            false => None
+        };
+    }
+    /// Deletes SandboxDocs older than SANDBOX_TTL_SECS. Docs from before created_on was a date are converted first. Returns the number of deleted docs.
+    /// Quota entries older than SANDBOX_QUOTA_WINDOW_SECS are also deleted.
+    pub fn sweep_expired(db: &Database) -> i64 {
+        let coll = (*db).collection(SANDBOX_COLL_NAME);
+        let find_options = FindOptions::builder().projection(doc! { "doc_id": 1, "created_on": 1 }).build();
+        if let Ok(cursor) = coll.find(doc! { "created_on": { "$type": "string" } }, find_options) {
+            for legacy_doc in cursor.filter_map(|item| item.ok()) {
+                // Unparseable dates are treated as expired
+                let created_on = SandboxDoc::parse_created_on(&legacy_doc).unwrap_or(Utc.timestamp_opt(0, 0).unwrap());
+                let update_query = doc! { "$set": { "created_on": Bson::DateTime(created_on) } };
+                if let Err(e) = coll.update_one(doc! { "_id": legacy_doc.get("_id").cloned().unwrap_or(Bson::Null) }, update_query, None) {
+                    eprintln!("Error when converting created_on of sandbox doc: {:?}", e);
+                }
+            }
+        }
+        let res = match coll.delete_many(doc! { "created_on": { "$lt": Bson::DateTime(SandboxDoc::get_expiry_cutoff()) } }, None) {
+            Ok(delete_res) => delete_res.deleted_count,
+            Err(e) => {
+                eprintln!("Error when deleting expired sandbox docs: {:?}", e);
+                0
+            }
+        };
+        let quota_cutoff = Utc::now() - Duration::seconds(SANDBOX_QUOTA_WINDOW_SECS);
+        let quota_coll = (*db).collection(SANDBOX_QUOTA_COLL_NAME);
+        if let Err(e) = quota_coll.delete_many(doc! { "created_on": { "$lt": Bson::DateTime(quota_cutoff) } }, None) {
+            eprintln!("Error when deleting expired sandbox quota entries: {:?}", e);
+        }
+        return res;
+    }
+    /// Deletes expired SandboxDocs every SANDBOX_SWEEP_INTERVAL_SECS. This blocks, so it should be run on its own thread.
+    pub fn run_sweeper(db: Database) {
+        loop {
+            SandboxDoc::sweep_expired(&db);
+            thread::sleep(time::Duration::from_secs(SANDBOX_SWEEP_INTERVAL_SECS));
+        }
+    }
+    /// n_entries is the number of quota entries in the window, including the new reservation.
+    fn is_within_quota(n_entries: i64) -> bool {
+        return n_entries <= SANDBOX_QUOTA_PER_IP;
+    }
+    /// SandboxDocs created before this are expired.
+    fn get_expiry_cutoff() -> DateTime<Utc> {
+        return Utc::now() - Duration::seconds(SANDBOX_TTL_SECS);
+    }
+    /// Reads created_on as a date. Docs from before created_on was a date have it as a String (from Utc::now().to_string()).
+    fn parse_created_on(doc_res: &Document) -> Option<DateTime<Utc>> {
+        return match doc_res.get("created_on") {
+            Some(Bson::DateTime(created_on)) => Some(*created_on),
+            Some(Bson::String(created_on)) => {
+                let created_on = created_on.trim_end_matches(" UTC");
+                NaiveDateTime::parse_from_str(created_on, "%Y-%m-%d %H:%M:%S%.f").ok().map(|dt| Utc.from_utc_datetime(&dt))
+            },
+            _ => None
+        };
+    }
+    /// IP addresses aren't stored directly.
+    fn hash_client_ip(client_ip: &str) -> String {
+        return str_to_hashed_string(client_ip, CLIENT_IP_HASH_SALT);
     }
 }
 
@@ -78,4 +193,26 @@ impl AppFeedback {
         let new_feedback = AppFeedback { feedback, contact, created_on };
         return new_feedback;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_admits_up_to_limit() {
+        // Each upload counts its own reservation, so the SANDBOX_QUOTA_PER_IP-th upload is the last one admitted
+        assert!(SandboxDoc::is_within_quota(1));
+        assert!(SandboxDoc::is_within_quota(SANDBOX_QUOTA_PER_IP));
+        assert!(!SandboxDoc::is_within_quota(SANDBOX_QUOTA_PER_IP + 1));
+    }
+
+    #[test]
+    fn client_ip_hash_is_salted() {
+        let ip_hash = SandboxDoc::hash_client_ip("203.0.113.7");
+        assert_eq!(ip_hash, SandboxDoc::hash_client_ip("203.0.113.7"));
+        assert_ne!(ip_hash, SandboxDoc::hash_client_ip("203.0.113.8"));
+        assert_ne!(ip_hash, str_to_hashed_string("203.0.113.7", SANDBOX_COLL_NAME));
+        assert!(!ip_hash.contains("203.0.113.7"));
+    }
+}
@@ -0,0 +1,301 @@
+/*
+/// Data Structures for searching UserDocs (full-text search, and occurrences of dictionary entries).
+*/
+
+use chrono::Utc;
+use crate::{
+    DatabaseItem,
+    config::{DOC_SEARCH_COLL_NAME, SEARCH_MAX_CANDIDATES, SEARCH_MAX_RESULTS, SEARCH_MAX_SNIPPETS, SEARCH_SNIPPET_CONTEXT_CHARS,
+        USER_DOC_COLL_NAME, VOCAB_OCCURRENCE_COLL_NAME, CONCORDANCE_MAX_LINES, CONCORDANCE_CONTEXT_CHARS, CONCORDANCE_WINDOW_TOKENS},
+    models::{
+        user::{UserDoc, UserDocPage},
+        zh::CnPhrase
+    },
+    search::{SearchQuery, extract_keyword_in_context, extract_search_terms, find_match_ranges, generate_hanzi_query_terms, is_title_match,
+        normalize_pinyin, parse_search_query, render_snippets, render_title_html, split_english_words}
+};
+use mongodb::{
+    bson::{doc, Bson, from_bson},
+    options::{FindOptions, ReplaceOptions},
+    sync::Database
+};
+use rocket::http::uri::Uri;
+use serde::{Serialize, Deserialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error
+};
+
+/// The search terms of a UserDoc (refer to search::extract_search_terms). Updated whenever the doc is saved.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DocSearchIndex {
+    pub doc_id: String,
+    username: String,
+    hanzi_terms: Vec<String>,
+    pinyin_terms: Vec<String>,
+    english_terms: Vec<String>,
+    pub updated_on: String
+}
+
+impl DatabaseItem for DocSearchIndex {
+    fn collection_name() -> &'static str { return DOC_SEARCH_COLL_NAME; }
+    fn all_field_names() -> Vec<&'static str> {
+        return vec!["doc_id", "username", "hanzi_terms", "pinyin_terms", "english_terms", "updated_on"];
+    }
+    fn primary_key(&self) -> &str { return &self.doc_id; }
+}
+
+/// A UserDoc matching a search, with highlighted snippets (escaped HTML with matches in <mark>).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DocSearchResult {
+    pub doc_id: String,
+    pub title: String,
+    pub title_html: String,
+    pub n_matches: usize,
+    pub snippets: Vec<String>,
+    #[serde(skip)]
+    score: usize
+}
+
+impl DocSearchIndex {
+    /// Generates the DocSearchIndex of a UserDoc from its title and tokenized_body_json.
+    pub fn new(doc_id: &str, username: &str, title: &str, phrases: &[CnPhrase]) -> Self {
+        let terms = extract_search_terms(title, phrases);
+        let new_index = DocSearchIndex {
+            doc_id: String::from(doc_id), username: String::from(username),
+            hanzi_terms: terms.hanzi_terms, pinyin_terms: terms.pinyin_terms, english_terms: terms.english_terms,
+            updated_on: Utc::now().to_string()
+        };
+        return new_index;
+    }
+    /// Inserts the DocSearchIndex, replacing the previous one for the doc.
+    pub fn try_upsert(&self, db: &Database) -> Result<(), Box<dyn Error>> {
+        let coll = (*db).collection(DOC_SEARCH_COLL_NAME);
+        let options = ReplaceOptions::builder().upsert(true).build();
+        coll.replace_one(doc! { "doc_id": &self.doc_id }, self.as_document(), options)?;
+        return Ok(());
+    }
+    /// Searches the user's UserDocs (refer to search::parse_search_query for how the query is matched).
+    /// Candidates are found using the index, then confirmed using their phrases and ranked by the number of matches (title matches rank first).
+    pub fn search(db: &Database, username: &str, q: &str) -> Result<Vec<DocSearchResult>, Box<dyn Error>> {
+        let query = parse_search_query(q).ok_or("Empty query")?;
+        let index_query = match &query {
+            SearchQuery::Hanzi(q) => doc! { "username": username, "hanzi_terms": { "$all": generate_hanzi_query_terms(q) } },
+            SearchQuery::Latin(words) => {
+                let pinyin_words: Vec<String> = words.iter().map(|word| normalize_pinyin(word)).collect();
+                // Stop words aren't indexed, so they're left out of the English terms (refer to search::split_english_words)
+                let english_words = split_english_words(&words.join(" "));
+                let mut term_queries = vec![
+                    doc! { "pinyin_terms": { "$all": pinyin_words.clone() } },
+                    doc! { "pinyin_terms": pinyin_words.concat() }
+                ];
+                if english_words.len() > 0 {
+                    term_queries.push(doc! { "english_terms": { "$all": english_words } });
+                }
+                doc! { "username": username, "$or": term_queries }
+            }
+        };
+        let coll = (*db).collection(DOC_SEARCH_COLL_NAME);
+        let options = FindOptions::builder()
+            .projection(doc! { "doc_id": 1 })
+            .limit(SEARCH_MAX_CANDIDATES)
+            .build();
+        let candidate_ids: Vec<String> = coll.find(index_query, options)?
+            .filter_map(|item| item.ok())
+            .filter_map(|index_doc| index_doc.get_str("doc_id").ok().map(String::from))
+            .collect();
+        let doc_coll = (*db).collection(USER_DOC_COLL_NAME);
+        let title_options = FindOptions::builder().projection(doc! { "doc_id": 1, "title": 1 }).build();
+        let mut res: Vec<DocSearchResult> = Vec::new();
+        for item in doc_coll.find(doc! { "username": username, "doc_id": { "$in": candidate_ids } }, title_options)? {
+            let doc_res = item?;
+            let (doc_id, title) = match (doc_res.get_str("doc_id"), doc_res.get_str("title")) {
+                (Ok(doc_id), Ok(title)) => (String::from(doc_id), String::from(title)),
+                _ => continue
+            };
+            let phrases = UserDoc::lookup_all_phrases(db, &doc_id);
+            let ranges = find_match_ranges(&query, &phrases);
+            let title_match = is_title_match(&title, &query);
+            if ranges.len() == 0 && !title_match {
+                continue;
+            }
+            let score = ranges.len() + if title_match { SEARCH_MAX_CANDIDATES as usize } else { 0 };
+            res.push(DocSearchResult {
+                doc_id,
+                title_html: render_title_html(&title, &query),
+                title,
+                n_matches: ranges.len(),
+                snippets: render_snippets(&phrases, &ranges, SEARCH_SNIPPET_CONTEXT_CHARS, SEARCH_MAX_SNIPPETS),
+                score
+            });
+        }
+        res.sort_by(|a, b| b.score.cmp(&a.score));
+        res.truncate(SEARCH_MAX_RESULTS);
+        return Ok(res);
+    }
+    /// Attempts to delete the search index of the user's UserDoc.
+    pub fn try_delete(db: &Database, username: &str, doc_id: &str) -> bool {
+        let coll = (*db).collection(DOC_SEARCH_COLL_NAME);
+        let res = match coll.delete_one(doc! { "username": username, "doc_id": doc_id }, None) {
+            Ok(delete_res) => delete_res.deleted_count > 0,
+            Err(_) => false
+        };
+        return res;
+    }
+}
+
+/// The positions of a dictionary entry (by uid) in a UserDoc, used for concordances.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VocabOccurrences {
+    pub uid: String,
+    username: String,
+    pub doc_id: String,
+    /// Indices of the phrases in the UserDoc's tokenized_body_json (in order)
+    pub token_indices: Vec<i32>
+}
+
+impl DatabaseItem for VocabOccurrences {
+    fn collection_name() -> &'static str { return VOCAB_OCCURRENCE_COLL_NAME; }
+    fn all_field_names() -> Vec<&'static str> {
+        return vec!["uid", "username", "doc_id", "token_indices"];
+    }
+    fn primary_key(&self) -> &str { return &self.uid; }
+}
+
+/// A keyword-in-context line for one occurrence of a phrase. url opens the reader at the page of the occurrence,
+/// with the phrase highlighted (refer to reader.html.tera).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConcordanceLine {
+    pub doc_id: String,
+    pub doc_title: String,
+    pub token_index: i32,
+    pub page: i32,
+    pub left: String,
+    pub phrase: String,
+    pub right: String,
+    pub url: String
+}
+
+impl VocabOccurrences {
+    /// Generates one VocabOccurrences per dictionary entry found in the phrases.
+    pub fn from_phrases(doc_id: &str, username: &str, phrases: &[CnPhrase]) -> Vec<Self> {
+        let mut token_indices: BTreeMap<&str, Vec<i32>> = BTreeMap::new();
+        for (i, phrase) in phrases.iter().enumerate().filter(|(_, phrase)| phrase.lookup_success) {
+            token_indices.entry(phrase.entry.uid.as_str()).or_default().push(i as i32);
+        }
+        let res = token_indices.into_iter().map(|(uid, token_indices)| VocabOccurrences {
+            uid: String::from(uid), username: String::from(username), doc_id: String::from(doc_id), token_indices
+        }).collect();
+        return res;
+    }
+    /// Replaces the VocabOccurrences of the UserDoc with the ones in its phrases.
+    pub fn try_replace_all(db: &Database, doc_id: &str, username: &str, phrases: &[CnPhrase]) -> Result<(), Box<dyn Error>> {
+        let coll = (*db).collection(VOCAB_OCCURRENCE_COLL_NAME);
+        coll.delete_many(doc! { "doc_id": doc_id }, None)?;
+        let new_docs: Vec<_> = VocabOccurrences::from_phrases(doc_id, username, phrases).iter()
+            .map(|occurrences| occurrences.as_document())
+            .collect();
+        if new_docs.len() > 0 {
+            coll.insert_many(new_docs, None)?;
+        }
+        return Ok(());
+    }
+    /// Looks up keyword-in-context lines for the dictionary entry in all of the user's UserDocs (up to CONCORDANCE_MAX_LINES).
+    /// Also returns the total number of occurrences. The doc titles and the pages around every line are each loaded in one query.
+    pub fn lookup_concordance(db: &Database, username: &str, uid: &str) -> (Vec<ConcordanceLine>, usize) {
+        let occurrence_docs = VocabOccurrences::try_lookup_all(db, doc! { "username": username, "uid": uid }).unwrap_or_default();
+        let all_occurrences: Vec<VocabOccurrences> = occurrence_docs.into_iter()
+            .filter_map(|occurrence_doc| from_bson(Bson::Document(occurrence_doc)).ok())
+            .collect();
+        let n_total = all_occurrences.iter().map(|occurrences| occurrences.token_indices.len()).sum();
+        // (doc_id, window_start, window_end) of each line
+        let windows: Vec<(String, i32, i32)> = all_occurrences.iter()
+            .flat_map(|occurrences| occurrences.token_indices.iter().map(move |&token_index| {
+                (occurrences.doc_id.clone(), i32::max(token_index - CONCORDANCE_WINDOW_TOKENS, 0), token_index + CONCORDANCE_WINDOW_TOKENS + 1)
+            }))
+            .take(CONCORDANCE_MAX_LINES)
+            .collect();
+        let doc_ids: Vec<&str> = all_occurrences.iter().map(|occurrences| occurrences.doc_id.as_str()).collect();
+        let doc_coll = (*db).collection(USER_DOC_COLL_NAME);
+        let title_options = FindOptions::builder().projection(doc! { "doc_id": 1, "title": 1 }).build();
+        let doc_titles: HashMap<String, String> = match doc_coll.find(doc! { "username": username, "doc_id": { "$in": doc_ids } }, title_options) {
+            Ok(cursor) => cursor.filter_map(|item| item.ok())
+                .filter_map(|doc_res| Some((String::from(doc_res.get_str("doc_id").ok()?), String::from(doc_res.get_str("title").ok()?))))
+                .collect(),
+            Err(_) => HashMap::new()
+        };
+        let doc_pages = UserDocPage::lookup_overlapping(db, &windows);
+        let encoded_uid = Uri::percent_encode(uid);
+        let mut res: Vec<ConcordanceLine> = Vec::new();
+        let lines = all_occurrences.iter()
+            .flat_map(|occurrences| occurrences.token_indices.iter().map(move |&token_index| (occurrences, token_index)))
+            .zip(windows.iter());
+        for ((occurrences, token_index), (_, window_start, window_end)) in lines {
+            let (doc_title, pages) = match (doc_titles.get(&occurrences.doc_id), doc_pages.get(&occurrences.doc_id)) {
+                (Some(doc_title), Some(pages)) => (doc_title, pages),
+                _ => continue
+            };
+            let phrases = UserDocPage::slice_phrases(pages, *window_start, *window_end);
+            let i = (token_index - window_start) as usize;
+            if i >= phrases.len() {
+                continue;
+            }
+            let (left, phrase, right) = extract_keyword_in_context(&phrases, i, CONCORDANCE_CONTEXT_CHARS);
+            // The reader highlights the n-th occurrence of the phrase on the page
+            let (page, page_start) = match pages.iter().find(|page| page.token_start <= token_index && token_index < page.token_end) {
+                Some(page) => (page.page, page.token_start),
+                None => (1, 0)
+            };
+            let n = occurrences.token_indices.iter().filter(|&&j| page_start <= j && j < token_index).count();
+            let url = format!("/doc/{}/{}?uid={}&occurrence={}", occurrences.doc_id, page, encoded_uid, n);
+            res.push(ConcordanceLine {
+                doc_id: occurrences.doc_id.clone(), doc_title: doc_title.clone(),
+                token_index, page, left, phrase, right, url
+            });
+        }
+        return (res, n_total);
+    }
+    /// Deletes the VocabOccurrences of the user's UserDoc.
+    pub fn try_delete_all(db: &Database, username: &str, doc_id: &str) -> bool {
+        let coll = (*db).collection(VOCAB_OCCURRENCE_COLL_NAME);
+        let res = coll.delete_many(doc! { "username": username, "doc_id": doc_id }, None).is_ok();
+        return res;
+    }
+}
+
+/* Public Functions */
+/// Indexes the UserDocs that don't have a DocSearchIndex or VocabOccurrences yet (e.g. docs saved before search was added).
+/// Docs are read one at a time (with only the fields needed), and phrases are only loaded for docs that need indexing.
+pub fn migrate_unindexed_docs(db: &Database) {
+    let coll = (*db).collection(USER_DOC_COLL_NAME);
+    let find_options = FindOptions::builder().projection(doc! { "doc_id": 1, "username": 1, "title": 1 }).build();
+    let cursor = match coll.find(doc! {}, find_options) {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            eprintln!("Error when searching for unindexed docs: {:?}", e);
+            return;
+        }
+    };
+    for doc_res in cursor.filter_map(|item| item.ok()) {
+        let doc_id = doc_res.get_str("doc_id").unwrap_or("");
+        let has_search_index = DocSearchIndex::try_lookup_one(db, doc! { "doc_id": doc_id }).is_some();
+        let has_occurrences = VocabOccurrences::try_lookup_one(db, doc! { "doc_id": doc_id }).is_some();
+        if has_search_index && has_occurrences {
+            continue;
+        }
+        let username = doc_res.get_str("username").unwrap_or("");
+        let title = doc_res.get_str("title").unwrap_or("");
+        let phrases = UserDoc::lookup_all_phrases(db, doc_id);
+        if !has_search_index {
+            let new_index = DocSearchIndex::new(doc_id, username, title, &phrases);
+            if let Err(e) = new_index.try_upsert(db) {
+                eprintln!("Error indexing doc {}: {:?}", doc_id, e);
+            }
+        }
+        if !has_occurrences {
+            if let Err(e) = VocabOccurrences::try_replace_all(db, doc_id, username, &phrases) {
+                eprintln!("Error indexing phrase occurrences of doc {}: {:?}", doc_id, e);
+            }
+        }
+    }
+}
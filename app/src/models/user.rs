@@ -5,21 +5,38 @@
 use chrono::Utc;
 use crate::{
     DatabaseItem,
+    TokenizerSession,
     scrape_text_from_url,
-    convert_string_to_tokenized_phrases,
+    convert_string_to_region,
+    convert_strings_to_region,
     html_rendering,
     auth::str_to_hashed_string,
-    config::{USER_COLL_NAME, USER_DOC_COLL_NAME, USER_VOCAB_COLL_NAME, USER_VOCAB_LIST_COLL_NAME},
+    config::{USER_COLL_NAME, USER_DOC_COLL_NAME, USER_DOC_PAGE_COLL_NAME, USER_VOCAB_COLL_NAME, USER_VOCAB_LIST_COLL_NAME, DOC_PAGE_MAX_CHARS},
     connect_to_redis,
-    models::zh::{CnType, CnPhonetics, CnEnDictEntry, CnPhrase}
+    markup::{BodyFormat, MarkupEvent, parse_markup, render_markup},
+    sections::{split_text_into_sections, split_markup_into_sections, split_cues_into_sections},
+    subtitles::SubtitleCue,
+    models::{
+        annotation::DocAnnotation,
+        collection::{DocCollection, parse_tags},
+        job::IngestJob,
+        progress::ReadingProgress,
+        search::{DocSearchIndex, VocabOccurrences},
+        zh::{CnType, CnPhonetics, CnGranularity, CnRegion, CnEnDictEntry, CnPhrase}
+    }
 };
 use mongodb::{
-    bson::{doc, Bson, from_bson},
+    bson::{doc, document::Document, Bson, from_bson},
+    options::FindOptions,
     sync::Database
 };
 use rand::{self, Rng};
 use serde::{Serialize, Deserialize};
-use std::error::Error;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt
+};
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -30,6 +47,10 @@ pub struct User {
     email: String,
     cn_type: CnType,
     cn_phonetics: CnPhonetics,
+    #[serde(default)]
+    cn_granularity: CnGranularity,
+    #[serde(default)]
+    cn_region: CnRegion,
     created_on: String
 }
 
@@ -51,7 +72,7 @@ impl DatabaseItem for User {
     fn collection_name() -> &'static str { return USER_COLL_NAME; }
     fn all_field_names() -> Vec<&'static str> { 
         return vec!["username", "pw_hash", "pw_salt",
-            "email", "cn_type", "cn_phonetics", "created_on"]; 
+            "email", "cn_type", "cn_phonetics", "cn_granularity", "cn_region", "created_on"]; 
     }
     fn primary_key(&self) -> &str { return &self.username; }
 }
@@ -62,27 +83,36 @@ impl User {
         let pw_salt = User::generate_pw_salt();
         let pw_hash = str_to_hashed_string(&password, &pw_salt);
         let (cn_type, cn_phonetics) = User::default_settings();
+        let cn_granularity = CnGranularity::default();
+        let cn_region = CnRegion::default();
         let created_on = Utc::now().to_string();
-        let new_user = User { username, pw_hash, pw_salt, email, cn_type, cn_phonetics, created_on };
+        let new_user = User { username, pw_hash, pw_salt, email, cn_type, cn_phonetics, cn_granularity, cn_region, created_on };
         return new_user;
     }
     /// Returns true if username exists, false otherwise.
     pub fn check_if_username_exists(db: &Database, username: &str) -> bool {
         let coll = (*db).collection(USER_COLL_NAME);
-        return (coll.find_one(doc! {"username": username }, None).unwrap()) != None;
-    }
-    /// Updates CnType+CnPhonetics settings via username.
-    pub fn update_user_settings(db: &Database, username: &str, cn_type: Option<CnType>, cn_phonetics: Option<CnPhonetics>) -> Result<(), Box<dyn Error>> {
-        let user = User::from_username(db, username).unwrap();
-        match (cn_type, cn_phonetics) {
-            (Some(new_type), Some(new_phonetics)) => {
-                user.try_update(db, 
-                    vec!["cn_type", "cn_phonetics"], 
-                    vec![new_type.as_str(), new_phonetics.as_str()])?;
-            },
-            (Some(new_type), None) => { user.try_update(db, vec!["cn_type"], vec![new_type.as_str()])?; }
-            (None, Some(new_phonetics)) => { user.try_update(db, vec!["cn_phonetics"], vec![new_phonetics.as_str()])?; }
-            (None, None) => {}
+        return coll.find_one(doc! {"username": username }, None).unwrap().is_some();
+    }
+    /// Updates CnType+CnPhonetics+CnGranularity+CnRegion settings via username.
+    /// Matches on username only, since older User documents may not have every setting field.
+    pub fn update_user_settings(db: &Database, username: &str, cn_type: Option<CnType>, cn_phonetics: Option<CnPhonetics>, cn_granularity: Option<CnGranularity>, cn_region: Option<CnRegion>) -> Result<(), Box<dyn Error>> {
+        let coll = (*db).collection(USER_COLL_NAME);
+        let mut update_doc = Document::new();
+        if let Some(new_type) = cn_type {
+            update_doc.insert("cn_type", new_type.as_str());
+        }
+        if let Some(new_phonetics) = cn_phonetics {
+            update_doc.insert("cn_phonetics", new_phonetics.as_str());
+        }
+        if let Some(new_granularity) = cn_granularity {
+            update_doc.insert("cn_granularity", new_granularity.as_str());
+        }
+        if let Some(new_region) = cn_region {
+            update_doc.insert("cn_region", new_region.as_str());
+        }
+        if !update_doc.is_empty() {
+            coll.update_one(doc! { "username": username }, doc! { "$set": update_doc }, None)?;
         }
         return Ok(());
     }
@@ -98,6 +128,22 @@ impl User {
         };
         return res_tup;
     }
+    /// Gets CnGranularity setting from username.
+    pub fn get_user_granularity(db: &Database, username: &str) -> CnGranularity {
+        let res = match User::from_username(db, username) {
+            Some(user) => user.cn_granularity,
+            None => CnGranularity::default()
+        };
+        return res;
+    }
+    /// Gets CnRegion setting from username.
+    pub fn get_user_region(db: &Database, username: &str) -> CnRegion {
+        let res = match User::from_username(db, username) {
+            Some(user) => user.cn_region,
+            None => CnRegion::default()
+        };
+        return res;
+    }
     /// Returns true if password is correct given username, false otherwise.
     pub fn check_password(db: &Database, username: &str, pw_to_check: &str) -> bool {
         let res = match User::from_username(db, username) {
@@ -132,10 +178,7 @@ impl User {
     fn from_username(db: &Database, username: &str) -> Option<Self> {
         let coll = (*db).collection(USER_COLL_NAME);
         let query_res = coll.find_one(doc! {"username": username}, None).unwrap();
-        let res: Option<Self> = match query_res {
-            Some(doc) => Some(from_bson(Bson::Document(doc)).unwrap()),
-            None => None,
-        };
+        let res: Option<Self> = query_res.map(|doc| from_bson(Bson::Document(doc)).unwrap());
         return res;
     }
     /// Returns true if username and email are available, false otherwise.
@@ -143,7 +186,45 @@ impl User {
         let coll = (*db).collection(USER_COLL_NAME);
         let username_query = coll.find_one(doc! {"username": username }, None).unwrap();
         let email_query = coll.find_one(doc! {"email": email}, None).unwrap();
-        return (username_query == None) && (email_query == None);
+        return username_query.is_none() && email_query.is_none();
+    }
+}
+
+/// Who can open a UserDoc using its share link (/share/<share_id>). Public docs are also listed on the owner's profile.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum DocVisibility {
+    Private,
+    Unlisted,
+    Public
+}
+
+impl DocVisibility {
+    pub fn as_str(&self) -> &str {
+        return match *self {
+            DocVisibility::Private => "Private",
+            DocVisibility::Unlisted => "Unlisted",
+            DocVisibility::Public => "Public"
+        };
+    }
+    pub fn from_str(s: &str) -> Option<Self> {
+        return match s.to_ascii_lowercase().as_str() {
+            "private" => Some(DocVisibility::Private),
+            "unlisted" => Some(DocVisibility::Unlisted),
+            "public" => Some(DocVisibility::Public),
+            _ => None
+        }
+    }
+}
+
+/// Docs are only readable by their owner until shared
+impl Default for DocVisibility {
+    fn default() -> Self { return DocVisibility::Private; }
+}
+
+/// Implements to_string()
+impl fmt::Display for DocVisibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.as_str());
     }
 }
 
@@ -153,34 +234,652 @@ pub struct UserDoc {
     username: String,
     pub title: String,
     pub body: String,
+    /// Only used by docs created before pagination (until migrated). Newer docs are rendered per page on demand (refer to render_page_html)
+    #[serde(default)]
     pub body_html: String,
+    /// Tokenized with CnGranularity::Word, so the doc can be rendered with any settings. Stored per page (refer to UserDocPage),
+    /// since the phrases of a long doc can pass MongoDB's 16MB document limit, so this is only set on new/re-tokenized docs
+    #[serde(skip)]
     pub tokenized_body_json: Vec<CnPhrase>,
     pub source: String, 
-    cn_type: CnType,
-    cn_phonetics: CnPhonetics,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub published_on: String,
+    /// Name of the FeedSubscription that imported this doc (empty if added manually)
+    #[serde(default)]
+    pub feed_name: String,
+    /// collection_id of the DocCollection the doc is filed under (empty if none)
+    #[serde(default)]
+    pub collection_id: String,
+    /// User-defined tags (refer to collection::parse_tags)
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub visibility: DocVisibility,
+    /// Random id used in the share link, generated the first time the doc is shared (empty until then)
+    #[serde(default)]
+    pub share_id: String,
+    /// Sanitized source HTML for Markdown/HTML uploads (empty for plain text)
+    #[serde(default)]
+    pub body_markup: String,
+    /// One heading per page (empty if the page has none), so the length is the number of pages
+    #[serde(default)]
+    pub page_headings: Vec<String>,
+    /// Stored separately in USER_DOC_PAGE_COLL_NAME (inserted with the UserDoc)
+    #[serde(skip)]
+    pages: Vec<UserDocPage>,
     pub created_on: String
 }
 
+/// Fields to change on a UserDoc (None keeps the current value). Refer to UserDoc::try_edit.
+#[derive(Default, Debug)]
+pub struct UserDocEdit {
+    pub title: Option<String>,
+    pub body: Option<String>,
+    /// For Markdown/HTML docs, the new body is read as HTML unless this is given
+    pub body_format: Option<BodyFormat>,
+    pub source: Option<String>,
+    /// Empty to remove the doc from its collection
+    pub collection_id: Option<String>,
+    pub tags: Option<Vec<String>>
+}
+
 impl DatabaseItem for UserDoc {
     fn collection_name() -> &'static str { return USER_DOC_COLL_NAME; }
     fn all_field_names() -> Vec<&'static str> {
-        return vec!["username", "title", "body", "tokenized_body_json",
-            "source", "cn_type", "cn_phonetics", "created_on"]
+        return vec!["username", "title", "body",
+            "source", "author", "published_on", "feed_name", "collection_id", "tags", "visibility", "share_id", "body_markup", "page_headings", "created_on"]
     }
     fn primary_key(&self) -> &str { return &self.title; }
+    /// Inserts the UserDoc, then its pages and search indices.
+    fn try_insert(&self, db: &Database) -> Result<String, Box<dyn Error>> where Self: Serialize {
+        let coll = (*db).collection(USER_DOC_COLL_NAME);
+        coll.insert_one(self.as_document(), None)?;
+        self.try_insert_pages(db)?;
+        self.try_update_search_index(db)?;
+        VocabOccurrences::try_replace_all(db, &self.doc_id, &self.username, &self.tokenized_body_json)?;
+        return Ok(String::from(self.primary_key()));
+    }
 }
 
 impl UserDoc {
     /// Generates a new UserDoc. For title collisions, a new title is automatically generated (appended by -#).
-    pub async fn new(db: &Database, username: String, desired_title: String, body: String, source: String) -> Self {
+    /// If region_conversion is specified, regional vocabulary in the body is converted first (e.g. 软件 => 软体 for Taiwan).
+    /// on_page is called after each page is tokenized, with the number of pages done and the total (e.g. for IngestJob progress).
+    /// Returns an error if the tokenizer can't be reached.
+    pub async fn new(db: &Database, username: String, desired_title: String, body: String, source: String, region_conversion: Option<CnRegion>, on_page: &dyn Fn(usize, usize)) -> Result<Self, Box<dyn Error>> {
         let doc_id = Uuid::new_v4().to_string();
-        let (cn_type, cn_phonetics) = User::get_user_settings(db, &username);
         let desired_title = desired_title.replace(" ", "");
-        let body_html = html_rendering::convert_string_to_tokenized_html(&body, &cn_type, &cn_phonetics).await;
-        let tokenized_body_json = convert_string_to_tokenized_phrases(&body).await;
-        // If title is non-unique, try appending digits until match
+        let body = match region_conversion {
+            Some(target_region) => convert_string_to_region(&body, &target_region).await,
+            None => body
+        };
+        let (tokenized_body_json, pages) = UserDoc::tokenize_text(&doc_id, &username, &body, on_page).await?;
+        let title = UserDoc::generate_unique_title(db, &username, desired_title);
+        let created_on = Utc::now().to_string();
+        let page_headings = pages.iter().map(|page| page.heading.clone()).collect();
+        let new_doc = UserDoc { doc_id, username, title, body, body_html: String::new(), tokenized_body_json, source,
+            author: String::new(), published_on: String::new(), feed_name: String::new(),
+            collection_id: String::new(), tags: Vec::new(), visibility: DocVisibility::Private, share_id: String::new(), body_markup: String::new(), page_headings, pages, created_on };
+        return Ok(new_doc);
+    }
+    /// Generates a new UserDoc with the readable title + text from the given URL. The byline and publish date are kept when found.
+    pub async fn from_url(db: &Database, username: String, url: String, region_conversion: Option<CnRegion>, on_page: &dyn Fn(usize, usize)) -> Result<Self, Box<dyn Error>> {
+        let article = scrape_text_from_url(&url).await?;
+        let mut new_doc = UserDoc::new(db, username, article.title, article.body, url, region_conversion, on_page).await?;
+        new_doc.author = article.byline;
+        new_doc.published_on = article.published_on;
+        return Ok(new_doc);
+    }
+    /// Generates a new UserDoc from subtitle cues. Each cue is a paragraph (prefixed by its timestamp in the reader),
+    /// and each tokenized phrase stores the start/end of its cue.
+    pub async fn from_subtitles(db: &Database, username: String, desired_title: String, cues: Vec<SubtitleCue>, source: String, region_conversion: Option<CnRegion>, on_page: &dyn Fn(usize, usize)) -> Result<Self, Box<dyn Error>> {
+        let doc_id = Uuid::new_v4().to_string();
+        let desired_title = desired_title.replace(" ", "");
+        let mut cues = cues;
+        if let Some(target_region) = region_conversion {
+            let cue_texts = cues.iter().map(|cue| cue.text.clone()).collect();
+            let converted_texts = convert_strings_to_region(cue_texts, &target_region).await;
+            for (cue, text) in cues.iter_mut().zip(converted_texts.into_iter()) {
+                cue.text = text;
+            }
+        }
+        let body = cues.iter().map(|cue| cue.text.as_str()).collect::<Vec<&str>>().join("\n");
+        let (tokenized_body_json, pages) = UserDoc::tokenize_cues(&doc_id, &username, cues, on_page).await?;
+        let title = UserDoc::generate_unique_title(db, &username, desired_title);
+        let created_on = Utc::now().to_string();
+        let page_headings = pages.iter().map(|page| page.heading.clone()).collect();
+        let new_doc = UserDoc { doc_id, username, title, body, body_html: String::new(), tokenized_body_json, source,
+            author: String::new(), published_on: String::new(), feed_name: String::new(),
+            collection_id: String::new(), tags: Vec::new(), visibility: DocVisibility::Private, share_id: String::new(), body_markup: String::new(), page_headings, pages, created_on };
+        return Ok(new_doc);
+    }
+    /// Generates a new UserDoc from a Markdown or HTML body, keeping the block structure (headings, lists, quotes, emphasis).
+    /// The markup is sanitized, and only its text is tokenized. The plain-text body has one line per block.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn from_markup(db: &Database, username: String, desired_title: String, markup: String, format: BodyFormat, source: String, region_conversion: Option<CnRegion>, on_page: &dyn Fn(usize, usize)) -> Result<Self, Box<dyn Error>> {
+        let doc_id = Uuid::new_v4().to_string();
+        let desired_title = desired_title.replace(" ", "");
+        let mut events = parse_markup(&markup, &format);
+        if let Some(target_region) = region_conversion {
+            let texts = events.iter()
+                .filter_map(|event| match event {
+                    MarkupEvent::Text(text) => Some(text.clone()),
+                    _ => None
+                })
+                .collect();
+            let mut converted_texts = convert_strings_to_region(texts, &target_region).await.into_iter();
+            for event in events.iter_mut() {
+                if let MarkupEvent::Text(text) = event {
+                    *text = converted_texts.next().unwrap_or_default();
+                }
+            }
+        }
+        let body_markup = render_markup(&events);
+        let (body, tokenized_body_json, pages) = UserDoc::tokenize_markup(&doc_id, &username, events, on_page).await?;
+        let title = UserDoc::generate_unique_title(db, &username, desired_title);
+        let created_on = Utc::now().to_string();
+        let page_headings = pages.iter().map(|page| page.heading.clone()).collect();
+        let new_doc = UserDoc { doc_id, username, title, body, body_html: String::new(), tokenized_body_json, source,
+            author: String::new(), published_on: String::new(), feed_name: String::new(),
+            collection_id: String::new(), tags: Vec::new(), visibility: DocVisibility::Private, share_id: String::new(), body_markup, page_headings, pages, created_on };
+        return Ok(new_doc);
+    }
+    /// Sets the doc_id (e.g. to one generated ahead of time), including on the pages.
+    pub fn set_doc_id(&mut self, doc_id: String) {
+        for page in self.pages.iter_mut() {
+            page.doc_id = doc_id.clone();
+        }
+        self.doc_id = doc_id;
+    }
+    /// Edits the title, body, source, collection and/or tags of the user's UserDoc. Returns the title, which gets -# appended on collisions,
+    /// and the job_id if a new body was queued to be re-tokenized into pages (refer to UserDoc::try_replace_body).
+    /// Everything is validated first, and the fields are saved in one update, so a failed edit doesn't save part of the change.
+    /// A new body replaces any queued body edit of the doc, and is rejected while a previous one is still processing.
+    /// On rename, the UserVocab saved from the doc are re-linked to the new title first, so a failed edit can be retried with the same title.
+    pub fn try_edit(db: &Database, username: &str, doc_id: &str, edit: UserDocEdit) -> Result<(String, Option<String>), Box<dyn Error>> {
+        let doc_res = UserDoc::try_lookup_one(db, doc! { "username": username, "doc_id": doc_id }).ok_or("No document found")?;
+        let mut user_doc: UserDoc = from_bson(Bson::Document(doc_res))?;
+        let UserDocEdit { title, body, body_format, source, collection_id, tags } = edit;
+        let title = title.map(|title| title.replace(" ", ""));
+        if title.as_deref() == Some("") {
+            return Err(Box::from("Title can't be empty"));
+        }
+        let mut set_doc = UserDoc::generate_organize_update(db, username, collection_id, tags)?;
+        if body.is_some() {
+            IngestJob::try_cancel_body_edits(db, doc_id)?;
+        }
+        let prev_title = user_doc.title.clone();
+        if let Some(title) = title {
+            if title != prev_title {
+                user_doc.title = UserDoc::generate_unique_title(db, username, title);
+            }
+        }
+        if let Some(source) = source {
+            user_doc.source = source;
+        }
+        if user_doc.title != prev_title {
+            UserVocab::try_rename_from_doc(db, username, doc_id, &user_doc.title)?;
+        }
+        set_doc.insert("title", &user_doc.title);
+        set_doc.insert("source", &user_doc.source);
+        let coll = (*db).collection(USER_DOC_COLL_NAME);
+        coll.update_one(doc! { "username": username, "doc_id": doc_id }, doc! { "$set": set_doc }, None)?;
+        let job_id = match body {
+            Some(body) => {
+                let body_format = match (body_format, user_doc.body_markup.len() > 0) {
+                    (Some(body_format), _) => body_format,
+                    (None, true) => BodyFormat::Html,
+                    (None, false) => BodyFormat::Text
+                };
+                let new_job = IngestJob::from_body_edit(String::from(username), doc_id, body, body_format);
+                Some(new_job.try_insert(db)?)
+            },
+            None => {
+                if user_doc.title != prev_title {
+                    user_doc.tokenized_body_json = UserDoc::lookup_all_phrases(db, doc_id);
+                    user_doc.try_update_search_index(db)?;
+                }
+                None
+            }
+        };
+        return Ok((user_doc.title, job_id));
+    }
+    /// Replaces the body of the user's UserDoc and re-tokenizes it into pages (for subtitle docs, cue timestamps are kept if the number of lines is unchanged).
+    /// Only the body fields are updated, so other edits made while re-tokenizing are kept. Returns the title. Refer to UserDoc::new for on_page.
+    pub async fn try_replace_body(db: &Database, username: &str, doc_id: &str, body: String, body_format: BodyFormat, on_page: &dyn Fn(usize, usize)) -> Result<String, Box<dyn Error>> {
+        let doc_res = UserDoc::try_lookup_one(db, doc! { "username": username, "doc_id": doc_id }).ok_or("No document found")?;
+        let mut user_doc: UserDoc = from_bson(Bson::Document(doc_res))?;
+        match body_format {
+            BodyFormat::Text => {
+                user_doc.body = body;
+                user_doc.body_markup = String::new();
+            },
+            format => { user_doc.body_markup = render_markup(&parse_markup(&body, &format)); }
+        }
+        // Needed to recover subtitle cues (refer to recover_cues)
+        user_doc.tokenized_body_json = UserDoc::lookup_all_phrases(db, doc_id);
+        user_doc.retokenize(on_page).await?;
+        let coll = (*db).collection(USER_DOC_COLL_NAME);
+        let update_query = doc! { "$set": {
+            "body": &user_doc.body, "body_markup": &user_doc.body_markup, "body_html": "", "page_headings": &user_doc.page_headings
+        }};
+        coll.update_one(doc! { "username": username, "doc_id": doc_id }, update_query, None)?;
+        user_doc.try_replace_pages(db).await?;
+        return Ok(user_doc.title);
+    }
+    /// Generates the fields to $set for filing the user's UserDoc under a collection (empty to remove it from its collection) and/or replacing its tags.
+    /// None keeps the current value. Returns an error if the collection isn't found.
+    fn generate_organize_update(db: &Database, username: &str, collection_id: Option<String>, tags: Option<Vec<String>>) -> Result<Document, Box<dyn Error>> {
+        let mut set_doc = Document::new();
+        if let Some(collection_id) = collection_id {
+            if collection_id.len() > 0 && DocCollection::from_collection_id(db, username, &collection_id).is_none() {
+                return Err(Box::from("No collection found"));
+            }
+            set_doc.insert("collection_id", collection_id);
+        }
+        if let Some(tags) = tags {
+            set_doc.insert("tags", tags);
+        }
+        return Ok(set_doc);
+    }
+    /// Renames the tag on all of the user's UserDocs (docs that already have new_tag keep a single copy).
+    /// An empty new_tag removes the tag. Returns the number of updated docs.
+    pub fn try_rename_tag(db: &Database, username: &str, tag: &str, new_tag: &str) -> Result<i64, Box<dyn Error>> {
+        let new_tags = parse_tags(new_tag);
+        if new_tags.len() > 1 {
+            return Err(Box::from("Tags can't contain commas"));
+        }
+        if new_tags.first().map(|s| s.as_str()) == Some(tag) {
+            return Ok(0);
+        }
+        let coll = (*db).collection(USER_DOC_COLL_NAME);
+        let query_doc = doc! { "username": username, "tags": tag };
+        if let Some(new_tag) = new_tags.first() {
+            coll.update_many(query_doc.clone(), doc! { "$addToSet": { "tags": new_tag } }, None)?;
+        }
+        let update_res = coll.update_many(query_doc, doc! { "$pull": { "tags": tag } }, None)?;
+        return Ok(update_res.modified_count);
+    }
+    /// Sets who can open the user's UserDoc using its share link. Returns the share_id (generated the first time the doc is shared).
+    /// Making the doc Private keeps the share_id, so the same link works again if it's re-shared.
+    pub fn try_set_visibility(db: &Database, username: &str, doc_id: &str, visibility: &DocVisibility) -> Result<String, Box<dyn Error>> {
+        let doc_res = UserDoc::try_lookup_one(db, doc! { "username": username, "doc_id": doc_id }).ok_or("No document found")?;
+        let share_id = match doc_res.get_str("share_id") {
+            Ok(share_id) if share_id.len() > 0 => String::from(share_id),
+            _ => Uuid::new_v4().to_string()
+        };
+        let coll = (*db).collection(USER_DOC_COLL_NAME);
+        let update_query = doc! { "$set": { "visibility": visibility.as_str(), "share_id": &share_id } };
+        coll.update_one(doc! { "username": username, "doc_id": doc_id }, update_query, None)?;
+        return Ok(share_id);
+    }
+    /// Looks up the UserDoc from its share link. Returns None if the doc doesn't exist or is Private.
+    pub fn from_share_id(db: &Database, share_id: &str) -> Option<Document> {
+        if share_id.len() == 0 {
+            return None;
+        }
+        let shared_visibility = vec![DocVisibility::Unlisted.as_str(), DocVisibility::Public.as_str()];
+        return UserDoc::try_lookup_one(db, doc! { "share_id": share_id, "visibility": { "$in": shared_visibility } });
+    }
+    /// Copies a shared UserDoc (with its pages) into the user's library, as a new Private doc outside of any collection.
+    /// Returns the doc_id of the copy.
+    pub fn try_clone_shared(db: &Database, share_id: &str, username: &str) -> Result<String, Box<dyn Error>> {
+        let doc_res = UserDoc::from_share_id(db, share_id).ok_or("No shared document found")?;
+        let mut new_doc: UserDoc = from_bson(Bson::Document(doc_res))?;
+        let page_docs = UserDocPage::try_lookup_all(db, doc! { "doc_id": &new_doc.doc_id }).unwrap_or_default();
+        new_doc.pages = page_docs.into_iter()
+            .filter_map(|page_doc| from_bson(Bson::Document(page_doc)).ok())
+            .collect();
+        new_doc.pages.sort_by_key(|page| page.page);
+        for page in new_doc.pages.iter_mut() {
+            page.username = String::from(username);
+        }
+        new_doc.tokenized_body_json = new_doc.pages.iter().flat_map(|page| page.phrases.clone()).collect();
+        new_doc.set_doc_id(Uuid::new_v4().to_string());
+        new_doc.title = UserDoc::generate_unique_title(db, username, new_doc.title.clone());
+        new_doc.username = String::from(username);
+        new_doc.feed_name = String::new();
+        new_doc.collection_id = String::new();
+        new_doc.tags = Vec::new();
+        new_doc.visibility = DocVisibility::Private;
+        new_doc.share_id = String::new();
+        new_doc.created_on = Utc::now().to_string();
+        new_doc.try_insert(db)?;
+        return Ok(new_doc.doc_id);
+    }
+    /// Generates the query for the user's UserDocs in the collection (including its sub-collections) and/or with the tag.
+    /// An empty collection_id matches docs that aren't in any collection.
+    pub fn generate_filter_query(db: &Database, username: &str, collection_id: Option<&str>, tag: Option<&str>) -> Document {
+        let mut query_doc = doc! { "username": username };
+        match collection_id {
+            Some("") => { query_doc.insert("collection_id", doc! { "$in": ["", Bson::Null] }); },
+            Some(collection_id) => {
+                let all_collections = DocCollection::lookup_all(db, username);
+                let collection_ids = DocCollection::get_descendant_ids(&all_collections, collection_id);
+                query_doc.insert("collection_id", doc! { "$in": collection_ids });
+            },
+            None => { }
+        }
+        if let Some(tag) = tag {
+            query_doc.insert("tags", tag);
+        }
+        return query_doc;
+    }
+    /// Returns the doc_ids of the UserDocs matching the query.
+    pub fn lookup_doc_ids(db: &Database, query_doc: Document) -> Vec<String> {
+        let coll = (*db).collection(USER_DOC_COLL_NAME);
+        let find_options = FindOptions::builder().projection(doc! { "doc_id": 1 }).build();
+        let res = match coll.find(query_doc, find_options) {
+            Ok(cursor) => cursor.filter_map(|item| item.ok())
+                .filter_map(|user_doc| user_doc.get_str("doc_id").ok().map(String::from))
+                .collect(),
+            Err(_) => Vec::new()
+        };
+        return res;
+    }
+    /// Counts the user's UserDocs per collection_id (only directly in the collection, not its sub-collections), in one query.
+    pub fn count_by_collection(db: &Database, username: &str) -> HashMap<String, i64> {
+        let coll = (*db).collection(USER_DOC_COLL_NAME);
+        let pipeline = vec![
+            doc! { "$match": { "username": username, "collection_id": { "$nin": ["", Bson::Null] } } },
+            doc! { "$group": { "_id": "$collection_id", "n_docs": { "$sum": 1 } } }
+        ];
+        let res = match coll.aggregate(pipeline, None) {
+            Ok(cursor) => cursor.filter_map(|item| item.ok())
+                .filter_map(|count_doc| {
+                    let collection_id = String::from(count_doc.get_str("_id").ok()?);
+                    let n_docs = count_doc.get_i32("n_docs").map(i64::from).or_else(|_| count_doc.get_i64("n_docs")).ok()?;
+                    return Some((collection_id, n_docs));
+                })
+                .collect(),
+            Err(_) => HashMap::new()
+        };
+        return res;
+    }
+    /// Renders the page of the UserDoc from its phrases, using the settings of settings_username (usually the reader).
+    /// Rendered pages are cached per page + settings (refer to html_rendering::cache_doc_page_html). Returns None if the page isn't found.
+    pub async fn render_page_html(db: &Database, doc_id: &str, page: i32, settings_username: &str) -> Option<String> {
+        let (cn_type, cn_phonetics) = User::get_user_settings(db, settings_username);
+        let cn_granularity = User::get_user_granularity(db, settings_username);
+        let cn_region = User::get_user_region(db, settings_username);
+        if let Some(html) = html_rendering::get_cached_doc_page_html(doc_id, page, &cn_type, &cn_phonetics, &cn_granularity, &cn_region).await {
+            return Some(html);
+        }
+        let page_doc = UserDocPage::try_lookup_one(db, doc! { "doc_id": doc_id, "page": page })?;
+        let UserDocPage { token_start, events, phrases, .. } = from_bson(Bson::Document(page_doc)).ok()?;
+        let html = match (events.len() > 0, phrases.iter().any(|phrase| phrase.cue_start_ms.is_some())) {
+            (true, _) => html_rendering::convert_markup_to_tokenized_html(&events, &phrases, token_start as usize, &cn_type, &cn_phonetics, &cn_granularity, &cn_region).await,
+            (false, true) => html_rendering::convert_cues_to_tokenized_html(&phrases, token_start as usize, &cn_type, &cn_phonetics, &cn_granularity, &cn_region).await,
+            (false, false) => html_rendering::convert_phrases_to_tokenized_html(&phrases, token_start as usize, &cn_type, &cn_phonetics, &cn_granularity, &cn_region).await
+        };
+        html_rendering::cache_doc_page_html(doc_id, page, &cn_type, &cn_phonetics, &cn_granularity, &cn_region, &html).await;
+        return Some(html);
+    }
+    /// Replaces the saved UserDoc (with the same doc_id), its pages and search indices, e.g. after it's re-tokenized.
+    pub async fn try_replace(&self, db: &Database) -> Result<String, Box<dyn Error>> {
         let coll = (*db).collection(USER_DOC_COLL_NAME);
-        let mut title_exists = (coll.find_one(doc! {"username": &username, "title": &desired_title, "cn_type": cn_type.as_str(), "cn_phonetics": cn_phonetics.as_str()}, None).unwrap()) != None;
+        coll.replace_one(doc! { "username": &self.username, "doc_id": &self.doc_id }, self.as_document(), None)?;
+        self.try_replace_pages(db).await?;
+        return Ok(String::from(self.primary_key()));
+    }
+    /// Replaces the pages and search indices of the saved UserDoc after it's re-tokenized.
+    /// Reading progress and annotations are moved to the new tokens, and its cached HTML is cleared.
+    async fn try_replace_pages(&self, db: &Database) -> Result<(), Box<dyn Error>> {
+        UserDocPage::try_delete_all(db, &self.username, &self.doc_id);
+        self.try_insert_pages(db)?;
+        self.try_update_search_index(db)?;
+        VocabOccurrences::try_replace_all(db, &self.doc_id, &self.username, &self.tokenized_body_json)?;
+        ReadingProgress::try_resize_all(db, &self.doc_id, self.tokenized_body_json.len() as i32)?;
+        DocAnnotation::try_reanchor_all(db, &self.doc_id, &self.tokenized_body_json)?;
+        html_rendering::clear_cached_doc_html(&self.doc_id).await;
+        return Ok(());
+    }
+    /// Migrates UserDocs saved per-setting (i.e. with cn_type and cn_phonetics, from before docs were rendered on demand).
+    /// Copies of the same doc (same body and source) are merged into the oldest copy, and the UserVocab saved from them are re-linked to it.
+    /// The remaining docs are re-tokenized into pages, and renamed if the title is already used by another doc.
+    /// Docs that fail (e.g. if the tokenizer is down) are logged and skipped, and the rest are still migrated.
+    /// Once every doc is migrated, the unique (username, title) index is created (copies share a title, so it can't exist before).
+    /// This is safe to re-run (failed docs are retried on the next start), since migrated docs no longer have cn_type.
+    pub async fn migrate_per_setting_docs(db: &Database) -> Result<(), Box<dyn Error>> {
+        let coll = (*db).collection(USER_DOC_COLL_NAME);
+        let find_options = FindOptions::builder().sort(doc! { "created_on": 1 }).build();
+        let legacy_docs = coll.find(doc! { "cn_type": { "$exists": true } }, find_options)?;
+        // (username, hash of body + source) => title of the merged doc
+        let mut merged_titles: HashMap<(String, String), String> = HashMap::new();
+        let mut n_failed = 0;
+        for legacy_doc in legacy_docs {
+            let legacy_doc = legacy_doc?;
+            let doc_id = String::from(legacy_doc.get_str("doc_id").unwrap_or(""));
+            if let Err(e) = UserDoc::try_migrate_per_setting_doc(db, legacy_doc, &mut merged_titles).await {
+                eprintln!("Error migrating per-setting doc {}: {:?}", doc_id, e);
+                n_failed += 1;
+            }
+        }
+        if n_failed > 0 {
+            return Err(Box::from(format!("{} per-setting docs weren't migrated (retried on the next start)", n_failed)));
+        }
+        return UserDoc::try_create_title_index(db);
+    }
+    /// Merges or re-tokenizes one per-setting doc (refer to migrate_per_setting_docs). Successfully migrated docs are added to merged_titles.
+    async fn try_migrate_per_setting_doc(db: &Database, legacy_doc: Document, merged_titles: &mut HashMap<(String, String), String>) -> Result<(), Box<dyn Error>> {
+        let coll = (*db).collection(USER_DOC_COLL_NAME);
+        let vocab_coll = (*db).collection(USER_VOCAB_COLL_NAME);
+        let cn_type = String::from(legacy_doc.get_str("cn_type").unwrap_or(""));
+        let mut user_doc: UserDoc = from_bson(Bson::Document(legacy_doc))?;
+        let legacy_title = user_doc.title.clone();
+        let key = (user_doc.username.clone(), str_to_hashed_string(&user_doc.body, &user_doc.source));
+        if let Some(merged_title) = merged_titles.get(&key) {
+            // Copy of an already-migrated doc
+            let vocab_query = doc! { "username": &user_doc.username, "from_doc_title": &legacy_title, "cn_type": &cn_type };
+            vocab_coll.update_many(vocab_query, doc! { "$set": { "from_doc_title": merged_title } }, None)?;
+            coll.delete_one(doc! { "username": &user_doc.username, "doc_id": &user_doc.doc_id }, None)?;
+            UserDocPage::try_delete_all(db, &user_doc.username, &user_doc.doc_id);
+            return Ok(());
+        }
+        let title_query = doc! { "username": &user_doc.username, "title": &legacy_title, "cn_type": { "$exists": false } };
+        if coll.find_one(title_query, None)?.is_some() {
+            user_doc.title = UserDoc::generate_unique_title(db, &user_doc.username, legacy_title.clone());
+            let vocab_query = doc! { "username": &user_doc.username, "from_doc_title": &legacy_title, "cn_type": &cn_type };
+            vocab_coll.update_many(vocab_query, doc! { "$set": { "from_doc_title": &user_doc.title } }, None)?;
+        }
+        user_doc.retokenize(&|_, _| { }).await?;
+        let title = user_doc.try_replace(db).await?;
+        merged_titles.insert(key, title);
+        return Ok(());
+    }
+    /// Creates the unique (username, title) index on UserDocs (does nothing if it already exists). Refer to migrate_per_setting_docs.
+    fn try_create_title_index(db: &Database) -> Result<(), Box<dyn Error>> {
+        let command = doc! {
+            "createIndexes": USER_DOC_COLL_NAME,
+            "indexes": [{ "key": { "username": 1, "title": 1 }, "name": "username_1_title_1", "unique": true }]
+        };
+        (*db).run_command(command, None)?;
+        return Ok(());
+    }
+    /// Migrates UserDocs that stored their phrases on the doc (tokenized_body_json) onto their pages, one doc at a time.
+    /// Docs that fail are logged and skipped. This is safe to re-run, since the field is removed from migrated docs.
+    pub fn migrate_doc_phrases_to_pages(db: &Database) -> Result<(), Box<dyn Error>> {
+        let coll = (*db).collection(USER_DOC_COLL_NAME);
+        let find_options = FindOptions::builder().projection(doc! { "doc_id": 1, "tokenized_body_json": 1 }).build();
+        let cursor = coll.find(doc! { "tokenized_body_json": { "$exists": true } }, find_options)?;
+        let mut n_failed = 0;
+        for doc_res in cursor {
+            let doc_res = doc_res?;
+            if let Err(e) = UserDoc::try_move_phrases_to_pages(db, &doc_res) {
+                eprintln!("Error moving phrases of doc {} to its pages: {:?}", doc_res.get_str("doc_id").unwrap_or(""), e);
+                n_failed += 1;
+            }
+        }
+        if n_failed > 0 {
+            return Err(Box::from(format!("{} docs didn't have their phrases moved (retried on the next start)", n_failed)));
+        }
+        return Ok(());
+    }
+    /// Copies the doc's stored tokenized_body_json onto its pages, then removes it from the doc (refer to migrate_doc_phrases_to_pages).
+    fn try_move_phrases_to_pages(db: &Database, doc_res: &Document) -> Result<(), Box<dyn Error>> {
+        let doc_id = doc_res.get_str("doc_id")?;
+        let phrase_list = doc_res.get_array("tokenized_body_json")?;
+        let page_coll = (*db).collection(USER_DOC_PAGE_COLL_NAME);
+        for page in UserDocPage::lookup_all_for_doc(db, doc_id) {
+            let start = (page.token_start.max(0) as usize).min(phrase_list.len());
+            let end = (page.token_end.max(0) as usize).min(phrase_list.len()).max(start);
+            let update_query = doc! { "$set": { "phrases": phrase_list[start..end].to_vec() } };
+            page_coll.update_one(doc! { "doc_id": doc_id, "page": page.page }, update_query, None)?;
+        }
+        let coll = (*db).collection(USER_DOC_COLL_NAME);
+        coll.update_one(doc! { "doc_id": doc_id }, doc! { "$unset": { "tokenized_body_json": "" } }, None)?;
+        return Ok(());
+    }
+    /// Updates the search index of the UserDoc (refer to DocSearchIndex).
+    fn try_update_search_index(&self, db: &Database) -> Result<(), Box<dyn Error>> {
+        let new_index = DocSearchIndex::new(&self.doc_id, &self.username, &self.title, &self.tokenized_body_json);
+        return new_index.try_upsert(db);
+    }
+    /// Inserts the pages of the UserDoc.
+    fn try_insert_pages(&self, db: &Database) -> Result<(), Box<dyn Error>> {
+        if self.pages.len() > 0 {
+            let page_coll = (*db).collection(USER_DOC_PAGE_COLL_NAME);
+            page_coll.insert_many(self.pages.iter().map(|page| page.as_document()), None)?;
+        }
+        return Ok(());
+    }
+    /// Re-tokenizes the UserDoc into pages from its saved content (body_markup, subtitle cues, or body).
+    /// Subtitle cues are recovered from the cue start/end of the previous phrases. Refer to UserDoc::new for on_page.
+    async fn retokenize(&mut self, on_page: &dyn Fn(usize, usize)) -> Result<(), Box<dyn Error>> {
+        let (tokenized_body_json, pages) = match (self.body_markup.len() > 0, self.recover_cues()) {
+            (true, _) => {
+                let events = parse_markup(&self.body_markup, &BodyFormat::Html);
+                let (body, tokenized_body_json, pages) = UserDoc::tokenize_markup(&self.doc_id, &self.username, events, on_page).await?;
+                self.body = body;
+                (tokenized_body_json, pages)
+            },
+            (false, Some(cues)) => UserDoc::tokenize_cues(&self.doc_id, &self.username, cues, on_page).await?,
+            (false, None) => UserDoc::tokenize_text(&self.doc_id, &self.username, &self.body, on_page).await?
+        };
+        self.page_headings = pages.iter().map(|page| page.heading.clone()).collect();
+        self.tokenized_body_json = tokenized_body_json;
+        self.pages = pages;
+        self.body_html = String::new();
+        return Ok(());
+    }
+    /// Recovers the subtitle cues from the phrases (one cue per line of the body). Returns None if the doc isn't from subtitles.
+    fn recover_cues(&self) -> Option<Vec<SubtitleCue>> {
+        let mut cue_times: Vec<(i64, i64)> = Vec::new();
+        for phrase in self.tokenized_body_json.iter() {
+            if let (Some(start_ms), Some(end_ms)) = (phrase.cue_start_ms, phrase.cue_end_ms) {
+                if cue_times.last() != Some(&(start_ms, end_ms)) {
+                    cue_times.push((start_ms, end_ms));
+                }
+            }
+        }
+        let lines: Vec<&str> = self.body.split('\n').collect();
+        if cue_times.len() == 0 || cue_times.len() != lines.len() {
+            return None;
+        }
+        let res = cue_times.into_iter().zip(lines)
+            .map(|((start_ms, end_ms), text)| SubtitleCue { start_ms, end_ms, text: String::from(text) })
+            .collect();
+        return Some(res);
+    }
+    /// Tokenizes a plain-text body page by page (over one TokenizerSession). Returns the phrases and the pages (each with its range of phrases),
+    /// or an error if the tokenizer can't be reached (e.g. while it restarts).
+    async fn tokenize_text(doc_id: &str, username: &str, body: &str, on_page: &dyn Fn(usize, usize)) -> Result<(Vec<CnPhrase>, Vec<UserDocPage>), Box<dyn Error>> {
+        let mut session = TokenizerSession::connect().await?;
+        let mut tokenized_body_json: Vec<CnPhrase> = Vec::new();
+        let mut pages: Vec<UserDocPage> = Vec::new();
+        let sections = split_text_into_sections(body, DOC_PAGE_MAX_CHARS);
+        let n_pages = sections.len();
+        for section in sections {
+            let token_start = tokenized_body_json.len();
+            tokenized_body_json.extend(session.tokenize(&section.text, &CnGranularity::Word).await?);
+            pages.push(UserDocPage::new(doc_id, username, pages.len() + 1, section.heading, token_start, tokenized_body_json[token_start..].to_vec(), Vec::new()));
+            on_page(pages.len(), n_pages);
+        }
+        return Ok((tokenized_body_json, pages));
+    }
+    /// Tokenizes subtitle cues page by page. Each phrase stores the start/end of its cue. Returns the phrases and the pages.
+    /// All cues are tokenized over one TokenizerSession.
+    async fn tokenize_cues(doc_id: &str, username: &str, cues: Vec<SubtitleCue>, on_page: &dyn Fn(usize, usize)) -> Result<(Vec<CnPhrase>, Vec<UserDocPage>), Box<dyn Error>> {
+        let mut session = TokenizerSession::connect().await?;
+        let mut tokenized_body_json: Vec<CnPhrase> = Vec::new();
+        let mut pages: Vec<UserDocPage> = Vec::new();
+        let sections = split_cues_into_sections(cues, DOC_PAGE_MAX_CHARS);
+        let n_pages = sections.len();
+        for section in sections {
+            let token_start = tokenized_body_json.len();
+            for cue in section.cues.iter() {
+                // Keep the newline between cues, so the phrases match the body
+                if tokenized_body_json.len() > 0 {
+                    tokenized_body_json.push(CnPhrase { raw_phrase: String::from("\n"), ..CnPhrase::default() });
+                }
+                let mut cue_phrases = session.tokenize(&cue.text, &CnGranularity::Word).await?;
+                for phrase in cue_phrases.iter_mut() {
+                    phrase.cue_start_ms = Some(cue.start_ms);
+                    phrase.cue_end_ms = Some(cue.end_ms);
+                }
+                tokenized_body_json.extend(cue_phrases);
+            }
+            pages.push(UserDocPage::new(doc_id, username, pages.len() + 1, section.heading, token_start, tokenized_body_json[token_start..].to_vec(), Vec::new()));
+            on_page(pages.len(), n_pages);
+        }
+        return Ok((tokenized_body_json, pages));
+    }
+    /// Tokenizes sanitized markup page by page. Each phrase stores the index of its text node. All text nodes are tokenized over one TokenizerSession.
+    /// Returns the plain-text body (one line per block), the phrases and the pages (each with its markup).
+    async fn tokenize_markup(doc_id: &str, username: &str, events: Vec<MarkupEvent>, on_page: &dyn Fn(usize, usize)) -> Result<(String, Vec<CnPhrase>, Vec<UserDocPage>), Box<dyn Error>> {
+        let mut session = TokenizerSession::connect().await?;
+        let mut body = String::new();
+        let mut tokenized_body_json: Vec<CnPhrase> = Vec::new();
+        let mut pages: Vec<UserDocPage> = Vec::new();
+        let sections = split_markup_into_sections(events, DOC_PAGE_MAX_CHARS);
+        let n_pages = sections.len();
+        for section in sections {
+            let token_start = tokenized_body_json.len();
+            // Text nodes are numbered per page, since each page is rendered separately (refer to html_rendering::convert_markup_to_tokenized_html)
+            let mut text_node = 0;
+            for event in section.events.iter() {
+                match event {
+                    MarkupEvent::Text(text) => {
+                        body += text;
+                        let mut text_phrases = session.tokenize(text, &CnGranularity::Word).await?;
+                        for phrase in text_phrases.iter_mut() {
+                            phrase.text_node = Some(text_node);
+                        }
+                        tokenized_body_json.extend(text_phrases);
+                        text_node += 1;
+                    },
+                    // Keep the newline between blocks, so the phrases match the body
+                    _ if event.is_line_break() && body.len() > 0 && !body.ends_with('\n') => {
+                        body += "\n";
+                        tokenized_body_json.push(CnPhrase { raw_phrase: String::from("\n"), ..CnPhrase::default() });
+                    },
+                    _ => { }
+                }
+            }
+            pages.push(UserDocPage::new(doc_id, username, pages.len() + 1, section.heading, token_start, tokenized_body_json[token_start..].to_vec(), section.events));
+            on_page(pages.len(), n_pages);
+        }
+        return Ok((body, tokenized_body_json, pages));
+    }
+    /// Looks up the phrases in [token_start, token_end) of the UserDoc's tokenized_body_json (from the pages that overlap the range).
+    pub fn lookup_phrases(db: &Database, doc_id: &str, token_start: i32, token_end: i32) -> Vec<CnPhrase> {
+        let ranges = vec![(String::from(doc_id), token_start, token_end)];
+        let res = match UserDocPage::lookup_overlapping(db, &ranges).get(doc_id) {
+            Some(pages) => UserDocPage::slice_phrases(pages, token_start, token_end),
+            None => Vec::new()
+        };
+        return res;
+    }
+    /// Looks up all phrases of the UserDoc (i.e. its tokenized_body_json).
+    pub fn lookup_all_phrases(db: &Database, doc_id: &str) -> Vec<CnPhrase> {
+        return UserDoc::lookup_phrases(db, doc_id, 0, i32::MAX);
+    }
+    /// Returns the desired title if unused, otherwise appends digits until unique (e.g. "title-1").
+    fn generate_unique_title(db: &Database, username: &str, desired_title: String) -> String {
+        let coll = (*db).collection(USER_DOC_COLL_NAME);
+        let mut title_exists = coll.find_one(doc! {"username": username, "title": &desired_title}, None).unwrap().is_some();
         let title = match title_exists {
             true => {
                 // Try new titles until unique one found
@@ -190,32 +889,31 @@ impl UserDoc {
                     count += 1;
                     let appended = format!("-{}", count);
                     new_title = desired_title.clone() + appended.as_str(); // need .clone() here because of loop
-                    title_exists = (coll.find_one(doc! {"username": &username, "title": &new_title, "cn_type": cn_type.as_str(), "cn_phonetics": cn_phonetics.as_str()}, None).unwrap()) != None;
+                    title_exists = coll.find_one(doc! {"username": username, "title": &new_title}, None).unwrap().is_some();
                 }
                 new_title
             },
             false => desired_title
         };
-        let created_on = Utc::now().to_string();
-        let new_doc = UserDoc { doc_id, username, title, body, body_html, tokenized_body_json, source, cn_type, cn_phonetics, created_on };
-        return new_doc;
-    }
-    /// Generates a new UserDoc with HTML-parsed title + text from the given URL.
-    pub async fn from_url(db: &Database, username: String, url: String) -> Self {
-        let (title_text, body_text) = scrape_text_from_url(&url).await;
-        return UserDoc::new(db, username, title_text, body_text, url).await;
+        return title;
     }
-    /// Attempts to delete a matching object in MongoDB.
-    pub async fn try_delete(db: &Database, username: &str, title: &str) -> bool {
-        let (cn_type, cn_phonetics) = User::get_user_settings(db, username);
+    /// Attempts to delete a matching object in MongoDB, along with its pages, cached HTML, and linked UserVocab (for all settings).
+    pub async fn try_delete(db: &Database, username: &str, doc_id: &str) -> bool {
         let coll = (*db).collection(USER_DOC_COLL_NAME);
-        let query_doc = doc! { "username": username, "title": title, "cn_type": cn_type.as_str(), "cn_phonetics": cn_phonetics.as_str() }; 
+        let query_doc = doc! { "username": username, "doc_id": doc_id }; 
         let res = match coll.delete_one(query_doc, None) {
             Ok(_) => {
-                match UserVocab::try_delete_all_from_title(db, username, title, &cn_type).await {
-                    Ok(b) => b,
-                    Err(_) => false
+                UserDocPage::try_delete_all(db, username, doc_id);
+                DocSearchIndex::try_delete(db, username, doc_id);
+                VocabOccurrences::try_delete_all(db, username, doc_id);
+                ReadingProgress::try_delete_all(db, username, doc_id);
+                DocAnnotation::try_delete_all(db, username, doc_id);
+                html_rendering::clear_cached_doc_html(doc_id).await;
+                let mut res = true;
+                for cn_type in [CnType::Simplified, CnType::Traditional].iter() {
+                    res = res && UserVocab::try_delete_all_from_doc(db, username, doc_id, cn_type).await.unwrap_or(false);
                 }
+                res
             },
             Err(_) => false,
         };
@@ -223,11 +921,105 @@ impl UserDoc {
     }
 }
 
+/// A page (chapter or fixed-size section) of a UserDoc, rendered on its own so the reader only loads one page of popovers.
+/// Pages are numbered from 1. The page's phrases are tokenized_body_json[token_start..token_end] of the UserDoc (stored here instead of on the UserDoc).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserDocPage {
+    pub doc_id: String,
+    username: String,
+    pub page: i32,
+    pub heading: String,
+    pub token_start: i32,
+    pub token_end: i32,
+    #[serde(default)]
+    pub phrases: Vec<CnPhrase>,
+    /// For Markdown/HTML documents, the sanitized markup of the page (empty otherwise)
+    #[serde(default)]
+    pub events: Vec<MarkupEvent>
+}
+
+impl DatabaseItem for UserDocPage {
+    fn collection_name() -> &'static str { return USER_DOC_PAGE_COLL_NAME; }
+    fn all_field_names() -> Vec<&'static str> {
+        return vec!["doc_id", "username", "page", "heading", "token_start", "token_end", "phrases", "events"];
+    }
+    fn primary_key(&self) -> &str { return &self.doc_id; }
+}
+
+impl UserDocPage {
+    pub fn new(doc_id: &str, username: &str, page: usize, heading: String, token_start: usize, phrases: Vec<CnPhrase>, events: Vec<MarkupEvent>) -> Self {
+        let token_end = token_start + phrases.len();
+        return UserDocPage { doc_id: String::from(doc_id), username: String::from(username), page: page as i32, heading,
+            token_start: token_start as i32, token_end: token_end as i32, phrases, events };
+    }
+    /// Looks up all pages of the UserDoc (in order), without their phrases and markup events.
+    pub fn lookup_all_for_doc(db: &Database, doc_id: &str) -> Vec<Self> {
+        let coll = (*db).collection(USER_DOC_PAGE_COLL_NAME);
+        let find_options = FindOptions::builder()
+            .projection(doc! { "phrases": 0, "events": 0 })
+            .sort(doc! { "page": 1 })
+            .build();
+        let res = match coll.find(doc! { "doc_id": doc_id }, find_options) {
+            Ok(cursor) => cursor.filter_map(|item| item.ok())
+                .filter_map(|page_doc| from_bson(Bson::Document(page_doc)).ok())
+                .collect(),
+            Err(_) => Vec::new()
+        };
+        return res;
+    }
+    /// Looks up the pages that overlap any of the (doc_id, token_start, token_end) ranges in one query, grouped by doc_id (in order).
+    pub fn lookup_overlapping(db: &Database, ranges: &[(String, i32, i32)]) -> HashMap<String, Vec<Self>> {
+        let mut res: HashMap<String, Vec<Self>> = HashMap::new();
+        let range_queries: Vec<Document> = ranges.iter()
+            .filter(|(_, token_start, token_end)| token_start < token_end)
+            .map(|(doc_id, token_start, token_end)| doc! { "doc_id": doc_id, "token_start": { "$lt": token_end }, "token_end": { "$gt": token_start } })
+            .collect();
+        if range_queries.len() == 0 {
+            return res;
+        }
+        let coll = (*db).collection(USER_DOC_PAGE_COLL_NAME);
+        let find_options = FindOptions::builder()
+            .projection(doc! { "events": 0 })
+            .sort(doc! { "doc_id": 1, "page": 1 })
+            .build();
+        if let Ok(cursor) = coll.find(doc! { "$or": range_queries }, find_options) {
+            for page_doc in cursor.filter_map(|item| item.ok()) {
+                if let Ok(page) = from_bson::<UserDocPage>(Bson::Document(page_doc)) {
+                    res.entry(page.doc_id.clone()).or_default().push(page);
+                }
+            }
+        }
+        return res;
+    }
+    /// Returns the phrases in [token_start, token_end) of the UserDoc from its pages (in order, e.g. from lookup_overlapping).
+    pub fn slice_phrases(pages: &[Self], token_start: i32, token_end: i32) -> Vec<CnPhrase> {
+        let mut res: Vec<CnPhrase> = Vec::new();
+        for page in pages.iter().filter(|page| page.token_start < token_end && token_start < page.token_end) {
+            let skip = (token_start - page.token_start).max(0) as usize;
+            let take = (token_end.min(page.token_end) - page.token_start).max(0) as usize - skip;
+            res.extend(page.phrases.iter().skip(skip).take(take).cloned());
+        }
+        return res;
+    }
+    /// Deletes all pages of the UserDoc.
+    pub fn try_delete_all(db: &Database, username: &str, doc_id: &str) -> bool {
+        let coll = (*db).collection(USER_DOC_PAGE_COLL_NAME);
+        let res = coll.delete_many(doc! { "username": username, "doc_id": doc_id }, None).is_ok();
+        return res;
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UserVocab {
     pub uid: String,
     username: String,
     pub from_doc_title: String,
+    /// doc_id of the UserDoc the phrase was saved from (empty for sandbox docs and docs shared by other users)
+    #[serde(default)]
+    pub from_doc_id: String,
+    /// share_id of the shared UserDoc the phrase was saved from (empty otherwise)
+    #[serde(default)]
+    pub from_share_id: String,
     cn_type: CnType,
     cn_phonetics: CnPhonetics,
     pub phrase: String,
@@ -236,7 +1028,10 @@ pub struct UserVocab {
     phrase_phonetics: String, /// If pinyin: formatted pinyin
     pub created_on: String,
     pub radical_map: String,
-    pub from_sandbox: bool
+    pub from_sandbox: bool,
+    /// For subtitle documents, the timestamp of the cue the phrase was saved from (e.g. "01:02")
+    #[serde(default)]
+    pub cue_timestamp: String
 }
 
 impl DatabaseItem for UserVocab {
@@ -249,30 +1044,32 @@ impl DatabaseItem for UserVocab {
     }
     fn collection_name() -> &'static str { return USER_VOCAB_COLL_NAME; }
     fn all_field_names() -> Vec<&'static str> {
-        return vec!["uid", "username", "from_doc_title",
+        return vec!["uid", "username", "from_doc_title", "from_doc_id", "from_share_id",
             "cn_type", "cn_phonetics", "phrase", "def",
-            "phrase_phonetics", "phrase_html", "created_on", "radical_map"];
+            "phrase_phonetics", "phrase_html", "created_on", "radical_map", "cue_timestamp"];
     }
     fn primary_key(&self) -> &str { return &self.uid; } // TODO: add doc_title uniqueness distinction
 }
 
 impl UserVocab {
     /// Looks-up UserVocab in Redis cache. If CEDICT match is found, then stores appropriate data.
-    pub async fn new(db: &Database, username: String, saved_uid: String, from_doc_title: String, from_sandbox: bool) -> Self {
+    /// cue_timestamp is empty unless the phrase was saved from a subtitle cue.
+    /// For sandbox docs, from_doc_title is the SandboxDoc doc_id and from_doc_id is empty. For shared docs, from_doc_id is empty and from_share_id is set afterwards.
+    pub async fn new(db: &Database, username: String, saved_uid: String, from_doc_title: String, from_doc_id: String, from_sandbox: bool, cue_timestamp: String) -> Self {
         // For lookup, try user-specified first
         let mut conn = connect_to_redis().await.unwrap();
         let (cn_type, cn_phonetics) = User::get_user_settings(db, &username);
         let uid = saved_uid.clone();
         let entry = CnEnDictEntry::from_uid(&mut conn, saved_uid).await;
         let created_on = Utc::now().to_string();
-        let radical_map = (&entry.radical_map).to_string();
+        let radical_map = entry.radical_map.to_string();
         let phrase_html = html_rendering::render_phrase_html(&entry, &cn_type, &cn_phonetics);
         let (phrase, def, phrase_phonetics) = UserVocab::extract_vocab_data(entry, &cn_type, &cn_phonetics);
         let new_vocab = UserVocab { 
-            uid, username, from_doc_title, def,
+            uid, username, from_doc_title, from_doc_id, from_share_id: String::new(), def,
             phrase, phrase_html, phrase_phonetics,
             cn_type, cn_phonetics, created_on, radical_map,
-            from_sandbox
+            from_sandbox, cue_timestamp
         };
         return new_vocab;
     }
@@ -299,20 +1096,47 @@ impl UserVocab {
         };
         let query_doc = doc! { "username": username, "phrase": phrase, "cn_type": cn_type.as_str() };
         let res = match coll.delete_one(query_doc, None) {
-            Ok(_) => {
-                match UserVocabList::remove_from_user_vocab_list(db, username, phrase, cn_type, uid) {
-                    Ok(_) => true,
-                    Err(_) => false
-                }
-            },
+            Ok(_) => UserVocabList::remove_from_user_vocab_list(db, username, phrase, cn_type, uid).is_ok(),
             Err(_) => false,
         };
         return res;
     }
+    /// Updates the from_doc_title of all UserVocab saved from a UserDoc (for all settings) after the UserDoc is renamed.
+    pub fn try_rename_from_doc(db: &Database, username: &str, from_doc_id: &str, new_title: &str) -> Result<(), Box<dyn Error>> {
+        let coll = (*db).collection(USER_VOCAB_COLL_NAME);
+        let query_doc = doc! { "username": username, "from_doc_id": from_doc_id };
+        coll.update_many(query_doc, doc! { "$set": { "from_doc_title": new_title } }, None)?;
+        return Ok(());
+    }
+    /// Sets from_doc_id on UserVocab saved before vocab was linked by doc_id, using the UserDoc with the matching title.
+    /// This is safe to re-run, since only UserVocab without from_doc_id are updated.
+    pub fn migrate_doc_title_links(db: &Database) {
+        let coll = (*db).collection(USER_VOCAB_COLL_NAME);
+        let cursor = match coll.find(doc! { "from_sandbox": false, "from_doc_id": { "$exists": false } }, None) {
+            Ok(cursor) => cursor,
+            Err(e) => {
+                eprintln!("Error when searching for vocab without doc_id: {:?}", e);
+                return;
+            }
+        };
+        for item in cursor {
+            let vocab_doc = match item {
+                Ok(vocab_doc) => vocab_doc,
+                Err(_) => continue
+            };
+            let username = vocab_doc.get_str("username").unwrap_or("");
+            let from_doc_title = vocab_doc.get_str("from_doc_title").unwrap_or("");
+            if let Some(user_doc) = UserDoc::try_lookup_one(db, doc! { "username": username, "title": from_doc_title }) {
+                let from_doc_id = user_doc.get_str("doc_id").unwrap_or("");
+                let vocab_id = vocab_doc.get("_id").cloned().unwrap_or(Bson::Null);
+                let _ = coll.update_one(doc! { "_id": vocab_id }, doc! { "$set": { "from_doc_id": from_doc_id } }, None);
+            }
+        }
+    }
     /// Attempts to delete all UserVocab linked to a given UserDoc.
-    pub async fn try_delete_all_from_title(db: &Database, username: &str, from_doc_title: &str, cn_type: &CnType) -> Result<bool, Box<dyn Error>> {
+    pub async fn try_delete_all_from_doc(db: &Database, username: &str, from_doc_id: &str, cn_type: &CnType) -> Result<bool, Box<dyn Error>> {
         let coll = (*db).collection(USER_VOCAB_COLL_NAME);
-        let query_doc = doc! { "username": username, "from_doc_title": from_doc_title, "cn_type": cn_type.as_str() };
+        let query_doc = doc! { "username": username, "from_doc_id": from_doc_id, "cn_type": cn_type.as_str() };
         let mut res = true;
         let cursor = coll.find(query_doc, None)?; 
         for item in cursor {
@@ -401,7 +1225,26 @@ impl UserVocabList {
             prev_doc.try_update(db, 
                 vec!["unique_char_list", "unique_uid_list"], 
                 vec![&unique_char_list, &unique_uid_list])?;
-        } else { }
+        }
         return Ok(());
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_page(page: usize, token_start: usize, raw_phrases: &[&str]) -> UserDocPage {
+        let phrases = raw_phrases.iter().map(|s| CnPhrase { raw_phrase: String::from(*s), ..CnPhrase::default() }).collect();
+        return UserDocPage::new("doc", "user", page, String::new(), token_start, phrases, Vec::new());
+    }
+
+    #[test]
+    fn slice_phrases_spans_pages() {
+        let pages = vec![make_page(1, 0, &["a", "b", "c"]), make_page(2, 3, &["d", "e"])];
+        let raw = |phrases: Vec<CnPhrase>| phrases.into_iter().map(|p| p.raw_phrase).collect::<Vec<String>>();
+        assert_eq!(raw(UserDocPage::slice_phrases(&pages, 1, 4)), vec!["b", "c", "d"]);
+        assert_eq!(raw(UserDocPage::slice_phrases(&pages, 0, i32::MAX)), vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(raw(UserDocPage::slice_phrases(&pages, 4, 5)), vec!["e"]);
+        assert!(UserDocPage::slice_phrases(&pages, 5, 9).is_empty());
+    }
+}
@@ -2,7 +2,7 @@
 /// Data Structures relating to Chinese text.
 */
 
-use crate::CacheItem;
+use crate::{CacheItem, is_erhua_char, config::TW_PHRASES_TRAD_KEY};
 use serde::{Serialize, Deserialize};
 use std::{
     collections::HashMap,
@@ -75,6 +75,77 @@ impl fmt::Display for CnPhonetics {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum CnGranularity {
+    Word,
+    Character
+}
+
+impl CnGranularity {
+    pub fn as_str(&self) -> &str {
+        return match *self {
+            CnGranularity::Word => "Word",
+            CnGranularity::Character => "Character"
+        };
+    }
+    pub fn from_str(s: &str) -> Option<Self> {
+        return match s.to_ascii_lowercase().as_str() {
+            "word" => Some(CnGranularity::Word),
+            "character" => Some(CnGranularity::Character),
+            "char" => Some(CnGranularity::Character),
+            _ => None
+        }
+    }
+}
+
+/// Defaults to word-level segmentation (the tokenizer output)
+impl Default for CnGranularity {
+    fn default() -> Self { return CnGranularity::Word; }
+}
+
+/// Implements to_string()
+impl fmt::Display for CnGranularity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.as_str());
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum CnRegion {
+    Mainland,
+    Taiwan
+}
+
+impl CnRegion {
+    pub fn as_str(&self) -> &str {
+        return match *self {
+            CnRegion::Mainland => "Mainland",
+            CnRegion::Taiwan => "Taiwan"
+        };
+    }
+    pub fn from_str(s: &str) -> Option<Self> {
+        return match s.to_ascii_lowercase().as_str() {
+            "mainland" => Some(CnRegion::Mainland),
+            "cn" => Some(CnRegion::Mainland),
+            "taiwan" => Some(CnRegion::Taiwan),
+            "tw" => Some(CnRegion::Taiwan),
+            _ => None
+        }
+    }
+}
+
+/// Defaults to Mainland usage (matches CEDICT)
+impl Default for CnRegion {
+    fn default() -> Self { return CnRegion::Mainland; }
+}
+
+/// Implements to_string()
+impl fmt::Display for CnRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.as_str());
+    }
+}
+
 /* Structs */
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct CnEnDictEntry {
@@ -85,7 +156,10 @@ pub struct CnEnDictEntry {
     pub formatted_pinyin: String,
     pub defn: String,
     pub zhuyin: String,
-    pub radical_map: String
+    pub radical_map: String,
+    /// Regional equivalent of the phrase (e.g. 軟體 for 软件 in Taiwan). Only populated by add_regional_phrase().
+    #[serde(default)]
+    pub regional_phrase: String
 }
 
 /// For CnEnDictEntry, the current uid is generated using: vec![simp, raw_pinyin]
@@ -96,26 +170,118 @@ impl CacheItem for CnEnDictEntry {
 impl CnEnDictEntry {
     /// Looks up a CEDICT entry in Redis using the specified uid.
     /// Defaults to entry with error text.
+    /// Erhua (儿化) uids missing from CEDICT fall back to the base word's entry (refer to from_phrase).
     pub async fn from_uid(conn: &mut Connection, uid: String) -> Self {
         let query_map = (*conn).hgetall::<&str, HashMap<String, String>>(&uid).await.unwrap();
+        if let (0, Some(base_uid)) = (query_map.len(), CnEnDictEntry::split_erhua_uid(&uid)) {
+            let base_map = (*conn).hgetall::<&str, HashMap<String, String>>(&base_uid).await.unwrap_or_default();
+            if base_map.len() > 0 {
+                return CnEnDictEntry::from_query_map(base_uid, &base_map).into_erhua();
+            }
+        }
         let res = match query_map.len() {
             0 => CnEnDictEntry::generate_errored_entry(&uid),
-            _ => CnEnDictEntry {
-                    uid,
-                    trad : query_map.get("trad").unwrap().to_owned(),
-                    simp: query_map.get("simp").unwrap().to_owned(),
-                    raw_pinyin: query_map.get("raw_pinyin").unwrap().to_owned(),
-                    formatted_pinyin: query_map.get("formatted_pinyin").unwrap().to_owned(),
-                    defn: query_map.get("defn").unwrap().to_owned(),
-                    zhuyin: query_map.get("zhuyin").unwrap().to_owned(),
-                    radical_map: query_map.get("radical_map").unwrap().to_owned(),
-                }
+            _ => CnEnDictEntry::from_query_map(uid, &query_map)
         };
         return res;
     }
+    /// Looks up a CEDICT entry using the tokenizer phrase and raw pinyin.
+    /// Erhua (儿化) phrases missing from CEDICT fall back to the base word's entry (e.g. 哪儿 => 哪).
+    pub async fn from_phrase(conn: &mut Connection, raw_phrase: &str, raw_phonetics: &str) -> Self {
+        let uid = CnEnDictEntry::generate_uid(vec![raw_phrase, raw_phonetics]);
+        let entry = CnEnDictEntry::from_uid(conn, uid).await;
+        if entry.lookup_succeeded() {
+            return entry;
+        }
+        if let Some((base_phrase, base_phonetics)) = CnEnDictEntry::split_erhua(raw_phrase, raw_phonetics) {
+            let base_uid = CnEnDictEntry::generate_uid(vec![&base_phrase, &base_phonetics]);
+            let base_entry = CnEnDictEntry::from_uid(conn, base_uid).await;
+            if base_entry.lookup_succeeded() {
+                return base_entry.into_erhua();
+            }
+        }
+        return entry;
+    }
+    /// Returns true if the entry is an erhua form generated from its base word (refer to into_erhua).
+    /// Erhua readings have one syllable fewer than chars, since 儿 is merged into the previous syllable.
+    pub fn is_generated_erhua(&self) -> bool {
+        let n_chars = self.simp.chars().count();
+        return self.simp.ends_with(is_erhua_char) && n_chars >= 2 && self.formatted_pinyin.split(' ').count() == n_chars - 1;
+    }
+    /// Adds the regional equivalent of the phrase from the phrase table, if one exists (e.g. 出租车 => 計程車 for Taiwan).
+    pub async fn add_regional_phrase(&mut self, conn: &mut Connection, cn_region: &CnRegion) {
+        if let CnRegion::Taiwan = cn_region {
+            let query_res = (*conn).hget::<&str, &str, Option<String>>(TW_PHRASES_TRAD_KEY, &self.simp).await;
+            if let Ok(Some(phrase)) = query_res {
+                self.regional_phrase = phrase;
+            }
+        }
+    }
     /// Returns true if object is a "failed lookup" entry, false otherwise.
     pub fn lookup_succeeded(&self) -> bool {
-        return self.formatted_pinyin != "";
+        return self.formatted_pinyin.len() > 0;
+    }
+    /// Splits an erhua phrase into its base phrase and pinyin. Returns None if the phrase isn't erhua.
+    /// Ex. ("一点儿", "yi1 dian3 r5") => ("一点", "yi1 dian3")
+    fn split_erhua(raw_phrase: &str, raw_phonetics: &str) -> Option<(String, String)> {
+        const ERHUA_PINYIN: [&str; 4] = ["r5", "r", "er2", "er5"];
+        let char_list: Vec<char> = raw_phrase.chars().collect();
+        let phonetic_list: Vec<&str> = raw_phonetics.split(' ').collect();
+        let n = char_list.len();
+        if n < 2 || n != phonetic_list.len() || !is_erhua_char(char_list[n-1]) || is_erhua_char(char_list[n-2]) {
+            return None;
+        }
+        if !ERHUA_PINYIN.contains(&phonetic_list[n-1].to_ascii_lowercase().as_str()) {
+            return None;
+        }
+        let base_phrase: String = char_list[0..n-1].iter().collect();
+        let base_phonetics = phonetic_list[0..n-1].join(" ");
+        return Some((base_phrase, base_phonetics));
+    }
+    /// Splits an erhua uid into the uid of its base word. Returns None if the uid isn't erhua.
+    /// Ex. "一点儿yi1dian3r5" => "一点yi1dian3"
+    fn split_erhua_uid(uid: &str) -> Option<String> {
+        let pinyin_start = uid.find(|c: char| c.is_ascii())?;
+        let (phrase, pinyin) = uid.split_at(pinyin_start);
+        let base_phrase = phrase.strip_suffix(is_erhua_char)?;
+        let base_pinyin = pinyin.strip_suffix("r5")?;
+        if base_phrase.len() == 0 || base_phrase.ends_with(is_erhua_char) || base_pinyin.len() == 0 {
+            return None;
+        }
+        return Some(format!("{}{}", base_phrase, base_pinyin));
+    }
+    /// Converts the base word entry into its erhua form, with its own uid so the erhua form is saved as vocab.
+    /// The 儿 is merged into the last syllable for both Pinyin (yī diǎnr) and Zhuyin (ㄧ ㄉㄧㄢˇㄦ).
+    fn into_erhua(self) -> Self {
+        let simp = self.simp + "儿";
+        let raw_pinyin = self.raw_pinyin + " r5";
+        let uid = CnEnDictEntry::generate_uid(vec![&simp, &raw_pinyin]);
+        let res = CnEnDictEntry {
+            uid,
+            trad: self.trad + "兒",
+            simp,
+            raw_pinyin,
+            formatted_pinyin: self.formatted_pinyin + "r",
+            zhuyin: self.zhuyin + "ㄦ",
+            ..self
+        };
+        return res;
+    }
+    /// Generates the entry from the Redis hash fields. Used in from_uid().
+    fn from_query_map(uid: String, query_map: &HashMap<String, String>) -> Self {
+        let get_field = |field: &str| query_map.get(field).cloned().unwrap_or_default();
+        let res = CnEnDictEntry {
+            trad: get_field("trad"),
+            simp: get_field("simp"),
+            raw_pinyin: get_field("raw_pinyin"),
+            formatted_pinyin: get_field("formatted_pinyin"),
+            defn: get_field("defn"),
+            zhuyin: get_field("zhuyin"),
+            radical_map: get_field("radical_map"),
+            uid,
+            regional_phrase: String::new()
+        };
+        return res;
     }
     /// Generates generic "failed lookup" entry.
     /// The uid is preserved so the failed case can be identified.
@@ -141,4 +307,47 @@ pub struct CnPhrase {
     pub lookup_success: bool,
     pub raw_phrase: String,
     pub raw_phonetics: String,
-}
\ No newline at end of file
+    /// For CnGranularity::Character, the uid of the word the character was split from
+    #[serde(default)]
+    pub parent_uid: Option<String>,
+    /// For subtitle documents, the start/end of the cue the phrase is from (in ms)
+    #[serde(default)]
+    pub cue_start_ms: Option<i64>,
+    #[serde(default)]
+    pub cue_end_ms: Option<i64>,
+    /// For Markdown/HTML documents, the index of the text node (in the sanitized markup of its page) the phrase is from
+    #[serde(default)]
+    pub text_node: Option<i32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn into_erhua_merges_the_reading() {
+        let base_entry = CnEnDictEntry {
+            uid: String::from("一点yi1dian3"),
+            trad: String::from("一點"),
+            simp: String::from("一点"),
+            raw_pinyin: String::from("yi1 dian3"),
+            formatted_pinyin: String::from("yī diǎn"),
+            zhuyin: String::from("ㄧ ㄉㄧㄢˇ"),
+            ..CnEnDictEntry::default()
+        };
+        let entry = base_entry.into_erhua();
+        assert_eq!(entry.uid, "一点儿yi1dian3r5");
+        assert_eq!(entry.simp, "一点儿");
+        assert_eq!(entry.formatted_pinyin, "yī diǎnr");
+        assert_eq!(entry.zhuyin, "ㄧ ㄉㄧㄢˇㄦ");
+        assert!(entry.is_generated_erhua());
+        assert_eq!(CnEnDictEntry::split_erhua_uid(&entry.uid), Some(String::from("一点yi1dian3")));
+    }
+
+    #[test]
+    fn split_erhua_uid_ignores_other_uids() {
+        assert_eq!(CnEnDictEntry::split_erhua_uid("女儿nv3er2"), None);
+        assert_eq!(CnEnDictEntry::split_erhua_uid("儿r5"), None);
+        assert_eq!(CnEnDictEntry::split_erhua_uid("一点yi1dian3"), None);
+    }
+}
@@ -0,0 +1,332 @@
+/*
+/// Module for reading numbers, dates, times, percentages and currency as spoken Chinese.
+*/
+
+use crate::models::zh::{CnType, CnPhonetics};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref DATE_RE: Regex = Regex::new(r"^(\d{4})[-/.](\d{1,2})[-/.](\d{1,2})$").unwrap();
+    static ref TIME_RE: Regex = Regex::new(r"^(\d{1,2}):(\d{2})(?::(\d{2}))?$").unwrap();
+    static ref PERCENT_RE: Regex = Regex::new(r"^([-+]?[\d,]+(?:\.\d+)?)%$").unwrap();
+    static ref CURRENCY_RE: Regex = Regex::new(r"^([¥$€£])([\d,]+(?:\.\d+)?)$").unwrap();
+    static ref DATE_PART_RE: Regex = Regex::new(r"^(\d{1,4})([年月日号])$").unwrap();
+    static ref NUMBER_RE: Regex = Regex::new(r"^[-+]?(\d{1,3}(,\d{3})+|\d+)(\.\d+)?$").unwrap();
+}
+
+/* Structs */
+/// The spoken reading of a numeric expression, stored as Simplified words.
+/// Ex. "35%" => ["百", "分", "之", "三十五"]
+#[derive(Clone, Debug, Default)]
+pub struct NumeralReading {
+    words: Vec<String>,
+    /// True if the reading includes the following token (e.g. "35" followed by "%")
+    consumes_next_phrase: bool
+}
+
+impl NumeralReading {
+    /// Returns true if the following token is part of the reading, so it shouldn't be rendered separately.
+    pub fn consumes_next_phrase(&self) -> bool {
+        return self.consumes_next_phrase;
+    }
+    /// Returns the reading as Chinese characters.
+    pub fn as_hanzi(&self, cn_type: &CnType) -> String {
+        let simp: String = self.words.concat();
+        let res = match cn_type {
+            CnType::Simplified => simp,
+            CnType::Traditional => simp.chars().map(|c| lookup_numeral_char(c).1).collect()
+        };
+        return res;
+    }
+    /// Returns the reading as phonetics. Pinyin syllables are joined per word, Zhuyin is space-delimited per char.
+    /// Ex. "2021年" => "èr líng èr yī nián", "35%" => "bǎi fēn zhī sānshíwǔ"
+    pub fn as_phonetics(&self, cn_phonetics: &CnPhonetics) -> String {
+        let res = match cn_phonetics {
+            CnPhonetics::Pinyin => {
+                self.words.iter()
+                    .map(|w| w.chars().map(|c| lookup_numeral_char(c).2).collect::<String>())
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            },
+            CnPhonetics::Zhuyin => {
+                self.words.iter()
+                    .flat_map(|w| w.chars().map(|c| lookup_numeral_char(c).3).collect::<Vec<&str>>())
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+            }
+        };
+        return res;
+    }
+    /// Adds each char as a separate word (e.g. for years and units).
+    fn push_chars(&mut self, s: &str) {
+        for c in s.chars() {
+            self.words.push(c.to_string());
+        }
+    }
+    /// Adds the string as a single word (e.g. for cardinal numbers).
+    fn push_word(&mut self, s: String) {
+        self.words.push(s);
+    }
+}
+
+/* Public Functions */
+/// Attempts to read a numeric token. next_phrase is the following token, used for context (e.g. "2021" followed by "年").
+/// Returns None if the token isn't a recognized numeric expression.
+pub fn read_numeral(phrase: &str, next_phrase: &str) -> Option<NumeralReading> {
+    let phrase = normalize_fullwidth(phrase.trim());
+    let first_char = phrase.chars().next()?;
+    if !(first_char.is_ascii_digit() || "-+¥$€£".contains(first_char)) {
+        return None;
+    }
+    let mut res = NumeralReading::default();
+    if let Some(caps) = DATE_RE.captures(&phrase) {
+        res.push_chars(&read_digits(&caps[1]));
+        res.push_chars("年");
+        res.push_word(read_integer(&caps[2]));
+        res.push_chars("月");
+        res.push_word(read_integer(&caps[3]));
+        res.push_chars("日");
+    } else if let Some(caps) = TIME_RE.captures(&phrase) {
+        let hour = match read_integer(&caps[1]).as_str() {
+            "二" => String::from("两"),
+            s => String::from(s)
+        };
+        res.push_word(hour);
+        res.push_chars("点");
+        if &caps[2] != "00" {
+            res.push_word(read_minutes(&caps[2]));
+            res.push_chars("分");
+        }
+        if let Some(seconds) = caps.get(3) {
+            res.push_word(read_minutes(seconds.as_str()));
+            res.push_chars("秒");
+        }
+    } else if let Some(caps) = PERCENT_RE.captures(&phrase) {
+        res.push_chars("百分之");
+        res.push_word(read_decimal(&caps[1]));
+    } else if let Some(caps) = CURRENCY_RE.captures(&phrase) {
+        res.push_word(read_decimal(&caps[2]));
+        let unit = match &caps[1] {
+            "$" => "美元",
+            "€" => "欧元",
+            "£" => "英镑",
+            _ => "元"
+        };
+        res.push_chars(unit);
+    } else if let Some(caps) = DATE_PART_RE.captures(&phrase) {
+        match &caps[2] {
+            "年" => res.push_chars(&read_digits(&caps[1])),
+            _ => res.push_word(read_integer(&caps[1]))
+        };
+        res.push_chars(&caps[2]);
+    } else if NUMBER_RE.is_match(&phrase) {
+        let next_phrase = normalize_fullwidth(next_phrase.trim());
+        let is_year = next_phrase.starts_with('年') && !phrase.contains('.') && !phrase.contains(',');
+        if is_year {
+            res.push_chars(&read_digits(&phrase));
+        } else if next_phrase == "%" {
+            res.push_chars("百分之");
+            res.push_word(read_decimal(&phrase));
+            res.consumes_next_phrase = true;
+        } else {
+            res.push_word(read_decimal(&phrase));
+        }
+    } else {
+        return None;
+    }
+    return Some(res);
+}
+
+/* Private Functions */
+/// Looks up (Simplified, Traditional, Pinyin, Zhuyin) for the chars used in numeral readings.
+/// Unknown chars are returned as-is with no phonetics.
+fn lookup_numeral_char(c: char) -> (char, char, &'static str, &'static str) {
+    const NUMERAL_CHARS: [(char, char, &str, &str); 30] = [
+        ('零', '零', "líng", "ㄌㄧㄥˊ"), ('一', '一', "yī", "ㄧ"), ('二', '二', "èr", "ㄦˋ"),
+        ('两', '兩', "liǎng", "ㄌㄧㄤˇ"), ('三', '三', "sān", "ㄙㄢ"), ('四', '四', "sì", "ㄙˋ"),
+        ('五', '五', "wǔ", "ㄨˇ"), ('六', '六', "liù", "ㄌㄧㄡˋ"), ('七', '七', "qī", "ㄑㄧ"),
+        ('八', '八', "bā", "ㄅㄚ"), ('九', '九', "jiǔ", "ㄐㄧㄡˇ"), ('十', '十', "shí", "ㄕˊ"),
+        ('百', '百', "bǎi", "ㄅㄞˇ"), ('千', '千', "qiān", "ㄑㄧㄢ"), ('万', '萬', "wàn", "ㄨㄢˋ"),
+        ('亿', '億', "yì", "ㄧˋ"), ('点', '點', "diǎn", "ㄉㄧㄢˇ"), ('负', '負', "fù", "ㄈㄨˋ"),
+        ('年', '年', "nián", "ㄋㄧㄢˊ"), ('月', '月', "yuè", "ㄩㄝˋ"), ('日', '日', "rì", "ㄖˋ"),
+        ('号', '號', "hào", "ㄏㄠˋ"), ('分', '分', "fēn", "ㄈㄣ"), ('之', '之', "zhī", "ㄓ"),
+        ('秒', '秒', "miǎo", "ㄇㄧㄠˇ"), ('元', '元', "yuán", "ㄩㄢˊ"), ('美', '美', "měi", "ㄇㄟˇ"),
+        ('欧', '歐', "ōu", "ㄡ"), ('英', '英', "yīng", "ㄧㄥ"), ('镑', '鎊', "bàng", "ㄅㄤˋ"),
+    ];
+    let res = match NUMERAL_CHARS.iter().find(|entry| entry.0 == c) {
+        Some(entry) => *entry,
+        None => (c, c, "", "")
+    };
+    return res;
+}
+
+/// Converts full-width digits and symbols to their ASCII equivalents.
+fn normalize_fullwidth(s: &str) -> String {
+    let res = s.chars()
+        .map(|c| match c {
+            '０'..='９' | '．' | '，' | '：' | '％' | '－' | '＋' | '／' | '＄' => {
+                std::char::from_u32(c as u32 - 0xFEE0).unwrap_or(c)
+            },
+            '￥' => '¥',
+            _ => c
+        })
+        .collect();
+    return res;
+}
+
+/// Reads each digit separately. Ex. "2021" => "二零二一"
+fn read_digits(digits: &str) -> String {
+    const DIGITS: [&str; 10] = ["零", "一", "二", "三", "四", "五", "六", "七", "八", "九"];
+    let res = digits.chars()
+        .filter_map(|c| c.to_digit(10))
+        .map(|d| DIGITS[d as usize])
+        .collect();
+    return res;
+}
+
+/// Reads minutes/seconds, keeping the leading zero. Ex. "05" => "零五", "30" => "三十"
+fn read_minutes(digits: &str) -> String {
+    let res = match digits.starts_with('0') && digits.trim_start_matches('0').len() > 0 {
+        true => format!("零{}", read_integer(digits)),
+        false => read_integer(digits)
+    };
+    return res;
+}
+
+/// Reads a signed decimal number with optional thousands separators. Ex. "-3.14" => "负三点一四"
+fn read_decimal(number: &str) -> String {
+    let mut res = String::with_capacity(number.len() * 6);
+    let number = number.replace(',', "");
+    let unsigned = number.trim_start_matches(['-', '+']);
+    if number.starts_with('-') {
+        res += "负";
+    }
+    let mut parts = unsigned.splitn(2, '.');
+    res += &read_integer(parts.next().unwrap_or(""));
+    if let Some(fraction) = parts.next() {
+        res += "点";
+        res += &read_digits(fraction);
+    }
+    return res;
+}
+
+/// Reads an integer using 4-digit sections (个, 万, 亿, 万亿). Very long numbers are read digit-by-digit.
+/// Ex. "100005" => "十万零五", "1050" => "一千零五十"
+fn read_integer(digits: &str) -> String {
+    const SECTION_UNITS: [&str; 4] = ["", "万", "亿", "万亿"];
+    let digits = digits.trim_start_matches('0');
+    if digits.len() == 0 {
+        return String::from("零");
+    } else if digits.len() > 16 {
+        return read_digits(digits);
+    }
+    let values: Vec<usize> = digits.chars()
+        .filter_map(|c| c.to_digit(10))
+        .map(|d| d as usize)
+        .collect();
+    let n_sections = (values.len() + 3) / 4;
+    let mut res = String::with_capacity(values.len() * 6);
+    let mut pending_zero = false;
+    for i in 0..n_sections {
+        let section_idx = n_sections - 1 - i;
+        let end = values.len() - section_idx * 4;
+        let start = end.saturating_sub(4);
+        let section = &values[start..end];
+        if section.iter().all(|d| *d == 0) {
+            pending_zero = res.len() > 0;
+            continue;
+        }
+        if res.len() > 0 && (pending_zero || section[0] == 0) {
+            res += "零";
+        }
+        res += &read_section(section);
+        res += SECTION_UNITS[section_idx];
+        pending_zero = false;
+    }
+    // 10-19 drop the leading 一
+    if res.starts_with("一十") {
+        res = res.replacen("一十", "十", 1);
+    }
+    return res;
+}
+
+/// Reads up to 4 digits with 千, 百, 十 units. Used in read_integer().
+fn read_section(section: &[usize]) -> String {
+    const DIGITS: [&str; 10] = ["零", "一", "二", "三", "四", "五", "六", "七", "八", "九"];
+    const UNITS: [&str; 4] = ["千", "百", "十", ""];
+    let offset = 4 - section.len();
+    let mut res = String::with_capacity(24);
+    let mut pending_zero = false;
+    for (i, d) in section.iter().enumerate() {
+        if *d == 0 {
+            pending_zero = res.len() > 0;
+            continue;
+        }
+        if pending_zero {
+            res += "零";
+            pending_zero = false;
+        }
+        res += DIGITS[*d];
+        res += UNITS[i + offset];
+    }
+    return res;
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(phrase: &str, next_phrase: &str) -> (String, String) {
+        let reading = read_numeral(phrase, next_phrase).unwrap();
+        return (reading.as_hanzi(&CnType::Simplified), reading.as_phonetics(&CnPhonetics::Pinyin));
+    }
+
+    #[test]
+    fn reads_years_digit_by_digit() {
+        assert_eq!(read("2021年", ""), (String::from("二零二一年"), String::from("èr líng èr yī nián")));
+        assert_eq!(read("2021", "年"), (String::from("二零二一"), String::from("èr líng èr yī")));
+    }
+
+    #[test]
+    fn reads_percentages() {
+        assert_eq!(read("35%", ""), (String::from("百分之三十五"), String::from("bǎi fēn zhī sānshíwǔ")));
+        let reading = read_numeral("35", "%").unwrap();
+        assert_eq!(reading.as_hanzi(&CnType::Simplified), "百分之三十五");
+        assert!(reading.consumes_next_phrase());
+        assert!(!read_numeral("35", "个").unwrap().consumes_next_phrase());
+    }
+
+    #[test]
+    fn reads_cardinal_numbers() {
+        assert_eq!(read("15", "").0, "十五");
+        assert_eq!(read("1050", "").0, "一千零五十");
+        assert_eq!(read("100005", "").0, "十万零五");
+        assert_eq!(read("1,000,000", "").0, "一百万");
+        assert_eq!(read("-3.14", "").0, "负三点一四");
+        assert_eq!(read("１２", "").0, "十二");
+    }
+
+    #[test]
+    fn reads_dates_times_and_currency() {
+        assert_eq!(read("2021-10-01", "").0, "二零二一年十月一日");
+        assert_eq!(read("2:05", "").0, "两点零五分");
+        assert_eq!(read("14:00", "").0, "十四点");
+        assert_eq!(read("$20", "").0, "二十美元");
+        assert_eq!(read("¥9.5", "").0, "九点五元");
+        assert_eq!(read("3月", "").0, "三月");
+    }
+
+    #[test]
+    fn reads_traditional_and_zhuyin() {
+        let reading = read_numeral("20000", "").unwrap();
+        assert_eq!(reading.as_hanzi(&CnType::Traditional), "二萬");
+        assert_eq!(reading.as_phonetics(&CnPhonetics::Zhuyin), "ㄦˋ ㄨㄢˋ");
+    }
+
+    #[test]
+    fn ignores_non_numeric_tokens() {
+        assert!(read_numeral("iPhone", "").is_none());
+        assert!(read_numeral("一", "").is_none());
+        assert!(read_numeral("12abc", "").is_none());
+    }
+}
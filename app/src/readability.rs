@@ -0,0 +1,265 @@
+/*
+/// Module for extracting the readable main content from HTML pages (title, body, byline, publish date).
+*/
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use scraper::{ElementRef, Html, Selector};
+use std::collections::HashMap;
+
+lazy_static! {
+    static ref BOILERPLATE_RE: Regex = Regex::new(r"(?i)comment|footer|nav|menu|sidebar|banner|cookie|consent|share|social|related|recommend|advert|\bads?\b|subscribe|breadcrumb|copyright|popup|promo").unwrap();
+    static ref MAYBE_CONTENT_RE: Regex = Regex::new(r"(?i)article|content|main|story").unwrap();
+    static ref POSITIVE_CLASS_RE: Regex = Regex::new(r"(?i)article|body|content|entry|main|page|post|text|story").unwrap();
+    static ref NEGATIVE_CLASS_RE: Regex = Regex::new(r"(?i)comment|footer|nav|menu|sidebar|banner|share|social|related|advert|promo|widget").unwrap();
+    static ref LINK_SELECTOR: Selector = Selector::parse("a").unwrap();
+    static ref BLOCK_SELECTOR: Selector = Selector::parse("h1, h2, h3, h4, h5, h6, p, li, blockquote, pre").unwrap();
+}
+
+/* Structs */
+/// The readable content of an article. Paragraphs in body are newline-delimited.
+#[derive(Clone, Debug, Default)]
+pub struct ExtractedArticle {
+    pub title: String,
+    pub body: String,
+    pub byline: String,
+    pub published_on: String
+}
+
+/* Public Functions */
+/// Extracts the main content of an HTML page, readability-style:
+/// paragraphs are scored by text density, scores are propagated to their parent (full) and grandparent (half),
+/// and the highest-scoring container is used as the article body. Boilerplate (nav, footers, comments, etc.) is skipped.
+pub fn extract_article(html_str: &str) -> ExtractedArticle {
+    let html = Html::parse_document(html_str);
+    let title = extract_title(&html);
+    let byline = extract_byline(&html);
+    let published_on = extract_published_on(&html);
+    let body = match find_top_candidate(&html) {
+        Some(candidate) => collect_block_text(&candidate),
+        None => String::new()
+    };
+    // Fallback: use all block text in the body
+    let body = match body.len() > 0 {
+        true => body,
+        false => {
+            let body_selector = Selector::parse("body").unwrap();
+            match html.select(&body_selector).next() {
+                Some(body_elem) => collect_block_text(&body_elem),
+                None => String::new()
+            }
+        }
+    };
+    return ExtractedArticle { title, body, byline, published_on };
+}
+
+/// Extracts all block text in the <body> without scoring, for pages that are entirely content (e.g. EPUB chapters).
+/// The title is the first heading, or <title> if there are no headings.
+pub fn extract_full_text(html_str: &str) -> ExtractedArticle {
+    let html = Html::parse_document(html_str);
+    let title = select_text(&html, "h1, h2, h3")
+        .or_else(|| select_text(&html, "title"))
+        .unwrap_or_default();
+    let body_selector = Selector::parse("body").unwrap();
+    let body = match html.select(&body_selector).next() {
+        Some(body_elem) => collect_block_text(&body_elem),
+        None => String::new()
+    };
+    return ExtractedArticle { title, body, ..ExtractedArticle::default() };
+}
+
+/* Private Functions */
+/// Returns the title from og:title, <title>, or the first <h1> (in that order).
+fn extract_title(html: &Html) -> String {
+    let res = select_meta_content(html, "meta[property=\"og:title\"]")
+        .or_else(|| select_text(html, "title"))
+        .or_else(|| select_text(html, "h1"))
+        .unwrap_or_default();
+    return res;
+}
+
+/// Returns the author from meta tags, rel=author links, or byline/author elements.
+fn extract_byline(html: &Html) -> String {
+    let res = select_meta_content(html, "meta[name=\"author\"]")
+        .or_else(|| select_meta_content(html, "meta[property=\"article:author\"]"))
+        .or_else(|| select_text(html, "[rel=\"author\"]"))
+        .or_else(|| select_text(html, "[itemprop=\"author\"]"))
+        .or_else(|| select_text(html, ".byline, .author"))
+        .unwrap_or_default();
+    return res;
+}
+
+/// Returns the publish date from meta tags or <time> elements, as written on the page.
+fn extract_published_on(html: &Html) -> String {
+    let time_selector = Selector::parse("time[datetime]").unwrap();
+    let res = select_meta_content(html, "meta[property=\"article:published_time\"]")
+        .or_else(|| select_meta_content(html, "meta[name=\"pubdate\"]"))
+        .or_else(|| select_meta_content(html, "meta[name=\"publishdate\"]"))
+        .or_else(|| select_meta_content(html, "meta[name=\"date\"]"))
+        .or_else(|| select_meta_content(html, "meta[itemprop=\"datePublished\"]"))
+        .or_else(|| {
+            html.select(&time_selector).next()
+                .and_then(|e| e.value().attr("datetime"))
+                .map(|s| s.trim().to_string())
+                .filter(|s| s.len() > 0)
+        })
+        .or_else(|| select_text(html, "[itemprop=\"datePublished\"]"))
+        .unwrap_or_default();
+    return res;
+}
+
+/// Returns the trimmed content attribute of the first matching <meta> tag, if non-empty.
+fn select_meta_content(html: &Html, selector_str: &str) -> Option<String> {
+    let selector = Selector::parse(selector_str).ok()?;
+    let res = html.select(&selector)
+        .filter_map(|e| e.value().attr("content"))
+        .map(collapse_whitespace)
+        .find(|s| s.len() > 0);
+    return res;
+}
+
+/// Returns the whitespace-collapsed text of the first matching element, if non-empty.
+fn select_text(html: &Html, selector_str: &str) -> Option<String> {
+    let selector = Selector::parse(selector_str).ok()?;
+    let res = html.select(&selector)
+        .map(|e| collapse_whitespace(&e.text().collect::<String>()))
+        .find(|s| s.len() > 0);
+    return res;
+}
+
+/// Scores paragraph containers and returns the best one. Returns None if no paragraph has enough text.
+fn find_top_candidate(html: &Html) -> Option<ElementRef> {
+    const MIN_PARAGRAPH_LEN: usize = 10;
+    let paragraph_selector = Selector::parse("p, pre, td, blockquote").unwrap();
+    let mut scores = HashMap::new();
+    for paragraph in html.select(&paragraph_selector) {
+        if is_boilerplate(&paragraph) {
+            continue;
+        }
+        let text = collapse_whitespace(&paragraph.text().collect::<String>());
+        let n_chars = text.chars().count();
+        if n_chars < MIN_PARAGRAPH_LEN {
+            continue;
+        }
+        // Commas (either width) suggest prose, and longer paragraphs are worth more (up to a limit)
+        let n_commas = text.chars().filter(|c| "，,、。".contains(*c)).count();
+        let score = 1.0 + n_commas as f64 + std::cmp::min(n_chars / 100, 3) as f64;
+        let parent = paragraph.parent().and_then(ElementRef::wrap);
+        if let Some(parent) = parent {
+            *scores.entry(parent.id()).or_insert(0.0) += score;
+            if let Some(grandparent) = parent.parent().and_then(ElementRef::wrap) {
+                *scores.entry(grandparent.id()).or_insert(0.0) += score / 2.0;
+            }
+        }
+    }
+    let mut top_candidate: Option<(ElementRef, f64)> = None;
+    for (node_id, score) in scores {
+        let candidate = match html.tree.get(node_id).and_then(ElementRef::wrap) {
+            Some(e) => e,
+            None => continue
+        };
+        let adjusted_score = (score + class_weight(&candidate)) * (1.0 - link_density(&candidate));
+        let is_better = match &top_candidate {
+            Some((_, top_score)) => adjusted_score > *top_score,
+            None => true
+        };
+        if is_better {
+            top_candidate = Some((candidate, adjusted_score));
+        }
+    }
+    return top_candidate.map(|(candidate, _)| candidate);
+}
+
+/// Returns true if the element (or any ancestor) looks like navigation, comments, ads, etc.
+/// <body> and <html> are skipped since page-level classes (e.g. "has-sidebar") say nothing about the content.
+fn is_boilerplate(elem: &ElementRef) -> bool {
+    const BOILERPLATE_TAGS: [&str; 6] = ["nav", "footer", "aside", "form", "script", "style"];
+    let res = std::iter::once(**elem).chain(elem.ancestors())
+        .filter_map(ElementRef::wrap)
+        .filter(|e| e.value().name() != "body" && e.value().name() != "html")
+        .any(|e| {
+            let value = e.value();
+            let class_and_id = format!("{} {}", value.attr("class").unwrap_or(""), value.id().unwrap_or(""));
+            BOILERPLATE_TAGS.contains(&value.name())
+                || (BOILERPLATE_RE.is_match(&class_and_id) && !MAYBE_CONTENT_RE.is_match(&class_and_id))
+        });
+    return res;
+}
+
+/// Returns a score adjustment based on the element's class and id (e.g. "article" is positive, "comment" is negative).
+fn class_weight(elem: &ElementRef) -> f64 {
+    const WEIGHT: f64 = 25.0;
+    let value = elem.value();
+    let mut res = 0.0;
+    for s in [value.attr("class"), value.id()].iter().flatten() {
+        if POSITIVE_CLASS_RE.is_match(s) {
+            res += WEIGHT;
+        }
+        if NEGATIVE_CLASS_RE.is_match(s) {
+            res -= WEIGHT;
+        }
+    }
+    return res;
+}
+
+/// Returns the ratio of link text to all text in the element (0.0 to 1.0).
+fn link_density(elem: &ElementRef) -> f64 {
+    let n_chars = elem.text().map(|s| s.chars().count()).sum::<usize>();
+    if n_chars == 0 {
+        return 0.0;
+    }
+    let n_link_chars = elem.select(&LINK_SELECTOR)
+        .flat_map(|a| a.text())
+        .map(|s| s.chars().count())
+        .sum::<usize>();
+    return n_link_chars as f64 / n_chars as f64;
+}
+
+/// Collects the text of block elements (headers, paragraphs, list items, etc.) in-order, one block per line.
+/// Only the innermost blocks are used so nested text isn't repeated.
+fn collect_block_text(container: &ElementRef) -> String {
+    let mut blocks: Vec<String> = Vec::new();
+    for block in container.select(&BLOCK_SELECTOR) {
+        let has_nested_block = block.select(&BLOCK_SELECTOR).next().is_some();
+        if has_nested_block || is_boilerplate(&block) {
+            continue;
+        }
+        let text = collapse_whitespace(&block.text().collect::<String>());
+        if text.len() > 0 {
+            blocks.push(text);
+        }
+    }
+    return blocks.join("\n");
+}
+
+/// Collapses runs of whitespace (including newlines) into a single space, and trims the ends.
+fn collapse_whitespace(s: &str) -> String {
+    return s.split_whitespace().collect::<Vec<&str>>().join(" ");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_article_keeps_content_and_drops_boilerplate() {
+        let article = extract_article(include_str!("../tests/fixtures/article.html"));
+        assert_eq!(article.title, "北京今年第一场雪");
+        assert_eq!(article.byline, "李明");
+        assert_eq!(article.published_on, "2021-11-07T08:00:00+08:00");
+        let paragraphs: Vec<&str> = article.body.split('\n').collect();
+        assert_eq!(paragraphs, vec![
+            "北京今年第一场雪",
+            "十一月七日，北京迎来了今年的第一场雪，比往年早了将近两个星期。",
+            "气象台表示，这次降雪主要集中在北部山区，市区的积雪不会太厚，但是气温会明显下降。",
+            "专家提醒市民，出门要注意保暖，开车的时候也要小心路滑。"
+        ]);
+    }
+
+    #[test]
+    fn extract_full_text_keeps_all_blocks() {
+        let article = extract_full_text("<html><body><h2>第一章</h2><p>他来了。</p><ul><li>一</li></ul></body></html>");
+        assert_eq!(article.title, "第一章");
+        assert_eq!(article.body, "第一章\n他来了。\n一");
+    }
+}
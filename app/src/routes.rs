@@ -7,23 +7,30 @@ use crate::{
     convert_rawstr_to_string, 
     DatabaseItem,
     auth::{get_username_from_cookie, add_user_cookie_to_context},
-    config::JWT_NAME,
+    config::{JWT_NAME, SANDBOX_TTL_SECS, SANDBOX_QUOTA_PER_IP, SANDBOX_QUOTA_WINDOW_SECS},
     html_rendering,
     models::{
+        annotation::DocAnnotation,
+        collection::DocCollection,
+        job::IngestJob,
+        progress::ReadingProgress,
         public::SandboxDoc,
-        user::{User, UserDoc, UserVocabList},
+        search::VocabOccurrences,
+        user::{DocVisibility, User, UserDoc, UserDocPage, UserVocabList},
     }
 };
 use mongodb::{
-    bson::doc,
+    bson::{doc, document::Document},
     sync::Database
 };
 use rocket::{
     http::{RawStr, Cookies},
+    response::Redirect,
     State,
 };
 use rocket_contrib::templates::Template;
 use std::collections::HashMap;
+use tokio::runtime::Handle;
 
 /* GET */
 /// /
@@ -51,28 +58,42 @@ pub fn about(cookies: Cookies, db: State<Database>) -> Template {
 }
 
 
-/// /sandbox
-#[get("/sandbox")]
-pub fn sandbox(cookies: Cookies, db: State<Database>) -> Template {
+/// /sandbox?<error>
+/// error is set when an upload fails (refer to /api/upload-sandbox-doc).
+#[get("/sandbox?<error>")]
+pub fn sandbox(cookies: Cookies, db: State<Database>, error: Option<&RawStr>) -> Template {
     let mut context: HashMap<&str, String> = HashMap::new();
     add_user_cookie_to_context(&cookies, &db, &mut context);
+    let error_message = match error.map(|s| s.as_str()) {
+        Some("quota") => Some(format!("Upload limit reached ({} sandbox documents every {} minutes), please try again later.",
+            SANDBOX_QUOTA_PER_IP, SANDBOX_QUOTA_WINDOW_SECS / 60)),
+        Some("fetch") => Some(String::from("Couldn't load that URL, please try a different article.")),
+        Some("upload") => Some(String::from("Something went wrong saving the document, please try again.")),
+        _ => None
+    };
+    if let Some(error_message) = error_message {
+        context.insert("sandbox_error", error_message);
+    }
     return Template::render("sandbox", context);
 }
 
 /// /sandbox/<doc_id>
+/// Expired (or unknown) docs show a message instead (refer to SANDBOX_TTL_SECS).
 #[get("/sandbox/<doc_id>")]
 pub fn sandbox_doc(cookies: Cookies, db: State<Database>, doc_id: &RawStr) -> Template {
     let mut context: HashMap<&str, String> = HashMap::new();
     add_user_cookie_to_context(&cookies, &db, &mut context);
     let doc_id = convert_rawstr_to_string(doc_id);
-    let query_doc = SandboxDoc::try_lookup_one(&db, 
-        doc!{ "doc_id": doc_id }
-    ).unwrap();
-    let body_html = query_doc.get_str("body_html").unwrap();
-    let cn_phonetics = query_doc.get_str("cn_phonetics").unwrap();
-    context.insert("cn_phonetics", String::from(cn_phonetics));
-    if body_html != "" {
-        context.insert("paragraph_html", String::from(body_html));
+    match SandboxDoc::lookup_unexpired(&db, &doc_id) {
+        Some(query_doc) => {
+            let body_html = query_doc.get_str("body_html").unwrap_or("");
+            let cn_phonetics = query_doc.get_str("cn_phonetics").unwrap_or("");
+            context.insert("cn_phonetics", String::from(cn_phonetics));
+            if body_html.len() > 0 {
+                context.insert("paragraph_html", String::from(body_html));
+            }
+        },
+        None => { context.insert("sandbox_expired_days", (SANDBOX_TTL_SECS / (24 * 60 * 60)).to_string()); }
     }
     return Template::render("reader", context);
 }
@@ -93,15 +114,25 @@ pub fn user_profile(cookies: Cookies, db: State<Database>, raw_username: &RawStr
     // Compare username with logged-in username from JWT
     match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
         Some(s) => { 
-            if &s == &username {
+            if s == username {
                 let (cn_type, cn_phonetics) = User::get_user_settings(&db, &username);
-                let doc_html = html_rendering::render_document_table(&db, &username);
-                let vocab_html = html_rendering::render_vocab_table(&db, &username);
+                let cn_granularity = User::get_user_granularity(&db, &username);
+                let cn_region = User::get_user_region(&db, &username);
+                let doc_html = html_rendering::render_document_table(&db, &username, None);
+                let vocab_html = html_rendering::render_vocab_table(&db, &username, None);
+                let feed_html = html_rendering::render_feed_table(&db, &username);
+                let collection_html = html_rendering::render_collection_table(&db, &username);
+                let collection_options = html_rendering::render_collection_options(&db, &username, "");
             
                 context.insert("doc_table", doc_html);
                 context.insert("vocab_table", vocab_html);
+                context.insert("feed_table", feed_html);
+                context.insert("collection_table", collection_html);
+                context.insert("collection_options", collection_options);
                 context.insert("cn_type", cn_type.to_string());
                 context.insert("cn_phonetics", cn_phonetics.to_string());
+                context.insert("cn_granularity", cn_granularity.to_string());
+                context.insert("cn_region", cn_region.to_string());
 
                 let mut user_uid_list_string = String::new();
                 match UserVocabList::try_lookup_one(&db, 
@@ -111,57 +142,264 @@ pub fn user_profile(cookies: Cookies, db: State<Database>, raw_username: &RawStr
                         },
                         None => { }
                 };
-                context.insert("user_uid_list_string", String::from(user_uid_list_string));   
+                context.insert("user_uid_list_string", user_uid_list_string);   
             }
             context.insert("logged_in_username", s);
         },
         None => { }
     }
-    if User::check_if_username_exists(&db, &username) == true {
+    if User::check_if_username_exists(&db, &username) {
+        // Other users (and logged-out visitors) see the user's Public docs
+        if !context.contains_key("doc_table") {
+            context.insert("public_doc_table", html_rendering::render_public_document_table(&db, &username));
+        }
         context.insert("username", username); 
     }
     return Template::render("profile", context);
 }
 
 /// /u/<raw_username>/<doc_title>
+/// Redirects to the doc_id-based reader URL, so links using the title keep working.
 #[get("/u/<raw_username>/<doc_title>")]
-pub fn user_doc(cookies: Cookies, db: State<Database>, raw_username: &RawStr, doc_title: &RawStr) -> Template {
-    let mut context: HashMap<&str, String> = HashMap::new(); // `String` needed b/c lifetimes
-    let username = convert_rawstr_to_string(raw_username);
-    // Compare username with logged-in username from JWT
+pub fn user_doc(db: State<Database>, raw_username: &RawStr, doc_title: &RawStr) -> Option<Redirect> {
+    let doc_id = lookup_doc_id_from_title(&db, raw_username, doc_title)?;
+    return Some(Redirect::permanent(uri!(doc_reader: doc_id)));
+}
+
+/// /u/<raw_username>/<doc_title>/<page>
+/// Redirects to the doc_id-based reader URL, so links using the title keep working.
+#[get("/u/<raw_username>/<doc_title>/<page>")]
+pub fn user_doc_page(db: State<Database>, raw_username: &RawStr, doc_title: &RawStr, page: i32) -> Option<Redirect> {
+    let doc_id = lookup_doc_id_from_title(&db, raw_username, doc_title)?;
+    return Some(Redirect::permanent(uri!(doc_reader_page: doc_id, page)));
+}
+
+/// /doc/<doc_id>
+/// Opens the page the user last read (refer to ReadingProgress).
+#[get("/doc/<doc_id>")]
+#[allow(clippy::result_large_err)]
+pub fn doc_reader(cookies: Cookies, db: State<Database>, rt: State<Handle>, doc_id: &RawStr) -> Result<Template, Redirect> {
+    if let Some(username) = get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        let doc_id = convert_rawstr_to_string(doc_id);
+        if let Some(progress) = ReadingProgress::from_doc_id(&db, &username, &doc_id) {
+            if progress.page > 1 {
+                return Err(Redirect::to(uri!(doc_reader_page: doc_id, progress.page)));
+            }
+        }
+    }
+    return Ok(render_user_doc_page(&cookies, &db, &rt, doc_id, 1));
+}
+
+/// /doc/<doc_id>/<page>
+#[get("/doc/<doc_id>/<page>")]
+pub fn doc_reader_page(cookies: Cookies, db: State<Database>, rt: State<Handle>, doc_id: &RawStr, page: i32) -> Template {
+    return render_user_doc_page(&cookies, &db, &rt, doc_id, page);
+}
+
+/// /share/<share_id>
+#[get("/share/<share_id>")]
+pub fn shared_doc(cookies: Cookies, db: State<Database>, rt: State<Handle>, share_id: &RawStr) -> Template {
+    return render_shared_doc_page(&cookies, &db, &rt, share_id, 1);
+}
+
+/// /share/<share_id>/<page>
+#[get("/share/<share_id>/<page>")]
+pub fn shared_doc_page(cookies: Cookies, db: State<Database>, rt: State<Handle>, share_id: &RawStr, page: i32) -> Template {
+    return render_shared_doc_page(&cookies, &db, &rt, share_id, page);
+}
+
+/// /collections/<collection_id>
+/// Shows the docs in the user's collection (including its sub-collections), and all vocab saved from them.
+#[get("/collections/<collection_id>")]
+pub fn doc_collection(cookies: Cookies, db: State<Database>, collection_id: &RawStr) -> Template {
+    let mut context: HashMap<&str, String> = HashMap::new();
+    add_user_cookie_to_context(&cookies, &db, &mut context);
+    let collection_id = convert_rawstr_to_string(collection_id);
     match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
-        Some(s) => { 
-            if &s == &username {
-                // Get html to render
-                let (cn_type, cn_phonetics) = User::get_user_settings(&db, &username);
-                let title = convert_rawstr_to_string(doc_title);
-                let doc_html_res = UserDoc::try_lookup_one(&db, 
-                    doc!{ "username": &username, "title": &title})
-                    .unwrap();
-                let doc_html = doc_html_res.get_str("body_html").unwrap();
-                let mut user_char_list_string = String::new();
+        Some(username) => {
+            if let Some(collection) = DocCollection::from_collection_id(&db, &username, &collection_id) {
+                let all_collections = DocCollection::lookup_all(&db, &username);
+                let collection_path = DocCollection::format_paths(&all_collections).remove(&collection_id).unwrap_or(collection.name);
+                let (cn_type, _) = User::get_user_settings(&db, &username);
+                let doc_html = html_rendering::render_document_table(&db, &username, Some(&collection_id));
+                let vocab_html = html_rendering::render_vocab_table(&db, &username, Some(&collection_id));
                 let mut user_uid_list_string = String::new();
                 match UserVocabList::try_lookup_one(&db, 
                     doc! { "username": &username, "cn_type": cn_type.as_str() }) {
                         Some(res) => {
-                            user_char_list_string += res.get_str("unique_char_list").unwrap();
                             user_uid_list_string += res.get_str("unique_uid_list").unwrap();
                         },
                         None => { }
                 };
-                context.insert("paragraph_html", String::from(doc_html));
-                context.insert("user_char_list_string", String::from(user_char_list_string));
-                context.insert("user_uid_list_string", String::from(user_uid_list_string));
-                context.insert("cn_phonetics", cn_phonetics.to_string());
+                context.insert("collection_id", collection_id);
+                context.insert("collection_path", collection_path);
+                context.insert("doc_table", doc_html);
+                context.insert("vocab_table", vocab_html);
+                context.insert("user_uid_list_string", user_uid_list_string);
+            }
+            context.insert("username", username);
+        },
+        None => { }
+    }
+    return Template::render("collection", context);
+}
+
+/// /vocab/<uid>
+/// Shows every occurrence of the dictionary entry in the user's docs as keyword-in-context lines, linked to the reader.
+#[get("/vocab/<uid>")]
+pub fn vocab_concordance(cookies: Cookies, db: State<Database>, uid: &RawStr) -> Template {
+    let mut context: HashMap<&str, String> = HashMap::new();
+    add_user_cookie_to_context(&cookies, &db, &mut context);
+    let uid = convert_rawstr_to_string(uid);
+    match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => {
+            let (lines, n_total) = VocabOccurrences::lookup_concordance(&db, &username, &uid);
+            let phrase = match lines.first() {
+                Some(line) => line.phrase.clone(),
+                None => uid.clone()
+            };
+            context.insert("concordance_table", html_rendering::render_concordance_table(&lines));
+            context.insert("n_total", n_total.to_string());
+            context.insert("n_shown", lines.len().to_string());
+            context.insert("phrase", phrase);
+            context.insert("username", username);
+        },
+        None => { }
+    }
+    return Template::render("concordance", context);
+}
+
+/// /jobs/<job_id>
+/// Shows the reader in a "processing" state until the IngestJob is done, then redirects to the document (refer to template.js).
+#[get("/jobs/<job_id>")]
+pub fn job_status(cookies: Cookies, db: State<Database>, job_id: &RawStr) -> Template {
+    let mut context: HashMap<&str, String> = HashMap::new();
+    add_user_cookie_to_context(&cookies, &db, &mut context);
+    let job_id = convert_rawstr_to_string(job_id);
+    match get_username_from_cookie(&db, cookies.get(JWT_NAME)) {
+        Some(username) => {
+            match IngestJob::from_job_id(&db, &username, &job_id) {
+                Some(job) => {
+                    context.insert("processing_job_id", job.job_id);
+                    context.insert("processing_stage", job.stage);
+                },
+                None => { }
             }
         },
         None =>  { context.insert("paragraph_html", String::from("<p>Not authenticated as user</p>")); }
     }
-    if User::check_if_username_exists(&db, &username) == true {
-        context.insert("username", username); 
+    return Template::render("reader", context);
+}
+
+/* Private Functions */
+/// Renders a single page of the UserDoc in the reader (with the user's current settings), with next/previous navigation.
+fn render_user_doc_page(cookies: &Cookies, db: &Database, rt: &Handle, doc_id: &RawStr, page: i32) -> Template {
+    let mut context: HashMap<&str, String> = HashMap::new(); // `String` needed b/c lifetimes
+    add_user_cookie_to_context(cookies, db, &mut context);
+    let doc_id = convert_rawstr_to_string(doc_id);
+    // Compare the doc owner with logged-in username from JWT
+    match get_username_from_cookie(db, cookies.get(JWT_NAME)) {
+        Some(username) => { 
+            let doc_res = UserDoc::try_lookup_one(db, doc!{ "username": &username, "doc_id": &doc_id });
+            if let Some(doc_res) = doc_res {
+                let doc_url = format!("/doc/{}", &doc_id);
+                add_doc_page_to_context(&mut context, db, rt, &doc_res, &doc_url, page, &username);
+                // Highlights are added on top of the rendered page (refer to html_rendering::render_annotation_marks)
+                let annotations = DocAnnotation::lookup_all(db, &username, &doc_id);
+                if let Some(paragraph_html) = context.get_mut("paragraph_html") {
+                    *paragraph_html = html_rendering::render_annotation_marks(paragraph_html, &annotations);
+                }
+                let pages = UserDocPage::lookup_all_for_doc(db, &doc_id);
+                context.insert("annotation_list_html", html_rendering::render_annotation_list(&annotations, &pages, &doc_url));
+                let doc_tags: Vec<&str> = match doc_res.get_array("tags") {
+                    Ok(tags) => tags.iter().filter_map(|tag| tag.as_str()).collect(),
+                    Err(_) => Vec::new()
+                };
+                context.insert("doc_tags", doc_tags.join(", "));
+                let collection_id = doc_res.get_str("collection_id").unwrap_or("");
+                context.insert("collection_options", html_rendering::render_collection_options(db, &username, collection_id));
+                let visibility = doc_res.get_str("visibility").ok()
+                    .and_then(DocVisibility::from_str)
+                    .unwrap_or_default();
+                if let Ok(share_id) = doc_res.get_str("share_id") {
+                    if share_id.len() > 0 && visibility != DocVisibility::Private {
+                        context.insert("share_url", uri!(shared_doc: share_id).to_string());
+                    }
+                }
+                context.insert("doc_visibility", visibility.to_string());
+                // Resumes at the last-read phrase, if it's on this page
+                if let Some(progress) = ReadingProgress::from_doc_id(db, &username, &doc_id) {
+                    if progress.page == page {
+                        context.insert("resume_token_index", progress.token_index.to_string());
+                    }
+                }
+                context.insert("doc_page", page.to_string());
+                context.insert("doc_id", doc_id);
+            }
+        },
+        None =>  { context.insert("paragraph_html", String::from("<p>Not authenticated as user</p>")); }
+    }
+    return Template::render("reader", context);
+}
+
+/// Renders a single page of a shared (Unlisted or Public) UserDoc in the reader, using the viewer's settings
+/// (or the default settings if not logged in). Private docs are rendered as not found.
+fn render_shared_doc_page(cookies: &Cookies, db: &Database, rt: &Handle, share_id: &RawStr, page: i32) -> Template {
+    let mut context: HashMap<&str, String> = HashMap::new();
+    add_user_cookie_to_context(cookies, db, &mut context);
+    let share_id = convert_rawstr_to_string(share_id);
+    if let Some(doc_res) = UserDoc::from_share_id(db, &share_id) {
+        let viewer = get_username_from_cookie(db, cookies.get(JWT_NAME)).unwrap_or_default();
+        add_doc_page_to_context(&mut context, db, rt, &doc_res, &format!("/share/{}", &share_id), page, &viewer);
+        context.insert("shared_by", String::from(doc_res.get_str("username").unwrap_or("")));
+        context.insert("share_id", share_id);
     }
     return Template::render("reader", context);
 }
+
+/// Adds the rendered page (rendered with the settings of settings_username), page navigation, and the saved vocab of settings_username to the reader context.
+/// Docs that haven't been migrated yet have no pages, so their full body_html is shown.
+fn add_doc_page_to_context(context: &mut HashMap<&str, String>, db: &Database, rt: &Handle, doc_res: &Document, doc_url: &str, page: i32, settings_username: &str) {
+    let doc_id = doc_res.get_str("doc_id").unwrap_or("");
+    let (cn_type, cn_phonetics) = User::get_user_settings(db, settings_username);
+    let doc_html = match doc_res.get_array("page_headings") {
+        Ok(page_headings) if page_headings.len() > 0 => {
+            let page_headings: Vec<&str> = page_headings.iter().map(|h| h.as_str().unwrap_or("")).collect();
+            let page_html = match rt.block_on(UserDoc::render_page_html(db, doc_id, page, settings_username)) {
+                Some(page_html) => page_html,
+                None => String::from("<p>Page not found</p>")
+            };
+            let page_nav_html = html_rendering::render_page_nav(doc_url, page, &page_headings);
+            context.insert("page_nav_html", page_nav_html);
+            page_html
+        },
+        _ => String::from(doc_res.get_str("body_html").unwrap_or(""))
+    };
+    let mut user_char_list_string = String::new();
+    let mut user_uid_list_string = String::new();
+    match UserVocabList::try_lookup_one(db, 
+        doc! { "username": settings_username, "cn_type": cn_type.as_str() }) {
+            Some(res) => {
+                user_char_list_string += res.get_str("unique_char_list").unwrap();
+                user_uid_list_string += res.get_str("unique_uid_list").unwrap();
+            },
+            None => { }
+    };
+    context.insert("paragraph_html", doc_html);
+    context.insert("doc_title", String::from(doc_res.get_str("title").unwrap_or("")));
+    context.insert("user_char_list_string", user_char_list_string);
+    context.insert("user_uid_list_string", user_uid_list_string);
+    context.insert("cn_phonetics", cn_phonetics.to_string());
+}
+
+/// Looks up the doc_id of the user's UserDoc from its title (used by the title-based URLs).
+fn lookup_doc_id_from_title(db: &Database, raw_username: &RawStr, doc_title: &RawStr) -> Option<String> {
+    let username = convert_rawstr_to_string(raw_username);
+    let title = convert_rawstr_to_string(doc_title);
+    let doc_res = UserDoc::try_lookup_one(db, doc!{ "username": &username, "title": &title })?;
+    return doc_res.get_str("doc_id").ok().map(String::from);
+}
+
 /* Custom Error Handlers */
 /// Loads custom 404 error page
 #[catch(404)]
@@ -0,0 +1,222 @@
+/*
+/// Module for Unicode script classification (e.g. Han vs. Latin text).
+*/
+
+use std::fmt;
+
+/* Enums */
+/// The script of a single char, as relevant for tokenization and rendering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Script {
+    Han,
+    Latin,
+    Digit,
+    CjkPunctuation,
+    Emoji,
+    Whitespace,
+    Other
+}
+
+impl Script {
+    pub fn as_str(&self) -> &str {
+        return match *self {
+            Script::Han => "Han",
+            Script::Latin => "Latin",
+            Script::Digit => "Digit",
+            Script::CjkPunctuation => "CjkPunctuation",
+            Script::Emoji => "Emoji",
+            Script::Whitespace => "Whitespace",
+            Script::Other => "Other"
+        };
+    }
+}
+
+/// Implements to_string()
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.as_str());
+    }
+}
+
+/* Public Functions */
+/// Classifies a char by script. Han is checked first since 〇 and 々 sit in the CJK punctuation block.
+/// Unicode ranges from: https://www.unicode.org/charts/
+pub fn classify_char(c: char) -> Script {
+    let res = if is_han_char(c) {
+        Script::Han
+    } else if is_digit_char(c) {
+        Script::Digit
+    } else if is_latin_char(c) {
+        Script::Latin
+    } else if is_cjk_punctuation_char(c) {
+        Script::CjkPunctuation
+    } else if is_emoji_char(c) {
+        Script::Emoji
+    } else if c.is_whitespace() {
+        Script::Whitespace
+    } else {
+        Script::Other
+    };
+    return res;
+}
+
+/// Returns true if the char is a Chinese character (CJK Unified Ideographs, all Extensions, and Compatibility Ideographs).
+pub fn is_han_char(c: char) -> bool {
+    return match c {
+        '\u{3005}' | '\u{3007}' => true, // 々, 〇
+        '\u{3400}'..='\u{4DBF}' => true, // Extension A
+        '\u{4E00}'..='\u{9FFF}' => true, // Unified Ideographs
+        '\u{F900}'..='\u{FAFF}' => true, // Compatibility Ideographs
+        '\u{20000}'..='\u{2A6DF}' => true, // Extension B
+        '\u{2A700}'..='\u{2EBEF}' => true, // Extensions C, D, E, F
+        '\u{2F800}'..='\u{2FA1F}' => true, // Compatibility Ideographs Supplement
+        '\u{30000}'..='\u{323AF}' => true, // Extensions G, H
+        _ => false
+    };
+}
+
+/// Returns true for ASCII and full-width digits.
+pub fn is_digit_char(c: char) -> bool {
+    return match c {
+        '0'..='9' => true,
+        '\u{FF10}'..='\u{FF19}' => true, // ０-９
+        _ => false
+    };
+}
+
+/// Returns true for ASCII, accented, and full-width Latin letters.
+pub fn is_latin_char(c: char) -> bool {
+    return match c {
+        'A'..='Z' | 'a'..='z' => true,
+        '\u{00D7}' | '\u{00F7}' => false, // × and ÷ sit inside Latin-1 Supplement
+        '\u{00C0}'..='\u{024F}' => true, // Latin-1 Supplement, Latin Extended-A/B
+        '\u{1E00}'..='\u{1EFF}' => true, // Latin Extended Additional
+        '\u{FF21}'..='\u{FF3A}' | '\u{FF41}'..='\u{FF5A}' => true, // Ａ-Ｚ, ａ-ｚ
+        _ => false
+    };
+}
+
+/// Returns true for Chinese punctuation (CJK Symbols, full-width forms, vertical forms, and common quotes).
+/// Chinese punctuation is a Chinese char that shouldn't be processed as a phrase.
+pub fn is_cjk_punctuation_char(c: char) -> bool {
+    if is_han_char(c) || is_digit_char(c) || is_latin_char(c) {
+        return false;
+    }
+    return match c {
+        '\u{00B7}' => true, // ·
+        '\u{2014}'..='\u{2026}' => true, // —, ‘’, “”, …
+        '\u{3000}'..='\u{303F}' => true, // CJK Symbols and Punctuation
+        '\u{FE10}'..='\u{FE1F}' => true, // Vertical Forms
+        '\u{FE30}'..='\u{FE4F}' => true, // CJK Compatibility Forms
+        '\u{FF00}'..='\u{FFEF}' => true, // Halfwidth and Fullwidth Forms
+        _ => false
+    };
+}
+
+/// Returns true for emoji, including the joiners/selectors used in emoji sequences.
+pub fn is_emoji_char(c: char) -> bool {
+    return match c {
+        '\u{200D}' | '\u{FE0F}' => true, // Zero-width joiner, variation selector-16
+        '\u{2600}'..='\u{27BF}' => true, // Misc Symbols, Dingbats
+        '\u{1F000}'..='\u{1F2FF}' => true, // Mahjong, Domino, Playing Cards, Enclosed Supplements
+        '\u{1F300}'..='\u{1FAFF}' => true, // Pictographs, Emoticons, Transport, Supplemental Symbols
+        _ => false
+    };
+}
+
+/// Returns true if any char in the string is a Chinese character.
+pub fn contains_han(s: &str) -> bool {
+    return s.chars().any(is_han_char);
+}
+
+/// Returns true if the string is non-empty and only has Chinese characters.
+pub fn is_entirely_han(s: &str) -> bool {
+    return s.len() > 0 && s.chars().all(is_han_char);
+}
+
+/// Returns true if any char in the string is Chinese punctuation.
+pub fn has_cjk_punctuation(s: &str) -> bool {
+    return s.chars().any(is_cjk_punctuation_char);
+}
+
+/// Splits the string into consecutive runs of the same Script.
+/// Ex. "2021年iPhone发布会🎉" => [(Digit, "2021"), (Han, "年"), (Latin, "iPhone"), (Han, "发布会"), (Emoji, "🎉")]
+pub fn split_by_script(s: &str) -> Vec<(Script, &str)> {
+    return split_into_runs(s, classify_char);
+}
+
+/// Splits a phrase into words, keeping letters and digits together. Used for tokens without Chinese chars.
+/// Ex. "iPhone12🎉" => ["iPhone12", "🎉"]
+pub fn split_into_words(s: &str) -> Vec<&str> {
+    let classify_word_char = |c: char| match classify_char(c) {
+        Script::Digit => Script::Latin,
+        script => script
+    };
+    return split_into_runs(s, classify_word_char).into_iter().map(|(_, run)| run).collect();
+}
+
+/* Private Functions */
+/// Splits the string into consecutive runs of chars with the same classification.
+fn split_into_runs<F: Fn(char) -> Script>(s: &str, classify: F) -> Vec<(Script, &str)> {
+    let mut res = Vec::new();
+    let mut run_start = 0;
+    let mut run_script: Option<Script> = None;
+    for (i, c) in s.char_indices() {
+        let curr_script = classify(c);
+        match run_script {
+            Some(prev_script) if prev_script != curr_script => {
+                res.push((prev_script, &s[run_start..i]));
+                run_start = i;
+                run_script = Some(curr_script);
+            },
+            Some(_) => { },
+            None => { run_script = Some(curr_script); }
+        }
+    }
+    if let Some(prev_script) = run_script {
+        res.push((prev_script, &s[run_start..]));
+    }
+    return res;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_char_handles_each_script() {
+        assert_eq!(classify_char('发'), Script::Han);
+        assert_eq!(classify_char('\u{20000}'), Script::Han); // Extension B (4 bytes in UTF-8)
+        assert_eq!(classify_char('〇'), Script::Han);
+        assert_eq!(classify_char('7'), Script::Digit);
+        assert_eq!(classify_char('７'), Script::Digit);
+        assert_eq!(classify_char('é'), Script::Latin);
+        assert_eq!(classify_char('Ｐ'), Script::Latin);
+        assert_eq!(classify_char('。'), Script::CjkPunctuation);
+        assert_eq!(classify_char('“'), Script::CjkPunctuation);
+        assert_eq!(classify_char('🎉'), Script::Emoji);
+        assert_eq!(classify_char(' '), Script::Whitespace);
+        assert_eq!(classify_char('×'), Script::Other);
+    }
+
+    #[test]
+    fn split_by_script_handles_mixed_input() {
+        let expected = vec![
+            (Script::Digit, "2021"),
+            (Script::Han, "年"),
+            (Script::Latin, "iPhone"),
+            (Script::Han, "发布会"),
+            (Script::Emoji, "🎉")
+        ];
+        assert_eq!(split_by_script("2021年iPhone发布会🎉"), expected);
+        assert_eq!(split_by_script("Café\u{20000}！"), vec![(Script::Latin, "Café"), (Script::Han, "\u{20000}"), (Script::CjkPunctuation, "！")]);
+        assert_eq!(split_by_script(""), vec![]);
+    }
+
+    #[test]
+    fn split_into_words_keeps_letters_and_digits_together() {
+        assert_eq!(split_into_words("iPhone12🎉"), vec!["iPhone12", "🎉"]);
+        assert_eq!(split_into_words("3D"), vec!["3D"]);
+        assert_eq!(split_into_words("OK👍👍"), vec!["OK", "👍👍"]);
+    }
+}
@@ -0,0 +1,323 @@
+/*
+/// Module for Chinese-aware full-text search (index terms, query parsing, and highlighted snippets).
+*/
+
+use crate::{
+    markup::escape_text,
+    models::zh::CnPhrase,
+    script::is_han_char
+};
+use std::collections::HashSet;
+
+/* Enums */
+/// A parsed search query.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SearchQuery {
+    /// Hanzi (Simplified or Traditional), matched through tokens and character n-grams
+    Hanzi(String),
+    /// Lowercase words, matched through the pinyin of tokens or the English definitions
+    Latin(Vec<String>)
+}
+
+/* Structs */
+/// Search terms of a document (refer to models::search::DocSearchIndex).
+#[derive(Clone, Debug, Default)]
+pub struct SearchTerms {
+    /// Tokens (Simplified + Traditional), plus character unigrams and bigrams of the body and title
+    pub hanzi_terms: Vec<String>,
+    /// Toneless pinyin of each token (e.g. "gongyinglian")
+    pub pinyin_terms: Vec<String>,
+    /// Words in the definitions of each token, and in the title
+    pub english_terms: Vec<String>
+}
+
+/* Public Functions */
+/// Parses the query as Hanzi if it contains any Han chars (whitespace is ignored), otherwise as words. Returns None for empty queries.
+pub fn parse_search_query(q: &str) -> Option<SearchQuery> {
+    let res = match q.chars().any(is_han_char) {
+        true => SearchQuery::Hanzi(q.chars().filter(|c| !c.is_whitespace()).collect()),
+        false => {
+            let words: Vec<String> = q.split_whitespace()
+                .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase())
+                .filter(|word| word.len() > 0)
+                .collect();
+            if words.len() == 0 {
+                return None;
+            }
+            SearchQuery::Latin(words)
+        }
+    };
+    return Some(res);
+}
+
+/// Generates the search terms of a document from its title and tokenized phrases.
+pub fn extract_search_terms(title: &str, phrases: &[CnPhrase]) -> SearchTerms {
+    let mut hanzi_terms: HashSet<String> = HashSet::new();
+    let mut pinyin_terms: HashSet<String> = HashSet::new();
+    let mut english_terms: HashSet<String> = HashSet::new();
+    for phrase in phrases.iter().filter(|phrase| phrase.lookup_success) {
+        hanzi_terms.insert(phrase.entry.simp.clone());
+        hanzi_terms.insert(phrase.entry.trad.clone());
+        pinyin_terms.insert(normalize_pinyin(&phrase.entry.raw_pinyin));
+        english_terms.extend(split_english_words(&phrase.entry.defn));
+    }
+    let title_chars: Vec<char> = title.chars().collect();
+    for text in get_text_variants(phrases).iter().chain(std::iter::once(&title_chars)) {
+        hanzi_terms.extend(generate_ngrams(text));
+    }
+    english_terms.extend(split_english_words(title));
+    pinyin_terms.remove("");
+    let mut res = SearchTerms {
+        hanzi_terms: hanzi_terms.into_iter().collect(),
+        pinyin_terms: pinyin_terms.into_iter().collect(),
+        english_terms: english_terms.into_iter().collect()
+    };
+    res.hanzi_terms.sort();
+    res.pinyin_terms.sort();
+    res.english_terms.sort();
+    return res;
+}
+
+/// Returns the terms a matching document must have in its hanzi_terms: the Han bigrams of the query
+/// (or its Han chars, for single-character queries). Matches need to be confirmed using find_match_ranges.
+pub fn generate_hanzi_query_terms(q: &str) -> Vec<String> {
+    let chars: Vec<char> = q.chars().collect();
+    let bigrams: Vec<String> = chars.windows(2)
+        .filter(|w| is_han_char(w[0]) && is_han_char(w[1]))
+        .map(|w| w.iter().collect())
+        .collect();
+    let res = match bigrams.len() {
+        0 => chars.iter().filter(|c| is_han_char(**c)).map(|c| c.to_string()).collect(),
+        _ => bigrams
+    };
+    return res;
+}
+
+/// Returns the char ranges (start, end) of each match in the text of the phrases (i.e. the raw_phrases joined).
+/// Hanzi queries match in either Simplified or Traditional. Word queries match whole tokens, by pinyin (one word per token,
+/// or all words as one token) or by a word in the definition.
+pub fn find_match_ranges(query: &SearchQuery, phrases: &[CnPhrase]) -> Vec<(usize, usize)> {
+    let mut res: Vec<(usize, usize)> = Vec::new();
+    match query {
+        SearchQuery::Hanzi(q) => {
+            let q: Vec<char> = q.chars().collect();
+            let variants = get_text_variants(phrases);
+            let n = variants[0].len();
+            let mut i = 0;
+            while q.len() > 0 && i + q.len() <= n {
+                if variants.iter().any(|text| text[i..i+q.len()] == q[..]) {
+                    res.push((i, i + q.len()));
+                    i += q.len();
+                } else {
+                    i += 1;
+                }
+            }
+        },
+        SearchQuery::Latin(words) => {
+            let pinyin_words: Vec<String> = words.iter().map(|word| normalize_pinyin(word)).collect();
+            let joined_pinyin = pinyin_words.concat();
+            let mut start = 0;
+            for phrase in phrases.iter() {
+                let end = start + phrase.raw_phrase.chars().count();
+                if phrase.lookup_success {
+                    let pinyin = normalize_pinyin(&phrase.entry.raw_pinyin);
+                    let english_words = split_english_words(&phrase.entry.defn);
+                    let is_match = pinyin == joined_pinyin
+                        || pinyin_words.contains(&pinyin)
+                        || words.iter().any(|word| english_words.contains(word));
+                    if is_match {
+                        res.push((start, end));
+                    }
+                }
+                start = end;
+            }
+        }
+    }
+    return res;
+}
+
+/// Checks if the title matches the query (as a substring, or containing all of the words, other than stop words).
+pub fn is_title_match(title: &str, query: &SearchQuery) -> bool {
+    let res = match query {
+        SearchQuery::Hanzi(q) => title.contains(q.as_str()),
+        SearchQuery::Latin(words) => {
+            let title_words = split_english_words(title);
+            let query_words = split_english_words(&words.join(" "));
+            query_words.len() > 0 && query_words.iter().all(|word| title_words.contains(word))
+        }
+    };
+    return res;
+}
+
+/// Renders up to max_snippets snippets of the text around the matches (with context_chars on each side), with matches in <mark>.
+/// Matches close to each other share a snippet. The text is escaped, and newlines are shown as spaces.
+pub fn render_snippets(phrases: &[CnPhrase], ranges: &[(usize, usize)], context_chars: usize, max_snippets: usize) -> Vec<String> {
+    let text: Vec<char> = phrases.iter().flat_map(|phrase| phrase.raw_phrase.chars()).collect();
+    let mut res: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < ranges.len() && res.len() < max_snippets {
+        let snippet_start = ranges[i].0.saturating_sub(context_chars);
+        // Group the following matches that fit in the snippet (up to 4x the context)
+        let mut j = i + 1;
+        while j < ranges.len() && ranges[j].0 <= ranges[j-1].1 + context_chars && ranges[j].1 <= snippet_start + 4 * context_chars {
+            j += 1;
+        }
+        let snippet_end = usize::min(ranges[j-1].1 + context_chars, text.len());
+        let mut snippet = String::new();
+        if snippet_start > 0 {
+            snippet += "…";
+        }
+        let mut pos = snippet_start;
+        for &(start, end) in ranges[i..j].iter() {
+            snippet += &format_snippet_text(&text[pos..start]);
+            snippet += format!("<mark>{}</mark>", format_snippet_text(&text[start..end])).as_str();
+            pos = end;
+        }
+        snippet += &format_snippet_text(&text[pos..snippet_end]);
+        if snippet_end < text.len() {
+            snippet += "…";
+        }
+        res.push(snippet);
+        i = j;
+    }
+    return res;
+}
+
+/// Renders the escaped title, with Hanzi matches in <mark>.
+pub fn render_title_html(title: &str, query: &SearchQuery) -> String {
+    let res = match query {
+        SearchQuery::Hanzi(q) if q.len() > 0 => {
+            title.split(q.as_str()).map(escape_text).collect::<Vec<String>>()
+                .join(format!("<mark>{}</mark>", escape_text(q)).as_str())
+        },
+        _ => escape_text(title)
+    };
+    return res;
+}
+
+/// Returns the text (left context, phrase, right context) of the phrase at index i, for keyword-in-context lines.
+/// The context stays within the phrase's sentence, and is cut to at most max_context_chars on each side.
+pub fn extract_keyword_in_context(phrases: &[CnPhrase], i: usize, max_context_chars: usize) -> (String, String, String) {
+    const SENTENCE_END_CHARS: [char; 8] = ['。', '！', '？', '；', '!', '?', ';', '\n'];
+    let is_sentence_end = |phrase: &CnPhrase| phrase.raw_phrase.contains(&SENTENCE_END_CHARS[..]);
+    let mut left: Vec<char> = Vec::new();
+    for phrase in phrases[..i].iter().rev() {
+        if is_sentence_end(phrase) || left.len() >= max_context_chars {
+            break;
+        }
+        left.splice(0..0, phrase.raw_phrase.chars());
+    }
+    let mut right: Vec<char> = Vec::new();
+    for phrase in phrases[i+1..].iter() {
+        if right.len() >= max_context_chars {
+            break;
+        }
+        right.extend(phrase.raw_phrase.chars());
+        if is_sentence_end(phrase) {
+            break;
+        }
+    }
+    if left.len() > max_context_chars {
+        left.drain(0..left.len() - max_context_chars);
+    }
+    right.truncate(max_context_chars);
+    let format_text = |text: &[char]| -> String {
+        return text.iter().map(|c| if c.is_whitespace() { ' ' } else { *c }).collect::<String>().trim().to_string();
+    };
+    return (format_text(&left), phrases[i].raw_phrase.clone(), format_text(&right));
+}
+
+/// Removes tones, spaces and digits from pinyin (e.g. "gong1 ying4 lian4", "gōngyìngliàn" => "gongyinglian"). ü is written as v.
+pub fn normalize_pinyin(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.to_lowercase().replace("u:", "v").chars() {
+        let c = match c {
+            'ā' | 'á' | 'ǎ' | 'à' => 'a',
+            'ē' | 'é' | 'ě' | 'è' => 'e',
+            'ī' | 'í' | 'ǐ' | 'ì' => 'i',
+            'ō' | 'ó' | 'ǒ' | 'ò' => 'o',
+            'ū' | 'ú' | 'ǔ' | 'ù' => 'u',
+            'ǖ' | 'ǘ' | 'ǚ' | 'ǜ' | 'ü' => 'v',
+            c => c
+        };
+        if c.is_ascii_lowercase() {
+            res.push(c);
+        }
+    }
+    return res;
+}
+
+/// Splits English text (e.g. a CEDICT definition, or a query) into lowercase words, skipping short and common words.
+pub fn split_english_words(s: &str) -> Vec<String> {
+    const STOP_WORDS: [&str; 12] = ["an", "the", "to", "of", "in", "on", "at", "by", "for", "and", "or", "sth"];
+    let mut res: Vec<String> = Vec::new();
+    for word in s.split(|c: char| !c.is_ascii_alphanumeric()) {
+        let word = word.to_lowercase();
+        if word.len() >= 2 && !STOP_WORDS.contains(&word.as_str()) && !res.contains(&word) {
+            res.push(word);
+        }
+    }
+    return res;
+}
+
+/* Private Functions */
+/// Returns the text of the phrases as-is, in Simplified, and in Traditional (as chars, all with the same length).
+/// Phrases whose dictionary entry has a different length are kept as-is in every variant.
+fn get_text_variants(phrases: &[CnPhrase]) -> Vec<Vec<char>> {
+    let mut res: Vec<Vec<char>> = vec![Vec::new(), Vec::new(), Vec::new()];
+    for phrase in phrases.iter() {
+        let raw: Vec<char> = phrase.raw_phrase.chars().collect();
+        let simp: Vec<char> = phrase.entry.simp.chars().collect();
+        let trad: Vec<char> = phrase.entry.trad.chars().collect();
+        let use_entry = phrase.lookup_success && simp.len() == raw.len() && trad.len() == raw.len();
+        res[0].extend(raw.iter());
+        match use_entry {
+            true => {
+                res[1].extend(simp);
+                res[2].extend(trad);
+            },
+            false => {
+                res[1].extend(raw.iter());
+                res[2].extend(raw.iter());
+            }
+        }
+    }
+    return res;
+}
+
+/// Generates the Han character unigrams and bigrams of the text.
+fn generate_ngrams(text: &[char]) -> Vec<String> {
+    let mut res: Vec<String> = Vec::new();
+    for (i, c) in text.iter().enumerate() {
+        if !is_han_char(*c) {
+            continue;
+        }
+        res.push(c.to_string());
+        if let Some(next_c) = text.get(i + 1) {
+            if is_han_char(*next_c) {
+                res.push([*c, *next_c].iter().collect());
+            }
+        }
+    }
+    return res;
+}
+
+/// Escapes the text for a snippet, showing newlines as spaces.
+fn format_snippet_text(text: &[char]) -> String {
+    let text: String = text.iter().map(|c| if *c == '\n' || *c == '\r' { ' ' } else { *c }).collect();
+    return escape_text(&text);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn title_match_skips_stop_words() {
+        let query = parse_search_query("The Art of War").unwrap();
+        assert!(is_title_match("Art-of-War", &query));
+        assert_eq!(split_english_words("the art of war"), vec!["art", "war"]);
+        let stop_word_query = parse_search_query("the of").unwrap();
+        assert!(!is_title_match("the-of", &stop_word_query));
+    }
+}
@@ -0,0 +1,190 @@
+/*
+/// Module for splitting long documents into ordered sections (chapters, or pages of a maximum size) for the reader.
+*/
+
+use crate::{
+    markup::MarkupEvent,
+    subtitles::{SubtitleCue, format_timestamp}
+};
+use regex::Regex;
+
+/* Structs */
+/// A section of a plain-text document. heading is the chapter title (if the section starts with one), otherwise empty.
+#[derive(Clone, Debug, Default)]
+pub struct TextSection {
+    pub heading: String,
+    pub text: String
+}
+
+/// A section of a Markdown/HTML document. heading is the text of its first <h1>/<h2> (if it starts with one), otherwise empty.
+#[derive(Clone, Debug, Default)]
+pub struct MarkupSection {
+    pub heading: String,
+    pub events: Vec<MarkupEvent>
+}
+
+/// A section of subtitle cues. heading is the start timestamp of the first cue.
+#[derive(Clone, Debug, Default)]
+pub struct CueSection {
+    pub heading: String,
+    pub cues: Vec<SubtitleCue>
+}
+
+/* Public Functions */
+/// Splits text into sections by line. A new section starts at each (short) chapter heading line (e.g. "第十二章 归来"),
+/// or when the section would exceed max_chars. Lines longer than max_chars are kept whole. Always returns at least one section.
+pub fn split_text_into_sections(body: &str, max_chars: usize) -> Vec<TextSection> {
+    let chapter_re = chapter_heading_regex();
+    let mut res: Vec<TextSection> = Vec::new();
+    let mut curr = TextSection::default();
+    let mut curr_chars = 0;
+    let n_lines = body.matches('\n').count() + 1;
+    for (i, line) in body.split('\n').enumerate() {
+        // Keep the newlines, so the sections join back to the body
+        let line = match i + 1 < n_lines {
+            true => format!("{}\n", line),
+            false => String::from(line)
+        };
+        let line_chars = line.chars().count();
+        let is_heading = line.trim().chars().count() <= 40 && chapter_re.is_match(&line);
+        // A section with only its heading line is never split off
+        let has_content = curr.text.trim().len() > 0 && curr.text.trim() != curr.heading;
+        if has_content && (is_heading || curr_chars + line_chars > max_chars) {
+            res.push(curr);
+            curr = TextSection::default();
+            curr_chars = 0;
+        }
+        if is_heading && curr.heading.len() == 0 && curr.text.trim().len() == 0 {
+            curr.heading = String::from(line.trim());
+        }
+        curr.text += &line;
+        curr_chars += line_chars;
+    }
+    res.push(curr);
+    return res;
+}
+
+/// Splits markup into sections between top-level elements. A new section starts at each top-level <h1>/<h2>,
+/// or once the section has at least max_chars of text. Always returns at least one section.
+pub fn split_markup_into_sections(events: Vec<MarkupEvent>, max_chars: usize) -> Vec<MarkupSection> {
+    let mut res: Vec<MarkupSection> = Vec::new();
+    let mut curr = MarkupSection::default();
+    let mut curr_chars = 0;
+    let mut depth = 0;
+    let mut heading_depth: Option<usize> = None;
+    for event in events {
+        match &event {
+            MarkupEvent::Open(tag) => {
+                if depth == 0 {
+                    let is_chapter = tag == "h1" || tag == "h2";
+                    if curr_chars > 0 && (is_chapter || curr_chars >= max_chars) {
+                        res.push(curr);
+                        curr = MarkupSection::default();
+                        curr_chars = 0;
+                    }
+                    if is_chapter && curr_chars == 0 && curr.heading.len() == 0 {
+                        heading_depth = Some(depth);
+                    }
+                }
+                depth += 1;
+            },
+            MarkupEvent::Close(_) => {
+                depth = depth.saturating_sub(1);
+                if heading_depth == Some(depth) {
+                    heading_depth = None;
+                }
+            },
+            MarkupEvent::Text(text) => {
+                if depth == 0 && curr_chars >= max_chars {
+                    res.push(curr);
+                    curr = MarkupSection::default();
+                    curr_chars = 0;
+                }
+                if heading_depth.is_some() {
+                    curr.heading += text.trim();
+                }
+                curr_chars += text.chars().count();
+            }
+        }
+        curr.events.push(event);
+    }
+    res.push(curr);
+    return res;
+}
+
+/// Splits subtitle cues into sections of at most max_chars of cue text. Always returns at least one section.
+pub fn split_cues_into_sections(cues: Vec<SubtitleCue>, max_chars: usize) -> Vec<CueSection> {
+    let mut res: Vec<CueSection> = Vec::new();
+    let mut curr = CueSection::default();
+    let mut curr_chars = 0;
+    for cue in cues {
+        let cue_chars = cue.text.chars().count();
+        if curr.cues.len() > 0 && curr_chars + cue_chars > max_chars {
+            res.push(curr);
+            curr = CueSection::default();
+            curr_chars = 0;
+        }
+        if curr.cues.len() == 0 {
+            curr.heading = format_timestamp(cue.start_ms);
+        }
+        curr_chars += cue_chars;
+        curr.cues.push(cue);
+    }
+    res.push(curr);
+    return res;
+}
+
+/* Private Functions */
+/// Matches the start of a line that looks like a chapter title. Ex. "第十二章 归来", "第3回", "序章", "Chapter 4"
+fn chapter_heading_regex() -> Regex {
+    return Regex::new(r"^\s*(第[0-9０-９零〇一二三四五六七八九十百千两]+[章回节節卷部篇集]|序章|序言|楔子|引子|尾声|尾聲|后记|後記|(?i:chapter)\s+\d+)").unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_cue(start_ms: i64, text: &str) -> SubtitleCue {
+        return SubtitleCue { start_ms, end_ms: start_ms + 1000, text: String::from(text) };
+    }
+
+    #[test]
+    fn text_splits_at_max_chars() {
+        // "一二三\n" is 4 chars, so two lines fit in 8 chars exactly
+        let body = "一二三\n四五六\n七八九";
+        let sections = split_text_into_sections(body, 8);
+        assert_eq!(sections.iter().map(|s| s.text.as_str()).collect::<Vec<&str>>(), vec!["一二三\n四五六\n", "七八九"]);
+        let sections = split_text_into_sections(body, 7);
+        assert_eq!(sections.iter().map(|s| s.text.as_str()).collect::<Vec<&str>>(), vec!["一二三\n", "四五六\n七八九"]);
+        // Sections join back to the body
+        assert_eq!(sections.iter().map(|s| s.text.as_str()).collect::<String>(), body);
+        // Lines longer than max_chars are kept whole
+        assert_eq!(split_text_into_sections(body, 2)[0].text, "一二三\n");
+        assert_eq!(split_text_into_sections("", 8).len(), 1);
+    }
+
+    #[test]
+    fn text_splits_at_chapter_headings() {
+        let body = "序言\n你好\n第一章 开始\n世界";
+        let sections = split_text_into_sections(body, 1000);
+        assert_eq!(sections.iter().map(|s| s.heading.as_str()).collect::<Vec<&str>>(), vec!["序言", "第一章 开始"]);
+        assert_eq!(sections[1].text, "第一章 开始\n世界");
+    }
+
+    #[test]
+    fn markup_and_cues_split_at_boundaries() {
+        let events = vec![
+            MarkupEvent::Open(String::from("p")), MarkupEvent::Text(String::from("你好")), MarkupEvent::Close(String::from("p")),
+            MarkupEvent::Open(String::from("h2")), MarkupEvent::Text(String::from("第二章")), MarkupEvent::Close(String::from("h2")),
+            MarkupEvent::Open(String::from("p")), MarkupEvent::Text(String::from("世界")), MarkupEvent::Close(String::from("p"))
+        ];
+        let sections = split_markup_into_sections(events, 1000);
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[1].heading, "第二章");
+        assert_eq!(sections[1].events.len(), 6);
+        let cues = vec![make_cue(0, "一二"), make_cue(1000, "三四"), make_cue(2000, "五")];
+        let sections = split_cues_into_sections(cues, 4);
+        assert_eq!(sections.iter().map(|s| s.cues.len()).collect::<Vec<usize>>(), vec![2, 1]);
+        assert_eq!(sections[1].heading, format_timestamp(2000));
+    }
+}
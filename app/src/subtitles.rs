@@ -0,0 +1,140 @@
+/*
+/// Module for parsing subtitle files (.srt, .vtt, .ass/.ssa) into timed cues.
+*/
+
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+use std::error::Error;
+
+/* Structs */
+/// A single subtitle cue. Multi-line cue text is joined with spaces, with styling tags removed.
+/// Timestamps are in milliseconds (i64 since bson has no unsigned types).
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SubtitleCue {
+    pub start_ms: i64,
+    pub end_ms: i64,
+    pub text: String
+}
+
+/* Public Functions */
+/// Returns true if the file extension is a supported subtitle format.
+pub fn is_subtitle_file(filename: &str) -> bool {
+    let filename = filename.to_lowercase();
+    return [".srt", ".vtt", ".ass", ".ssa"].iter().any(|ext| filename.ends_with(ext));
+}
+
+/// Parses subtitle cues based on the file extension. Cues are sorted by start time, and empty cues are skipped.
+pub fn parse_subtitles(filename: &str, contents: &str) -> Result<Vec<SubtitleCue>, Box<dyn Error>> {
+    let contents = contents.trim_start_matches('\u{FEFF}').replace("\r\n", "\n").replace('\r', "\n");
+    let lowercase_filename = filename.to_lowercase();
+    let mut cues = match (lowercase_filename.ends_with(".ass"), lowercase_filename.ends_with(".ssa")) {
+        (false, false) => parse_srt_or_vtt(&contents),
+        _ => parse_ass(&contents)
+    };
+    cues.retain(|cue| cue.text.len() > 0);
+    cues.sort_by_key(|cue| cue.start_ms);
+    if cues.len() == 0 {
+        return Err(Box::from(format!("No subtitle cues found in {}", filename)));
+    }
+    return Ok(cues);
+}
+
+/// Formats a timestamp for display. Ex. 62500 => "01:02", 3723000 => "1:02:03"
+pub fn format_timestamp(ms: i64) -> String {
+    let total_secs = ms.max(0) / 1000;
+    let (hours, mins, secs) = (total_secs / 3600, (total_secs % 3600) / 60, total_secs % 60);
+    let res = match hours > 0 {
+        true => format!("{}:{:02}:{:02}", hours, mins, secs),
+        false => format!("{:02}:{:02}", mins, secs)
+    };
+    return res;
+}
+
+/* Private Functions */
+/// SRT and WebVTT are both blank-line delimited blocks, with a "start --> end" timing line
+/// (optionally preceded by a cue id). WebVTT header, NOTE, STYLE and REGION blocks have no timing line.
+fn parse_srt_or_vtt(contents: &str) -> Vec<SubtitleCue> {
+    let mut res = Vec::new();
+    for block in contents.split("\n\n") {
+        let lines: Vec<&str> = block.lines().collect();
+        let timing_idx = match lines.iter().position(|line| line.contains("-->")) {
+            Some(i) => i,
+            None => continue
+        };
+        let mut timing = lines[timing_idx].splitn(2, "-->");
+        let start_ms = timing.next().and_then(|s| parse_timestamp(s.trim()));
+        // WebVTT cue settings follow the end timestamp (e.g. "00:04.000 align:start")
+        let end_ms = timing.next()
+            .and_then(|s| s.split_whitespace().next())
+            .and_then(parse_timestamp);
+        if let (Some(start_ms), Some(end_ms)) = (start_ms, end_ms) {
+            let text = clean_cue_text(&lines[timing_idx+1..].join(" "));
+            res.push(SubtitleCue { start_ms, end_ms, text });
+        }
+    }
+    return res;
+}
+
+/// Parses "Dialogue:" lines in the [Events] section, using the "Format:" line to find the Start, End, and Text fields.
+/// Text is always the last field, so it can contain commas.
+fn parse_ass(contents: &str) -> Vec<SubtitleCue> {
+    let default_format = ["layer", "start", "end", "style", "name", "marginl", "marginr", "marginv", "effect", "text"];
+    let mut format: Vec<String> = default_format.iter().map(|s| String::from(*s)).collect();
+    let mut in_events = false;
+    let mut res = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_events = line.eq_ignore_ascii_case("[events]");
+            continue;
+        } else if !in_events {
+            continue;
+        }
+        if let Some(format_str) = line.strip_prefix("Format:") {
+            format = format_str.split(',').map(|s| s.trim().to_lowercase()).collect();
+        } else if let Some(dialogue_str) = line.strip_prefix("Dialogue:") {
+            let fields: Vec<&str> = dialogue_str.splitn(format.len(), ',').collect();
+            let get_field = |name: &str| format.iter().position(|f| f == name).and_then(|i| fields.get(i)).map(|s| s.trim());
+            let start_ms = get_field("start").and_then(parse_timestamp);
+            let end_ms = get_field("end").and_then(parse_timestamp);
+            if let (Some(start_ms), Some(end_ms), Some(text)) = (start_ms, end_ms, get_field("text")) {
+                res.push(SubtitleCue { start_ms, end_ms, text: clean_cue_text(text) });
+            }
+        }
+    }
+    return res;
+}
+
+/// Parses "HH:MM:SS,mmm" (SRT), "HH:MM:SS.mmm" or "MM:SS.mmm" (WebVTT), and "H:MM:SS.cc" (ASS) into milliseconds.
+fn parse_timestamp(s: &str) -> Option<i64> {
+    let s = s.replace(',', ".");
+    let mut parts: Vec<&str> = s.split(':').collect();
+    if parts.len() < 2 || parts.len() > 3 {
+        return None;
+    }
+    let secs_str = parts.pop()?;
+    let mut secs_parts = secs_str.splitn(2, '.');
+    let secs: i64 = secs_parts.next()?.parse().ok()?;
+    // Fractions are padded/truncated to 3 digits. Ex. ".5" => 500, ".05" => 50, ".0501" => 50
+    let ms: i64 = match secs_parts.next() {
+        Some(fraction) => format!("{:0<3}", fraction).get(0..3)?.parse().ok()?,
+        None => 0
+    };
+    let mins: i64 = parts.pop()?.parse().ok()?;
+    let hours: i64 = match parts.pop() {
+        Some(h) => h.parse().ok()?,
+        None => 0
+    };
+    return Some(((hours * 60 + mins) * 60 + secs) * 1000 + ms);
+}
+
+/// Removes ASS override blocks ({\i1}), HTML-style tags (<i>, <c.yellow>), WebVTT karaoke timestamps (<00:01.500>),
+/// and ASS line breaks (\N, \n, \h).
+fn clean_cue_text(s: &str) -> String {
+    let ass_override_re = Regex::new(r"\{[^}]*\}").unwrap();
+    let tag_re = Regex::new(r"</?[a-zA-Z][^>]*>|<[\d:.]+>").unwrap();
+    let res = ass_override_re.replace_all(s, "");
+    let res = tag_re.replace_all(&res, "");
+    let res = res.replace("\\N", " ").replace("\\n", " ").replace("\\h", " ");
+    return res.split_whitespace().collect::<Vec<&str>>().join(" ");
+}
@@ -21,7 +21,8 @@ let read_span = () => {
     if (span_index < n) {
         let e = spans[span_index];
         e.focus();
-        let phrase = e.innerText.split('\n')[1].replace('\t', '');
+        // Numerals store their spoken reading (e.g. 2021年 => 二零二一年)
+        let phrase = e.dataset.reading || e.innerText.split('\n')[1].replace('\t', '');
         window.location.hash = `~${phrase}`;
         span_index += 1;
     } else if (span_index == n) {
@@ -4,6 +4,8 @@ let cn_phonetics_pinyin = (e) => { e.value = "pinyin"; }
 let cn_phonetics_zhuyin = (e) => { e.value = "zhuyin"; }
 let cn_type_simp = (e) => { e.value = "simp"; }
 let cn_type_trad = (e) => { e.value = "trad"; }
+let cn_granularity_word = (e) => { e.value = "word"; }
+let cn_granularity_character = (e) => { e.value = "character"; }
 let setType = (type_string) => {
     if (type_string === 'pinyin') {
         document.getElementById('phonetic-setting').innerHTML = "Render Pinyin";
@@ -17,6 +19,12 @@ let setType = (type_string) => {
     } else if (type_string === 'trad') {
         document.getElementById('char-setting').innerHTML = "Render Traditional";
         document.getElementsByName('cn_type').forEach(cn_type_trad);
+    } else if (type_string === 'word') {
+        document.getElementById('granularity-setting').innerHTML = "Split by Word";
+        document.getElementsByName('cn_granularity').forEach(cn_granularity_word);
+    } else if (type_string === 'character') {
+        document.getElementById('granularity-setting').innerHTML = "Split by Character";
+        document.getElementsByName('cn_granularity').forEach(cn_granularity_character);
     }
 }
 let processLuckyButton = (id) => {
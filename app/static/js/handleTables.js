@@ -1,7 +1,9 @@
 /// Load DataTables
 $(document).ready(() => { 
-    $('#doc-table').DataTable();
+    $('#doc-table').DataTable({ order: [] }); // keep the docs grouped by collection
     $('#vocab-table').DataTable();
+    $('#collection-table').DataTable();
+    $('#public-doc-table').DataTable({ order: [] }); // newest first
 });
 
 /// Remove characters that cause display issues
@@ -24,21 +26,27 @@ let perform_download = (csv, anchor_id, filename) => {
 }
 
 /// Formats GET request to /api/docs-to-csv and downloads as .csv
-let download_doc_table_as_csv = (anchor_id) => {
+/// query (optional) filters the docs, e.g. '?collection_id=...'
+let download_doc_table_as_csv = (anchor_id, query = '') => {
     let xhr = new XMLHttpRequest();
-    xhr.open("GET", "/api/get-all-user-items");
+    xhr.open("GET", "/api/get-all-user-items" + query);
     xhr.onreadystatechange = () => {
         if (xhr.readyState == 4 && xhr.status == 200) {
-            let csv_header = 'title,body,source,created_on\n';
+            let csv_header = 'doc_id,title,body,source,collection,tags,created_on\n';
             let csv_body = '';
             let json = JSON.parse(xhr.response);
             let json_list = json.doc_list;
+            let collection_paths = {};
+            json.collections.forEach((c) => { collection_paths[c.collection_id] = c.path; });
             for (i = 0; i < json_list.length; i++) {
+                let doc_id = escape_formatting(json_list[i].doc_id);
                 let title = escape_formatting(json_list[i].title);
                 let body = escape_formatting(json_list[i].body);
                 let source = escape_formatting(json_list[i].source);
+                let collection = escape_formatting(collection_paths[json_list[i].collection_id] || '');
+                let tags = escape_formatting((json_list[i].tags || []).join(';'));
                 let created_on = escape_formatting(json_list[i].created_on);
-                csv_body += `${title},${body},${source},${created_on}\n`;
+                csv_body += `${doc_id},${title},${body},${source},${collection},${tags},${created_on}\n`;
             }
             let csv = csv_header + csv_body;
             perform_download(csv, anchor_id, 'duguo-documents.csv');
@@ -48,12 +56,13 @@ let download_doc_table_as_csv = (anchor_id) => {
 }
 
 /// Formats GET request to /api/vocab-to-csv and downloads as .csv
-let download_vocab_table_as_csv = (anchor_id) => {
+/// query (optional) filters the vocab by doc, e.g. '?collection_id=...'
+let download_vocab_table_as_csv = (anchor_id, query = '') => {
     let xhr = new XMLHttpRequest();
-    xhr.open("GET", "/api/get-all-user-items");
+    xhr.open("GET", "/api/get-all-user-items" + query);
     xhr.onreadystatechange = () => {
         if (xhr.readyState == 4 && xhr.status == 200) {
-            let csv_header = 'phrase,phonetics,definition,radical_map,from_doc_title,saved_on,phrase_html\n';
+            let csv_header = 'phrase,phonetics,definition,radical_map,from_doc_title,from_doc_id,saved_on,phrase_html\n';
             let csv_body = '';
             let json = JSON.parse(xhr.response);
             let json_list = json.vocab_list;
@@ -63,13 +72,46 @@ let download_vocab_table_as_csv = (anchor_id) => {
                 let def = escape_formatting(json_list[i].def);
                 let radical_map = escape_formatting(json_list[i].radical_map);
                 let from_doc = escape_formatting(json_list[i].from_doc_title);
+                let from_doc_id = escape_formatting(json_list[i].from_doc_id || '');
                 let created_on = escape_formatting(json_list[i].created_on);
                 let phrase_html = escape_formatting(json_list[i].phrase_html);
-                csv_body += `${phrase},${phonetics},${def},${radical_map},${from_doc},${created_on},${phrase_html}\n`;
+                csv_body += `${phrase},${phonetics},${def},${radical_map},${from_doc},${from_doc_id},${created_on},${phrase_html}\n`;
             }
             let csv = csv_header + csv_body;
             perform_download(csv, anchor_id, 'duguo-vocab.csv');
         }
     }
     xhr.send();
-}
\ No newline at end of file
+}
+
+/// Searches the user's docs using /api/search/docs, then lists the results (with snippets) in the specified element
+/// Titles and snippets are escaped server-side, with matches in <mark>
+let search_docs = (event, results_id) => {
+    event.preventDefault();
+    let q = new FormData(event.target).get('q');
+    let results_div = document.getElementById(results_id);
+    if (q.trim() == '') {
+        results_div.innerHTML = '';
+        return;
+    }
+    let xhr = new XMLHttpRequest();
+    xhr.open("GET", `/api/search/docs?q=${encodeURIComponent(q)}`);
+    xhr.onreadystatechange = () => {
+        if (xhr.readyState == 4 && xhr.status == 200) {
+            let json = JSON.parse(xhr.response);
+            if (json.error !== undefined) {
+                results_div.textContent = json.error;
+                return;
+            }
+            let html = `<p class="text-muted">${json.results.length} document(s) found</p>`;
+            json.results.forEach((res) => {
+                html += `<div class="mb-3"><a href="/doc/${encodeURIComponent(res.doc_id)}">${res.title_html}</a>`;
+                html += ` <span class="badge bg-secondary">${res.n_matches}</span>`;
+                res.snippets.forEach((snippet) => { html += `<br/><small>${snippet}</small>`; });
+                html += '</div>';
+            });
+            results_div.innerHTML = html;
+        }
+    }
+    xhr.send();
+}
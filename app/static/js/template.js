@@ -71,6 +71,14 @@ let postUserSetting = (hash_string) => {
                 document.getElementById('char-setting').innerHTML = "Use Simplified";
             } else if (hash_string === 'trad') {
                 document.getElementById('char-setting').innerHTML = "Use Traditional";
+            } else if (hash_string === 'word') {
+                document.getElementById('granularity-setting').innerHTML = "Split by Word";
+            } else if (hash_string === 'character') {
+                document.getElementById('granularity-setting').innerHTML = "Split by Character";
+            } else if (hash_string === 'mainland') {
+                document.getElementById('region-setting').innerHTML = "Mainland Usage";
+            } else if (hash_string === 'taiwan') {
+                document.getElementById('region-setting').innerHTML = "Taiwan Usage";
             }
         }
         window.location.reload();
@@ -81,6 +89,13 @@ let postUserSetting = (hash_string) => {
     xhr.send(params);
 }
 
+/// Tracks the last phrase opened, so saved vocab can record its subtitle cue (if any)
+let last_phrase_span = null;
+document.addEventListener('focusin', (event) => {
+    let span = event.target.closest ? event.target.closest('span[data-bs-content]') : null;
+    if (span) { last_phrase_span = span; }
+});
+
 /**
  * Sends POST request to /api/upload-vocab (defined in users.rs).
  * @param {String} hash_string Phrase uid (currently: simplified+raw_pinyin)
@@ -91,11 +106,19 @@ let postNewVocab = (hash_string) => {
     xhr.setRequestHeader("Content-type", "application/x-www-form-urlencoded");
     let from_sandbox = window.location.href.includes('/sandbox/');
     let params = `phrase_uid=${hash_string}&from_sandbox=${from_sandbox}`;
+    let doc_info = document.getElementById('doc-info');
     if (from_sandbox) {
         let doc_uuid = window.location.href.split('/').pop();
         params += `&from_doc_title=${doc_uuid}`;
+    } else if (doc_info.dataset.shareId) {
+        // Shared docs belong to another user, so the vocab links to the share link instead
+        params += `&from_doc_title=${encodeURIComponent(doc_info.dataset.docTitle)}&from_share_id=${encodeURIComponent(doc_info.dataset.shareId)}`;
     } else {
-        params += `&from_doc_title=${document.title}`;
+        params += `&from_doc_title=${encodeURIComponent(doc_info.dataset.docTitle)}&from_doc_id=${doc_info.dataset.docId}`;
+    }
+    let cue = last_phrase_span ? last_phrase_span.closest('[data-cue-start]') : null;
+    if (cue) {
+        params += `&cue_timestamp=${encodeURIComponent(cue.dataset.cueStart)}`;
     }
     xhr.onload = () => {
         if (xhr.status == 202) {
@@ -3,8 +3,27 @@ from pypinyin import pinyin as pfmt
 from pypinyin import Style
 import pandas as pd
 import redis
-from config import DB_NAME, DB_URI, USER_COLL_NAME, USER_DOC_COLL_NAME, USER_VOCAB_COLL_NAME, USER_VOCAB_LIST_COLL_NAME
+from config import DB_NAME, DB_URI, USER_COLL_NAME, SANDBOX_COLL_NAME, SANDBOX_QUOTA_COLL_NAME, USER_DOC_COLL_NAME, USER_DOC_PAGE_COLL_NAME, USER_VOCAB_COLL_NAME, USER_VOCAB_LIST_COLL_NAME, COLLECTION_COLL_NAME, DOC_SEARCH_COLL_NAME, VOCAB_OCCURRENCE_COLL_NAME, READING_PROGRESS_COLL_NAME, ANNOTATION_COLL_NAME
 from config import REDIS_HOST, REDIS_PORT, REDIS_PASSWORD, SORTED_CEDICT_CSV_PATH, RADICALS_OUTPUT_PATH
+from config import TW_PHRASES_PATH, TW_PHRASES_TRAD_KEY, TW_PHRASES_SIMP_KEY
+
+def create_index(coll, keys, unique=False):
+    """
+    Creates the index. Errors are printed instead of raised, so one failed index doesn't skip the rest
+    """
+    try:
+        coll.create_index(keys, unique=unique)
+    except Exception as e:
+        print(f'Skipping index {keys} on {coll.name}: {e}')
+
+def drop_index(coll, name):
+    """
+    Drops the index if it exists
+    """
+    try:
+        coll.drop_index(name)
+    except Exception:
+        pass
 
 def init_mongodb():
     """
@@ -14,26 +33,65 @@ def init_mongodb():
     db = client[DB_NAME]
     colls = {
         'user': db[USER_COLL_NAME],
+        'sandbox': db[SANDBOX_COLL_NAME],
+        'sandbox_quota': db[SANDBOX_QUOTA_COLL_NAME],
         'user_docs': db[USER_DOC_COLL_NAME],
+        'user_doc_pages': db[USER_DOC_PAGE_COLL_NAME],
         'user_vocab': db[USER_VOCAB_COLL_NAME],
-        'user_vocab_list': db[USER_VOCAB_LIST_COLL_NAME]
+        'user_vocab_list': db[USER_VOCAB_LIST_COLL_NAME],
+        'collections': db[COLLECTION_COLL_NAME],
+        'doc_search': db[DOC_SEARCH_COLL_NAME],
+        'vocab_occurrences': db[VOCAB_OCCURRENCE_COLL_NAME],
+        'reading_progress': db[READING_PROGRESS_COLL_NAME],
+        'annotations': db[ANNOTATION_COLL_NAME]
     }
     # User Indices
-    colls['user'].create_index([ ("username", 1)], unique=True)
-    colls['user'].create_index([ ("email", 1)], unique=True)
-    colls['user'].create_index([ ("username", 1), ("email", 1)], unique=True)
+    create_index(colls['user'], [ ("username", 1)], unique=True)
+    create_index(colls['user'], [ ("email", 1)], unique=True)
+    create_index(colls['user'], [ ("username", 1), ("email", 1)], unique=True)
+    # Sandbox Doc Indices (expired docs are deleted by SandboxDoc::run_sweeper)
+    create_index(colls['sandbox'], [ ("doc_id", 1)])
+    create_index(colls['sandbox'], [ ("created_on", 1)])
+    # Sandbox Quota Index (one entry per upload, refer to SandboxDoc::try_reserve_quota)
+    create_index(colls['sandbox_quota'], [ ("client_ip_hash", 1), ("created_on", 1)])
     # User Doc Indices
-    colls['user_docs'].create_index([ ("username", 1)])
-    colls['user_docs'].create_index([ ("username", 1), ("cn_type", 1), ("cn_phonetics", 1)])
-    colls['user_docs'].create_index([ ("username", 1), ("title", 1), ("cn_type", 1), ("cn_phonetics", 1)], unique=True)
+    create_index(colls['user_docs'], [ ("username", 1)])
+    create_index(colls['user_docs'], [ ("doc_id", 1)], unique=True)
+    # Docs used to be saved per-setting. The unique (username, title) index is created by UserDoc::migrate_per_setting_docs once the copies are merged
+    drop_index(colls['user_docs'], 'username_1_cn_type_1_cn_phonetics_1')
+    drop_index(colls['user_docs'], 'username_1_title_1_cn_type_1_cn_phonetics_1')
+    create_index(colls['user_docs'], [ ("username", 1), ("collection_id", 1)])
+    create_index(colls['user_docs'], [ ("username", 1), ("tags", 1)])
+    create_index(colls['user_docs'], [ ("share_id", 1)])
+    create_index(colls['user_docs'], [ ("username", 1), ("visibility", 1)])
+    # User Doc Page Index
+    create_index(colls['user_doc_pages'], [ ("doc_id", 1), ("page", 1)], unique=True)
     # User Vocab Indices
-    colls['user_vocab'].create_index([ ("username", 1)])
-    colls['user_vocab'].create_index([ ("username", 1), ("phrase", 1)])
-    colls['user_vocab'].create_index([ ("username", 1), ("cn_type", 1), ("cn_phonetics", 1)])
-    colls['user_vocab'].create_index([ ("username", 1), ("phrase", 1), ("cn_type", 1), ("cn_phonetics", 1)], unique=True)
+    create_index(colls['user_vocab'], [ ("username", 1)])
+    create_index(colls['user_vocab'], [ ("username", 1), ("phrase", 1)])
+    create_index(colls['user_vocab'], [ ("username", 1), ("cn_type", 1), ("cn_phonetics", 1)])
+    create_index(colls['user_vocab'], [ ("username", 1), ("phrase", 1), ("cn_type", 1), ("cn_phonetics", 1)], unique=True)
     # User Vocab List Index
-    colls['user_vocab_list'].create_index([ ("username", 1) ])
-    colls['user_vocab_list'].create_index([ ("username", 1), ("cn_type", 1) ], unique=True)
+    create_index(colls['user_vocab_list'], [ ("username", 1) ])
+    create_index(colls['user_vocab_list'], [ ("username", 1), ("cn_type", 1) ], unique=True)
+    # Collection Indices
+    create_index(colls['collections'], [ ("username", 1)])
+    create_index(colls['collections'], [ ("collection_id", 1)], unique=True)
+    # Doc Search Indices
+    create_index(colls['doc_search'], [ ("doc_id", 1)], unique=True)
+    create_index(colls['doc_search'], [ ("username", 1), ("hanzi_terms", 1)])
+    create_index(colls['doc_search'], [ ("username", 1), ("pinyin_terms", 1)])
+    create_index(colls['doc_search'], [ ("username", 1), ("english_terms", 1)])
+    # Vocab Occurrence Indices
+    create_index(colls['vocab_occurrences'], [ ("username", 1), ("uid", 1)])
+    create_index(colls['vocab_occurrences'], [ ("doc_id", 1)])
+    # Reading Progress Indices
+    create_index(colls['reading_progress'], [ ("username", 1), ("doc_id", 1)], unique=True)
+    create_index(colls['reading_progress'], [ ("doc_id", 1)])
+    # Annotation Indices
+    create_index(colls['annotations'], [ ("annotation_id", 1)], unique=True)
+    create_index(colls['annotations'], [ ("username", 1), ("doc_id", 1)])
+    create_index(colls['annotations'], [ ("doc_id", 1)])
     return
 
 def convert_digits_to_chars(s):
@@ -44,8 +102,7 @@ def convert_digits_to_chars(s):
         '1': '一', '2': '二', '3': '三',
         '4': '四', '5': '五', '6': '六',
         '7': '七', '8': '八', '9': '九',
-        '0': '零',
-        '%': '啪', # FYI: same pinyin, not actually the same word
+        '0': '零', # '%' is left as-is, numerals.rs reads it with the preceding number (百分之...)
     }
     for k, v in repl_dict.items():
         s = s.replace(k, v)
@@ -113,6 +170,18 @@ def load_cedict(conn):
             assert(conn.hset(uid, k, entry[k]) == 1)
     return
 
+def load_tw_phrases(conn):
+    """
+    Loads the Mainland -> Taiwan regional phrase table into Redis hashes (keyed by the Mainland Simplified phrase)
+    """
+    print(f'Loading regional phrases to Redis from {TW_PHRASES_PATH}')
+    phrase_df = pd.read_csv(TW_PHRASES_PATH)
+    for _, row in phrase_df.iterrows():
+        mainland_simp, taiwan_trad, taiwan_simp = row
+        conn.hset(TW_PHRASES_TRAD_KEY, mainland_simp, taiwan_trad)
+        conn.hset(TW_PHRASES_SIMP_KEY, mainland_simp, taiwan_simp)
+    return
+
 if __name__ == '__main__':
     try:
         init_mongodb()
@@ -132,4 +201,6 @@ if __name__ == '__main__':
     if size > 110000:
         print('Redis has >110k documents - thus assuming CEDICT is loaded, skipping operation...')
     else:
-        load_cedict(conn)
\ No newline at end of file
+        load_cedict(conn)
+    # Phrase table is small, so always reload to pick-up changes
+    load_tw_phrases(conn)
\ No newline at end of file
@@ -6,11 +6,14 @@ import errno
 import selectors
 import types
 import time
+import unicodedata
+from collections import deque
+from concurrent.futures import ThreadPoolExecutor
 import pandas as pd
 from pypinyin import pinyin as pfmt
 from pypinyin import Style
 import opencc
-from config import TOKENIZER_HOST, TOKENIZER_PORT, MAX_BUF, SORTED_CEDICT_CSV_PATH
+from config import TOKENIZER_HOST, TOKENIZER_PORT, MAX_BUF, HEADER_BYTES, SORTED_CEDICT_CSV_PATH
 
 # NLP import from: https://spacy.io/models/zh
 nlp = spacy.load("zh_core_web_sm")
@@ -23,6 +26,9 @@ trad_converter = opencc.OpenCC('t2s.json')
 sel = selectors.DefaultSelector()
 IPV4 = socket.AF_INET
 TCP = socket.SOCK_STREAM
+# Tokenizing is CPU-bound, so it runs on a worker thread to keep the selector loop responsive (e.g. to other connections)
+# A single worker keeps spaCy calls sequential, and results are returned per-connection in the order received
+tokenize_executor = ThreadPoolExecutor(max_workers=1)
 
 # Create CEDICT_DF, CEDICT_SET (used for more accurate pinyin lookup)
 CEDICT_DF = pd.read_csv(SORTED_CEDICT_CSV_PATH)
@@ -30,12 +36,16 @@ CEDICT_SET = set(CEDICT_DF.loc[:, 'simp'])
 CEDICT_DF['raw_pinyin_lower'] = CEDICT_DF.loc[:, 'raw_pinyin'].apply(str.lower)
 CEDICT_DF.set_index(['simp', 'raw_pinyin_lower'], inplace=True)
 
-# Chinese chars use 3 bytes, so a phrase with Chinese chars will return false.
-# Ex. len('京报') = 2
-#     len(bytes('京报', 'utf-8')) = 6
-entire_phrase_is_english = lambda p: len(p) == len(bytes(p, 'utf-8'))
-entire_phrase_is_chinese = lambda p: (3 * len(p)) == len(bytes(p, 'utf-8'))
+# Classify by Unicode character name rather than UTF-8 byte length (Extension B chars use 4 bytes, accented Latin uses 2)
+# This covers all CJK Unified/Compatibility Ideograph blocks known to unicodedata, plus 々 and 〇
+HAN_NAME_PREFIXES = ('CJK UNIFIED IDEOGRAPH', 'CJK COMPATIBILITY IDEOGRAPH', 'IDEOGRAPHIC ITERATION MARK', 'IDEOGRAPHIC NUMBER ZERO')
+is_han_char = lambda c: unicodedata.name(c, '').startswith(HAN_NAME_PREFIXES)
+entire_phrase_is_english = lambda p: not any(is_han_char(c) for c in p)
+entire_phrase_is_chinese = lambda p: len(p) > 0 and all(is_han_char(c) for c in p)
 flatten_list = lambda l: [i for j in l for i in j] # [[a], [b], [c]] => [a, b, c]
+# Erhua (儿化) phrases are often missing from CEDICT, so they are kept whole if the base word is in CEDICT (e.g. 一点儿 => 一点)
+ERHUA_CHARS = {'儿', '兒'}
+is_erhua_phrase = lambda p: len(p) > 1 and p[-1] in ERHUA_CHARS and p[-2] not in ERHUA_CHARS and p not in CEDICT_SET and p[:-1] in CEDICT_SET
 
 def break_down_large_token_into_subtoken_list(t):
     """
@@ -62,6 +72,8 @@ def tokenize_str(s):
     # Convert to Simplified, then tokenize
     s = trad_converter.convert(s)
     s = s.replace(' ', '') # remove whitespace (large whitespace is inconsistently tokenized)
+    if len(s) == 0:
+        return ''
     tokens = tokenizer(s)
     # Get NER component as set (if any)
     token_entities = nlp(' '.join([str(t) for t in tokens])).ents
@@ -71,7 +83,7 @@ def tokenize_str(s):
     j = 0
     for i in range(len(tokens)):
         t = str(tokens[i])
-        if t in CEDICT_SET or not entire_phrase_is_chinese(t):
+        if t in CEDICT_SET or is_erhua_phrase(t) or not entire_phrase_is_chinese(t):
             str_tokens[j] = t
             j += 1
         else:
@@ -82,6 +94,14 @@ def tokenize_str(s):
             j += n_st
     while str_tokens[-1] == '':
         str_tokens.pop()
+    # Merge a standalone 儿 into the previous token when the erhua form is in CEDICT (e.g. 一点, 儿 => 一点儿)
+    merged_tokens = []
+    for t in str_tokens:
+        if t in ERHUA_CHARS and len(merged_tokens) > 0 and merged_tokens[-1] + t in CEDICT_SET:
+            merged_tokens[-1] += t
+        else:
+            merged_tokens.append(t)
+    str_tokens = merged_tokens
     # Handle special characters to match tokenizer output
     # for special characters within an alphanumeric phrase, tokenizer splits it but pfmt doesn't
     n_pinyin  = len(s)
@@ -127,11 +147,37 @@ def tokenize_str(s):
                     cedict_res_df.sort_values(acending=phrase in token_entities, inplace=True)
                     raw_pinyin = cedict_res_df.raw_pinyin.iloc[0]
             except:
+                # For erhua, the 儿 is unstressed and merged into the previous syllable (CEDICT uses r5)
+                if is_erhua_phrase(phrase):
+                    pypinyin_list[-1] = 'r5'
                 raw_pinyin = ' '.join(pypinyin_list)
         delimited_list[i] = f"{phrase}`{raw_pinyin}"
     delimited_str = '$'.join(delimited_list)
     return delimited_str
 
+def write_frame(payload):
+    """
+    Prepends the HEADER_BYTES header (payload size as ASCII digits, space-padded) to the payload bytes
+    """
+    header = bytes(str(len(payload)), 'utf-8')
+    header += b' ' * (HEADER_BYTES - len(header))
+    return header + payload
+
+def read_frame(buf):
+    """
+    Given the received bytes, returns (payload, remaining bytes) for the first complete frame,
+        or (None, buf) if the frame hasn't been fully received yet.
+    Raises ValueError for a malformed header or a frame larger than MAX_BUF
+    """
+    if len(buf) < HEADER_BYTES:
+        return None, buf
+    n_bytes = int(buf[:HEADER_BYTES].decode('utf-8').strip())
+    if n_bytes > MAX_BUF:
+        raise ValueError(f'frame of {n_bytes} bytes is larger than MAX_BUF')
+    if len(buf) < HEADER_BYTES + n_bytes:
+        return None, buf
+    return buf[HEADER_BYTES:HEADER_BYTES+n_bytes], buf[HEADER_BYTES+n_bytes:]
+
 if __name__ == '__main__':
     # Multi-threaded connections
     print("Starting socket server...")
@@ -146,7 +192,7 @@ if __name__ == '__main__':
         conn, addr = sock.accept()
         print(f'accepted connection from: {addr}')
         conn.setblocking(False)
-        data = types.SimpleNamespace(addr=addr, inb=b'', outb=b'')
+        data = types.SimpleNamespace(addr=addr, inb=b'', outb=b'', pending=deque())
         events = selectors.EVENT_READ | selectors.EVENT_WRITE
         sel.register(conn, events, data=data)
 
@@ -157,24 +203,45 @@ if __name__ == '__main__':
             try:
                 recv_data = sock.recv(MAX_BUF)  # Ready to read
                 if recv_data:
-                    # run NLP parser, then send results back
-                    recv_data = tokenize_str(str(recv_data ,'utf-8'))
-                    data.outb += bytes(recv_data, 'utf-8')
+                    # A chunk can span multiple recv() calls, so buffer until each frame is complete.
+                    # Complete frames are queued for the worker thread (results are sent back on EVENT_WRITE)
+                    data.inb += recv_data
+                    frame, data.inb = read_frame(data.inb)
+                    while frame is not None:
+                        data.pending.append(tokenize_executor.submit(tokenize_str, str(frame, 'utf-8')))
+                        frame, data.inb = read_frame(data.inb)
                 else:
                     print(f'closing connection to: {data.addr}')
                     sel.unregister(sock)
                     sock.close()
+                    return
+            except ValueError as e:
+                print(f'closing connection to {data.addr} due to invalid frame: {e}')
+                sel.unregister(sock)
+                sock.close()
+                return
             except SocketError as e:
                 if e.errno != errno.ECONNRESET:
                     raise e
                 print(f'connection to {data.addr} was reset by sender')
         if mask & selectors.EVENT_WRITE:
+            # Only finished results at the front are sent, so results stay in the order the frames were received
+            while data.pending and data.pending[0].done():
+                try:
+                    tokenized_str = data.pending.popleft().result()
+                except Exception as e:
+                    print(f'closing connection to {data.addr} due to tokenizer error: {e}')
+                    sel.unregister(sock)
+                    sock.close()
+                    return
+                data.outb += write_frame(bytes(tokenized_str, 'utf-8'))
             if data.outb:
-                header = bytes(str(len(data.outb)), 'utf-8')
-                header += b' ' * (64 - len(header)) # 64-byte header
-                sock.sendall(header)
-                sock.sendall(data.outb)  # Ready to write
-                data.outb = []
+                # Non-blocking socket, so send what fits and keep the rest for the next EVENT_WRITE
+                try:
+                    n_sent = sock.send(data.outb)  # Ready to write
+                    data.outb = data.outb[n_sent:]
+                except BlockingIOError:
+                    pass
 
     while True:
         events = sel.select(timeout=None)